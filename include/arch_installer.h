@@ -0,0 +1,54 @@
+/*
+ * C interface to arch-installer, provided by libarch_installer.so.
+ *
+ * Functions taking a `char **result` store a JSON document there: on success
+ * the same report `arch-installer --json` prints for the command, on failure
+ * {"error": "...", "causes": ["..."], "kind": "...", "details": {...}}. They
+ * return 0 on success and 1 on failure. Free each result with arch_installer_free(). `result` may be NULL
+ * if the document is not needed.
+ *
+ * Strings are UTF-8 and NUL-terminated. Nothing is printed to stdout and no
+ * confirmation is ever asked for.
+ */
+
+#ifndef ARCH_INSTALLER_H
+#define ARCH_INSTALLER_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Optional settings, mirroring --root, --user and --state-dir. Call them
+ * before arch_installer_init(); each takes effect once per process. */
+int arch_installer_set_root(const char *root);
+void arch_installer_set_user_mode(int user);
+int arch_installer_set_state_dir(const char *dir);
+
+/* Loads the config file. Call once before anything below. */
+int arch_installer_init(void);
+
+/* Installs a package file or URL into `prefix` (NULL for /usr/local, which
+ * in user mode means ~/.local, as with --prefix). `options` is NULL or a JSON object such as {"relocate": true, "stow": false}
+ * with the long names of the install options. */
+int arch_installer_install(const char *package, const char *prefix, const char *options, char **result);
+
+/* Uninstalls `package` from `prefix`, or from the only prefix it is in when
+ * `prefix` is NULL. */
+int arch_installer_uninstall(const char *package, const char *prefix, char **result);
+
+/* Lists installed packages, only those in `prefix` unless it is NULL. */
+int arch_installer_list(const char *prefix, char **result);
+
+/* Installed packages whose name or description contains `query`. */
+int arch_installer_search(const char *query, char **result);
+
+/* The package providing `command` on PATH. */
+int arch_installer_which(const char *command, char **result);
+
+void arch_installer_free(char *result);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ARCH_INSTALLER_H */
@@ -0,0 +1,153 @@
+use crate::archive::pkginfo_values;
+use anyhow::{Context, Result, bail};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `desc` sections in the order pacman writes them, with the `.PKGINFO` key
+/// each one is filled from.
+const DESC_FIELDS: [(&str, &str); 15] = [
+    ("NAME", "pkgname"),
+    ("VERSION", "pkgver"),
+    ("BASE", "pkgbase"),
+    ("DESC", "pkgdesc"),
+    ("URL", "url"),
+    ("ARCH", "arch"),
+    ("BUILDDATE", "builddate"),
+    ("PACKAGER", "packager"),
+    ("SIZE", "size"),
+    ("GROUPS", "group"),
+    ("LICENSE", "license"),
+    ("REPLACES", "replaces"),
+    ("DEPENDS", "depend"),
+    ("OPTDEPENDS", "optdepend"),
+    ("PROVIDES", "provides"),
+];
+
+/// The entry a package would get in pacman's local database under `root`.
+/// Fails if pacman is not set up there or already knows this package.
+pub fn entry_dir(root: &Path, pkginfo: &str) -> Result<PathBuf> {
+    let db_dir = root.join("var/lib/pacman/local");
+    if !db_dir.is_dir() {
+        bail!(
+            "pacman database not found at {}; --register-alpm only works on Arch-based systems",
+            db_dir.display()
+        );
+    }
+    let name = pkginfo_values(pkginfo, "pkgname").first().copied().unwrap_or_default();
+    let version = pkginfo_values(pkginfo, "pkgver").first().copied().unwrap_or_default();
+    if name.is_empty() || version.is_empty() {
+        bail!(".PKGINFO lacks pkgname or pkgver, cannot register the package with pacman");
+    }
+    if let Some(existing) = fs::read_dir(&db_dir)?
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find(|entry| entry.rsplitn(3, '-').nth(2) == Some(name))
+    {
+        bail!("pacman already has {} installed as {}", name, existing);
+    }
+    Ok(db_dir.join(format!("{}-{}", name, version)))
+}
+
+/// Writes `desc`, `files` and `mtree` for the installed `files` (absolute
+/// paths as seen from `root`) into `entry`.
+pub fn register(entry: &Path, root: &Path, pkginfo: &str, files: &[PathBuf]) -> Result<()> {
+    fs::create_dir_all(entry).context(format!("Failed to create {}", entry.display()))?;
+    let installed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut desc = String::new();
+    for (section, key) in DESC_FIELDS {
+        let values = pkginfo_values(pkginfo, key);
+        if !values.is_empty() {
+            desc.push_str(&format!("%{}%\n{}\n\n", section, values.join("\n")));
+        }
+        if section == "BUILDDATE" {
+            desc.push_str(&format!("%INSTALLDATE%\n{}\n\n", installed_at));
+        }
+        if section == "LICENSE" {
+            desc.push_str("%VALIDATION%\nnone\n\n");
+        }
+    }
+    fs::write(entry.join("desc"), desc)?;
+
+    let mut paths = BTreeSet::new();
+    for file in files {
+        let relative = file.strip_prefix("/").unwrap_or(file);
+        for dir in relative.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                paths.insert((dir.to_path_buf(), true));
+            }
+        }
+        paths.insert((relative.to_path_buf(), false));
+    }
+    let mut listing = String::from("%FILES%\n");
+    for (path, is_dir) in &paths {
+        listing.push_str(&path.to_string_lossy());
+        listing.push_str(if *is_dir { "/\n" } else { "\n" });
+    }
+    listing.push('\n');
+    fs::write(entry.join("files"), listing)?;
+
+    let mut mtree = String::from(MTREE_HEADER);
+    for (path, _) in &paths {
+        mtree.push_str(&mtree_line(path, &root.join(path), true)?);
+    }
+    let mut encoder = GzEncoder::new(fs::File::create(entry.join("mtree"))?, Compression::default());
+    encoder.write_all(mtree.as_bytes())?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// The start of an mtree listing, with the defaults its lines leave out.
+pub(crate) const MTREE_HEADER: &str = "#mtree\n/set type=file uid=0 gid=0 mode=644\n";
+
+/// The mtree line for the file at `full`, listed as `relative`, in the form
+/// pacman writes: with owners only when `owners` is set and they are not
+/// root, and digests for regular files.
+pub(crate) fn mtree_line(relative: &Path, full: &Path, owners: bool) -> Result<String> {
+    let meta = full.symlink_metadata().context(format!("Failed to inspect {}", full.display()))?;
+    let mut line = format!(
+        "./{} time={}.0 mode={:o}",
+        escape(&relative.to_string_lossy()),
+        meta.mtime(),
+        meta.permissions().mode() & 0o7777
+    );
+    if owners && (meta.uid() != 0 || meta.gid() != 0) {
+        line.push_str(&format!(" uid={} gid={}", meta.uid(), meta.gid()));
+    }
+    if meta.is_dir() {
+        line.push_str(" type=dir");
+    } else if meta.file_type().is_symlink() {
+        let target = fs::read_link(full)?;
+        line.push_str(&format!(" type=link link={}", escape(&target.to_string_lossy())));
+    } else {
+        let data = fs::read(full)?;
+        line.push_str(&format!(
+            " size={} md5digest={:x} sha256digest={:x}",
+            meta.len(),
+            Md5::digest(&data),
+            Sha256::digest(&data)
+        ));
+    }
+    line.push('\n');
+    Ok(line)
+}
+
+/// mtree escapes whitespace, backslashes and non-printable bytes as octal.
+fn escape(path: &str) -> String {
+    let mut escaped = String::new();
+    for byte in path.bytes() {
+        if byte.is_ascii_graphic() && byte != b'\\' && byte != b'#' {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    escaped
+}
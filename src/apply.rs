@@ -0,0 +1,359 @@
+//! Batches of install, uninstall and upgrade operations run as one
+//! transaction, for provisioning scripts. A batch is a text file with one
+//! operation per line, written like the command that would do it:
+//!
+//! ```text
+//! # Comments and blank lines are ignored.
+//! install https://example.org/htop-3.3.0-1-x86_64.pkg.tar.zst --relocate
+//! upgrade ./neovim-0.10.0-1-x86_64.pkg.tar.zst --prefix /opt/nvim
+//! uninstall vim
+//! ```
+//!
+//! Every line is checked and every package fetched before anything changes.
+//! If an operation fails, the ones before it are undone in reverse order.
+
+use crate::archive::{DOWNLOADED, fetch_package, get_package_name, is_url, prefetch_packages, read_pkginfo};
+use crate::config;
+use crate::delta;
+use crate::error::InstallerError;
+use crate::installer::{InstallOptions, install_package, read_confirmation, reinstall_package, uninstall_files};
+use crate::output::{self, AppliedOperation, ApplyReport};
+use crate::paths::resolve_prefix;
+use crate::records::{InstalledPackage, get_log_path, installed_packages, read_install_options};
+use crate::repair::source_package;
+use crate::store;
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Parser, Subcommand};
+use log::{info, warn};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+#[derive(Subcommand, Clone)]
+pub enum Operation {
+    Install {
+        package: String,
+        #[arg(long, default_value = "/usr/local")]
+        prefix: String,
+        #[command(flatten)]
+        options: InstallOptions,
+    },
+    Uninstall {
+        package: String,
+        /// Default: the only prefix the package is installed in
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Replaces an installed package with the given package file or URL
+    Upgrade {
+        package: String,
+        /// Default: the only prefix the package is installed in
+        #[arg(long)]
+        prefix: Option<String>,
+        #[command(flatten)]
+        options: InstallOptions,
+    },
+}
+
+#[derive(Parser)]
+#[command(name = "apply", no_binary_name = true)]
+struct Line {
+    #[command(subcommand)]
+    operation: Operation,
+}
+
+/// An operation with the package name and prefix it applies to worked out.
+struct Step {
+    operation: Operation,
+    line: usize,
+    name: String,
+    prefix: String,
+}
+
+/// How to take back an operation that went through.
+enum Undo {
+    Remove(InstalledPackage),
+    Restore {
+        package: InstalledPackage,
+        source: String,
+        options: InstallOptions,
+    },
+}
+
+/// Parses a batch, reporting the first bad line by number.
+pub fn parse(text: &str) -> Result<Vec<(usize, Operation)>> {
+    let mut operations = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = shlex::split(line).ok_or_else(|| anyhow!("line {}: unbalanced quotes", index + 1))?;
+        let parsed = Line::try_parse_from(words).map_err(|err| {
+            let message = err.render().to_string();
+            let message = message.lines().next().unwrap_or_default().trim_start_matches("error: ").to_string();
+            anyhow!("line {}: {}", index + 1, message)
+        })?;
+        operations.push((index + 1, parsed.operation));
+    }
+    Ok(operations)
+}
+
+/// Reads a batch from `path`, or from stdin when it is `-`.
+pub fn read(path: &Path) -> Result<Vec<(usize, Operation)>> {
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).context("Failed to read operations from stdin")?;
+        text
+    } else {
+        fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?
+    };
+    parse(&text).context(format!("Invalid operations in {}", path.display()))
+}
+
+/// Works out the prefix of every operation against what is installed now
+/// plus what earlier operations install or remove.
+fn resolve(operations: Vec<(usize, Operation)>) -> Result<Vec<Step>> {
+    let mut installed = installed_packages()?;
+    let mut steps = Vec::new();
+    for (line, operation) in operations {
+        let name = get_package_name(match &operation {
+            Operation::Install { package, .. }
+            | Operation::Uninstall { package, .. }
+            | Operation::Upgrade { package, .. } => package,
+        });
+        let prefix = match &operation {
+            Operation::Install { prefix, .. } => resolve_prefix(prefix.clone())?,
+            Operation::Uninstall { prefix, .. } | Operation::Upgrade { prefix, .. } => {
+                find_prefix(&installed, &name, prefix.clone().map(resolve_prefix).transpose()?)
+                    .context(format!("line {}", line))?
+            }
+        };
+        let package = InstalledPackage {
+            name: name.clone(),
+            prefix: prefix.clone(),
+        };
+        match &operation {
+            Operation::Install { .. } if !installed.contains(&package) => installed.push(package),
+            Operation::Uninstall { .. } => installed.retain(|other| other != &package),
+            _ => {}
+        }
+        steps.push(Step {
+            operation,
+            line,
+            name,
+            prefix,
+        });
+    }
+    Ok(steps)
+}
+
+fn find_prefix(installed: &[InstalledPackage], name: &str, prefix: Option<String>) -> Result<String> {
+    let prefixes: Vec<String> = installed
+        .iter()
+        .filter(|package| package.name == name && prefix.as_ref().is_none_or(|prefix| &package.prefix == prefix))
+        .map(|package| package.prefix.clone())
+        .collect();
+    match prefixes.as_slice() {
+        [prefix] => Ok(prefix.clone()),
+        [] => Err(InstallerError::NotInstalled {
+            package: name.to_string(),
+            prefix,
+        }
+        .into()),
+        _ => Err(InstallerError::AmbiguousPrefix {
+            package: name.to_string(),
+            prefixes,
+        }
+        .into()),
+    }
+}
+
+/// Downloads every package up front and makes sure each one is readable.
+fn fetch(steps: &[Step]) -> Result<()> {
+    for step in steps {
+        if let Operation::Upgrade { package, options, .. } = &step.operation
+            && options.delta
+            && is_url(package)
+            && let Err(err) = delta::fetch(package, &step.prefix)
+        {
+            warn!("Delta upgrade failed, downloading the whole package: {:#}", err);
+        }
+    }
+    let fetched = DOWNLOADED.lock().unwrap().clone().unwrap_or_default();
+    let wanted: Vec<String> = steps
+        .iter()
+        .filter_map(|step| match &step.operation {
+            Operation::Install { package, .. } | Operation::Upgrade { package, .. } => Some(package.clone()),
+            Operation::Uninstall { .. } => None,
+        })
+        .filter(|package| !fetched.contains_key(package))
+        .collect();
+    prefetch_packages(&wanted, config::get().parallel_downloads.unwrap_or(4))?;
+    for step in steps {
+        if let Operation::Install { package, .. } | Operation::Upgrade { package, .. } = &step.operation {
+            read_pkginfo(&fetch_package(package)?).context(format!("line {}", step.line))?;
+        }
+    }
+    Ok(())
+}
+
+fn confirm(steps: &[Step]) -> Result<bool> {
+    let mut text = format!("Operations to apply ({}):\n", steps.len());
+    for step in steps {
+        let action = match step.operation {
+            Operation::Install { .. } => "install",
+            Operation::Uninstall { .. } => "uninstall",
+            Operation::Upgrade { .. } => "upgrade",
+        };
+        text.push_str(&format!("  - {} {} in {}\n", action, step.name, step.prefix));
+    }
+    text.push_str("Are you sure you want to apply them? [y/N]");
+    output::prompt(&text)?;
+    read_confirmation()
+}
+
+/// What to undo `package` with once it is uninstalled: the package file it
+/// came from, laid out as before.
+fn restore(package: InstalledPackage) -> Option<Undo> {
+    match source_package(&package) {
+        Ok(source) => Some(Undo::Restore {
+            options: read_install_options(&package.name, &package.prefix),
+            package,
+            source,
+        }),
+        Err(err) => {
+            warn!("{} cannot be restored if the batch fails: {:#}", package.name, err);
+            None
+        }
+    }
+}
+
+fn run(step: &Step, undo: &mut Vec<Undo>) -> Result<AppliedOperation> {
+    let package = InstalledPackage {
+        name: step.name.clone(),
+        prefix: step.prefix.clone(),
+    };
+    match &step.operation {
+        Operation::Install { package: source, options, .. } => {
+            let options = InstallOptions { yes: true, ..options.clone() };
+            let report = install_package(source, &step.prefix, &options)?;
+            undo.push(Undo::Remove(package));
+            Ok(AppliedOperation::Install(report))
+        }
+        Operation::Uninstall { .. } => {
+            let restore = restore(package);
+            let report = uninstall_files(&step.name, &step.prefix, false)?;
+            undo.extend(restore);
+            Ok(AppliedOperation::Uninstall(report))
+        }
+        Operation::Upgrade { package: source, options, .. } => {
+            let options = InstallOptions { yes: true, ..options.clone() };
+            let restore = restore(package.clone());
+            match reinstall_package(source, &step.prefix, &options, false) {
+                Ok(report) => {
+                    undo.extend(restore);
+                    undo.push(Undo::Remove(package));
+                    Ok(AppliedOperation::Upgrade(report))
+                }
+                Err(err) => {
+                    if !get_log_path(&step.name, &step.prefix).exists() {
+                        undo.extend(restore);
+                    }
+                    Err(err)
+                }
+            }
+        }
+    }
+}
+
+/// Takes back what went through, newest first. Failures are only warned
+/// about so the rest still gets undone.
+fn roll_back(undo: Vec<Undo>) {
+    for step in undo.into_iter().rev() {
+        let result = match &step {
+            Undo::Remove(package) => uninstall_files(&package.name, &package.prefix, false).map(|_| ()),
+            Undo::Restore { package, source, options } => {
+                let options = InstallOptions { yes: true, ..options.clone() };
+                install_package(source, &package.prefix, &options).map(|_| ())
+            }
+        };
+        let (Undo::Remove(package) | Undo::Restore { package, .. }) = &step;
+        match result {
+            Ok(()) => info!("Rolled back {} in {}", package.name, package.prefix),
+            Err(err) => warn!("Failed to roll back {} in {}: {:#}", package.name, package.prefix, err),
+        }
+    }
+}
+
+/// Runs `operations` in order, asking once for all of them unless `yes` is
+/// set. Nothing changes if a line is invalid or a package cannot be fetched;
+/// if an operation fails, the earlier ones are rolled back.
+pub fn apply(operations: Vec<(usize, Operation)>, yes: bool) -> Result<ApplyReport> {
+    if operations.is_empty() {
+        bail!("No operations to apply");
+    }
+    let steps = resolve(operations)?;
+    fetch(&steps)?;
+    if !yes && !confirm(&steps)? {
+        return Err(InstallerError::Cancelled.into());
+    }
+    let mut report = ApplyReport { operations: Vec::new() };
+    let mut undo = Vec::new();
+    for step in &steps {
+        match run(step, &mut undo) {
+            Ok(applied) => report.operations.push(applied),
+            Err(err) => {
+                let done = report.operations.len();
+                roll_back(undo);
+                store::prune()?;
+                if done == 0 {
+                    return Err(err.context(format!("line {}", step.line)));
+                }
+                return Err(err.context(format!("line {} failed; rolled back {} earlier operations", step.line, done)));
+            }
+        }
+    }
+    store::prune()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Operation, parse};
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let operations = parse("# setup\n\n   \ninstall ./a.pkg.tar.zst\n  # indented\nuninstall b\n").unwrap();
+        let lines: Vec<usize> = operations.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [4, 6]);
+        assert!(matches!(&operations[1].1, Operation::Uninstall { package, prefix: None } if package == "b"));
+    }
+
+    #[test]
+    fn unbalanced_quotes_report_the_line() {
+        let err = parse("uninstall a\ninstall 'b.pkg.tar.zst\n").err().unwrap();
+        assert_eq!(err.to_string(), "line 2: unbalanced quotes");
+    }
+
+    #[test]
+    fn unknown_operations_report_the_line() {
+        let err = parse("# nothing\nremove a\n").err().unwrap();
+        assert!(err.to_string().starts_with("line 2: "), "{}", err);
+        assert!(err.to_string().contains("remove"), "{}", err);
+    }
+
+    #[test]
+    fn install_options_are_flattened() {
+        let operations = parse("install 'my pkg.pkg.tar.zst' --relocate --prefix /opt/tool\n").unwrap();
+        let [(1, Operation::Install { package, prefix, options })] = operations.as_slice() else {
+            panic!("expected one install");
+        };
+        assert_eq!(package, "my pkg.pkg.tar.zst");
+        assert_eq!(prefix, "/opt/tool");
+        assert!(options.relocate);
+        assert!(!options.dedup);
+        let operations = parse("upgrade a --dedup").unwrap();
+        assert!(matches!(&operations[0].1, Operation::Upgrade { options, prefix: None, .. } if options.dedup));
+    }
+}
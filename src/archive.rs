@@ -0,0 +1,338 @@
+use crate::error::InstallerError;
+use crate::events::{self, DownloadReader, Event};
+use crate::output;
+use crate::paths::get_cache_dir;
+use crate::privsep;
+use crate::repo;
+use crate::timings::{self, Phase};
+use anyhow::{Context, Result};
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tar::Archive;
+use zstd::stream::read::Decoder;
+
+/// Extracts a package into `temp_dir`. Run as root, the archive is unpacked
+/// by an unprivileged child process.
+pub fn extract_pkg_zst(pkg_path: &str, temp_dir: &str) -> Result<()> {
+    let file = File::open(pkg_path)
+        .context(format!("Failed to open package {}", pkg_path))?;
+    events::emit(Event::Extracting {
+        package: pkg_path.to_string(),
+    });
+    if privsep::is_enabled() {
+        return privsep::unpack(file, pkg_path, temp_dir);
+    }
+    let size = file.metadata()?.len();
+    unpack_pkg_zst(file, size, pkg_path, temp_dir)
+}
+
+/// Decompresses and unpacks a package read from `reader`, `size` bytes long
+/// (0 if unknown); `pkg_path` names it in messages.
+pub fn unpack_pkg_zst(reader: impl Read, size: u64, pkg_path: &str, temp_dir: &str) -> Result<()> {
+    let bar = output::bytes_bar(size, "Extracting");
+    let start = Instant::now();
+    let bad_archive = |err: io::Error| InstallerError::BadArchive {
+        package: pkg_path.to_string(),
+        reason: err.to_string(),
+    };
+    let decoder = timings::TimedReader::new(Decoder::new(bar.wrap_read(reader)).map_err(bad_archive)?);
+    let mut archive = Archive::new(decoder);
+    // Keep setuid and setgid bits so installing can refuse or keep them.
+    archive.set_preserve_permissions(true);
+    fs::create_dir_all(temp_dir)?;
+    archive.unpack(temp_dir).map_err(bad_archive)?;
+    output::finish_bar(&bar);
+    let decoder = archive.into_inner();
+    timings::record(Phase::Decompress, decoder.elapsed, decoder.bytes);
+    timings::record(Phase::Extract, start.elapsed().saturating_sub(decoder.elapsed), decoder.bytes);
+    info!("Extracted package {} to {}", pkg_path, temp_dir);
+    Ok(())
+}
+
+pub fn is_url(package: &str) -> bool {
+    package.starts_with("https://") || package.starts_with("http://")
+}
+
+/// Packages already downloaded by this run, by URL.
+pub(crate) static DOWNLOADED: Mutex<Option<HashMap<String, PathBuf>>> = Mutex::new(None);
+
+/// Downloads `package` into the cache if it is a URL and returns the local
+/// path to install from.
+pub fn fetch_package(package: &str) -> Result<String> {
+    if !is_url(package) {
+        // A bare name is looked up in the configured repositories.
+        if !package.contains(['/', '.'])
+            && !Path::new(package).exists()
+            && let Some(found) = repo::find(package)
+        {
+            let path = fetch_package(&found.location)?;
+            if let Err(err) = found.verify(Path::new(&path)) {
+                if is_url(&found.location) {
+                    let _ = fs::remove_file(&path);
+                    DOWNLOADED.lock().unwrap().as_mut().map(|done| done.remove(&found.location));
+                }
+                return Err(err);
+            }
+            return Ok(path);
+        }
+        return Ok(package.to_string());
+    }
+    if let Some(path) = DOWNLOADED.lock().unwrap().as_ref().and_then(|done| done.get(package)) {
+        return Ok(path.to_string_lossy().into_owned());
+    }
+    let bar = output::bytes_bar(0, "Downloading");
+    let start = Instant::now();
+    let path = download(package, &bar)?;
+    output::finish_bar(&bar);
+    timings::record(Phase::Download, start.elapsed(), bar.position());
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Where `url` is downloaded to in the cache.
+pub(crate) fn download_path(url: &str) -> PathBuf {
+    let file_name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|url| url.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("package.pkg.tar.zst");
+    get_cache_dir().join("downloads").join(file_name)
+}
+
+/// Downloads `url` into the cache, adding its size and progress to `bar`.
+pub(crate) fn download(url: &str, bar: &ProgressBar) -> Result<PathBuf> {
+    let path = download_path(url);
+    let download_dir = path.parent().unwrap();
+    fs::create_dir_all(download_dir)
+        .context(format!("Failed to create {}", download_dir.display()))?;
+    let partial = path.with_extension("part");
+    let failed = |reason: String| InstallerError::Download {
+        url: url.to_string(),
+        reason,
+    };
+    let response = ureq::get(url).call().map_err(|err| failed(err.to_string()))?;
+    let body = response.into_body();
+    let size = body.content_length();
+    bar.inc_length(size.unwrap_or(0));
+    events::emit(Event::DownloadStarted {
+        url: url.to_string(),
+        size,
+    });
+    let mut file = File::create(&partial).context(format!("Failed to create {}", partial.display()))?;
+    let downloaded = io::copy(&mut bar.wrap_read(DownloadReader::new(body.into_reader(), url, size)), &mut file)
+        .map_err(|err| failed(err.to_string()))?;
+    fs::rename(&partial, &path)?;
+    events::emit(Event::DownloadFinished {
+        url: url.to_string(),
+        path: path.display().to_string(),
+        size: downloaded,
+    });
+    info!("Downloaded {} to {}", url, path.display());
+    DOWNLOADED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(url.to_string(), path.clone());
+    Ok(path)
+}
+
+/// Downloads every URL among `packages` up front, `limit` at a time, under a
+/// single progress bar; later `fetch_package` calls reuse the files. Failed
+/// downloads are only logged, since callers may have other sources to try.
+pub fn prefetch_packages(packages: &[String], limit: usize) -> Result<()> {
+    let mut urls: Vec<&String> = packages.iter().filter(|package| is_url(package)).collect();
+    urls.sort();
+    urls.dedup();
+    if urls.is_empty() {
+        return Ok(());
+    }
+    debug!("Downloading {} packages, {} at a time", urls.len(), limit);
+    let bar = output::files_bar(0, urls.len(), "Downloading");
+    let done = AtomicUsize::new(0);
+    let start = Instant::now();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(limit.max(1)).build()?;
+    pool.install(|| {
+        urls.par_iter().for_each(|url| {
+            if let Err(err) = download(url, &bar) {
+                warn!("{:#}", err);
+            }
+            output::advance_files(&bar, done.fetch_add(1, Ordering::Relaxed) + 1, urls.len(), 0);
+        })
+    });
+    output::finish_bar(&bar);
+    timings::record(Phase::Download, start.elapsed(), bar.position());
+    Ok(())
+}
+
+/// The package name in `pkg_path`: the `name` of a
+/// `name-pkgver-pkgrel-arch.pkg.tar.*` file, which may contain hyphens as
+/// split packages' names do, or an installed package's name as is.
+pub fn get_package_name(pkg_path: &str) -> String {
+    let file_name = Path::new(pkg_path)
+        .file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or("unknown".to_string());
+    if let Some((stem, _)) = file_name.split_once(".pkg.tar")
+        && let [_, _, _, name] = stem.rsplitn(4, '-').collect::<Vec<_>>()[..]
+    {
+        return name.to_string();
+    }
+    if !file_name.contains('.') {
+        return file_name;
+    }
+    file_name
+        .split('-')
+        .next()
+        .map(|s| s.to_string())
+        .unwrap_or("unknown".to_string())
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct PkgInfo {
+    pub name: String,
+    /// The PKGBUILD this package was built from; differs from `name` for
+    /// split packages.
+    pub pkgbase: String,
+    pub version: String,
+    pub description: String,
+    pub url: String,
+    pub arch: String,
+    pub size: u64,
+    pub licenses: Vec<String>,
+    pub depends: Vec<String>,
+    pub optdepends: Vec<String>,
+}
+
+pub(crate) fn parse_pkginfo_content(content: &str) -> PkgInfo {
+    let mut info = PkgInfo::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match key {
+            "pkgname" => info.name = value,
+            "pkgbase" => info.pkgbase = value,
+            "pkgver" => info.version = value,
+            "pkgdesc" => info.description = value,
+            "url" => info.url = value,
+            "arch" => info.arch = value,
+            "size" => info.size = value.parse().unwrap_or(0),
+            "license" => info.licenses.push(value),
+            "depend" => info.depends.push(value),
+            "optdepend" => info.optdepends.push(value),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Every value of `key` in `.PKGINFO` content, for keys that may repeat.
+pub(crate) fn pkginfo_values<'a>(content: &'a str, key: &str) -> Vec<&'a str> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(" = "))
+        .filter(|(name, _)| *name == key)
+        .map(|(_, value)| value.trim())
+        .collect()
+}
+
+/// Reads the metadata file `name` straight out of a package file without
+/// extracting the rest.
+pub(crate) fn read_metadata(pkg_path: &str, name: &str) -> Result<Option<String>> {
+    let file = File::open(pkg_path).context(format!("Failed to open package {}", pkg_path))?;
+    let mut archive = Archive::new(Decoder::new(file)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(name) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads `.PKGINFO` straight out of a package file without extracting the rest.
+pub fn read_pkginfo(pkg_path: &str) -> Result<PkgInfo> {
+    match read_metadata(pkg_path, ".PKGINFO")? {
+        Some(content) => Ok(parse_pkginfo_content(&content)),
+        None => Err(InstallerError::BadArchive {
+            package: pkg_path.to_string(),
+            reason: "it has no .PKGINFO".to_string(),
+        }
+        .into()),
+    }
+}
+
+/// How a package was built, from its `.BUILDINFO`.
+#[derive(Default, Clone, Serialize)]
+pub struct BuildInfo {
+    pub format: u32,
+    pub pkgbase: String,
+    pub pkgbuild_sha256sum: String,
+    pub packager: String,
+    /// Seconds since the epoch.
+    pub builddate: u64,
+    pub builddir: String,
+    pub buildtool: String,
+    pub buildtoolver: String,
+    pub buildenv: Vec<String>,
+    pub options: Vec<String>,
+    /// Every package installed in the build environment, as `name-version-arch`.
+    pub installed: Vec<String>,
+}
+
+pub(crate) fn parse_buildinfo_content(content: &str) -> BuildInfo {
+    let mut info = BuildInfo::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(" = ") else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match key {
+            "format" => info.format = value.parse().unwrap_or(0),
+            "pkgbase" => info.pkgbase = value,
+            "pkgbuild_sha256sum" => info.pkgbuild_sha256sum = value,
+            "packager" => info.packager = value,
+            "builddate" => info.builddate = value.parse().unwrap_or(0),
+            "builddir" => info.builddir = value,
+            "buildtool" => info.buildtool = value,
+            "buildtoolver" => info.buildtoolver = value,
+            "buildenv" => info.buildenv.push(value),
+            "options" => info.options.push(value),
+            "installed" => info.installed.push(value),
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Reads `.BUILDINFO` out of a package file; packages not built by makepkg
+/// may have none.
+pub fn read_buildinfo(pkg_path: &str) -> Result<Option<BuildInfo>> {
+    Ok(read_metadata(pkg_path, ".BUILDINFO")?.map(|content| parse_buildinfo_content(&content)))
+}
+
+pub(crate) fn parse_pkginfo(temp_dir: &str) -> Result<PkgInfo> {
+    let pkginfo_path = format!("{}/.PKGINFO", temp_dir);
+    let content = fs::read_to_string(&pkginfo_path)
+        .context(format!("Failed to read .PKGINFO from {}", pkginfo_path))?;
+    Ok(parse_pkginfo_content(&content))
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher).context(format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
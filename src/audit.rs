@@ -0,0 +1,187 @@
+//! Known vulnerabilities in installed packages, from the Arch Linux security
+//! tracker's advisory groups (`https://security.archlinux.org/all.json`) or
+//! a local copy of that file. A group lists the affected package names, the
+//! CVEs it covers and the first fixed version, if any.
+
+use crate::config;
+use crate::error::InstallerError;
+use crate::output::{AuditReport, Vulnerability};
+use crate::records::{InstalledPackage, read_installed_pkginfo};
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fs;
+
+const TRACKER_URL: &str = "https://security.archlinux.org/all.json";
+
+#[derive(Deserialize)]
+struct Group {
+    name: String,
+    packages: Vec<String>,
+    status: String,
+    severity: String,
+    #[serde(rename = "type")]
+    kind: String,
+    fixed: Option<String>,
+    #[serde(default)]
+    issues: Vec<String>,
+}
+
+/// Reads the advisory groups from `source`, a URL or a file path, by default
+/// the `advisories` config key or the security tracker.
+fn load(source: Option<&str>) -> Result<Vec<Group>> {
+    let source = source.or(config::get().advisories.as_deref()).unwrap_or(TRACKER_URL);
+    debug!("Reading advisories from {}", source);
+    let text = if source.starts_with("https://") || source.starts_with("http://") {
+        let failed = |reason: String| InstallerError::Download {
+            url: source.to_string(),
+            reason,
+        };
+        let response = ureq::get(source).call().map_err(|err| failed(err.to_string()))?;
+        response
+            .into_body()
+            .with_config()
+            .limit(64 * 1024 * 1024)
+            .read_to_string()
+            .map_err(|err| failed(err.to_string()))?
+    } else {
+        fs::read_to_string(source).context(format!("Failed to read {}", source))?
+    };
+    serde_json::from_str(&text).context(format!("Invalid advisory database {}", source))
+}
+
+/// Checks the installed versions of `packages` against the advisories in
+/// `source`. A package is affected by a group naming it unless the group is
+/// marked not affected or the installed version is at least the fixed one.
+pub fn audit(packages: &[InstalledPackage], source: Option<&str>) -> Result<AuditReport> {
+    let groups = load(source)?;
+    let mut report = AuditReport {
+        checked: packages.len(),
+        vulnerabilities: Vec::new(),
+    };
+    for package in packages {
+        let Some(pkginfo) = read_installed_pkginfo(&package.name, &package.prefix) else {
+            debug!("No version recorded for {} ({}), skipping", package.name, package.prefix);
+            continue;
+        };
+        for group in &groups {
+            if group.status == "Not affected" || !group.packages.contains(&pkginfo.name) {
+                continue;
+            }
+            if let Some(fixed) = &group.fixed
+                && vercmp(&pkginfo.version, fixed) != Ordering::Less
+            {
+                continue;
+            }
+            report.vulnerabilities.push(Vulnerability {
+                package: package.name.clone(),
+                prefix: package.prefix.clone(),
+                version: pkginfo.version.clone(),
+                advisory: group.name.clone(),
+                severity: group.severity.clone(),
+                kind: group.kind.clone(),
+                issues: group.issues.clone(),
+                fixed: group.fixed.clone(),
+            });
+        }
+    }
+    report
+        .vulnerabilities
+        .sort_by(|a, b| severity_rank(&a.severity).cmp(&severity_rank(&b.severity)).then(a.package.cmp(&b.package)));
+    Ok(report)
+}
+
+/// Orders severities from the most serious down.
+fn severity_rank(severity: &str) -> usize {
+    ["Critical", "High", "Medium", "Low"].iter().position(|known| *known == severity).unwrap_or(4)
+}
+
+/// Compares two `[epoch:]version[-release]` strings the way pacman does.
+pub(crate) fn vercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (epoch_a, version_a, release_a) = split_evr(a);
+    let (epoch_b, version_b, release_b) = split_evr(b);
+    rpmvercmp(epoch_a, epoch_b).then_with(|| rpmvercmp(version_a, version_b)).then_with(|| {
+        match (release_a, release_b) {
+            (Some(a), Some(b)) => rpmvercmp(a, b),
+            _ => Ordering::Equal,
+        }
+    })
+}
+
+fn split_evr(evr: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, rest) = match evr.split_once(':') {
+        Some((epoch, rest)) if epoch.bytes().all(|byte| byte.is_ascii_digit()) => (epoch, rest),
+        _ => ("0", evr),
+    };
+    match rest.rsplit_once('-') {
+        Some((version, release)) => (epoch, version, Some(release)),
+        None => (epoch, rest, None),
+    }
+}
+
+/// Compares runs of digits numerically and runs of letters as strings, the
+/// way `rpmvercmp` in libalpm does; separators only count by their length.
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut one, mut two) = (0, 0);
+    while one < a.len() && two < b.len() {
+        let (start_one, start_two) = (one, two);
+        while one < a.len() && !a[one].is_ascii_alphanumeric() {
+            one += 1;
+        }
+        while two < b.len() && !b[two].is_ascii_alphanumeric() {
+            two += 1;
+        }
+        if one == a.len() || two == b.len() {
+            break;
+        }
+        if one - start_one != two - start_two {
+            return (one - start_one).cmp(&(two - start_two));
+        }
+        let is_num = a[one].is_ascii_digit();
+        let segment_end = |bytes: &[u8], start: usize| {
+            bytes[start..]
+                .iter()
+                .position(|byte| if is_num { !byte.is_ascii_digit() } else { !byte.is_ascii_alphabetic() })
+                .map_or(bytes.len(), |len| start + len)
+        };
+        let (end_one, end_two) = (segment_end(a, one), segment_end(b, two));
+        let (segment_one, segment_two) = (&a[one..end_one], &b[two..end_two]);
+        if segment_two.is_empty() {
+            return if is_num { Ordering::Greater } else { Ordering::Less };
+        }
+        let order = if is_num {
+            let trim = |digits: &[u8]| {
+                let zeros = digits.iter().take_while(|digit| **digit == b'0').count();
+                digits[zeros..].to_vec()
+            };
+            let (number_one, number_two) = (trim(segment_one), trim(segment_two));
+            number_one.len().cmp(&number_two.len()).then(number_one.cmp(&number_two))
+        } else {
+            segment_one.cmp(segment_two)
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+        (one, two) = (end_one, end_two);
+    }
+    let (rest_one, rest_two) = (&a[one.min(a.len())..], &b[two.min(b.len())..]);
+    if rest_one.is_empty() && rest_two.is_empty() {
+        return Ordering::Equal;
+    }
+    // A remaining alphabetic part never beats an empty one: 1.0 > 1.0a.
+    if (rest_one.is_empty() && !rest_two.first().is_some_and(u8::is_ascii_alphabetic))
+        || rest_one.first().is_some_and(u8::is_ascii_alphabetic)
+    {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
@@ -0,0 +1,336 @@
+//! A small `makepkg` for simple PKGBUILDs, such as most AUR packages that
+//! build from a release tarball. Sources are downloaded and checked here
+//! and unpacked as the build user; the PKGBUILD is then sourced and its
+//! `prepare`, `build`, `check` and `package` functions run by bash in a
+//! sandbox: a user namespace in which the builder is root (so `package` can
+//! set any owner, like under fakeroot) and every mount but the build
+//! directory is read-only. The package directory is packaged the way
+//! `create` does it.
+//!
+//! Not supported: split packages, VCS sources, `pkgver()` and makepkg's
+//! post-processing of the package directory (stripping and the like).
+
+use crate::archive::download;
+use crate::create::{Metadata, create_package};
+use crate::output::{self, CreateReport};
+use crate::paths::{get_cache_dir, invoking_user, is_root};
+use crate::sandbox::write_proc;
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use md5::Md5;
+use nix::mount::{MsFlags, mount};
+use nix::sched::{CloneFlags, unshare};
+use nix::sys::prctl;
+use nix::unistd::{Gid, Uid, setgid, setgroups, setuid};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::lchown;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use walkdir::WalkDir;
+
+/// Prints the PKGBUILD variables used here as NUL-terminated `key=value`
+/// records, arrays one element each, including `$CARCH`-specific ones.
+const METADATA_SCRIPT: &str = r#"
+source "$1" || exit 1
+emit() { local key=$1; shift; for value in "$@"; do printf '%s=%s\0' "$key" "$value"; done; }
+for key in pkgname arch license depends optdepends provides conflicts backup noextract \
+        source md5sums sha1sums sha256sums sha512sums b2sums; do
+    eval 'emit "$key" "${'$key'[@]}"'
+    case $key in source|*sums|depends|optdepends|provides|conflicts)
+        eval 'emit "$key" "${'$key'_'$CARCH'[@]}"'
+    esac
+done
+emit pkgver "$pkgver"; emit pkgrel "$pkgrel"; emit epoch "$epoch"
+emit pkgdesc "$pkgdesc"; emit url "$url"
+declare -F pkgver >/dev/null && emit function pkgver
+declare -F package >/dev/null && emit function package
+true
+"#;
+
+/// Runs the functions of the PKGBUILD in `$1` the way makepkg does, each
+/// from `$srcdir` and stopping at the first failing command.
+const BUILD_SCRIPT: &str = r#"
+set -e
+source "$1"
+for function in prepare build check package; do
+    if declare -F "$function" >/dev/null; then
+        echo "==> Running $function()" >&2
+        cd "$srcdir"
+        "$function"
+    fi
+done
+"#;
+
+/// Copies the source `$1` into `$srcdir` as `$2` and, with `$3` set, unpacks
+/// it there, as the build user rather than root. bsdtar reads zip files too;
+/// GNU tar covers the rest elsewhere.
+const FETCH_SCRIPT: &str = r#"
+set -e
+cd "$srcdir"
+cp -- "$1" "$2"
+[ -n "$3" ] || exit 0
+tar=tar; command -v bsdtar >/dev/null && tar=bsdtar
+"$tar" --no-same-owner --no-same-permissions -xf "$2"
+"#;
+
+/// Source archives that are unpacked into `$srcdir` unless in `noextract`.
+const ARCHIVES: [&str; 9] =
+    [".tar", ".tar.gz", ".tgz", ".tar.xz", ".tar.bz2", ".tar.zst", ".tar.lz", ".tar.lzma", ".zip"];
+
+/// What a PKGBUILD sets, key by key.
+struct Variables(HashMap<String, Vec<String>>);
+
+impl Variables {
+    fn values(&self, key: &str) -> &[String] {
+        self.0.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    fn value(&self, key: &str) -> &str {
+        self.values(key).first().map_or("", String::as_str)
+    }
+}
+
+/// The sandbox a build runs in.
+struct Sandbox {
+    /// The directory that stays writable.
+    build_dir: PathBuf,
+    /// The user to build as when running as root.
+    user: Option<(Uid, Gid)>,
+}
+
+impl Sandbox {
+    /// Runs `script` with bash and `args` in the sandbox, with the usual
+    /// makepkg variables set.
+    fn command(&self, script: &str, args: &[&Path], env: &[(&str, &Path)]) -> Result<Command> {
+        let build_dir = CString::new(self.build_dir.as_os_str().as_bytes())?;
+        // Every mount is made read-only, apart from the pseudo file systems.
+        let mounts: Vec<CString> = fs::read_to_string("/proc/self/mountinfo")?
+            .lines()
+            .filter_map(|line| line.split(' ').nth(4))
+            .filter(|point| !["/proc", "/sys", "/dev"].iter().any(|skip| Path::new(point).starts_with(skip)))
+            .filter_map(|point| CString::new(point.replace("\\040", " ")).ok())
+            .collect();
+        let (uid, gid) = self.user.unwrap_or((Uid::current(), Gid::current()));
+        let uid_map = format!("0 {} 1", uid).into_bytes();
+        let gid_map = format!("0 {} 1", gid).into_bytes();
+        let drop_to = self.user;
+
+        let mut command = Command::new("bash");
+        command.args(["--noprofile", "--norc", "-c", script, "bash"]).args(args);
+        command.env("CARCH", std::env::consts::ARCH);
+        command.env("HOME", self.build_dir.join("home")).env("TMPDIR", self.build_dir.join("tmp"));
+        for (key, value) in env {
+            command.env(key, value);
+        }
+        // SAFETY: only system calls run between fork and exec; every string
+        // was built beforehand.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some((uid, gid)) = drop_to {
+                    setgroups(&[])?;
+                    setgid(gid)?;
+                    setuid(uid)?;
+                    // Changing users makes /proc/self root's; this gives it back for the maps below.
+                    prctl::set_dumpable(true)?;
+                }
+                unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+                write_proc(c"/proc/self/setgroups", b"deny")?;
+                write_proc(c"/proc/self/uid_map", &uid_map)?;
+                write_proc(c"/proc/self/gid_map", &gid_map)?;
+                mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)?;
+                // A mount of its own keeps the build directory writable.
+                mount(Some(build_dir.as_c_str()), build_dir.as_c_str(), None::<&str>, MsFlags::MS_BIND, None::<&str>)?;
+                for point in &mounts {
+                    let flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+                    let _ = mount(None::<&str>, point.as_c_str(), None::<&str>, flags, None::<&str>);
+                }
+                Ok(())
+            });
+        }
+        Ok(command)
+    }
+}
+
+/// Reads the variables of the PKGBUILD at `pkgbuild` by sourcing it in the
+/// sandbox, since a PKGBUILD is a shell script.
+fn read_variables(sandbox: &Sandbox, pkgbuild: &Path) -> Result<Variables> {
+    let output = sandbox
+        .command(METADATA_SCRIPT, &[pkgbuild], &[])?
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to start the build sandbox; building needs unprivileged user namespaces")?;
+    if !output.status.success() {
+        bail!("Failed to read {}", pkgbuild.display());
+    }
+    let mut variables: HashMap<String, Vec<String>> = HashMap::new();
+    for record in output.stdout.split(|byte| *byte == 0).filter(|record| !record.is_empty()) {
+        let record = String::from_utf8_lossy(record);
+        if let Some((key, value)) = record.split_once('=') {
+            variables.entry(key.to_string()).or_default().push(value.to_string());
+        }
+    }
+    Ok(Variables(variables))
+}
+
+/// Whether the file at `path` has the checksum `expected` for `kind`
+/// (`sha256sums` and the like).
+fn checksum_matches(path: &Path, kind: &str, expected: &str) -> Result<bool> {
+    let data = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+    let actual = match kind {
+        "md5sums" => format!("{:x}", Md5::digest(&data)),
+        "sha1sums" => format!("{:x}", Sha1::digest(&data)),
+        "sha256sums" => format!("{:x}", Sha256::digest(&data)),
+        _ => format!("{:x}", Sha512::digest(&data)),
+    };
+    Ok(actual.eq_ignore_ascii_case(expected))
+}
+
+/// Puts each source of the PKGBUILD into `src_dir`, downloading URLs and
+/// copying local files from `start_dir`, checks them against the checksums
+/// given and unpacks archives. Only the download runs as root; the rest
+/// happens in the sandbox.
+fn fetch_sources(sandbox: &Sandbox, variables: &Variables, start_dir: &Path, src_dir: &Path) -> Result<()> {
+    let sources = variables.values("source");
+    let sums = ["sha512sums", "sha256sums", "sha1sums", "md5sums"]
+        .into_iter()
+        .find(|kind| variables.values(kind).len() == sources.len() && !sources.is_empty());
+    if sums.is_none() && !sources.is_empty() {
+        warn!("No checksums this builder can check for the sources; they are used unverified");
+    }
+    let bar = output::bytes_bar(0, "Downloading");
+    for (index, source) in sources.iter().enumerate() {
+        let (name, location) = match source.split_once("::") {
+            Some((name, location)) => (Some(name), location),
+            None => (None, &source[..]),
+        };
+        let file_name = name.unwrap_or_else(|| location.rsplit('/').next().unwrap_or(location));
+        if file_name.is_empty() || file_name.contains('/') || file_name.contains("..") {
+            bail!("Invalid source file name {:?} in {}", file_name, source);
+        }
+        let fetched = if let Some((scheme, _)) = location.split_once("://") {
+            if !matches!(scheme, "http" | "https" | "ftp") {
+                bail!("Unsupported source {}: only http, https and ftp URLs and local files can be built", location);
+            }
+            download(location, &bar)?
+        } else {
+            let local = start_dir.join(location);
+            let local = fs::canonicalize(&local).context(format!("Cannot find source {}", local.display()))?;
+            if !local.starts_with(start_dir) {
+                bail!("Source {} is outside {}", location, start_dir.display());
+            }
+            local
+        };
+        if let Some(kind) = sums {
+            let expected = &variables.values(kind)[index];
+            if expected != "SKIP" && !checksum_matches(&fetched, kind, expected)? {
+                bail!("{} does not match its checksum in {}", file_name, kind);
+            }
+        }
+        let noextract = variables.values("noextract").iter().any(|skip| skip == file_name);
+        let extract = !noextract && ARCHIVES.iter().any(|extension| file_name.ends_with(extension));
+        if extract {
+            debug!("Extracting {}", file_name);
+        }
+        let args = [fetched.as_path(), Path::new(file_name), Path::new(if extract { "1" } else { "" })];
+        let status = sandbox
+            .command(FETCH_SCRIPT, &args, &[("srcdir", src_dir)])?
+            .status()
+            .context("Failed to start the build sandbox; building needs unprivileged user namespaces")?;
+        if !status.success() {
+            bail!("Failed to put source {} into {}", file_name, src_dir.display());
+        }
+    }
+    output::finish_bar(&bar);
+    Ok(())
+}
+
+/// Builds the package of the PKGBUILD at `pkgbuild` and writes it into
+/// `out_dir`.
+pub fn build_package(pkgbuild: &Path, out_dir: &Path) -> Result<CreateReport> {
+    let pkgbuild = fs::canonicalize(pkgbuild).context(format!("Cannot find {}", pkgbuild.display()))?;
+    let start_dir = pkgbuild.parent().context("PKGBUILD without a directory")?.to_path_buf();
+    let user = if is_root() {
+        let user = invoking_user().context("Refusing to build as root; run this as a regular user")?;
+        Some((user.uid, user.gid))
+    } else {
+        None
+    };
+    let base = get_cache_dir().join("build");
+    fs::create_dir_all(&base).context(format!("Failed to create {}", base.display()))?;
+    let build_dir = tempfile::Builder::new().prefix("arch-installer-build-").tempdir_in(&base)?;
+    let sandbox = Sandbox {
+        build_dir: build_dir.path().to_path_buf(),
+        user,
+    };
+    for dir in ["src", "pkg", "home", "tmp"] {
+        fs::create_dir(build_dir.path().join(dir))?;
+    }
+    let give_to_user = || -> Result<()> {
+        if let Some((uid, gid)) = user {
+            for entry in WalkDir::new(build_dir.path()) {
+                lchown(entry?.path(), Some(uid.as_raw()), Some(gid.as_raw()))?;
+            }
+        }
+        Ok(())
+    };
+    give_to_user()?;
+
+    let variables = read_variables(&sandbox, &pkgbuild)?;
+    let names = variables.values("pkgname");
+    let [name] = names else {
+        bail!("Split packages are not supported: {} builds {}", pkgbuild.display(), names.join(", "));
+    };
+    if variables.values("function").iter().any(|function| function == "pkgver") {
+        bail!("PKGBUILDs with a pkgver() function (VCS packages) are not supported");
+    }
+    if !variables.values("function").iter().any(|function| function == "package") {
+        bail!("{} has no package() function", pkgbuild.display());
+    }
+    let arches = variables.values("arch");
+    let arch = if arches.iter().any(|arch| arch == "any") {
+        "any"
+    } else if arches.iter().any(|arch| arch == std::env::consts::ARCH) {
+        std::env::consts::ARCH
+    } else {
+        bail!("{} is not for {} (arch: {})", name, std::env::consts::ARCH, arches.join(" "));
+    };
+    let mut version = format!("{}-{}", variables.value("pkgver"), variables.value("pkgrel"));
+    if !variables.value("epoch").is_empty() && variables.value("epoch") != "0" {
+        version = format!("{}:{}", variables.value("epoch"), version);
+    }
+    let src_dir = build_dir.path().join("src");
+    let pkg_dir = build_dir.path().join("pkg").join(name);
+    fetch_sources(&sandbox, &variables, &start_dir, &src_dir)?;
+    fs::create_dir(&pkg_dir)?;
+    give_to_user()?;
+
+    info!("Building {} {}", name, version);
+    let status = sandbox
+        .command(BUILD_SCRIPT, &[&pkgbuild], &[("srcdir", &src_dir), ("pkgdir", &pkg_dir), ("startdir", &start_dir)])?
+        .status()
+        .context("Failed to start the build sandbox; building needs unprivileged user namespaces")?;
+    if !status.success() {
+        bail!("Building {} failed", name);
+    }
+    let metadata = Metadata {
+        name: name.clone(),
+        version,
+        description: variables.value("pkgdesc").to_string(),
+        arch: arch.to_string(),
+        url: Some(variables.value("url").to_string()).filter(|url| !url.is_empty()),
+        license: variables.values("license").to_vec(),
+        depends: variables.values("depends").to_vec(),
+        optdepends: variables.values("optdepends").to_vec(),
+        provides: variables.values("provides").to_vec(),
+        conflicts: variables.values("conflicts").to_vec(),
+        packager: std::env::var("PACKAGER").ok(),
+        backup: variables.values("backup").to_vec(),
+    };
+    create_package(&pkg_dir, &metadata, out_dir)
+}
@@ -0,0 +1,582 @@
+use crate::convert::{Source, append_file, gzip, tar_payload};
+use crate::elf::is_elf_file;
+use crate::paths::find_executable;
+use crate::sbom::timestamp;
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tar::Builder;
+use walkdir::WalkDir;
+
+/// Libraries every desktop system provides and that must match the host's
+/// drivers or C library, following the AppImage excludelist.
+const HOST_LIBRARIES: &[&str] = &[
+    "libc", "libm", "libdl", "libpthread", "librt", "libresolv", "libutil", "libnsl", "libanl",
+    "libmvec", "libGL", "libEGL", "libGLX", "libGLdispatch", "libOpenGL", "libGLESv2", "libdrm",
+    "libgbm", "libX11", "libX11-xcb", "libxcb", "libasound", "libfontconfig", "libfreetype",
+    "libharfbuzz", "libexpat", "libz", "libuuid", "libcom_err", "libgpg-error", "libICE", "libSM",
+    "libusb-1.0", "libjack", "libpipewire-0.3",
+];
+
+/// Used when a package ships no icon, since an AppImage requires one.
+const PLACEHOLDER_ICON: &str = "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"256\" height=\"256\">\
+<rect width=\"256\" height=\"256\" rx=\"32\" fill=\"#1793d1\"/></svg>\n";
+
+fn is_host_library(name: &str) -> bool {
+    let base = name.split(".so").next().unwrap_or(name);
+    base.starts_with("ld-") || HOST_LIBRARIES.contains(&base)
+}
+
+/// Copies `relative` of the package into `dest`, keeping symlinks as they are.
+fn copy_item(source: &Source, relative: &Path, dest: &Path) -> Result<()> {
+    let from = source.tree().join(relative);
+    let to = dest.join(relative);
+    let meta = from.symlink_metadata()?;
+    if meta.is_dir() {
+        fs::create_dir_all(&to)?;
+    } else if meta.file_type().is_symlink() {
+        symlink(fs::read_link(&from)?, &to)?;
+    } else {
+        fs::copy(&from, &to).context(format!("Failed to copy {}", relative.display()))?;
+    }
+    Ok(())
+}
+
+/// Copies the package contents into `app_dir`.
+fn copy_payload(source: &Source, app_dir: &Path) -> Result<()> {
+    for relative in source.payload() {
+        copy_item(source, &relative, app_dir)?;
+    }
+    Ok(())
+}
+
+/// Shared libraries `path` needs according to `ldd`, resolved to host paths.
+/// Libraries already in the AppDir resolve there through `LD_LIBRARY_PATH`.
+fn needed_libraries(path: &Path, lib_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let output = match Command::new("ldd").arg(path).env("LD_LIBRARY_PATH", lib_dir).output() {
+        Ok(output) => output,
+        Err(err) if err.kind() == ErrorKind::NotFound => bail!("ldd is required to collect shared libraries"),
+        Err(err) => return Err(err.into()),
+    };
+    let mut libraries = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((name, target)) = line.trim().split_once(" => ") else {
+            continue;
+        };
+        if target.starts_with("not found") {
+            warn!("{} needs {}, which is not on this system", path.display(), name);
+            continue;
+        }
+        if let Some(resolved) = target.split(" (").next().filter(|resolved| resolved.starts_with('/')) {
+            libraries.push((name.to_string(), PathBuf::from(resolved)));
+        }
+    }
+    Ok(libraries)
+}
+
+/// Copies the shared libraries the package's binaries need, except those the
+/// host must provide, into `usr/lib` of the AppDir.
+fn bundle_libraries(app_dir: &Path) -> Result<()> {
+    let lib_dir = app_dir.join("usr/lib");
+    fs::create_dir_all(&lib_dir)?;
+    let elves: Vec<PathBuf> = WalkDir::new(app_dir.join("usr"))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_file() && is_elf_file(entry.path()))
+        .map(|entry| entry.into_path())
+        .collect();
+    let mut bundled = BTreeSet::new();
+    for elf in elves {
+        for (name, resolved) in needed_libraries(&elf, &lib_dir)? {
+            if resolved.starts_with(app_dir) || is_host_library(&name) || !bundled.insert(name.clone()) {
+                continue;
+            }
+            let target = lib_dir.join(&name);
+            if target.symlink_metadata().is_ok() {
+                continue;
+            }
+            debug!("Bundling {} from {}", name, resolved.display());
+            fs::copy(&resolved, &target).context(format!("Failed to copy {}", resolved.display()))?;
+        }
+    }
+    if !bundled.is_empty() {
+        info!("Bundled {} shared libraries", bundled.len());
+    }
+    Ok(())
+}
+
+fn desktop_value<'a>(entry: &'a str, key: &str) -> Option<&'a str> {
+    entry
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.trim_start().strip_prefix('='))
+        .map(str::trim)
+}
+
+/// The package's `.desktop` file, preferring one named after the package.
+fn find_desktop_file(app_dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(app_dir.join("usr/share/applications"))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "desktop"))
+        .collect();
+    files.sort();
+    let preferred = files.iter().position(|path| path.file_stem().is_some_and(|stem| stem == name));
+    preferred.or(if files.is_empty() { None } else { Some(0) }).map(|index| files.swap_remove(index))
+}
+
+/// The binary AppRun starts: the one the `.desktop` file runs, else the one
+/// named after the package, else the first in `usr/bin`.
+fn main_binary(app_dir: &Path, name: &str, exec: Option<&str>) -> Result<String> {
+    let bin_dir = app_dir.join("usr/bin");
+    if let Some(exec) = exec.and_then(|exec| exec.split_whitespace().next())
+        && let Some(file) = Path::new(exec.trim_matches('"')).file_name()
+        && bin_dir.join(file).exists()
+    {
+        return Ok(file.to_string_lossy().into_owned());
+    }
+    if bin_dir.join(name).exists() {
+        return Ok(name.to_string());
+    }
+    let mut binaries: Vec<String> = fs::read_dir(&bin_dir)
+        .map(|dir| dir.filter_map(|e| e.ok()).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    binaries.sort();
+    binaries
+        .into_iter()
+        .next()
+        .context("Package has no executables in usr/bin to bundle")
+}
+
+/// Rewrites `Exec`/`TryExec` to bare command names, which AppRun puts on
+/// `PATH`, and `Icon` to the top-level icon name.
+fn rewrite_desktop_entry(entry: &str, icon: &str) -> String {
+    let mut out = String::new();
+    for line in entry.lines() {
+        let rewritten = match line.split_once('=') {
+            Some((key, value)) if key.trim() == "Exec" || key.trim() == "TryExec" => {
+                let (command, args) = value.trim().split_once(' ').unwrap_or((value.trim(), ""));
+                let command = Path::new(command.trim_matches('"')).file_name().map(|file| file.to_string_lossy());
+                let command = command.unwrap_or_default();
+                if args.is_empty() { format!("{}={}", key, command) } else { format!("{}={} {}", key, command, args) }
+            }
+            Some((key, _)) if key.trim() == "Icon" => format!("Icon={}", icon),
+            _ => line.to_string(),
+        };
+        out.push_str(&rewritten);
+        out.push('\n');
+    }
+    out
+}
+
+/// Rough icon size from paths like `icons/hicolor/128x128/apps`; scalable
+/// icons win.
+fn icon_rank(path: &Path) -> u32 {
+    if path.extension().is_some_and(|ext| ext == "svg") {
+        return u32::MAX;
+    }
+    path.components()
+        .filter_map(|component| component.as_os_str().to_str()?.split_once('x')?.0.parse().ok())
+        .next()
+        .unwrap_or(1)
+}
+
+/// Finds the largest icon called `icon` (or at that path) inside the AppDir.
+fn find_icon(app_dir: &Path, icon: &str) -> Option<PathBuf> {
+    if icon.starts_with('/') {
+        let path = app_dir.join(icon.trim_start_matches('/'));
+        return path.is_file().then_some(path);
+    }
+    ["usr/share/icons", "usr/share/pixmaps"]
+        .iter()
+        .flat_map(|dir| WalkDir::new(app_dir.join(dir)).into_iter().filter_map(|e| e.ok()))
+        .filter(|entry| entry.path().file_stem().is_some_and(|stem| stem == icon))
+        .filter(|entry| {
+            entry.path().extension().is_some_and(|ext| ext == "png" || ext == "svg" || ext == "xpm")
+        })
+        .map(|entry| entry.into_path())
+        .max_by_key(|path| icon_rank(path))
+}
+
+/// Puts the `.desktop` file, icon and `.DirIcon` at the top of the AppDir and
+/// writes the AppRun launcher.
+fn write_entry_points(app_dir: &Path, name: &str) -> Result<()> {
+    let desktop = find_desktop_file(app_dir, name);
+    let entry = match &desktop {
+        Some(path) => fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    let binary = main_binary(app_dir, name, desktop_value(&entry, "Exec"))?;
+
+    let icon_name = desktop_value(&entry, "Icon").unwrap_or(name).to_string();
+    let icon_stem = Path::new(&icon_name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| name.to_string());
+    let icon_file = match find_icon(app_dir, &icon_name) {
+        Some(path) => {
+            let ext = path.extension().unwrap_or_default().to_string_lossy().into_owned();
+            let file = format!("{}.{}", icon_stem, ext);
+            fs::copy(&path, app_dir.join(&file))?;
+            file
+        }
+        None => {
+            warn!("No icon named {} in the package, using a placeholder", icon_name);
+            let file = format!("{}.svg", icon_stem);
+            fs::write(app_dir.join(&file), PLACEHOLDER_ICON)?;
+            file
+        }
+    };
+    symlink(&icon_file, app_dir.join(".DirIcon"))?;
+
+    let entry = if desktop.is_some() {
+        rewrite_desktop_entry(&entry, &icon_stem)
+    } else {
+        warn!("Package has no .desktop file, generating one for {}", binary);
+        format!(
+            "[Desktop Entry]\nType=Application\nName={}\nExec={}\nIcon={}\nTerminal=true\nCategories=Utility;\n",
+            name, binary, icon_stem
+        )
+    };
+    let desktop_name = desktop
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|file| file.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("{}.desktop", name));
+    fs::write(app_dir.join(desktop_name), entry)?;
+
+    let app_run = app_dir.join("AppRun");
+    fs::write(
+        &app_run,
+        format!(
+            "#!/bin/sh\n\
+             # Generated by arch-installer for {}\n\
+             HERE=\"$(dirname \"$(readlink -f \"$0\")\")\"\n\
+             export PATH=\"$HERE/usr/bin:$PATH\"\n\
+             export LD_LIBRARY_PATH=\"$HERE/usr/lib${{LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}}\"\n\
+             export XDG_DATA_DIRS=\"$HERE/usr/share:${{XDG_DATA_DIRS:-/usr/local/share:/usr/share}}\"\n\
+             exec \"$HERE/usr/bin/{}\" \"$@\"\n",
+            name, binary
+        ),
+    )?;
+    fs::set_permissions(&app_run, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+/// appimagetool's name for the package's architecture.
+fn appimage_arch(arch: &str) -> &str {
+    match arch {
+        "" | "any" => std::env::consts::ARCH,
+        "armv7h" => "armhf",
+        other => other,
+    }
+}
+
+/// Lays out an AppDir for the package in `out_dir` and packs it with
+/// `appimagetool`. Without the tool the AppDir is left in place and returned
+/// instead, so it can be packed or run elsewhere.
+pub fn to_appimage(source: &Source, out_dir: &Path) -> Result<PathBuf> {
+    let name = source.value("pkgname");
+    let arch = appimage_arch(source.value("arch"));
+    fs::create_dir_all(out_dir)?;
+    let app_dir = out_dir.join(format!("{}.AppDir", name));
+    if app_dir.symlink_metadata().is_ok() {
+        bail!("{} already exists; remove it first", app_dir.display());
+    }
+    fs::create_dir(&app_dir)?;
+    copy_payload(source, &app_dir)?;
+    bundle_libraries(&app_dir)?;
+    write_entry_points(&app_dir, name)?;
+
+    let image = out_dir.join(format!("{}-{}-{}.AppImage", name, source.value("pkgver"), arch));
+    let result = Command::new("appimagetool")
+        .arg("--no-appstream")
+        .arg(&app_dir)
+        .arg(&image)
+        .env("ARCH", arch)
+        .output();
+    match result {
+        Ok(output) if output.status.success() => {
+            debug!("{}", String::from_utf8_lossy(&output.stdout));
+            fs::remove_dir_all(&app_dir)?;
+            Ok(image)
+        }
+        Ok(output) => bail!(
+            "appimagetool failed on {}: {}",
+            app_dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            warn!(
+                "appimagetool not found; pack the AppDir with `appimagetool {}` or start it with its AppRun",
+                app_dir.display()
+            );
+            Ok(app_dir)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The Go architecture name and variant OCI image configs use.
+fn oci_platform(arch: &str) -> (&str, Option<&str>) {
+    match arch {
+        "" | "any" => oci_platform(std::env::consts::ARCH),
+        "x86_64" => ("amd64", None),
+        "aarch64" => ("arm64", None),
+        "armv7h" => ("arm", Some("v7")),
+        "i686" => ("386", None),
+        other => (other, None),
+    }
+}
+
+/// Writes `data` as a content-addressed blob and returns its descriptor.
+fn add_blob(layout: &mut Builder<Vec<u8>>, media_type: &str, data: &[u8]) -> Result<Value> {
+    let digest = format!("{:x}", Sha256::digest(data));
+    append_file(layout, &format!("blobs/sha256/{}", digest), 0o644, data)?;
+    Ok(json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{}", digest),
+        "size": data.len(),
+    }))
+}
+
+/// Builds `<name>-<version>.oci.tar` in `out_dir`: an OCI image layout
+/// archive with a single layer holding the package's file tree, ready for
+/// `podman load` or `skopeo copy oci-archive:...`. Install scripts do not
+/// run, as nothing is installed.
+pub fn to_oci(source: &Source, out_dir: &Path) -> Result<PathBuf> {
+    let name = source.value("pkgname");
+    let version = source.value("pkgver");
+    if source.has_install_script() {
+        warn!("The package has an install script, which is not run for the image layer");
+    }
+    let mut layer = Vec::new();
+    tar_payload(source, &mut layer)?;
+    let diff_id = format!("sha256:{:x}", Sha256::digest(&layer));
+    let layer = gzip(&layer)?;
+
+    let created = source.value("builddate").parse().map(timestamp).unwrap_or_else(|_| timestamp(0));
+    let mut labels = json!({
+        "org.opencontainers.image.title": name,
+        "org.opencontainers.image.version": version,
+        "org.opencontainers.image.created": created,
+    });
+    for (label, key) in [
+        ("org.opencontainers.image.description", "pkgdesc"),
+        ("org.opencontainers.image.url", "url"),
+        ("org.opencontainers.image.vendor", "packager"),
+    ] {
+        if !source.value(key).is_empty() {
+            labels[label] = json!(source.value(key));
+        }
+    }
+    let licenses = source.values("license");
+    if !licenses.is_empty() {
+        labels["org.opencontainers.image.licenses"] = json!(licenses.join(" AND "));
+    }
+
+    let (architecture, variant) = oci_platform(source.value("arch"));
+    let mut config = json!({
+        "created": created,
+        "architecture": architecture,
+        "os": "linux",
+        "config": { "Labels": labels },
+        "rootfs": { "type": "layers", "diff_ids": [diff_id] },
+        "history": [{
+            "created": created,
+            "created_by": format!("arch-installer bundle oci {}-{}", name, version),
+        }],
+    });
+    if let Some(variant) = variant {
+        config["variant"] = json!(variant);
+    }
+
+    let mut layout = Builder::new(Vec::new());
+    append_file(&mut layout, "oci-layout", 0o644, br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    let layer = add_blob(&mut layout, "application/vnd.oci.image.layer.v1.tar+gzip", &layer)?;
+    let config = add_blob(
+        &mut layout,
+        "application/vnd.oci.image.config.v1+json",
+        &serde_json::to_vec(&config)?,
+    )?;
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": config,
+        "layers": [layer],
+        "annotations": labels,
+    });
+    let mut manifest = add_blob(
+        &mut layout,
+        "application/vnd.oci.image.manifest.v1+json",
+        &serde_json::to_vec(&manifest)?,
+    )?;
+    // Tags cannot contain the `:` of an epoch.
+    let tag = version.replace(':', "_");
+    manifest["annotations"] = json!({ "org.opencontainers.image.ref.name": format!("{}:{}", name, tag) });
+    let index = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [manifest],
+    });
+    append_file(&mut layout, "index.json", 0o644, &serde_json::to_vec(&index)?)?;
+
+    fs::create_dir_all(out_dir)?;
+    let out_path = out_dir.join(format!("{}-{}.oci.tar", name, tag));
+    fs::write(&out_path, layout.into_inner()?).context(format!("Failed to write {}", out_path.display()))?;
+    Ok(out_path)
+}
+
+/// Directories systemd-sysext merges into the running system.
+const SYSEXT_HIERARCHIES: [&str; 2] = ["usr", "opt"];
+
+/// Where systemd-sysext looks for extension images to merge.
+const EXTENSIONS_DIR: &str = "/var/lib/extensions";
+
+/// The architecture name systemd uses in `extension-release` files.
+fn systemd_arch(arch: &str) -> &str {
+    match arch {
+        "" | "any" => systemd_arch(std::env::consts::ARCH),
+        "x86_64" => "x86-64",
+        "aarch64" => "arm64",
+        "armv7h" => "arm",
+        "i686" => "x86",
+        other => other,
+    }
+}
+
+/// Read-only file systems make smaller images; ext4 is the fallback that is
+/// always there.
+fn sysext_format() -> &'static str {
+    if find_executable("mkfs.erofs").is_some() {
+        "erofs"
+    } else if find_executable("mksquashfs").is_some() {
+        "squashfs"
+    } else {
+        "ext4"
+    }
+}
+
+/// Builds `<name>.raw` in `out_dir`: a systemd system extension image with
+/// the package's `/usr` and `/opt` on a verity-protected partition, signed
+/// with `key` and `certificate` when given, for `systemd-sysext merge`.
+/// Files elsewhere and install scripts are left out, as extensions only
+/// overlay those two hierarchies.
+pub fn to_sysext(source: &Source, out_dir: &Path, key: Option<&Path>, certificate: Option<&Path>) -> Result<PathBuf> {
+    let name = source.value("pkgname");
+    if find_executable("systemd-repart").is_none() {
+        bail!("systemd-repart (systemd 252 or newer) is required to build system extensions");
+    }
+    if source.has_install_script() {
+        warn!("The package has an install script, which is not run for the extension");
+    }
+    let work = tempfile::tempdir()?;
+    let tree = work.path().join("tree");
+    fs::create_dir(&tree)?;
+    let mut dropped = BTreeSet::new();
+    for relative in source.payload() {
+        let top = relative.components().next().unwrap().as_os_str().to_string_lossy().into_owned();
+        if !SYSEXT_HIERARCHIES.contains(&top.as_str()) {
+            dropped.insert(top);
+            continue;
+        }
+        copy_item(source, &relative, &tree)?;
+    }
+    for top in &dropped {
+        warn!("Leaving out /{}, as system extensions only hold /usr and /opt", top);
+    }
+
+    let release_dir = tree.join("usr/lib/extension-release.d");
+    fs::create_dir_all(&release_dir)?;
+    fs::write(
+        release_dir.join(format!("extension-release.{}", name)),
+        format!(
+            "ID=_any\nARCHITECTURE={}\nSYSEXT_ID={}\nSYSEXT_VERSION_ID={}\n",
+            systemd_arch(source.value("arch")),
+            name,
+            source.value("pkgver")
+        ),
+    )?;
+
+    // systemd 252 cannot size a partition to the files it is given, so the
+    // root partition gets room for them and the file system's own overhead.
+    let content: u64 = WalkDir::new(&tree)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|meta| meta.len())
+        .sum();
+    let copy_files: String = SYSEXT_HIERARCHIES
+        .iter()
+        .filter(|top| tree.join(top).is_dir())
+        .map(|top| format!("CopyFiles=/{}:/{}\n", top, top))
+        .collect();
+    let definitions = work.path().join("repart.d");
+    fs::create_dir(&definitions)?;
+    fs::write(
+        definitions.join("10-root.conf"),
+        format!(
+            "[Partition]\nType=root\nFormat={}\n{}SizeMinBytes={}\nVerity=data\nVerityMatchKey=root\n",
+            sysext_format(),
+            copy_files,
+            (content + content / 4 + (16 << 20)).next_multiple_of(4096)
+        ),
+    )?;
+    fs::write(definitions.join("20-verity.conf"), "[Partition]\nType=root-verity\nVerity=hash\nVerityMatchKey=root\n")?;
+
+    let mut repart = Command::new("systemd-repart");
+    match (key, certificate) {
+        (Some(key), Some(certificate)) => {
+            fs::write(
+                definitions.join("30-signature.conf"),
+                "[Partition]\nType=root-verity-sig\nVerity=signature\nVerityMatchKey=root\n",
+            )?;
+            repart.arg(format!("--private-key={}", key.display()));
+            repart.arg(format!("--certificate={}", certificate.display()));
+        }
+        (None, None) => warn!("No --key given; the image is unsigned and merges only where that is allowed"),
+        _ => bail!("Signing an extension needs both a private key and its certificate"),
+    }
+
+    fs::create_dir_all(out_dir)?;
+    let image = out_dir.join(format!("{}.raw", name));
+    let partial = out_dir.join(format!("{}.raw.part", name));
+    let _ = fs::remove_file(&partial);
+    let output = repart
+        .arg(format!("--definitions={}", definitions.display()))
+        .arg(format!("--root={}", tree.display()))
+        .args(["--empty=create", "--size=auto", "--dry-run=no"])
+        .arg(&partial)
+        .output()
+        .context("Failed to run systemd-repart")?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial);
+        bail!("systemd-repart failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    debug!("{}", String::from_utf8_lossy(&output.stdout));
+    fs::rename(&partial, &image)?;
+    Ok(image)
+}
+
+/// Puts `image` where systemd-sysext finds it, replacing an older build of
+/// the extension, and merges it into the running system.
+pub fn install_sysext(image: &Path) -> Result<PathBuf> {
+    let dest = Path::new(EXTENSIONS_DIR).join(image.file_name().unwrap());
+    fs::create_dir_all(EXTENSIONS_DIR).context(format!("Failed to create {}", EXTENSIONS_DIR))?;
+    fs::copy(image, &dest).context(format!("Failed to copy the image to {}", dest.display()))?;
+    info!("Installed {}", dest.display());
+    match Command::new("systemd-sysext").arg("refresh").output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => bail!("systemd-sysext refresh failed: {}", String::from_utf8_lossy(&output.stderr).trim()),
+        Err(err) if err.kind() == ErrorKind::NotFound => bail!("systemd-sysext is not installed"),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(dest)
+}
@@ -0,0 +1,76 @@
+use crate::output::{PackageCheck, PermissionProblem};
+use crate::paths::{rooted, unrooted};
+use crate::quarantine::quarantined_files;
+use crate::records::{InstalledPackage, get_log_path};
+use anyhow::Result;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Looks for what a recorded file should not have: a mode that stops it from
+/// running or lets anyone change it.
+fn permission_problems(path: &Path, mode: u32, quarantined: bool) -> Vec<&'static str> {
+    let mut problems = Vec::new();
+    let in_bin = path.parent().and_then(|dir| dir.file_name()).is_some_and(|dir| dir == "bin");
+    if in_bin && mode & 0o111 == 0 && !quarantined {
+        problems.push("not executable");
+    }
+    if mode & 0o002 != 0 {
+        problems.push("world-writable");
+    }
+    problems
+}
+
+/// Checks the recorded files of one package without reading their contents:
+/// missing files, symlinks to nothing, suspicious modes and directories the
+/// package left empty.
+pub fn check(package: &InstalledPackage) -> Result<PackageCheck> {
+    let log = fs::read_to_string(get_log_path(&package.name, &package.prefix))?;
+    let mut report = PackageCheck {
+        name: package.name.clone(),
+        prefix: package.prefix.clone(),
+        checked: 0,
+        missing: Vec::new(),
+        dangling: Vec::new(),
+        permissions: Vec::new(),
+        empty_dirs: Vec::new(),
+    };
+    let quarantined: HashSet<PathBuf> = quarantined_files(&package.name, &package.prefix)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    let mut dirs = BTreeSet::new();
+    for line in log.lines().filter(|line| !line.is_empty()) {
+        report.checked += 1;
+        let path = rooted(line);
+        if let Some(dir) = path.parent() {
+            dirs.insert(dir.to_path_buf());
+        }
+        let Ok(meta) = path.symlink_metadata() else {
+            report.missing.push(line.to_string());
+            continue;
+        };
+        if meta.file_type().is_symlink() {
+            if !path.exists() {
+                report.dangling.push(line.to_string());
+            }
+            continue;
+        }
+        let mode = meta.permissions().mode();
+        let problems = permission_problems(&path, mode, quarantined.contains(&path));
+        if !problems.is_empty() {
+            report.permissions.push(PermissionProblem {
+                path: line.to_string(),
+                mode: format!("{:o}", mode & 0o7777),
+                problem: problems.join(", "),
+            });
+        }
+    }
+    for dir in dirs {
+        if fs::read_dir(&dir).is_ok_and(|mut entries| entries.next().is_none()) {
+            report.empty_dirs.push(unrooted(&dir).display().to_string());
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,171 @@
+use crate::archive::{download_path, is_url};
+use crate::output::{CleanReport, CleanedItem, format_size};
+use crate::paths::{get_cache_dir, get_staging_dir, rooted};
+use crate::records::{get_log_path, get_source_path, installed_packages, remove_records};
+use crate::stats::dir_size;
+use crate::store;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Temporary directories younger than this may belong to a run still going.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+fn is_stale(path: &Path) -> bool {
+    path.symlink_metadata()
+        .and_then(|meta| meta.modified())
+        .is_ok_and(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() > STALE_AFTER)
+}
+
+/// Name of the package in a `<name>-<pkgver>-<pkgrel>-<arch>.pkg.tar.zst`
+/// file name.
+fn cached_package_name(file_name: &str) -> Option<&str> {
+    let stem = file_name.strip_suffix(".pkg.tar.zst")?;
+    stem.rsplitn(4, '-').nth(3)
+}
+
+struct Cleaner {
+    dry_run: bool,
+    report: CleanReport,
+}
+
+impl Cleaner {
+    fn remove(&mut self, kind: &str, path: &Path) -> Result<()> {
+        let size = if path.is_dir() { dir_size(path) } else { path.metadata().map_or(0, |meta| meta.len()) };
+        if !self.dry_run {
+            if path.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            }
+            .context(format!("Failed to remove {}", path.display()))?;
+        }
+        let verb = if self.dry_run { "Would remove" } else { "Removed" };
+        info!("{} {} {} ({})", verb, kind, path.display(), format_size(size));
+        self.report.reclaimed += size;
+        self.report.removed.push(CleanedItem {
+            kind: kind.to_string(),
+            path: path.display().to_string(),
+            size,
+        });
+        Ok(())
+    }
+
+    /// Keeps the `keep` newest downloads of each package, and any download a
+    /// package is still installed from; drops leftover partial downloads.
+    fn downloads(&mut self, keep: usize, in_use: &BTreeSet<PathBuf>) -> Result<()> {
+        let dir = get_cache_dir().join("downloads");
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return Ok(());
+        };
+        let mut by_name: HashMap<String, Vec<(SystemTime, PathBuf)>> = HashMap::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            match cached_package_name(&file_name) {
+                Some(name) => {
+                    let modified = entry.metadata()?.modified()?;
+                    by_name.entry(name.to_string()).or_default().push((modified, path));
+                }
+                None if is_stale(&path) => self.remove("partial download", &path)?,
+                None => {}
+            }
+        }
+        for versions in by_name.values_mut() {
+            versions.sort_by_key(|(modified, _)| Reverse(*modified));
+            for (_, path) in versions.iter().skip(keep) {
+                if in_use.contains(path) {
+                    debug!("Keeping {}, an installed package came from it", path.display());
+                    continue;
+                }
+                self.remove("cached package", path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Extraction and staging directories left behind by runs that crashed.
+    fn temporary_dirs(&mut self, prefixes: &BTreeSet<String>) -> Result<()> {
+        let mut dirs = Vec::new();
+        if let Ok(entries) = fs::read_dir(get_cache_dir()) {
+            dirs.extend(
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter(|entry| entry.file_name().to_string_lossy().starts_with(".tmp"))
+                    .map(|entry| entry.path()),
+            );
+        }
+        for prefix in prefixes {
+            if let Ok(entries) = fs::read_dir(get_staging_dir(prefix)) {
+                dirs.extend(entries.filter_map(|e| e.ok()).map(|entry| entry.path()));
+            }
+        }
+        for dir in dirs.iter().filter(|dir| is_stale(dir)) {
+            let kind = if dir.starts_with(get_cache_dir()) { "extraction directory" } else { "staging directory" };
+            self.remove(kind, dir)?;
+        }
+        if !self.dry_run {
+            for prefix in prefixes {
+                let _ = fs::remove_dir(get_staging_dir(prefix));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records of installations whose prefix is gone or none of whose files
+    /// exist any more, which `uninstall` would only trip over.
+    fn orphaned_records(&mut self) -> Result<()> {
+        for installed in installed_packages()? {
+            let log = fs::read_to_string(get_log_path(&installed.name, &installed.prefix))?;
+            let gone = !rooted(&installed.prefix).is_dir()
+                || log.lines().filter(|line| !line.is_empty()).all(|line| rooted(line).symlink_metadata().is_err());
+            if !gone {
+                continue;
+            }
+            let path = get_log_path(&installed.name, &installed.prefix);
+            if !self.dry_run {
+                remove_records(&installed.name, &installed.prefix)?;
+            }
+            let verb = if self.dry_run { "Would remove" } else { "Removed" };
+            info!("{} the record of {} ({}), none of its files exist", verb, installed.name, installed.prefix);
+            self.report.removed.push(CleanedItem {
+                kind: "record".to_string(),
+                path: path.display().to_string(),
+                size: 0,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Frees space taken by the cache and by leftovers of failed runs, keeping
+/// `keep` downloads of each package. With `dry_run` nothing is removed.
+pub fn clean(keep: usize, dry_run: bool) -> Result<CleanReport> {
+    let installed = installed_packages()?;
+    let in_use: BTreeSet<PathBuf> = installed
+        .iter()
+        .filter_map(|package| fs::read_to_string(get_source_path(&package.name, &package.prefix)).ok())
+        .map(|source| if is_url(&source) { download_path(&source) } else { PathBuf::from(source) })
+        .collect();
+    let mut prefixes: BTreeSet<String> = installed.into_iter().map(|package| package.prefix).collect();
+    prefixes.insert("/usr/local".to_string());
+    let mut cleaner = Cleaner {
+        dry_run,
+        report: CleanReport {
+            dry_run,
+            removed: Vec::new(),
+            reclaimed: 0,
+        },
+    };
+    cleaner.downloads(keep, &in_use)?;
+    cleaner.temporary_dirs(&prefixes)?;
+    cleaner.orphaned_records()?;
+    if !dry_run {
+        store::prune()?;
+    }
+    Ok(cleaner.report)
+}
@@ -0,0 +1,82 @@
+use crate::handlers::ExternalHandler;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings from `config.toml`. Every key is optional and command-line
+/// options take precedence.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Directory for installed package records, overriding the default
+    /// state directory.
+    pub state_dir: Option<PathBuf>,
+    /// How many packages to download at once.
+    pub parallel_downloads: Option<usize>,
+    /// How many downloads of each package `clean` keeps in the cache.
+    pub cache_keep: Option<usize>,
+    /// Whether unprivileged installs into system prefixes ask for
+    /// authorization through polkit (the default) instead of failing.
+    pub polkit: Option<bool>,
+    /// Whether commands run as root unpack packages as an unprivileged
+    /// user (the default).
+    pub privilege_separation: Option<bool>,
+    /// URL or path of the advisory database `audit` reads, in the format of
+    /// the Arch Linux security tracker's `all.json`.
+    pub advisories: Option<String>,
+    /// Base URL of the rebuilderd instance `rebuild-check` asks.
+    pub rebuilderd: Option<String>,
+    /// Whether installs and upgrades that take a while end with a desktop
+    /// notification (the default) when run in a graphical session.
+    pub notifications: Option<bool>,
+    /// How many seconds a transaction takes before it ends with a
+    /// notification; 30 by default.
+    pub notify_after: Option<u64>,
+    /// Repository databases (`<repo>.db` paths or URLs) that `install` looks
+    /// bare package names up in, in order.
+    pub repos: Vec<String>,
+    /// Directories for files distributions lay out differently.
+    pub layout: LayoutOverrides,
+    /// File-class handlers run through external commands.
+    #[serde(rename = "handler")]
+    pub handlers: Vec<ExternalHandler>,
+}
+
+/// The `[layout]` table: where shell completions and pixmaps go instead of
+/// the directories found on the host. Relative paths are taken under the
+/// data directory (`/usr/local/share` or `~/.local/share`).
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LayoutOverrides {
+    pub bash_completions: Option<PathBuf>,
+    pub zsh_completions: Option<PathBuf>,
+    pub fish_completions: Option<PathBuf>,
+    pub pixmaps: Option<PathBuf>,
+}
+
+/// Reads the config file at `path`; a missing file means defaults.
+pub fn load(path: &Path) -> Result<()> {
+    let config = if path.exists() {
+        let text = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).context(format!("Invalid config file {}", path.display()))?
+    } else {
+        Config::default()
+    };
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// The state directory set in the environment with `ARCH_INSTALLER_STATE_DIR`
+/// or else in the config file, for when `--state-dir` is not given.
+pub fn state_dir() -> Option<PathBuf> {
+    let from_env = std::env::var_os("ARCH_INSTALLER_STATE_DIR").filter(|dir| !dir.is_empty()).map(PathBuf::from);
+    from_env.or_else(|| get().state_dir.clone())
+}
@@ -0,0 +1,588 @@
+use crate::archive::pkginfo_values;
+use anyhow::{Context, Result, bail};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{debug, warn};
+use md5::Md5;
+use sha2::Digest;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tar::{Builder, Header, HeaderMode};
+use walkdir::WalkDir;
+
+/// An extracted Arch package: the file tree plus its metadata files.
+pub struct Source {
+    tree: PathBuf,
+    pkginfo: String,
+    install: Option<String>,
+}
+
+impl Source {
+    pub fn open(tree: &Path) -> Result<Self> {
+        let pkginfo_path = tree.join(".PKGINFO");
+        let pkginfo = fs::read_to_string(&pkginfo_path)
+            .context(format!("Failed to read .PKGINFO from {}", pkginfo_path.display()))?;
+        let source = Source {
+            tree: tree.to_path_buf(),
+            install: fs::read_to_string(tree.join(".INSTALL")).ok(),
+            pkginfo,
+        };
+        if source.value("pkgname").is_empty() || source.value("pkgver").is_empty() {
+            bail!(".PKGINFO lacks pkgname or pkgver");
+        }
+        Ok(source)
+    }
+
+    pub fn tree(&self) -> &Path {
+        &self.tree
+    }
+
+    pub fn has_install_script(&self) -> bool {
+        self.install.is_some()
+    }
+
+    pub fn value(&self, key: &str) -> &str {
+        pkginfo_values(&self.pkginfo, key).first().copied().unwrap_or_default()
+    }
+
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        pkginfo_values(&self.pkginfo, key)
+    }
+
+    /// Package contents relative to the tree, skipping the metadata files at
+    /// its top level.
+    pub fn payload(&self) -> Vec<PathBuf> {
+        WalkDir::new(&self.tree)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| entry.depth() > 1 || !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(|e| e.ok())
+            .filter_map(|entry| entry.path().strip_prefix(&self.tree).ok().map(Path::to_path_buf))
+            .collect()
+    }
+
+    /// A maintainer script that sources `.INSTALL` and then runs `dispatch`,
+    /// or `None` when the package defines none of `functions`.
+    fn scriptlet(&self, functions: &[&str], dispatch: &str) -> Option<String> {
+        let install = self.install.as_ref()?;
+        if !functions.iter().any(|function| install.contains(function)) {
+            return None;
+        }
+        Some(format!("#!/bin/bash\n{}\n{}exit 0\n", install.trim_end(), dispatch))
+    }
+}
+
+/// Arch dependency names rarely match other distributions', so they are
+/// left out of converted packages.
+fn warn_dependencies(source: &Source, distribution: &str) {
+    let depends = source.values("depend");
+    if !depends.is_empty() {
+        warn!(
+            "Arch dependencies are not carried over, install their {} equivalents yourself: {}",
+            distribution,
+            depends.join(", ")
+        );
+    }
+}
+
+/// Calls an `.INSTALL` hook only if the package defines it.
+fn hook(function: &str, args: &str) -> String {
+    format!("declare -F {} >/dev/null && {} {}", function, function, args)
+}
+
+pub fn tar_payload(source: &Source, writer: impl Write) -> Result<()> {
+    let mut tar = Builder::new(writer);
+    tar.mode(HeaderMode::Deterministic);
+    tar.follow_symlinks(false);
+    for relative in source.payload() {
+        tar.append_path_with_name(source.tree.join(&relative), Path::new(".").join(&relative))
+            .context(format!("Failed to archive {}", relative.display()))?;
+    }
+    tar.into_inner()?.flush()?;
+    Ok(())
+}
+
+pub fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+pub fn append_file(tar: &mut Builder<Vec<u8>>, name: &str, mode: u32, content: &[u8]) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(0);
+    header.set_cksum();
+    tar.append_data(&mut header, Path::new(".").join(name), content)?;
+    Ok(())
+}
+
+fn debian_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "i686" => "i386",
+        "armv7h" => "armhf",
+        "any" => "all",
+        other => other,
+    }
+}
+
+/// Debian package names allow lowercase letters, digits and `+-.` only.
+fn debian_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "+-.".contains(c) { c } else { '-' })
+        .collect()
+}
+
+fn deb_control(source: &Source) -> String {
+    let name = debian_name(source.value("pkgname"));
+    let description = match source.value("pkgdesc") {
+        "" => name.clone(),
+        desc => desc.to_string(),
+    };
+    let maintainer = match source.value("packager") {
+        "" | "Unknown Packager" => "arch-installer <root@localhost>",
+        packager => packager,
+    };
+    let mut control = format!(
+        "Package: {}\nVersion: {}\nArchitecture: {}\nMaintainer: {}\n",
+        name,
+        source.value("pkgver"),
+        debian_arch(source.value("arch")),
+        maintainer
+    );
+    if let Ok(size) = source.value("size").parse::<u64>() {
+        control.push_str(&format!("Installed-Size: {}\n", size.div_ceil(1024)));
+    }
+    let provides = source.values("provides");
+    if !provides.is_empty() {
+        let names: Vec<String> = provides
+            .iter()
+            .map(|provide| debian_name(provide.split(['=', '<', '>']).next().unwrap_or(provide)))
+            .collect();
+        control.push_str(&format!("Provides: {}\n", names.join(", ")));
+    }
+    control.push_str("Section: misc\nPriority: optional\n");
+    if !source.value("url").is_empty() {
+        control.push_str(&format!("Homepage: {}\n", source.value("url")));
+    }
+    control.push_str(&format!("Description: {}\n", description));
+    control
+}
+
+/// Maps the `.INSTALL` hooks onto dpkg's maintainer scripts and their
+/// arguments.
+fn deb_scripts(source: &Source) -> Vec<(&'static str, String)> {
+    let version = format!("'{}'", source.value("pkgver"));
+    let upgrade = format!("{} \"$2\"", version);
+    let scripts = [
+        (
+            "preinst",
+            vec!["pre_install", "pre_upgrade"],
+            format!(
+                "case \"$1\" in\n    install) {} ;;\n    upgrade) {} ;;\nesac\n",
+                hook("pre_install", &version),
+                hook("pre_upgrade", &upgrade)
+            ),
+        ),
+        (
+            "postinst",
+            vec!["post_install", "post_upgrade"],
+            format!(
+                "if [ \"$1\" = configure ]; then\n    if [ -z \"$2\" ]; then\n        {}\n    else\n        {}\n    fi\nfi\n",
+                hook("post_install", &version),
+                hook("post_upgrade", &upgrade)
+            ),
+        ),
+        (
+            "prerm",
+            vec!["pre_remove"],
+            format!("[ \"$1\" = remove ] && {}\n", hook("pre_remove", &version)),
+        ),
+        (
+            "postrm",
+            vec!["post_remove"],
+            format!("[ \"$1\" = remove ] && {}\n", hook("post_remove", &version)),
+        ),
+    ];
+    scripts
+        .into_iter()
+        .filter_map(|(name, functions, dispatch)| Some((name, source.scriptlet(&functions, &dispatch)?)))
+        .collect()
+}
+
+/// Builds `<name>_<version>_<arch>.deb` in `out_dir` from an extracted
+/// package.
+pub fn to_deb(source: &Source, out_dir: &Path) -> Result<PathBuf> {
+    let name = debian_name(source.value("pkgname"));
+    let version = source.value("pkgver");
+    warn_dependencies(source, "Debian");
+    let mut md5sums = String::new();
+    for relative in source.payload() {
+        let path = source.tree.join(&relative);
+        if path.symlink_metadata()?.is_file() {
+            md5sums.push_str(&format!("{:x}  {}\n", Md5::digest(fs::read(&path)?), relative.display()));
+        }
+    }
+    let mut control = Builder::new(Vec::new());
+    append_file(&mut control, "control", 0o644, deb_control(source).as_bytes())?;
+    append_file(&mut control, "md5sums", 0o644, md5sums.as_bytes())?;
+    for (script, text) in deb_scripts(source) {
+        debug!("adding maintainer script {}", script);
+        append_file(&mut control, script, 0o755, text.as_bytes())?;
+    }
+    let control = gzip(&control.into_inner()?)?;
+    let mut data = Vec::new();
+    tar_payload(source, &mut data)?;
+    let data = gzip(&data)?;
+
+    let arch = debian_arch(source.value("arch"));
+    let out_path = out_dir.join(format!("{}_{}_{}.deb", name, version.replace(':', "%3a"), arch));
+    let file = File::create(&out_path).context(format!("Failed to create {}", out_path.display()))?;
+    let mut deb = ar::Builder::new(file);
+    for (member, content) in [
+        ("debian-binary", b"2.0\n".as_slice()),
+        ("control.tar.gz", &control),
+        ("data.tar.gz", &data),
+    ] {
+        let mut header = ar::Header::new(member.as_bytes().to_vec(), content.len() as u64);
+        header.set_mode(0o100644);
+        deb.append(&header, content)?;
+    }
+    Ok(out_path)
+}
+
+const RPM_INT16: u32 = 3;
+const RPM_INT32: u32 = 4;
+const RPM_STRING: u32 = 6;
+const RPM_BIN: u32 = 7;
+const RPM_STRING_ARRAY: u32 = 8;
+const RPM_I18NSTRING: u32 = 9;
+
+enum RpmValue {
+    Int16(Vec<u16>),
+    Int32(Vec<u32>),
+    Str(String),
+    Bin(Vec<u8>),
+    StrArray(Vec<String>),
+    I18n(String),
+}
+
+/// An RPM header structure, used both for the signature and the main header.
+#[derive(Default)]
+struct RpmHeader {
+    entries: Vec<(u32, RpmValue)>,
+}
+
+impl RpmHeader {
+    fn add(&mut self, tag: u32, value: RpmValue) {
+        self.entries.push((tag, value));
+    }
+
+    fn string(&mut self, tag: u32, value: &str) {
+        self.add(tag, RpmValue::Str(value.to_string()));
+    }
+
+    /// Serializes the entries as one immutable region tagged `region`.
+    fn build(mut self, region: u32) -> Vec<u8> {
+        self.entries.sort_by_key(|(tag, _)| *tag);
+        let count = self.entries.len() as u32 + 1;
+        let mut index = Vec::new();
+        let mut store = Vec::new();
+        for (tag, value) in self.entries {
+            let (kind, align, items, bytes) = match value {
+                RpmValue::Int16(values) => {
+                    (RPM_INT16, 2, values.len(), values.iter().flat_map(|v| v.to_be_bytes()).collect())
+                }
+                RpmValue::Int32(values) => {
+                    (RPM_INT32, 4, values.len(), values.iter().flat_map(|v| v.to_be_bytes()).collect())
+                }
+                RpmValue::Str(value) => (RPM_STRING, 1, 1, nul_terminated(&[value])),
+                RpmValue::Bin(value) => (RPM_BIN, 1, value.len(), value),
+                RpmValue::StrArray(values) => (RPM_STRING_ARRAY, 1, values.len(), nul_terminated(&values)),
+                RpmValue::I18n(value) => (RPM_I18NSTRING, 1, 1, nul_terminated(&[value])),
+            };
+            while !store.len().is_multiple_of(align) {
+                store.push(0);
+            }
+            index.push([tag, kind, store.len() as u32, items as u32]);
+            store.extend(bytes);
+        }
+        let trailer = store.len() as u32;
+        for field in [region, RPM_BIN, (-(count as i32 * 16)) as u32, 16] {
+            store.extend(field.to_be_bytes());
+        }
+        index.insert(0, [region, RPM_BIN, trailer, 16]);
+        let mut header = vec![0x8e, 0xad, 0xe8, 0x01, 0, 0, 0, 0];
+        header.extend(count.to_be_bytes());
+        header.extend((store.len() as u32).to_be_bytes());
+        for entry in index {
+            header.extend(entry.iter().flat_map(|field| field.to_be_bytes()));
+        }
+        header.extend(store);
+        header
+    }
+}
+
+fn nul_terminated(values: &[String]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.bytes().chain([0])).collect()
+}
+
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, fields: [u32; 13], data: &[u8]) {
+    archive.extend(b"070701");
+    for field in fields {
+        archive.extend(format!("{:08x}", field).bytes());
+    }
+    archive.extend(name.bytes());
+    archive.push(0);
+    while !archive.len().is_multiple_of(4) {
+        archive.push(0);
+    }
+    archive.extend(data);
+    while !archive.len().is_multiple_of(4) {
+        archive.push(0);
+    }
+}
+
+fn rpm_arch(arch: &str) -> &str {
+    match arch {
+        "any" => "noarch",
+        "armv7h" => "armv7hl",
+        other => other,
+    }
+}
+
+/// Splits an Arch `epoch:pkgver-pkgrel` version into its parts.
+fn split_version(version: &str) -> (Option<u32>, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().ok(), rest),
+        None => (None, version),
+    };
+    let (version, release) = rest.rsplit_once('-').unwrap_or((rest, "1"));
+    (epoch, version, release)
+}
+
+/// Maps the `.INSTALL` hooks onto RPM scriptlets, which only learn how many
+/// instances of the package remain installed.
+fn rpm_scripts(source: &Source) -> Vec<(u32, String)> {
+    let version = format!("'{}'", source.value("pkgver"));
+    let scripts = [
+        (
+            1023,
+            vec!["pre_install", "pre_upgrade"],
+            format!(
+                "if [ \"$1\" -gt 1 ]; then\n    {}\nelse\n    {}\nfi\n",
+                hook("pre_upgrade", &format!("{} ''", version)),
+                hook("pre_install", &version)
+            ),
+        ),
+        (
+            1024,
+            vec!["post_install", "post_upgrade"],
+            format!(
+                "if [ \"$1\" -gt 1 ]; then\n    {}\nelse\n    {}\nfi\n",
+                hook("post_upgrade", &format!("{} ''", version)),
+                hook("post_install", &version)
+            ),
+        ),
+        (
+            1025,
+            vec!["pre_remove"],
+            format!("[ \"$1\" -eq 0 ] && {}\n", hook("pre_remove", &version)),
+        ),
+        (
+            1026,
+            vec!["post_remove"],
+            format!("[ \"$1\" -eq 0 ] && {}\n", hook("post_remove", &version)),
+        ),
+    ];
+    scripts
+        .into_iter()
+        .filter_map(|(tag, functions, dispatch)| Some((tag, source.scriptlet(&functions, &dispatch)?)))
+        .collect()
+}
+
+/// Builds `<name>-<version>-<release>.<arch>.rpm` in `out_dir` from an
+/// extracted package. Only files and symlinks are owned by the package, so
+/// directories shared with the rest of the system are left alone.
+pub fn to_rpm(source: &Source, out_dir: &Path) -> Result<PathBuf> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    let name = source.value("pkgname");
+    let (epoch, version, release) = split_version(source.value("pkgver"));
+    let arch = rpm_arch(source.value("arch"));
+    warn_dependencies(source, "RPM");
+    let backup = source.values("backup");
+
+    let mut sizes = Vec::new();
+    let mut modes = Vec::new();
+    let mut mtimes = Vec::new();
+    let mut digests = Vec::new();
+    let mut links = Vec::new();
+    let mut flags = Vec::new();
+    let mut dirs: Vec<String> = Vec::new();
+    let mut dir_indexes = Vec::new();
+    let mut basenames = Vec::new();
+    let mut payload = Vec::new();
+    for relative in source.payload() {
+        let path = source.tree.join(&relative);
+        let meta = path.symlink_metadata()?;
+        if meta.is_dir() {
+            continue;
+        }
+        let (data, digest, link) = if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path)?.to_string_lossy().into_owned();
+            (target.clone().into_bytes(), String::new(), target)
+        } else {
+            let data = fs::read(&path)?;
+            let digest = format!("{:x}", sha2::Sha256::digest(&data));
+            (data, digest, String::new())
+        };
+        let index = sizes.len() as u32;
+        let mode = (meta.mode() & 0o170000) | (meta.permissions().mode() & 0o7777);
+        cpio_entry(
+            &mut payload,
+            &format!("./{}", relative.display()),
+            [
+                index + 1,
+                mode,
+                0,
+                0,
+                1,
+                meta.mtime() as u32,
+                data.len() as u32,
+                0,
+                0,
+                0,
+                0,
+                relative.as_os_str().len() as u32 + 3,
+                0,
+            ],
+            &data,
+        );
+        let dir = format!("/{}/", relative.parent().unwrap_or(Path::new("")).display());
+        let dir = dir.replace("//", "/");
+        let dir_index = match dirs.iter().position(|existing| *existing == dir) {
+            Some(position) => position,
+            None => {
+                dirs.push(dir);
+                dirs.len() - 1
+            }
+        };
+        dir_indexes.push(dir_index as u32);
+        basenames.push(relative.file_name().unwrap_or_default().to_string_lossy().into_owned());
+        sizes.push(data.len() as u32);
+        modes.push(mode as u16);
+        mtimes.push(meta.mtime() as u32);
+        digests.push(digest);
+        links.push(link);
+        // %config(noreplace) for files listed in the package's backup array.
+        flags.push(if backup.contains(&relative.to_string_lossy().as_ref()) { 1 | 16 } else { 0 });
+    }
+    cpio_entry(&mut payload, "TRAILER!!!", [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 11, 0], &[]);
+    let payload_size = payload.len() as u32;
+    let payload = gzip(&payload)?;
+
+    let count = sizes.len();
+    let summary = match source.value("pkgdesc") {
+        "" => name.to_string(),
+        desc => desc.to_string(),
+    };
+    let mut header = RpmHeader::default();
+    header.add(100, RpmValue::StrArray(vec!["C".to_string()]));
+    header.string(1000, name);
+    header.string(1001, version);
+    header.string(1002, release);
+    if let Some(epoch) = epoch {
+        header.add(1003, RpmValue::Int32(vec![epoch]));
+    }
+    header.add(1004, RpmValue::I18n(summary.clone()));
+    header.add(1005, RpmValue::I18n(summary));
+    header.add(1006, RpmValue::Int32(vec![source.value("builddate").parse().unwrap_or(0)]));
+    header.string(1007, "localhost");
+    header.add(1009, RpmValue::Int32(vec![source.value("size").parse().unwrap_or(0)]));
+    let licenses = source.values("license");
+    header.string(1014, &if licenses.is_empty() { "Unknown".to_string() } else { licenses.join(" AND ") });
+    if !source.value("packager").is_empty() {
+        header.string(1015, source.value("packager"));
+    }
+    header.add(1016, RpmValue::I18n("Unspecified".to_string()));
+    if !source.value("url").is_empty() {
+        header.string(1020, source.value("url"));
+    }
+    header.string(1021, "linux");
+    header.string(1022, arch);
+    for (tag, script) in rpm_scripts(source) {
+        debug!("adding scriptlet for tag {}", tag);
+        header.string(tag, &script);
+        // The matching *PROG tag names the interpreter.
+        header.string(tag + 62, "/bin/bash");
+    }
+    header.add(1028, RpmValue::Int32(sizes));
+    header.add(1030, RpmValue::Int16(modes));
+    header.add(1033, RpmValue::Int16(vec![0; count]));
+    header.add(1034, RpmValue::Int32(mtimes));
+    header.add(1035, RpmValue::StrArray(digests));
+    header.add(1036, RpmValue::StrArray(links));
+    header.add(1037, RpmValue::Int32(flags));
+    header.add(1039, RpmValue::StrArray(vec!["root".to_string(); count]));
+    header.add(1040, RpmValue::StrArray(vec!["root".to_string(); count]));
+    let evr = source.value("pkgver").to_string();
+    header.string(1044, &format!("{}-{}-{}.src.rpm", name, version, release));
+    header.add(1047, RpmValue::StrArray(vec![name.to_string()]));
+    header.add(1112, RpmValue::Int32(vec![8]));
+    header.add(1113, RpmValue::StrArray(vec![evr]));
+    // rpmlib(...) features the payload relies on, flagged RPMSENSE_RPMLIB | LESS | EQUAL.
+    header.add(
+        1049,
+        RpmValue::StrArray(vec![
+            "rpmlib(CompressedFileNames)".to_string(),
+            "rpmlib(PayloadFilesHavePrefix)".to_string(),
+        ]),
+    );
+    header.add(1048, RpmValue::Int32(vec![0x0100_000a; 2]));
+    header.add(1050, RpmValue::StrArray(vec!["3.0.4-1".to_string(), "4.0-1".to_string()]));
+    header.add(1095, RpmValue::Int32(vec![1; count]));
+    header.add(1096, RpmValue::Int32((1..=count as u32).collect()));
+    header.add(1097, RpmValue::StrArray(vec![String::new(); count]));
+    header.add(1116, RpmValue::Int32(dir_indexes));
+    header.add(1117, RpmValue::StrArray(basenames));
+    header.add(1118, RpmValue::StrArray(dirs));
+    header.string(1124, "cpio");
+    header.string(1125, "gzip");
+    header.string(1126, "9");
+    header.add(5011, RpmValue::Int32(vec![8]));
+    let header = header.build(63);
+
+    let mut signed = header.clone();
+    signed.extend(&payload);
+    let mut signature = RpmHeader::default();
+    signature.string(273, &format!("{:x}", sha2::Sha256::digest(&header)));
+    signature.add(1000, RpmValue::Int32(vec![signed.len() as u32]));
+    signature.add(1004, RpmValue::Bin(Md5::digest(&signed).to_vec()));
+    signature.add(1007, RpmValue::Int32(vec![payload_size]));
+    let mut signature = signature.build(62);
+    while !signature.len().is_multiple_of(8) {
+        signature.push(0);
+    }
+
+    let full_name = format!("{}-{}-{}", name, version, release);
+    let mut lead = vec![0xed, 0xab, 0xee, 0xdb, 3, 0, 0, 0, 0, 1];
+    let mut lead_name = full_name.clone().into_bytes();
+    lead_name.resize(66, 0);
+    lead_name[65] = 0;
+    lead.extend(lead_name);
+    lead.extend([0, 1, 0, 5]);
+    lead.extend([0; 16]);
+
+    let out_path = out_dir.join(format!("{}.{}.rpm", full_name, arch));
+    let mut file = File::create(&out_path).context(format!("Failed to create {}", out_path.display()))?;
+    file.write_all(&lead)?;
+    file.write_all(&signature)?;
+    file.write_all(&signed)?;
+    Ok(out_path)
+}
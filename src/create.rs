@@ -0,0 +1,272 @@
+//! Building Arch packages from a directory laid out the way the files are to
+//! be installed (`usr/bin/foo`, `usr/share/applications/foo.desktop`), like
+//! the `pkg/` directory `makepkg` packages: a `.PKGINFO` from the metadata
+//! given, a `.MTREE` listing every file, and the tree, owned by root.
+//!
+//! The metadata comes from flags or from a TOML descriptor with the same
+//! keys, so nobody writes `.PKGINFO` by hand:
+//!
+//! ```toml
+//! name = "mytool"
+//! version = "1.2"
+//! description = "My tool"
+//! depends = ["glibc", "zlib>=1.3"]
+//! backup = ["etc/mytool.conf"]
+//! ```
+
+use crate::alpm::{MTREE_HEADER, mtree_line};
+use crate::convert::gzip;
+use crate::output::CreateReport;
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tar::{Builder, EntryType, Header, HeaderMode};
+use walkdir::WalkDir;
+
+/// What goes into the `.PKGINFO` of a created package.
+#[derive(Args, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metadata {
+    /// Package name
+    #[arg(long, default_value = "", required_unless_present = "descriptor", hide_default_value = true)]
+    pub name: String,
+    /// Version, as pkgver-pkgrel; a missing pkgrel is taken as 1
+    #[arg(long, default_value = "", required_unless_present = "descriptor", hide_default_value = true)]
+    pub version: String,
+    /// One-line description
+    #[arg(long, default_value = "")]
+    pub description: String,
+    /// Architecture the package is for, or "any" (the host's by default)
+    #[arg(long, default_value = std::env::consts::ARCH)]
+    pub arch: String,
+    /// Upstream URL
+    #[arg(long)]
+    pub url: Option<String>,
+    /// Licenses, as SPDX identifiers
+    #[arg(long, value_delimiter = ',')]
+    pub license: Vec<String>,
+    /// Packages this one depends on, with optional version constraints
+    #[arg(long, value_delimiter = ',')]
+    pub depends: Vec<String>,
+    /// Optional dependencies, as "name: what for"
+    #[arg(long)]
+    pub optdepends: Vec<String>,
+    /// Virtual packages this one provides
+    #[arg(long, value_delimiter = ',')]
+    pub provides: Vec<String>,
+    /// Packages this one cannot be installed alongside
+    #[arg(long, value_delimiter = ',')]
+    pub conflicts: Vec<String>,
+    /// Who built the package, as "Name <email>"
+    #[arg(long)]
+    pub packager: Option<String>,
+    /// Configuration files, relative to DIR, that pacman keeps when the user has edited them
+    #[arg(long, value_delimiter = ',')]
+    pub backup: Vec<String>,
+}
+
+impl Metadata {
+    /// Reads a TOML descriptor with the keys of the flags, such as `name`
+    /// and `depends`; the architecture defaults to the host's.
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        let mut meta: Self = toml::from_str(&text).context(format!("Invalid descriptor {}", path.display()))?;
+        if meta.name.is_empty() || meta.version.is_empty() {
+            bail!("{} needs at least a name and a version", path.display());
+        }
+        if meta.arch.is_empty() {
+            meta.arch = std::env::consts::ARCH.to_string();
+        }
+        Ok(meta)
+    }
+}
+
+/// Package names as pacman accepts them: lowercase letters, digits and
+/// `@._+-`, not starting with a hyphen or dot.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(['-', '.'])
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "@._+-".contains(c))
+}
+
+/// `version` as `[epoch:]pkgver-pkgrel`, with pkgrel 1 when left out.
+fn full_version(version: &str) -> Result<String> {
+    let (pkgver, pkgrel) = version.rsplit_once('-').unwrap_or((version, "1"));
+    let upstream = pkgver.split_once(':').map_or(pkgver, |(_, upstream)| upstream);
+    if upstream.is_empty() || upstream.contains(|c: char| c.is_whitespace() || c == '/' || c == '-') {
+        bail!("Invalid version {}", version);
+    }
+    if pkgrel.is_empty() || !pkgrel.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        bail!("Invalid pkgrel in version {}", version);
+    }
+    Ok(format!("{}-{}", pkgver, pkgrel))
+}
+
+/// The files of the tree at `dir`, relative to it and sorted, leaving out
+/// metadata files at its top level.
+pub(crate) fn payload(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dir)?;
+        if entry.depth() == 1 && relative.to_string_lossy().starts_with('.') {
+            warn!("Leaving out {}, names starting with a dot are reserved for package metadata", relative.display());
+            continue;
+        }
+        paths.push(relative.to_path_buf());
+    }
+    Ok(paths)
+}
+
+/// The build date: `SOURCE_DATE_EPOCH` for reproducible builds, or now.
+pub(crate) fn build_date() -> Result<u64> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse().context(format!("Invalid SOURCE_DATE_EPOCH {}", epoch)),
+        Err(_) => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+    }
+}
+
+fn pkginfo(meta: &Metadata, version: &str, size: u64, builddate: u64) -> String {
+    let mut pkginfo = format!("# Generated by arch-installer {}\n", env!("CARGO_PKG_VERSION"));
+    let mut add = |key: &str, value: &str| pkginfo.push_str(&format!("{} = {}\n", key, value));
+    add("pkgname", &meta.name);
+    add("pkgbase", &meta.name);
+    add("pkgver", version);
+    add("pkgdesc", &meta.description);
+    if let Some(url) = &meta.url {
+        add("url", url);
+    }
+    add("builddate", &builddate.to_string());
+    add("packager", meta.packager.as_deref().unwrap_or("Unknown Packager"));
+    add("size", &size.to_string());
+    add("arch", &meta.arch);
+    for (key, values) in [
+        ("license", &meta.license),
+        ("conflict", &meta.conflicts),
+        ("provides", &meta.provides),
+        ("depend", &meta.depends),
+        ("optdepend", &meta.optdepends),
+        ("backup", &meta.backup),
+    ] {
+        for value in values {
+            add(key, value);
+        }
+    }
+    pkginfo
+}
+
+/// Adds a metadata file such as `.PKGINFO`, owned by root.
+fn append_metadata(tar: &mut Builder<Vec<u8>>, name: &str, content: &[u8], mtime: u64) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_username("root")?;
+    header.set_groupname("root")?;
+    tar.append_data(&mut header, name, content)?;
+    Ok(())
+}
+
+/// Adds the file at `full` to `tar` as `name`, with its mode and mtime but
+/// owned by root.
+fn append_entry(tar: &mut Builder<Vec<u8>>, name: &Path, full: &Path) -> Result<()> {
+    let meta = full.symlink_metadata()?;
+    let mut header = Header::new_gnu();
+    header.set_metadata_in_mode(&meta, HeaderMode::Complete);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_username("root")?;
+    header.set_groupname("root")?;
+    if meta.file_type().is_symlink() {
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, name, fs::read_link(full)?)?;
+    } else if meta.is_dir() {
+        tar.append_data(&mut header, name, io::empty())?;
+    } else {
+        tar.append_data(&mut header, name, File::open(full)?)?;
+    }
+    Ok(())
+}
+
+/// The size of the regular files among `paths` below `dir`, for `.PKGINFO`.
+pub(crate) fn tree_size(dir: &Path, paths: &[PathBuf]) -> u64 {
+    paths
+        .iter()
+        .filter_map(|path| dir.join(path).symlink_metadata().ok())
+        .filter(|file| file.is_file())
+        .map(|file| file.len())
+        .sum()
+}
+
+/// Writes the package `out` from `pkginfo` and the files at `paths` below
+/// `dir`, with a `.MTREE` listing them.
+pub(crate) fn write_package(dir: &Path, paths: &[PathBuf], pkginfo: &str, builddate: u64, out: &Path) -> Result<()> {
+    // The .MTREE lists .PKGINFO as well, so it is written out first.
+    let scratch = tempfile::tempdir()?;
+    let pkginfo_path = scratch.path().join(".PKGINFO");
+    fs::write(&pkginfo_path, pkginfo)?;
+    File::options().write(true).open(&pkginfo_path)?.set_modified(UNIX_EPOCH + Duration::from_secs(builddate))?;
+    let mut mtree = String::from(MTREE_HEADER);
+    mtree.push_str(&mtree_line(Path::new(".PKGINFO"), &pkginfo_path, false)?);
+    for path in paths {
+        mtree.push_str(&mtree_line(path, &dir.join(path), false)?);
+    }
+
+    let mut tar = Builder::new(Vec::new());
+    append_metadata(&mut tar, ".PKGINFO", pkginfo.as_bytes(), builddate)?;
+    append_metadata(&mut tar, ".MTREE", &gzip(mtree.as_bytes())?, builddate)?;
+    for path in paths {
+        append_entry(&mut tar, path, &dir.join(path)).context(format!("Failed to archive {}", path.display()))?;
+    }
+    let archive = tar.into_inner()?;
+
+    if let Some(out_dir) = out.parent() {
+        fs::create_dir_all(out_dir).context(format!("Failed to create {}", out_dir.display()))?;
+    }
+    let compressed = zstd::stream::encode_all(archive.as_slice(), 0)?;
+    fs::write(out, compressed).context(format!("Failed to write {}", out.display()))
+}
+
+/// Packages the tree at `dir` with `meta` into
+/// `<out_dir>/<name>-<version>-<arch>.pkg.tar.zst`.
+pub fn create_package(dir: &Path, meta: &Metadata, out_dir: &Path) -> Result<CreateReport> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    if !valid_name(&meta.name) {
+        bail!("Invalid package name {}", meta.name);
+    }
+    let version = full_version(&meta.version)?;
+    let paths = payload(dir)?;
+    if !paths.iter().any(|path| !dir.join(path).is_dir()) {
+        warn!("{} holds no files, the package will be empty", dir.display());
+    }
+    if !paths.iter().any(|path| path.starts_with("usr")) {
+        warn!("Nothing in {} is under usr/; files are installed from usr/bin, usr/lib and usr/share", dir.display());
+    }
+    let mut meta = meta.clone();
+    for backup in &mut meta.backup {
+        *backup = backup.trim_start_matches('/').to_string();
+        if !dir.join(&*backup).is_file() {
+            warn!("Backup file {} is not in {}", backup, dir.display());
+        }
+    }
+    let size = tree_size(dir, &paths);
+    let builddate = build_date()?;
+    let pkginfo = pkginfo(&meta, &version, size, builddate);
+    debug!("Generated .PKGINFO:\n{}", pkginfo);
+    let out = out_dir.join(format!("{}-{}-{}.pkg.tar.zst", meta.name, version, meta.arch));
+    write_package(dir, &paths, &pkginfo, builddate, &out)?;
+    Ok(CreateReport {
+        package: meta.name.clone(),
+        version,
+        path: out.display().to_string(),
+        signature: None,
+    })
+}
@@ -0,0 +1,146 @@
+use anyhow::{Context, Result, bail};
+use arch_installer::archive::{get_package_name, is_url};
+use arch_installer::installer::{InstallOptions, install_package, uninstall_files};
+use arch_installer::paths::is_root;
+use arch_installer::query::{ListOptions, list_packages};
+use arch_installer::records::find_install_prefix;
+use arch_installer::timings;
+use log::{info, warn};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::{Gid, Group, Uid, User, getgrouplist};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::ffi::CString;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+pub const DEFAULT_SOCKET: &str = "/run/arch-installer.sock";
+
+/// Held while a request changes installed packages, so connections never
+/// modify the installation database concurrently.
+static CHANGES: Mutex<()> = Mutex::new(());
+
+/// One line of JSON sent by a client.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+enum Request {
+    List,
+    Install {
+        package: String,
+        #[serde(default = "default_prefix")]
+        prefix: String,
+        #[serde(flatten)]
+        options: InstallOptions,
+    },
+    Uninstall {
+        package: String,
+        prefix: Option<String>,
+    },
+}
+
+fn default_prefix() -> String {
+    "/usr/local".to_string()
+}
+
+/// Whether `uid` is root or a member of `group`, primary or supplementary.
+fn is_authorized(uid: Uid, group: &str) -> Result<bool> {
+    if uid.is_root() {
+        return Ok(true);
+    }
+    let Some(group) = Group::from_name(group)? else {
+        return Ok(false);
+    };
+    let Some(user) = User::from_uid(uid)? else {
+        return Ok(false);
+    };
+    let groups: Vec<Gid> = getgrouplist(&CString::new(user.name)?, user.gid)?;
+    Ok(groups.contains(&group.gid))
+}
+
+fn handle(request: Request, uid: Uid, group: &str) -> Result<Value> {
+    match request {
+        Request::List => Ok(serde_json::to_value(list_packages(&ListOptions::default())?)?),
+        Request::Install { package, prefix, mut options } => {
+            if !is_authorized(uid, group)? {
+                bail!("Installing requires membership in the {} group", group);
+            }
+            // The daemon's working directory means nothing to the client.
+            if !is_url(&package) && !Path::new(&package).is_absolute() {
+                bail!("Package paths must be absolute: {}", package);
+            }
+            info!("uid {} requested install of {} into {}", uid, package, prefix);
+            options.yes = true;
+            let _guard = CHANGES.lock().unwrap_or_else(|err| err.into_inner());
+            let result = install_package(&package, &prefix, &options);
+            timings::report();
+            Ok(serde_json::to_value(result?)?)
+        }
+        Request::Uninstall { package, prefix } => {
+            if !is_authorized(uid, group)? {
+                bail!("Uninstalling requires membership in the {} group", group);
+            }
+            let _guard = CHANGES.lock().unwrap_or_else(|err| err.into_inner());
+            let prefix = find_install_prefix(&get_package_name(&package), prefix)?;
+            info!("uid {} requested uninstall of {} from {}", uid, package, prefix);
+            Ok(serde_json::to_value(uninstall_files(&package, &prefix, false)?)?)
+        }
+    }
+}
+
+/// Answers each request line of a connection with one response line,
+/// `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+fn serve(stream: UnixStream, group: &str) -> Result<()> {
+    let uid = Uid::from_raw(getsockopt(&stream, PeerCredentials)?.uid());
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line)
+            .context("Malformed request")
+            .and_then(|request| handle(request, uid, group))
+        {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(err) => {
+                warn!("Request from uid {} failed: {:#}", uid, err);
+                json!({ "ok": false, "error": format!("{:#}", err) })
+            }
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+/// Listens on `socket`, serving each connection on its own thread.
+pub fn run(socket: &Path, group: &str) -> Result<()> {
+    if !is_root() {
+        bail!("The daemon must run as root to install system-wide");
+    }
+    if socket.symlink_metadata().is_ok() {
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket).context(format!("Failed to listen on {}", socket.display()))?;
+    // Anyone may connect and list; install and uninstall check the peer.
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o666))?;
+    info!("Listening on {}", socket.display());
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let group = group.to_string();
+                thread::spawn(move || {
+                    if let Err(err) = serve(stream, &group) {
+                        warn!("Connection failed: {:#}", err);
+                    }
+                });
+            }
+            Err(err) => warn!("Failed to accept a connection: {}", err),
+        }
+    }
+    Ok(())
+}
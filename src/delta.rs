@@ -0,0 +1,164 @@
+use crate::archive::{DOWNLOADED, download, download_path, get_package_name, is_url};
+use crate::output::{self, format_size};
+use crate::records::get_source_path;
+use crate::timings::{self, Phase};
+use anyhow::{Context, Result, bail};
+use log::{debug, info};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+/// Deltas are zstd frames of the new package's tarball compressed with the
+/// old one's as a reference prefix, the format of `zstd --patch-from`. They
+/// are published next to the new package as
+/// `<name>-<oldver>-<oldrel>_to_<newver>-<newrel>-<arch>.delta`.
+pub fn delta_name(old: &str, new: &str) -> Option<String> {
+    fn split(file: &str) -> Option<(&str, &str, &str)> {
+        let stem = Path::new(file).file_name()?.to_str()?.strip_suffix(".pkg.tar.zst")?;
+        let (rest, arch) = stem.rsplit_once('-')?;
+        let mut parts = rest.rsplitn(3, '-');
+        let (_rel, _ver, name) = (parts.next()?, parts.next()?, parts.next()?);
+        Some((name, &rest[name.len() + 1..], arch))
+    }
+    let (old_name, old_version, _) = split(old)?;
+    let (name, version, arch) = split(new)?;
+    (old_name == name && old_version != version)
+        .then(|| format!("{}-{}_to_{}-{}.delta", name, old_version, version, arch))
+}
+
+fn decompress(package: &Path) -> Result<Vec<u8>> {
+    let file = File::open(package).context(format!("Failed to open {}", package.display()))?;
+    zstd::stream::decode_all(file).context(format!("Failed to decompress {}", package.display()))
+}
+
+/// Writes the delta from `old` to `new` into `out_dir`.
+pub fn create(old: &Path, new: &Path, out_dir: &Path) -> Result<PathBuf> {
+    let Some(name) = delta_name(&old.to_string_lossy(), &new.to_string_lossy()) else {
+        bail!("{} and {} are not two versions of one package", old.display(), new.display());
+    };
+    let old_tar = decompress(old)?;
+    let new_tar = decompress(new)?;
+    fs::create_dir_all(out_dir)?;
+    let path = out_dir.join(name);
+    let mut encoder = Encoder::with_ref_prefix(BufWriter::new(File::create(&path)?), 19, &old_tar)?;
+    // The window has to reach back over the whole old tarball.
+    let window = (old_tar.len() + new_tar.len()).next_power_of_two().trailing_zeros().clamp(10, 31);
+    encoder.window_log(window)?;
+    encoder.long_distance_matching(true)?;
+    encoder.include_checksum(true)?;
+    encoder.set_pledged_src_size(Some(new_tar.len() as u64))?;
+    io::copy(&mut new_tar.as_slice(), &mut encoder)?;
+    encoder.finish()?;
+    info!(
+        "Delta is {} for a {} package",
+        format_size(fs::metadata(&path)?.len()),
+        format_size(fs::metadata(new)?.len())
+    );
+    Ok(path)
+}
+
+/// Rebuilds `new` from the `old` package and a delta between them.
+fn apply(old: &Path, delta: &Path, new: &Path) -> Result<()> {
+    let old_tar = decompress(old)?;
+    let mut decoder = Decoder::with_ref_prefix(BufReader::new(File::open(delta)?), &old_tar)?;
+    decoder.window_log_max(31)?;
+    let partial = new.with_extension("part");
+    zstd::stream::copy_encode(decoder, File::create(&partial)?, 0)
+        .context(format!("Failed to apply {}", delta.display()))?;
+    fs::rename(&partial, new)?;
+    Ok(())
+}
+
+/// The locally available package the installed version of `url`'s package in
+/// `prefix` was installed from, if any.
+fn installed_source(url: &str, prefix: &str) -> Option<PathBuf> {
+    let source = fs::read_to_string(get_source_path(&get_package_name(url), prefix)).ok()?;
+    let path = if is_url(&source) { download_path(&source) } else { PathBuf::from(source) };
+    path.is_file().then_some(path)
+}
+
+/// Tries to build the package at `url` from a published delta against the
+/// version installed in `prefix`, so only the delta is downloaded. Does
+/// nothing when there is no package to patch or no delta was published, and
+/// `fetch_package` then downloads the whole package.
+pub fn fetch(url: &str, prefix: &str) -> Result<()> {
+    let Some(old) = installed_source(url, prefix) else {
+        debug!("No cached package of the installed version to patch from");
+        return Ok(());
+    };
+    let Some(name) = delta_name(&old.to_string_lossy(), url.split(['?', '#']).next().unwrap_or(url)) else {
+        return Ok(());
+    };
+    let delta_url = format!("{}/{}", url.rsplit_once('/').map_or(url, |(dir, _)| dir), name);
+    let bar = output::bytes_bar(0, "Downloading");
+    let start = Instant::now();
+    let delta = match download(&delta_url, &bar) {
+        Ok(delta) => delta,
+        Err(err) => {
+            output::finish_bar(&bar);
+            debug!("No delta available: {:#}", err);
+            return Ok(());
+        }
+    };
+    output::finish_bar(&bar);
+    timings::record(Phase::Download, start.elapsed(), bar.position());
+    let path = download_path(url);
+    timings::time(Phase::Decompress, 0, || apply(&old, &delta, &path))?;
+    let _ = fs::remove_file(&delta);
+    info!(
+        "Built {} from a {} delta against {}",
+        path.display(),
+        format_size(bar.position()),
+        old.display()
+    );
+    DOWNLOADED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(url.to_string(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::delta_name;
+
+    #[test]
+    fn names_the_step_between_versions() {
+        assert_eq!(
+            delta_name("/cache/htop-3.3.0-1-x86_64.pkg.tar.zst", "https://example.org/htop-3.4.0-2-x86_64.pkg.tar.zst"),
+            Some("htop-3.3.0-1_to_3.4.0-2-x86_64.delta".to_string())
+        );
+    }
+
+    #[test]
+    fn same_version_has_no_delta() {
+        assert_eq!(delta_name("htop-3.3.0-1-x86_64.pkg.tar.zst", "htop-3.3.0-1-x86_64.pkg.tar.zst"), None);
+    }
+
+    #[test]
+    fn different_packages_have_no_delta() {
+        assert_eq!(delta_name("htop-3.3.0-1-x86_64.pkg.tar.zst", "btop-3.4.0-1-x86_64.pkg.tar.zst"), None);
+        assert_eq!(delta_name("htop-3.3.0-1-x86_64.pkg.tar.xz", "htop-3.4.0-1-x86_64.pkg.tar.zst"), None);
+    }
+
+    #[test]
+    fn keeps_epochs() {
+        assert_eq!(
+            delta_name("vim-9.1.0-1-x86_64.pkg.tar.zst", "vim-1:9.1.1-1-x86_64.pkg.tar.zst"),
+            Some("vim-9.1.0-1_to_1:9.1.1-1-x86_64.delta".to_string())
+        );
+    }
+
+    #[test]
+    fn names_may_contain_dashes() {
+        assert_eq!(
+            delta_name("python-foo-bar-1.0-1-any.pkg.tar.zst", "python-foo-bar-1.1-1-any.pkg.tar.zst"),
+            Some("python-foo-bar-1.0-1_to_1.1-1-any.delta".to_string())
+        );
+        assert_eq!(delta_name("python-foo-1.0-1-any.pkg.tar.zst", "python-foo-bar-1.1-1-any.pkg.tar.zst"), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Checks on `.desktop` files before they are installed: an entry with
+//! missing keys or an `Exec` that points nowhere gives a launcher that does
+//! nothing. `desktop-file-validate` adds its errors when it is installed.
+
+use crate::paths::rooted;
+use log::debug;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The program an `Exec` or `TryExec` value runs: its first word, unquoted.
+fn program(value: &str) -> &str {
+    let value = value.trim();
+    match value.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+        None => value.split_whitespace().next().unwrap_or_default(),
+    }
+}
+
+/// Whether `program` will be there once the package is installed: shipped
+/// in it (`installed` maps in-package paths to destinations) or already on
+/// the system, on `PATH` for bare names.
+fn resolves(program: &str, installed: &HashMap<PathBuf, PathBuf>) -> bool {
+    if program.contains('/') {
+        return installed.contains_key(Path::new(program)) || rooted(program).exists();
+    }
+    installed.contains_key(&Path::new("/usr/bin").join(program))
+        || std::env::var_os("PATH")
+            .is_some_and(|path| std::env::split_paths(&path).any(|dir| rooted(dir.join(program)).is_file()))
+}
+
+/// What is wrong with the desktop entry in `content`.
+pub(crate) fn problems(content: &str, installed: &HashMap<PathBuf, PathBuf>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut groups = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .skip_while(|line| !line.starts_with('['));
+    match groups.next() {
+        Some("[Desktop Entry]") => {}
+        Some(group) => problems.push(format!("the first group is {}, not [Desktop Entry]", group)),
+        None => {
+            problems.push("no [Desktop Entry] group".to_string());
+            return problems;
+        }
+    }
+    let keys: HashMap<&str, &str> = groups
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect();
+    for key in ["Type", "Name"] {
+        if !keys.contains_key(key) {
+            problems.push(format!("required key {} is missing", key));
+        }
+    }
+    let is_application = keys.get("Type") == Some(&"Application");
+    if is_application && !keys.contains_key("Exec") && keys.get("DBusActivatable") != Some(&"true") {
+        problems.push("an application needs an Exec key".to_string());
+    }
+    if keys.get("Type") == Some(&"Link") && !keys.contains_key("URL") {
+        problems.push("a link needs a URL key".to_string());
+    }
+    for key in ["Exec", "TryExec"] {
+        if let Some(value) = keys.get(key) {
+            let program = program(value);
+            if program.is_empty() {
+                problems.push(format!("{} is empty", key));
+            } else if !resolves(program, installed) {
+                problems.push(format!("{} runs {}, which is neither in the package nor installed", key, program));
+            }
+        }
+    }
+    problems
+}
+
+/// The errors `desktop-file-validate` finds in the file at `path`, if it is
+/// installed.
+pub(crate) fn validator_errors(path: &Path) -> Vec<String> {
+    let Ok(output) = Command::new("desktop-file-validate").arg(path).output() else {
+        return Vec::new();
+    };
+    debug!("desktop-file-validate {}: {}", path.display(), String::from_utf8_lossy(&output.stdout).trim());
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once(": error: ").map(|(_, error)| error.to_string()))
+        .collect()
+}
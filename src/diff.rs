@@ -0,0 +1,150 @@
+//! Comparing two package files, such as two versions of one package before
+//! upgrading: the files they hold, by size and SHA-256, and their `.PKGINFO`.
+
+use crate::archive::{fetch_package, read_metadata};
+use crate::output::{Change, DiffReport, FieldDiff, FileDiff, RelationDiff};
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use zstd::stream::read::Decoder;
+
+/// `.PKGINFO` keys listing relations or files, compared as sets.
+const LIST_KEYS: [&str; 10] = [
+    "depend",
+    "optdepend",
+    "makedepend",
+    "checkdepend",
+    "provides",
+    "conflict",
+    "replaces",
+    "group",
+    "license",
+    "backup",
+];
+
+/// What a package holds at a path.
+#[derive(PartialEq)]
+struct Item {
+    size: u64,
+    /// Missing for directories and symlinks.
+    sha256: Option<String>,
+    link: Option<String>,
+    mode: u32,
+}
+
+/// The `.PKGINFO` and the files of the package file at `path`, by path.
+fn contents(path: &str) -> Result<(String, BTreeMap<String, Item>)> {
+    let Some(pkginfo) = read_metadata(path, ".PKGINFO")? else {
+        bail!("{} has no .PKGINFO", path);
+    };
+    let mut items = BTreeMap::new();
+    let file = File::open(path).context(format!("Failed to open package {}", path))?;
+    let mut archive = tar::Archive::new(Decoder::new(file)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().trim_start_matches("./").trim_end_matches('/').to_string();
+        if name.is_empty() || name.starts_with('.') {
+            continue;
+        }
+        let header = entry.header();
+        let mode = header.mode()? & 0o7777;
+        let link = entry.link_name()?.map(|link| link.to_string_lossy().into_owned());
+        let sha256 = if header.entry_type().is_file() {
+            let mut hasher = Sha256::new();
+            io::copy(&mut entry, &mut hasher)?;
+            Some(format!("{:x}", hasher.finalize()))
+        } else {
+            None
+        };
+        items.insert(
+            name,
+            Item {
+                size: entry.size(),
+                sha256,
+                link,
+                mode,
+            },
+        );
+    }
+    Ok((pkginfo, items))
+}
+
+/// Every value of each `.PKGINFO` key, comments left out.
+fn fields(pkginfo: &str) -> BTreeMap<&str, Vec<&str>> {
+    let mut fields: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let lines = pkginfo.lines().filter(|line| !line.starts_with('#'));
+    for (key, value) in lines.filter_map(|line| line.split_once(" = ")) {
+        fields.entry(key).or_default().push(value.trim());
+    }
+    fields
+}
+
+/// Compares the package files or URLs `old` and `new`.
+pub fn diff_packages(old: &str, new: &str) -> Result<DiffReport> {
+    let (old_pkginfo, old_items) = contents(&fetch_package(old)?)?;
+    let (new_pkginfo, new_items) = contents(&fetch_package(new)?)?;
+
+    let (old_fields, new_fields) = (fields(&old_pkginfo), fields(&new_pkginfo));
+    let keys: BTreeSet<&str> = old_fields.keys().chain(new_fields.keys()).copied().collect();
+    let mut metadata = Vec::new();
+    let mut relations = Vec::new();
+    for key in keys {
+        let old_values = old_fields.get(key).cloned().unwrap_or_default();
+        let new_values = new_fields.get(key).cloned().unwrap_or_default();
+        if LIST_KEYS.contains(&key) {
+            let added: Vec<String> =
+                new_values.iter().filter(|value| !old_values.contains(value)).map(|value| value.to_string()).collect();
+            let removed: Vec<String> =
+                old_values.iter().filter(|value| !new_values.contains(value)).map(|value| value.to_string()).collect();
+            if !added.is_empty() || !removed.is_empty() {
+                relations.push(RelationDiff {
+                    key: key.to_string(),
+                    added,
+                    removed,
+                });
+            }
+        } else if old_values != new_values {
+            metadata.push(FieldDiff {
+                key: key.to_string(),
+                old: Some(old_values.join(" ")).filter(|value| !value.is_empty()),
+                new: Some(new_values.join(" ")).filter(|value| !value.is_empty()),
+            });
+        }
+    }
+
+    let mut files = Vec::new();
+    let paths: BTreeSet<&String> = old_items.keys().chain(new_items.keys()).collect();
+    for path in paths {
+        let (before, after) = (old_items.get(path), new_items.get(path));
+        let change = match (before, after) {
+            (None, Some(_)) => Change::Added,
+            (Some(_), None) => Change::Removed,
+            (Some(before), Some(after)) if before != after => Change::Modified,
+            _ => continue,
+        };
+        files.push(FileDiff {
+            change,
+            path: path.clone(),
+            old_size: before.map(|item| item.size),
+            new_size: after.map(|item| item.size),
+            old_sha256: before.and_then(|item| item.sha256.clone()),
+            new_sha256: after.and_then(|item| item.sha256.clone()),
+            old_mode: before.map(|item| format!("{:o}", item.mode)),
+            new_mode: after.map(|item| format!("{:o}", item.mode)),
+            old_link: before.and_then(|item| item.link.clone()),
+            new_link: after.and_then(|item| item.link.clone()),
+        });
+    }
+    let total = |items: &BTreeMap<String, Item>| items.values().map(|item| item.size).sum::<u64>() as i64;
+    Ok(DiffReport {
+        old: old.to_string(),
+        new: new.to_string(),
+        metadata,
+        relations,
+        size_change: total(&new_items) - total(&old_items),
+        files,
+    })
+}
+
@@ -0,0 +1,81 @@
+//! Running a command again under sudo or doas when it failed for lack of
+//! root, or with `--user` when the prefix cannot be written at all, instead
+//! of telling the user to do it.
+
+use arch_installer::error::kind;
+use arch_installer::output;
+use arch_installer::paths::{find_executable, get_log_dir, is_root};
+use std::io::{self, IsTerminal};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+const TOOLS: [&str; 2] = ["sudo", "doas"];
+
+/// Offers to rerun this command with the same arguments under the first
+/// elevation tool found, when `err` is a `needs_root` failure and someone is
+/// at the terminal to answer. The package records stay where they were
+/// unless `--state-dir` was given. Returns only if nothing was rerun, and
+/// whether `err` has been printed already.
+pub fn offer(err: &anyhow::Error, explicit_state_dir: bool) -> bool {
+    if kind(err) != "needs_root" || is_root() || output::is_json() || !io::stdin().is_terminal() {
+        return false;
+    }
+    let Some((tool, path)) = TOOLS.iter().find_map(|tool| Some((*tool, find_executable(tool)?))) else {
+        return false;
+    };
+    let Ok(exe) = std::env::current_exe() else {
+        return false;
+    };
+    output::print_error(err);
+    let question = format!("Run the command again with {}? [y/N]", tool);
+    if output::prompt(&question).is_err() {
+        return true;
+    }
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() || answer.trim().to_lowercase() != "y" {
+        return true;
+    }
+    let mut command = Command::new(path);
+    command.arg(exe);
+    if !explicit_state_dir {
+        command.arg("--state-dir").arg(get_log_dir());
+    }
+    command.args(std::env::args_os().skip(1));
+    let err = command.exec();
+    output::print_error(&anyhow::anyhow!("Failed to run {}: {}", tool, err));
+    true
+}
+
+/// Offers to rerun this command with `--user` and without `--prefix` when
+/// `err` is a `read_only_prefix` failure, as on image-based systems where
+/// the home directory is the place left to install into. Returns like
+/// [`offer`].
+pub fn offer_user(err: &anyhow::Error) -> bool {
+    if kind(err) != "read_only_prefix" || output::is_json() || !io::stdin().is_terminal() {
+        return false;
+    }
+    let Ok(exe) = std::env::current_exe() else {
+        return false;
+    };
+    output::print_error(err);
+    if output::prompt("Install into ~/.local with --user instead? [y/N]").is_err() {
+        return true;
+    }
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() || answer.trim().to_lowercase() != "y" {
+        return true;
+    }
+    let mut args = std::env::args_os().skip(1);
+    let mut command = Command::new(&exe);
+    command.arg("--user");
+    while let Some(arg) = args.next() {
+        if arg == "--prefix" {
+            args.next();
+        } else if !arg.to_string_lossy().starts_with("--prefix=") {
+            command.arg(arg);
+        }
+    }
+    let err = command.exec();
+    output::print_error(&anyhow::anyhow!("Failed to run {}: {}", exe.display(), err));
+    true
+}
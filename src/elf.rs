@@ -0,0 +1,329 @@
+use crate::paths::{get_root, rooted};
+use anyhow::{Result, bail};
+use object::Endianness;
+use object::build::elf::{Builder, Dynamic, SectionData};
+use object::elf;
+use object::read::elf::{Dyn, FileHeader, ProgramHeader};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Cheap check used before handing a file to the ELF parser.
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+/// Whether the file at `path` starts with the ELF magic.
+pub(crate) fn is_elf_file(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && is_elf(&magic)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ElfKind {
+    Executable,
+    PieExecutable,
+    SharedObject,
+    Other,
+}
+
+/// What the ELF header and program headers say about a file.
+pub struct ElfInfo {
+    pub is_64: bool,
+    pub machine: u16,
+    pub kind: ElfKind,
+    pub interpreter: Option<String>,
+}
+
+impl ElfInfo {
+    /// Whether this machine can run the file, counting 32-bit x86 and ARM
+    /// binaries as runnable on their 64-bit hosts.
+    pub fn runs_on_host(&self) -> bool {
+        let host = host_machine();
+        self.machine == host
+            || (host == elf::EM_X86_64 && self.machine == elf::EM_386)
+            || (host == elf::EM_AARCH64 && self.machine == elf::EM_ARM)
+    }
+}
+
+impl fmt::Display for ElfInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ElfKind::Executable => "executable",
+            ElfKind::PieExecutable => "PIE executable",
+            ElfKind::SharedObject => "shared object",
+            ElfKind::Other => "object",
+        };
+        write!(
+            f,
+            "ELF {}-bit {} {}",
+            if self.is_64 { 64 } else { 32 },
+            machine_name(self.machine),
+            kind
+        )?;
+        match &self.interpreter {
+            Some(interpreter) => write!(f, ", interpreter {}", interpreter),
+            None if self.kind == ElfKind::Executable => write!(f, ", static"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The `e_machine` value of binaries built for this machine.
+pub fn host_machine() -> u16 {
+    match std::env::consts::ARCH {
+        "x86_64" => elf::EM_X86_64,
+        "x86" => elf::EM_386,
+        "aarch64" => elf::EM_AARCH64,
+        "arm" => elf::EM_ARM,
+        "riscv64" | "riscv32" => elf::EM_RISCV,
+        "powerpc64" => elf::EM_PPC64,
+        "powerpc" => elf::EM_PPC,
+        "s390x" => elf::EM_S390,
+        "loongarch64" => elf::EM_LOONGARCH,
+        "mips" | "mips64" => elf::EM_MIPS,
+        _ => elf::EM_NONE,
+    }
+}
+
+pub fn machine_name(machine: u16) -> String {
+    match machine {
+        elf::EM_X86_64 => "x86_64".to_string(),
+        elf::EM_386 => "i686".to_string(),
+        elf::EM_AARCH64 => "aarch64".to_string(),
+        elf::EM_ARM => "arm".to_string(),
+        elf::EM_RISCV => "riscv".to_string(),
+        elf::EM_PPC64 => "powerpc64".to_string(),
+        elf::EM_PPC => "powerpc".to_string(),
+        elf::EM_S390 => "s390x".to_string(),
+        elf::EM_LOONGARCH => "loongarch64".to_string(),
+        elf::EM_MIPS => "mips".to_string(),
+        other => format!("machine {}", other),
+    }
+}
+
+/// This machine's architecture as Arch Linux and Arch Linux ARM name it in
+/// `.PKGINFO` and repository URLs.
+pub fn host_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86" => "i686",
+        "arm" => "armv7h",
+        other => other,
+    }
+}
+
+/// Whether packages built for `arch` run on this machine, counting 32-bit
+/// x86 and ARM packages as runnable on their 64-bit hosts like
+/// [`ElfInfo::runs_on_host`] does.
+pub fn arch_runs_on_host(arch: &str) -> bool {
+    match (host_arch(), arch) {
+        (_, "" | "any") => true,
+        ("x86_64", "i686" | "pentium4") | ("aarch64", "armv7h" | "armv6h") => true,
+        (host, arch) => host == arch,
+    }
+}
+
+/// Whether the target system's C library is musl, as on Alpine and the musl
+/// flavour of Void, going by the loader its shell uses or, for a statically
+/// linked shell, by what `ldd` says it is.
+pub(crate) fn host_is_musl() -> bool {
+    static MUSL: OnceLock<bool> = OnceLock::new();
+    *MUSL.get_or_init(|| {
+        let shell = fs::read(rooted("/bin/sh")).ok().and_then(|data| inspect(&data));
+        if let Some(interpreter) = shell.and_then(|info| info.interpreter) {
+            return interpreter.contains("ld-musl");
+        }
+        get_root() == Path::new("/")
+            && Command::new("ldd")
+                .arg("--version")
+                .output()
+                .is_ok_and(|output| String::from_utf8_lossy(&output.stderr).contains("musl"))
+    })
+}
+
+/// Parses the ELF header of `data`, or returns `None` for anything that is
+/// not a well-formed ELF file.
+pub fn inspect(data: &[u8]) -> Option<ElfInfo> {
+    if !is_elf(data) {
+        return None;
+    }
+    // EI_CLASS, right after the magic.
+    match *data.get(4)? {
+        elf::ELFCLASS32 => inspect_header::<elf::FileHeader32<Endianness>>(data, false),
+        elf::ELFCLASS64 => inspect_header::<elf::FileHeader64<Endianness>>(data, true),
+        _ => None,
+    }
+}
+
+fn inspect_header<H: FileHeader<Endian = Endianness>>(data: &[u8], is_64: bool) -> Option<ElfInfo> {
+    let header = H::parse(data).ok()?;
+    let endian = header.endian().ok()?;
+    let mut interpreter = None;
+    let mut pie_flag = false;
+    for segment in header.program_headers(endian, data).ok()? {
+        if let Ok(Some(interp)) = segment.interpreter(endian, data) {
+            interpreter = Some(String::from_utf8_lossy(interp).into_owned());
+        }
+        if let Ok(Some(dynamics)) = segment.dynamic(endian, data) {
+            pie_flag |= dynamics.iter().any(|dynamic| {
+                dynamic.d_tag(endian).into() == u64::from(elf::DT_FLAGS_1)
+                    && dynamic.d_val(endian).into() & u64::from(elf::DF_1_PIE) != 0
+            });
+        }
+    }
+    let kind = match header.e_type(endian) {
+        elf::ET_EXEC => ElfKind::Executable,
+        elf::ET_DYN if pie_flag || interpreter.is_some() => ElfKind::PieExecutable,
+        elf::ET_DYN => ElfKind::SharedObject,
+        _ => ElfKind::Other,
+    };
+    Some(ElfInfo {
+        is_64,
+        machine: header.e_machine(endian),
+        kind,
+        interpreter,
+    })
+}
+
+/// Points an ELF file's library search path at `runpath` and lets
+/// `map_interpreter` replace its interpreter. `/usr/lib` entries of an
+/// existing RUNPATH/RPATH are rewritten to `runpath`; files without one get a
+/// new `DT_RUNPATH`. Returns `None` when nothing needs to change, e.g. for
+/// static binaries.
+pub fn relocate(
+    data: &[u8],
+    runpath: &str,
+    map_interpreter: impl Fn(&str) -> Option<String>,
+) -> Result<Option<Vec<u8>>> {
+    let mut builder = Builder::read(data)?;
+    let mut changed = false;
+    let Some(dynamics) = builder.dynamic_data_mut() else {
+        return Ok(None);
+    };
+    let existing = dynamics.iter_mut().find_map(|dynamic| match dynamic {
+        Dynamic::String { tag: elf::DT_RUNPATH | elf::DT_RPATH, val } => Some(val),
+        _ => None,
+    });
+    match existing {
+        Some(val) => {
+            let old = String::from_utf8_lossy(val).into_owned();
+            let new = rewrite_search_path(&old, runpath);
+            if new != old {
+                *val = new.into_bytes().into();
+                changed = true;
+            }
+        }
+        None => {
+            dynamics.push(Dynamic::String {
+                tag: elf::DT_RUNPATH,
+                val: runpath.as_bytes().to_vec().into(),
+            });
+            changed = true;
+        }
+    }
+    if let Some(interp) = builder.interp_data_mut() {
+        let old = String::from_utf8_lossy(interp.as_slice()).trim_end_matches('\0').to_string();
+        if let Some(new) = map_interpreter(&old).filter(|new| *new != old) {
+            let mut bytes = new.into_bytes();
+            bytes.push(0);
+            *interp = bytes.into();
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(None);
+    }
+    make_room(&mut builder)?;
+    let mut buffer = Vec::new();
+    builder.write(&mut buffer)?;
+    Ok(Some(buffer))
+}
+
+fn rewrite_search_path(old: &str, runpath: &str) -> String {
+    let mut entries: Vec<String> = old
+        .split(':')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.strip_prefix("/usr/lib") {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", runpath, rest),
+            _ => entry.to_string(),
+        })
+        .collect();
+    if !entries.iter().any(|entry| entry == runpath) {
+        entries.push(runpath.to_string());
+    }
+    entries.join(":")
+}
+
+/// Moves sections that grew, or that sit where the extra program header
+/// goes, into a new `PT_LOAD` segment at the end of the file. Only sections
+/// whose addresses the dynamic table derives automatically can be moved.
+fn make_room(builder: &mut Builder) -> Result<()> {
+    builder.delete_orphans();
+    builder.delete_unused_versions();
+    let header_size = builder.class().program_header_size() as u64;
+    let headers_end = builder.header.e_phoff + builder.program_headers_size() as u64 + header_size;
+    let interp = builder.interp_section();
+    let mut moved = Vec::new();
+    for section in &builder.sections {
+        if !section.is_alloc() || section.sh_type == elf::SHT_NOBITS {
+            continue;
+        }
+        let grows = builder.section_size(section) as u64 > section.sh_size;
+        if !grows && section.sh_offset >= headers_end {
+            continue;
+        }
+        let movable = match &section.data {
+            SectionData::Data(_) => Some(section.id()) == interp,
+            SectionData::Note(_)
+            | SectionData::Dynamic(_)
+            | SectionData::DynamicString
+            | SectionData::DynamicSymbol
+            | SectionData::Hash
+            | SectionData::GnuHash
+            | SectionData::GnuVersym
+            | SectionData::GnuVerdef
+            | SectionData::GnuVerneed => true,
+            _ => false,
+        };
+        if !movable {
+            bail!("cannot make room for section {}", String::from_utf8_lossy(&section.name));
+        }
+        moved.push(section.id());
+    }
+    builder.set_section_sizes();
+    let align = builder.load_align.max(0x1000);
+    let segment_id = builder.segments.add_load_segment(elf::PF_R | elf::PF_W, align).id();
+    for id in &moved {
+        let section = builder.sections.get_mut(*id);
+        builder.segments.get_mut(segment_id).append_section(section);
+    }
+    let headers_size = builder.program_headers_size() as u64;
+    for segment in builder.segments.iter_mut() {
+        if segment.id() == segment_id {
+            continue;
+        }
+        let (inside, kept): (Vec<_>, Vec<_>) = segment.sections.iter().partition(|id| moved.contains(id));
+        match segment.p_type {
+            elf::PT_PHDR => {
+                segment.p_filesz = headers_size;
+                segment.p_memsz = headers_size;
+            }
+            elf::PT_LOAD if segment.p_offset == 0 && segment.p_filesz < headers_end => {
+                bail!("first load segment is too small for the program headers");
+            }
+            elf::PT_INTERP | elf::PT_NOTE | elf::PT_DYNAMIC | elf::PT_GNU_PROPERTY if !inside.is_empty() => {
+                if !kept.is_empty() {
+                    bail!("cannot move a segment that is only partly relocated");
+                }
+                segment.recalculate_ranges(&builder.sections);
+                continue;
+            }
+            _ => {}
+        }
+        segment.sections = kept;
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+//! Failures callers may want to tell apart. They travel inside
+//! `anyhow::Error` like any other error, with context added on top; use
+//! [`find`] to get at one, and [`kind`] for the name `--json` reports.
+
+use crate::output::format_size;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConflictingFile {
+    pub path: String,
+    pub owner: String,
+}
+
+/// A `.desktop` file with something wrong in it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DesktopProblem {
+    pub path: String,
+    pub problem: String,
+}
+
+/// A file that would run with its owner's or group's privileges.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SetuidFile {
+    pub path: String,
+    /// Permission bits in octal, e.g. `4755`.
+    pub mode: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum InstallerError {
+    /// The prefix is only writable by root.
+    NeedsRoot {
+        prefix: String,
+        /// What was about to happen, e.g. `install to`.
+        #[serde(skip)]
+        action: &'static str,
+    },
+    /// Destination files belong to the system package manager.
+    Conflict { files: Vec<ConflictingFile> },
+    NoSpace {
+        path: String,
+        needed: u64,
+        available: u64,
+        /// What the package says it takes once installed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        installed_size: Option<u64>,
+    },
+    /// The file is not a readable Arch Linux package.
+    BadArchive { package: String, reason: String },
+    Download { url: String, reason: String },
+    NotInstalled { package: String, prefix: Option<String> },
+    AlreadyInstalled { package: String, prefix: String },
+    /// The package is in several prefixes and none was chosen.
+    AmbiguousPrefix { package: String, prefixes: Vec<String> },
+    /// The package has setuid or setgid files and `--allow-setuid` was not given.
+    Setuid { package: String, files: Vec<SetuidFile> },
+    /// `--strict-desktop` was given and `.desktop` files are broken.
+    InvalidDesktop { package: String, problems: Vec<DesktopProblem> },
+    /// The prefix is on a read-only or distribution-managed `/usr`, as on
+    /// image-based systems, where not even root can install into it.
+    ReadOnlyPrefix { prefix: String, reason: String },
+    /// The package is built for another architecture and `--ignore-arch`
+    /// was not given.
+    WrongArch { package: String, arch: String, host: String },
+    /// `verify` found installed files missing or modified.
+    VerifyFailed { failed: usize, total: usize },
+    /// `repair` could not restore some files.
+    RepairFailed { package: String, failed: usize },
+    /// `check` found problems in installed packages.
+    CheckFailed { failed: usize, total: usize },
+    /// `import` could not install some packages of the manifest.
+    ImportFailed { packages: Vec<String> },
+    /// `audit` found known vulnerabilities in installed packages.
+    Vulnerable { count: usize },
+    /// `rebuild-check` could not confirm that the package is the official,
+    /// reproduced build.
+    NotReproduced { package: String, version: String, arch: String, reason: String },
+    /// The user answered no to a confirmation prompt.
+    Cancelled,
+}
+
+impl InstallerError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InstallerError::NeedsRoot { .. } => "needs_root",
+            InstallerError::Conflict { .. } => "conflict",
+            InstallerError::NoSpace { .. } => "no_space",
+            InstallerError::BadArchive { .. } => "bad_archive",
+            InstallerError::Download { .. } => "download",
+            InstallerError::NotInstalled { .. } => "not_installed",
+            InstallerError::AlreadyInstalled { .. } => "already_installed",
+            InstallerError::AmbiguousPrefix { .. } => "ambiguous_prefix",
+            InstallerError::Setuid { .. } => "setuid",
+            InstallerError::InvalidDesktop { .. } => "invalid_desktop",
+            InstallerError::ReadOnlyPrefix { .. } => "read_only_prefix",
+            InstallerError::WrongArch { .. } => "wrong_arch",
+            InstallerError::VerifyFailed { .. } => "verify_failed",
+            InstallerError::RepairFailed { .. } => "repair_failed",
+            InstallerError::CheckFailed { .. } => "check_failed",
+            InstallerError::ImportFailed { .. } => "import_failed",
+            InstallerError::Vulnerable { .. } => "vulnerable",
+            InstallerError::NotReproduced { .. } => "not_reproduced",
+            InstallerError::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl fmt::Display for InstallerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstallerError::NeedsRoot { prefix, action } => {
+                write!(f, "Please run the program with sudo or doas to {} {}", action, prefix)
+            }
+            InstallerError::Conflict { files } => {
+                writeln!(f, "Files owned by the system package manager ({}):", files.len())?;
+                for file in files {
+                    writeln!(f, "  - {} (owned by {})", file.path, file.owner)?;
+                }
+                write!(f, "Rerun with --on-conflict=skip to leave them alone or --on-conflict=force to overwrite them.")
+            }
+            InstallerError::NoSpace { path, needed, available, installed_size } => {
+                let (needed, available) = (format_size(*needed), format_size(*available));
+                write!(f, "Not enough free space on {}: {} needed, {} available", path, needed, available)?;
+                if let Some(size) = installed_size {
+                    write!(f, " (package installed size: {})", format_size(*size))?;
+                }
+                write!(f, ". Free up space or choose another --prefix.")
+            }
+            InstallerError::BadArchive { package, reason } => {
+                write!(f, "{} is not a valid Arch Linux package: {}", package, reason)
+            }
+            InstallerError::Download { url, reason } => write!(f, "Failed to download {}: {}", url, reason),
+            InstallerError::NotInstalled { package, prefix: Some(prefix) } => {
+                write!(f, "Package {} is not installed in {}", package, prefix)
+            }
+            InstallerError::NotInstalled { package, prefix: None } => {
+                write!(f, "Package {} is not installed. Run install first.", package)
+            }
+            InstallerError::AlreadyInstalled { package, prefix } => {
+                write!(f, "Package {} is already installed in {}", package, prefix)
+            }
+            InstallerError::AmbiguousPrefix { package, prefixes } => write!(
+                f,
+                "Package {} is installed in several prefixes ({}); choose one with --prefix",
+                package,
+                prefixes.join(", ")
+            ),
+            InstallerError::Setuid { package, files } => {
+                writeln!(f, "{} contains setuid or setgid files ({}):", package, files.len())?;
+                for file in files {
+                    writeln!(f, "  - {} (mode {})", file.path, file.mode)?;
+                }
+                writeln!(f, "They would run with the privileges of their owner or group.")?;
+                write!(f, "Rerun with --allow-setuid to install them anyway.")
+            }
+            InstallerError::InvalidDesktop { package, problems } => {
+                writeln!(f, "{} has broken .desktop files ({} problems):", package, problems.len())?;
+                for problem in problems {
+                    writeln!(f, "  - {}: {}", problem.path, problem.problem)?;
+                }
+                write!(f, "Rerun without --strict-desktop to install them anyway.")
+            }
+            InstallerError::ReadOnlyPrefix { prefix, reason } => {
+                writeln!(f, "Cannot install into {}: {}.", prefix, reason)?;
+                write!(
+                    f,
+                    "Install into your home directory with --user instead, or into a prefix under /var such as \
+                     --prefix=/var/opt/arch-installer."
+                )
+            }
+            InstallerError::WrongArch { package, arch, host } => {
+                writeln!(f, "{} is built for {}, but this machine is {}.", package, arch, host)?;
+                write!(f, "Rerun with --ignore-arch to install it anyway, e.g. into a sysroot with --root.")
+            }
+            InstallerError::VerifyFailed { failed, total } => {
+                write!(f, "{} of {} packages have missing or modified files", failed, total)
+            }
+            InstallerError::RepairFailed { package, failed } => {
+                write!(f, "{} files of {} could not be restored", failed, package)
+            }
+            InstallerError::CheckFailed { failed, total } => {
+                write!(f, "{} of {} packages have problems", failed, total)
+            }
+            InstallerError::ImportFailed { packages } => write!(f, "Failed to import: {}", packages.join(", ")),
+            InstallerError::Vulnerable { count } => write!(f, "{} known vulnerabilities in installed packages", count),
+            InstallerError::NotReproduced { package, version, arch, reason } => {
+                write!(f, "{} {} ({}) {}", package, version, arch, reason)
+            }
+            InstallerError::Cancelled => write!(f, "Cancelled by user."),
+        }
+    }
+}
+
+impl std::error::Error for InstallerError {}
+
+/// The typed error behind `err`, if any.
+pub fn find(err: &anyhow::Error) -> Option<&InstallerError> {
+    err.chain().find_map(|cause| cause.downcast_ref::<InstallerError>())
+}
+
+/// Rebuilds the error a `--json` child process printed to `stdout`, keeping
+/// the kinds callers act on typed.
+pub(crate) fn from_child_report(stdout: &[u8], fallback: &str) -> anyhow::Error {
+    let Ok(report) = serde_json::from_slice::<Value>(stdout) else {
+        return anyhow::anyhow!("{}", fallback);
+    };
+    let details = &report["details"];
+    match report["kind"].as_str() {
+        Some("cancelled") => return InstallerError::Cancelled.into(),
+        Some("bad_archive") => {
+            return InstallerError::BadArchive {
+                package: details["package"].as_str().unwrap_or_default().to_string(),
+                reason: details["reason"].as_str().unwrap_or_default().to_string(),
+            }
+            .into();
+        }
+        Some("setuid") => {
+            return InstallerError::Setuid {
+                package: details["package"].as_str().unwrap_or_default().to_string(),
+                files: serde_json::from_value(details["files"].clone()).unwrap_or_default(),
+            }
+            .into();
+        }
+        Some("wrong_arch") => {
+            return InstallerError::WrongArch {
+                package: details["package"].as_str().unwrap_or_default().to_string(),
+                arch: details["arch"].as_str().unwrap_or_default().to_string(),
+                host: details["host"].as_str().unwrap_or_default().to_string(),
+            }
+            .into();
+        }
+        Some("invalid_desktop") => {
+            return InstallerError::InvalidDesktop {
+                package: details["package"].as_str().unwrap_or_default().to_string(),
+                problems: serde_json::from_value(details["problems"].clone()).unwrap_or_default(),
+            }
+            .into();
+        }
+        _ => {}
+    }
+    let mut messages: Vec<String> = std::iter::once(&report["error"])
+        .chain(report["causes"].as_array().into_iter().flatten())
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect();
+    let mut err = anyhow::anyhow!("{}", messages.pop().unwrap_or_else(|| fallback.to_string()));
+    while let Some(message) = messages.pop() {
+        err = err.context(message);
+    }
+    err
+}
+
+#[derive(Serialize)]
+pub struct ExitCode {
+    pub code: i32,
+    pub kind: &'static str,
+    pub description: &'static str,
+}
+
+/// What the command line tool exits with for each failure class. Codes are
+/// never reused or renumbered; new classes get new codes.
+pub const EXIT_CODES: &[ExitCode] = &[
+    ExitCode { code: 0, kind: "success", description: "The command succeeded" },
+    ExitCode { code: 1, kind: "other", description: "Any failure not listed below" },
+    ExitCode { code: 2, kind: "usage", description: "Invalid command line arguments" },
+    ExitCode { code: 3, kind: "cancelled", description: "A confirmation prompt was answered with no" },
+    ExitCode { code: 4, kind: "needs_root", description: "The prefix needs root; rerun with sudo or doas" },
+    ExitCode { code: 5, kind: "not_installed", description: "The package is not installed" },
+    ExitCode { code: 6, kind: "ambiguous_prefix", description: "The package is in several prefixes; pass --prefix" },
+    ExitCode { code: 7, kind: "already_installed", description: "The package is already installed there" },
+    ExitCode { code: 8, kind: "conflict", description: "Files belong to the system package manager" },
+    ExitCode { code: 9, kind: "bad_archive", description: "The file is not a readable Arch Linux package" },
+    ExitCode { code: 10, kind: "download", description: "Downloading the package failed" },
+    ExitCode { code: 11, kind: "no_space", description: "Not enough free space in the destination" },
+    ExitCode { code: 12, kind: "io", description: "Reading or writing files failed" },
+    ExitCode { code: 13, kind: "setuid", description: "The package has setuid or setgid files; pass --allow-setuid" },
+    ExitCode { code: 14, kind: "invalid_desktop", description: "--strict-desktop refused broken .desktop files" },
+    ExitCode { code: 15, kind: "read_only_prefix", description: "The prefix is read-only or managed by the system" },
+    ExitCode { code: 16, kind: "wrong_arch", description: "The package is for another machine; pass --ignore-arch" },
+    ExitCode { code: 17, kind: "verify_failed", description: "verify found missing or modified files" },
+    ExitCode { code: 18, kind: "repair_failed", description: "repair could not restore some files" },
+    ExitCode { code: 19, kind: "check_failed", description: "check found problems in installed packages" },
+    ExitCode { code: 20, kind: "import_failed", description: "import could not install some packages" },
+    ExitCode { code: 21, kind: "vulnerable", description: "audit found known vulnerabilities" },
+    ExitCode { code: 22, kind: "not_reproduced", description: "rebuild-check could not confirm a reproduced build" },
+];
+
+/// The exit code for `err`, from [`EXIT_CODES`].
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    let kind = kind(err);
+    EXIT_CODES.iter().find(|code| code.kind == kind).map_or(1, |code| code.code)
+}
+
+/// Name of the failure class: an [`InstallerError`] kind, `io` for other
+/// operating system errors, or `other`.
+pub fn kind(err: &anyhow::Error) -> &'static str {
+    if let Some(error) = find(err) {
+        error.kind()
+    } else if err.chain().any(|cause| cause.is::<io::Error>()) {
+        "io"
+    } else {
+        "other"
+    }
+}
@@ -0,0 +1,115 @@
+//! Progress events for frontends that draw their own progress bars and
+//! dialogs. Subscribe a callback with [`subscribe`]; `--json-progress`
+//! subscribes one that prints each event to stderr as a line of JSON.
+
+use crate::output::{Action, FileKind};
+use serde::Serialize;
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock};
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    DownloadStarted {
+        url: String,
+        /// Bytes to download, when the server says.
+        size: Option<u64>,
+    },
+    DownloadProgress {
+        url: String,
+        done: u64,
+        size: Option<u64>,
+    },
+    DownloadFinished {
+        url: String,
+        path: String,
+        size: u64,
+    },
+    Extracting {
+        package: String,
+    },
+    /// A file that the system package manager already owns is in the way.
+    Conflict {
+        path: String,
+        owner: String,
+    },
+    /// The installer is waiting for an answer on stdin.
+    Prompt {
+        question: String,
+    },
+    CopyStarted {
+        package: String,
+        files: usize,
+        bytes: u64,
+    },
+    /// One planned file was placed or skipped; `done` of `total` are handled.
+    File {
+        action: Action,
+        kind: FileKind,
+        path: String,
+        done: usize,
+        total: usize,
+    },
+}
+
+type Callback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+static SUBSCRIBERS: RwLock<Vec<Callback>> = RwLock::new(Vec::new());
+
+/// Calls `callback` with every event from now on, on whichever thread the
+/// event happens. To receive events elsewhere, send them on a channel:
+/// `subscribe(move |event| { let _ = sender.send(event.clone()); })`.
+pub fn subscribe(callback: impl Fn(&Event) + Send + Sync + 'static) {
+    SUBSCRIBERS.write().unwrap_or_else(|err| err.into_inner()).push(Arc::new(callback));
+}
+
+/// Whether anyone listens, in which case progress bars are left to them.
+pub fn is_subscribed() -> bool {
+    !SUBSCRIBERS.read().unwrap_or_else(|err| err.into_inner()).is_empty()
+}
+
+pub(crate) fn emit(event: Event) {
+    for callback in SUBSCRIBERS.read().unwrap_or_else(|err| err.into_inner()).iter() {
+        callback(&event);
+    }
+}
+
+/// Reports `DownloadProgress` for what is read through it, once per MiB so
+/// fast downloads do not flood subscribers.
+pub(crate) struct DownloadReader<R> {
+    inner: R,
+    url: String,
+    size: Option<u64>,
+    done: u64,
+    reported: u64,
+}
+
+const REPORT_EVERY: u64 = 1024 * 1024;
+
+impl<R: Read> DownloadReader<R> {
+    pub(crate) fn new(inner: R, url: &str, size: Option<u64>) -> Self {
+        DownloadReader {
+            inner,
+            url: url.to_string(),
+            size,
+            done: 0,
+            reported: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for DownloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.done += read as u64;
+        if self.done - self.reported >= REPORT_EVERY || (read == 0 && self.done > self.reported) {
+            self.reported = self.done;
+            emit(Event::DownloadProgress {
+                url: self.url.clone(),
+                done: self.done,
+                size: self.size,
+            });
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,224 @@
+//! C interface to the installer, declared in `include/arch_installer.h`.
+//!
+//! Every call that produces a result stores a JSON document in `*result`:
+//! the same report `--json` prints on success, or `{"error": ..., "causes":
+//! [...], "kind": ...}` on failure. The caller frees it with `arch_installer_free`.
+
+use crate::archive::get_package_name;
+use crate::config;
+use crate::installer::{InstallOptions, install_package, uninstall_files};
+use crate::output::{self, ErrorReport};
+use crate::paths::{get_config_path, migrate_legacy_state, resolve_prefix, set_root, set_state_dir, set_user_mode};
+use crate::query::{ListOptions, list_packages, search_packages, which_command};
+use crate::records::find_install_prefix;
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use serde::Serialize;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+/// Reads an optional string argument; NULL means not given.
+///
+/// # Safety
+///
+/// `arg` must be NULL or point to a NUL-terminated string.
+unsafe fn optional_arg(arg: *const c_char, name: &str) -> Result<Option<String>> {
+    if arg.is_null() {
+        return Ok(None);
+    }
+    let arg = unsafe { CStr::from_ptr(arg) };
+    Ok(Some(arg.to_str().context(format!("{} is not valid UTF-8", name))?.to_string()))
+}
+
+/// # Safety
+///
+/// `arg` must be NULL or point to a NUL-terminated string.
+unsafe fn required_arg(arg: *const c_char, name: &str) -> Result<String> {
+    unsafe { optional_arg(arg, name) }?.ok_or_else(|| anyhow!("{} must not be NULL", name))
+}
+
+/// Runs `f` without letting a panic unwind into C, and hands its result to
+/// the caller as JSON. Returns 0 on success and 1 on failure.
+///
+/// # Safety
+///
+/// `result` must be NULL or point to writable storage for a pointer.
+unsafe fn respond<T: Serialize>(result: *mut *mut c_char, f: impl FnOnce() -> Result<T>) -> c_int {
+    let outcome = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| Err(anyhow!("arch-installer panicked")));
+    let (status, json) = match outcome.and_then(|value| Ok(serde_json::to_string(&value)?)) {
+        Ok(json) => (0, json),
+        Err(err) => (1, serde_json::to_string(&ErrorReport::from_error(&err)).unwrap_or_default()),
+    };
+    if !result.is_null() {
+        let json = CString::new(json).unwrap_or_default();
+        unsafe { *result = json.into_raw() };
+    }
+    status
+}
+
+/// Loads the config file and moves records left by old versions, as the
+/// command line tool does on start. Call after the `set_*` functions.
+#[unsafe(no_mangle)]
+pub extern "C" fn arch_installer_init() -> c_int {
+    // Nothing of ours may end up on the host application's stdout.
+    output::set_json(true);
+    if let Err(err) = config::load(&get_config_path()) {
+        warn!("{:#}", err);
+        return 1;
+    }
+    if let Some(state_dir) = config::state_dir() {
+        set_state_dir(state_dir);
+    }
+    if let Err(err) = migrate_legacy_state() {
+        warn!("could not move package records to the new state directory: {}", err);
+    }
+    0
+}
+
+/// Operates on the system mounted at `root`, like `--root`.
+///
+/// # Safety
+///
+/// `root` must point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_set_root(root: *const c_char) -> c_int {
+    match unsafe { required_arg(root, "root") } {
+        Ok(root) => {
+            set_root(PathBuf::from(root));
+            0
+        }
+        Err(_) => 1,
+    }
+}
+
+/// Installs into `~/.local` and keeps per-user records when `user` is
+/// nonzero, like `--user`.
+#[unsafe(no_mangle)]
+pub extern "C" fn arch_installer_set_user_mode(user: c_int) {
+    set_user_mode(user != 0);
+}
+
+/// Keeps package records in `dir`, like `--state-dir`.
+///
+/// # Safety
+///
+/// `dir` must point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_set_state_dir(dir: *const c_char) -> c_int {
+    match unsafe { required_arg(dir, "dir") } {
+        Ok(dir) => {
+            set_state_dir(PathBuf::from(dir));
+            0
+        }
+        Err(_) => 1,
+    }
+}
+
+/// Installs `package`, a file or URL, into `prefix` (NULL for
+/// `/usr/local`, which is `~/.local` in user mode, as with `--prefix`).
+/// `options` is NULL or a JSON object with the install options, e.g.
+/// `{"relocate": true}`. Never asks for confirmation.
+///
+/// # Safety
+///
+/// The strings must be NULL or NUL-terminated, `result` NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_install(
+    package: *const c_char,
+    prefix: *const c_char,
+    options: *const c_char,
+    result: *mut *mut c_char,
+) -> c_int {
+    let package = unsafe { required_arg(package, "package") };
+    let prefix = unsafe { optional_arg(prefix, "prefix") };
+    let options = unsafe { optional_arg(options, "options") };
+    unsafe {
+        respond(result, || {
+            let mut options: InstallOptions = match options? {
+                Some(json) => serde_json::from_str(&json).context("Invalid install options")?,
+                None => InstallOptions::default(),
+            };
+            options.yes = true;
+            let prefix = resolve_prefix(prefix?.unwrap_or_else(|| "/usr/local".to_string()))?;
+            install_package(&package?, &prefix, &options)
+        })
+    }
+}
+
+/// Uninstalls `package` from `prefix`, or from the only prefix it is
+/// installed in when `prefix` is NULL.
+///
+/// # Safety
+///
+/// The strings must be NULL or NUL-terminated, `result` NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_uninstall(
+    package: *const c_char,
+    prefix: *const c_char,
+    result: *mut *mut c_char,
+) -> c_int {
+    let package = unsafe { required_arg(package, "package") };
+    let prefix = unsafe { optional_arg(prefix, "prefix") };
+    unsafe {
+        respond(result, || {
+            let package = package?;
+            let prefix = find_install_prefix(&get_package_name(&package), prefix?.map(resolve_prefix).transpose()?)?;
+            uninstall_files(&package, &prefix, false)
+        })
+    }
+}
+
+/// Lists installed packages, only those in `prefix` unless it is NULL.
+///
+/// # Safety
+///
+/// `prefix` must be NULL or NUL-terminated, `result` NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_list(prefix: *const c_char, result: *mut *mut c_char) -> c_int {
+    let prefix = unsafe { optional_arg(prefix, "prefix") };
+    unsafe {
+        respond(result, || {
+            let options = ListOptions {
+                prefix: prefix?.map(resolve_prefix).transpose()?,
+                ..Default::default()
+            };
+            list_packages(&options)
+        })
+    }
+}
+
+/// Installed packages whose name or description contains `query`.
+///
+/// # Safety
+///
+/// `query` must be NUL-terminated, `result` NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_search(query: *const c_char, result: *mut *mut c_char) -> c_int {
+    let query = unsafe { required_arg(query, "query") };
+    unsafe { respond(result, || search_packages(&query?, false, None)) }
+}
+
+/// The package, installed by arch-installer or natively, that provides
+/// `command` on `PATH`.
+///
+/// # Safety
+///
+/// `command` must be NUL-terminated, `result` NULL or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_which(command: *const c_char, result: *mut *mut c_char) -> c_int {
+    let command = unsafe { required_arg(command, "command") };
+    unsafe { respond(result, || which_command(&command?)) }
+}
+
+/// Frees a result string returned by the other functions; NULL is ignored.
+///
+/// # Safety
+///
+/// `string` must be NULL or a result string not freed before.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn arch_installer_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
@@ -0,0 +1,748 @@
+//! Handlers for classes of files beyond the binaries, libraries, `.desktop`
+//! files and icons the installer places itself. A handler can claim packaged
+//! files and say where they go, and is told about installed and removed files
+//! it owns so it can refresh caches. Handlers come from three places, tried
+//! in this order: [`register`] for library users, `[[handler]]` tables in the
+//! config file, and the built-in ones.
+
+use crate::config;
+use crate::elf;
+use crate::installer::clean_empty_dirs;
+use crate::layout::{self, Kind};
+use crate::paths::{DestDirs, get_log_dir, get_root, is_root, is_system_wide, rooted, unrooted, updates_caches};
+use anyhow::{Context, Result, bail};
+use glob::Pattern;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+pub trait Handler: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Where to install `path`, relative to the package root (e.g.
+    /// `usr/share/fonts/TTF/a.ttf`), or `None` to leave it alone.
+    fn destination(&self, _path: &Path, _prefix: &str, _dirs: &DestDirs) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether the installed file at `path` is one this handler looks after.
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool;
+
+    /// Called once per transaction with the owned files that were installed.
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once per transaction with the owned files that were removed.
+    fn removed(&self, _paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        Ok(())
+    }
+}
+
+static REGISTERED: RwLock<Vec<Arc<dyn Handler>>> = RwLock::new(Vec::new());
+
+/// Adds a handler that takes precedence over configured and built-in ones.
+pub fn register(handler: impl Handler + 'static) {
+    REGISTERED.write().unwrap_or_else(|err| err.into_inner()).push(Arc::new(handler));
+}
+
+/// A handler defined in the config file, running external commands:
+///
+/// ```toml
+/// [[handler]]
+/// name = "emacs"
+/// match = "usr/share/emacs/site-lisp/**"
+/// destination = "{share}/emacs/site-lisp"
+/// after_install = ["emacs", "--batch", "-f", "batch-byte-recompile-directory", "{dir}"]
+/// ```
+///
+/// Files below the literal part of `match` keep their layout under
+/// `destination`. `{dir}` in the commands is the rooted destination.
+#[derive(Deserialize, Clone)]
+pub struct ExternalHandler {
+    pub name: String,
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Where matched files go, relative to the prefix unless absolute;
+    /// `{prefix}` and `{share}` are replaced.
+    pub destination: String,
+    #[serde(default)]
+    pub after_install: Vec<String>,
+    #[serde(default)]
+    pub after_uninstall: Vec<String>,
+}
+
+struct Configured {
+    spec: ExternalHandler,
+    pattern: Pattern,
+    /// Leading directories of the pattern without wildcards.
+    base: PathBuf,
+}
+
+impl Configured {
+    fn new(spec: &ExternalHandler) -> Result<Self> {
+        let pattern = Pattern::new(&spec.pattern).context(format!("Invalid pattern in handler {}", spec.name))?;
+        let base = Path::new(&spec.pattern)
+            .components()
+            .take_while(|component| !component.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect();
+        Ok(Configured {
+            spec: spec.clone(),
+            pattern,
+            base,
+        })
+    }
+
+    fn dir(&self, prefix: &str, dirs: &DestDirs) -> PathBuf {
+        let destination = self
+            .spec
+            .destination
+            .replace("{prefix}", prefix)
+            .replace("{share}", &unrooted(&dirs.share).to_string_lossy());
+        rooted(Path::new(prefix).join(destination))
+    }
+
+    fn run(&self, command: &[String], prefix: &str, dirs: &DestDirs) -> Result<()> {
+        let Some((program, args)) = command.split_first() else {
+            return Ok(());
+        };
+        let dir = self.dir(prefix, dirs).to_string_lossy().into_owned();
+        let args: Vec<String> = args.iter().map(|arg| arg.replace("{dir}", &dir)).collect();
+        debug!("Running {} {}", program, args.join(" "));
+        let output = Command::new(program).args(&args).output().context(format!("Failed to run {}", program))?;
+        if !output.status.success() {
+            bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
+    }
+}
+
+impl Handler for Configured {
+    fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    fn destination(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        if !self.pattern.matches_path(path) {
+            return None;
+        }
+        Some(self.dir(prefix, dirs).join(path.strip_prefix(&self.base).ok()?))
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        path.starts_with(self.dir(prefix, dirs))
+    }
+
+    fn installed(&self, _paths: &[PathBuf], prefix: &str, dirs: &DestDirs) -> Result<()> {
+        self.run(&self.spec.after_install, prefix, dirs)
+    }
+
+    fn removed(&self, _paths: &[PathBuf], prefix: &str, dirs: &DestDirs) -> Result<()> {
+        self.run(&self.spec.after_uninstall, prefix, dirs)
+    }
+}
+
+/// Keeps `mimeinfo.cache` in the applications directory current.
+struct DesktopDatabase;
+
+impl Handler for DesktopDatabase {
+    fn name(&self) -> &str {
+        "desktop-database"
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        updates_caches(prefix) && path.starts_with(&dirs.desktop)
+    }
+
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        update_desktop_database(&dirs.desktop)
+    }
+
+    fn removed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        update_desktop_database(&dirs.desktop)
+    }
+}
+
+fn update_desktop_database(dir: &Path) -> Result<()> {
+    // Not every system has desktop-file-utils; nothing to keep current then.
+    let Ok(output) = Command::new("update-desktop-database").arg(dir).output() else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        bail!("failed to update desktop database: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    info!("Desktop database updated");
+    Ok(())
+}
+
+/// Keeps the `icon-theme.cache` of each icon theme with installed icons
+/// current, so menus pick up new icons without waiting for a login.
+struct IconCache;
+
+impl IconCache {
+    /// The theme directories, such as `icons/hicolor`, the icons in `paths` are in.
+    fn themes(paths: &[PathBuf], dirs: &DestDirs) -> BTreeSet<PathBuf> {
+        paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(&dirs.icon).ok()?.components().next())
+            .map(|theme| dirs.icon.join(theme))
+            .filter(|theme| theme.is_dir())
+            .collect()
+    }
+}
+
+impl Handler for IconCache {
+    fn name(&self) -> &str {
+        "icon-cache"
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        updates_caches(prefix) && path.starts_with(&dirs.icon)
+    }
+
+    fn installed(&self, paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        for theme in Self::themes(paths, dirs) {
+            update_icon_cache(&theme)?;
+        }
+        Ok(())
+    }
+
+    fn removed(&self, paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        for theme in Self::themes(paths, dirs) {
+            let cache = theme.join("icon-theme.cache");
+            let mut rest = fs::read_dir(&theme)?.filter_map(|entry| entry.ok()).filter(|entry| entry.path() != cache);
+            if rest.next().is_none() {
+                // The last icons of the theme are gone; so is the theme.
+                let _ = fs::remove_file(&cache);
+                clean_empty_dirs(&theme)?;
+            } else {
+                update_icon_cache(&theme)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn update_icon_cache(theme: &Path) -> Result<()> {
+    // Running programs notice changed themes by these mtimes, cache or not.
+    for path in [theme.to_path_buf(), theme.join("index.theme")] {
+        if let Err(err) = File::open(&path).and_then(|file| file.set_modified(SystemTime::now()))
+            && path.exists()
+        {
+            debug!("Cannot touch {}: {}", path.display(), err);
+        }
+    }
+    // Without GTK installed there is nothing that reads the cache. Themes in
+    // data dirs other than /usr/share usually have no index.theme of their
+    // own, hence --ignore-theme-index.
+    let Ok(output) = Command::new("gtk-update-icon-cache").args(["-q", "-f", "-t"]).arg(theme).output() else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("failed to update icon cache of {}: {}", theme.display(), stderr.trim());
+    }
+    info!("Icon cache of {} updated", theme.display());
+    Ok(())
+}
+
+/// Installs shared MIME-info definitions (`usr/share/mime/packages/*.xml`)
+/// and rebuilds the MIME database from them, which registers the file types
+/// and their associations.
+struct MimeDatabase;
+
+impl Handler for MimeDatabase {
+    fn name(&self) -> &str {
+        "mime-database"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        let file = path.strip_prefix("usr/share/mime/packages").ok()?;
+        if file.components().count() != 1 || file.extension().is_none_or(|extension| extension != "xml") {
+            return None;
+        }
+        Some(dirs.share.join("mime/packages").join(file))
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        updates_caches(prefix) && path.starts_with(dirs.share.join("mime/packages"))
+    }
+
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        update_mime_database(&dirs.share.join("mime"))
+    }
+
+    fn removed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        let mime = dirs.share.join("mime");
+        if !mime.join("packages").is_dir() {
+            // Everything else in there was generated from the definitions
+            // that were just removed.
+            debug!("Removing {}", mime.display());
+            if mime.exists() {
+                fs::remove_dir_all(&mime).context(format!("Failed to remove {}", mime.display()))?;
+            }
+            return Ok(());
+        }
+        update_mime_database(&mime)
+    }
+}
+
+fn update_mime_database(dir: &Path) -> Result<()> {
+    // Not every system has shared-mime-info.
+    let Ok(output) = Command::new("update-mime-database").arg(dir).output() else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        bail!("failed to update MIME database: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    info!("MIME database updated");
+    Ok(())
+}
+
+/// Installs fonts from `usr/share/fonts` into the system or user font
+/// directory and rebuilds the fontconfig cache for it, so applications can
+/// use them without a relogin.
+struct FontCache;
+
+impl Handler for FontCache {
+    fn name(&self) -> &str {
+        "font-cache"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        Some(dirs.share.join("fonts").join(path.strip_prefix("usr/share/fonts").ok()?))
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        updates_caches(prefix) && path.starts_with(dirs.share.join("fonts"))
+    }
+
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        update_font_cache(&dirs.share.join("fonts"))
+    }
+
+    fn removed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        let fonts = dirs.share.join("fonts");
+        // fontconfig drops the cache of a directory that is gone by itself.
+        if !fonts.is_dir() {
+            return Ok(());
+        }
+        update_font_cache(&fonts)
+    }
+}
+
+fn update_font_cache(dir: &Path) -> Result<()> {
+    // Without fontconfig nothing would find the fonts through a cache anyway.
+    let Ok(output) = Command::new("fc-cache").arg("-f").arg(dir).output() else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        bail!("failed to update font cache: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    info!("Font cache of {} updated", dir.display());
+    Ok(())
+}
+
+/// Refreshes the AppStream cache software centers such as GNOME Software and
+/// KDE Discover list applications from, when the installer placed metainfo.
+struct AppStream;
+
+impl Handler for AppStream {
+    fn name(&self) -> &str {
+        "appstream"
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        updates_caches(prefix) && path.starts_with(&dirs.metainfo)
+    }
+
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        refresh_appstream_cache()
+    }
+
+    fn removed(&self, _paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        refresh_appstream_cache()
+    }
+}
+
+fn refresh_appstream_cache() -> Result<()> {
+    // The cache lives in /var/cache, which is the host's with --root.
+    if get_root() != Path::new("/") {
+        return Ok(());
+    }
+    // Without appstream installed, software centers read metainfo directly.
+    let Ok(output) = Command::new("appstreamcli").args(["refresh-cache", "--force"]).output() else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        bail!("failed to refresh AppStream cache: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    info!("AppStream cache refreshed");
+    Ok(())
+}
+
+/// Installs thumbnailers (`usr/share/thumbnailers/*.thumbnailer`), which
+/// file managers read directly to preview the package's file types.
+struct Thumbnailers;
+
+impl Handler for Thumbnailers {
+    fn name(&self) -> &str {
+        "thumbnailers"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        Some(dirs.share.join("thumbnailers").join(path.strip_prefix("usr/share/thumbnailers").ok()?))
+    }
+
+    fn owns(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> bool {
+        path.starts_with(dirs.share.join("thumbnailers"))
+    }
+}
+
+/// Installs man pages (`usr/share/man`) into the data directory, whose
+/// `man` subdirectory `man` searches next to each `bin` on `PATH`.
+struct ManPages;
+
+impl Handler for ManPages {
+    fn name(&self) -> &str {
+        "man-pages"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        Some(dirs.share.join("man").join(path.strip_prefix("usr/share/man").ok()?))
+    }
+
+    fn owns(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> bool {
+        path.starts_with(dirs.share.join("man"))
+    }
+}
+
+/// The loader 32-bit x86 programs name, which every distribution with
+/// 32-bit support provides.
+const LOADER_32: &str = "/lib/ld-linux.so.2";
+
+/// Installs 32-bit libraries of multilib packages (`usr/lib32`) into the
+/// directory this distribution keeps them in, next to the prefix's `lib`:
+/// `lib/i386-linux-gnu` on Debian and Ubuntu, `lib` itself where 64-bit
+/// libraries are in `lib64` as on Fedora and openSUSE, and `lib32` as on
+/// Arch elsewhere. The dynamic linker's cache is refreshed for them.
+struct Lib32;
+
+impl Lib32 {
+    fn dir(dirs: &DestDirs) -> PathBuf {
+        let real_dir = |dir: &str| rooted(dir).symlink_metadata().is_ok_and(|meta| meta.is_dir());
+        if real_dir("/usr/lib/x86_64-linux-gnu") || real_dir("/usr/lib/i386-linux-gnu") {
+            dirs.lib.join("i386-linux-gnu")
+        } else if real_dir("/usr/lib64") {
+            dirs.lib.clone()
+        } else {
+            dirs.lib.with_file_name("lib32")
+        }
+    }
+
+    /// Directories the dynamic linker searches: the built-in ones and those
+    /// `/etc/ld.so.conf` lists, following its `include` lines.
+    fn search_dirs() -> BTreeSet<PathBuf> {
+        let mut dirs: BTreeSet<PathBuf> = ["/lib", "/usr/lib", "/lib32", "/usr/lib32"].map(PathBuf::from).into();
+        let mut configs = vec![rooted("/etc/ld.so.conf")];
+        while let Some(config) = configs.pop() {
+            for line in fs::read_to_string(&config).unwrap_or_default().lines().map(str::trim) {
+                if let Some(pattern) = line.strip_prefix("include ") {
+                    let pattern = rooted(pattern.trim()).to_string_lossy().into_owned();
+                    configs.extend(glob::glob(&pattern).into_iter().flatten().filter_map(|path| path.ok()));
+                } else if line.starts_with('/') {
+                    dirs.insert(PathBuf::from(line.trim_end_matches('/')));
+                }
+            }
+        }
+        dirs
+    }
+}
+
+impl Handler for Lib32 {
+    fn name(&self) -> &str {
+        "lib32"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        Some(Self::dir(dirs).join(path.strip_prefix("usr/lib32").ok()?))
+    }
+
+    fn owns(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> bool {
+        let dir = Self::dir(dirs);
+        if !path.starts_with(&dir) {
+            return false;
+        }
+        // Where 32-bit libraries share `lib`, only they are this handler's.
+        dir != dirs.lib || fs::read(path).ok().and_then(|data| elf::inspect(&data)).is_some_and(|info| !info.is_64)
+    }
+
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, dirs: &DestDirs) -> Result<()> {
+        if !rooted(LOADER_32).exists() {
+            warn!(
+                "This system has no 32-bit loader ({}); install its 32-bit C library (lib32-glibc, \
+                 libc6:i386 or glibc.i686) for these libraries to load",
+                LOADER_32
+            );
+        }
+        let dir = unrooted(&Self::dir(dirs));
+        if !Self::search_dirs().contains(&dir) {
+            warn!("{} is not searched for libraries; add it to a file in /etc/ld.so.conf.d", dir.display());
+        }
+        update_linker_cache()
+    }
+
+    fn removed(&self, paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        for dir in paths.iter().filter_map(|path| path.parent()) {
+            let _ = fs::remove_dir(dir);
+        }
+        update_linker_cache()
+    }
+}
+
+fn update_linker_cache() -> Result<()> {
+    // Only root can write the cache; another root's is rebuilt in there.
+    if !is_root() {
+        return Ok(());
+    }
+    let mut ldconfig = Command::new("ldconfig");
+    if get_root() != Path::new("/") {
+        ldconfig.arg("-r").arg(get_root());
+    }
+    // Systems without ldconfig, such as musl ones, have no cache to update.
+    let Ok(output) = ldconfig.output() else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        bail!("failed to update the dynamic linker cache: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    info!("Dynamic linker cache updated");
+    Ok(())
+}
+
+/// Where KDE looks for services, service types and file manager service
+/// menus, under the data directory.
+const KSERVICE_DIRS: [&str; 5] = ["kservices5", "kservices6", "kservicetypes5", "kservicetypes6", "kio/servicemenus"];
+
+/// Installs KDE service files and rebuilds the KService cache (`ksycoca`)
+/// so KDE menus and Dolphin pick them up. The cache is per user, so it is
+/// only rebuilt when not running as root; sessions of other users notice
+/// the changed directories on their own.
+struct KServices;
+
+impl Handler for KServices {
+    fn name(&self) -> &str {
+        "kservices"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        let relative = path.strip_prefix("usr/share").ok()?;
+        KSERVICE_DIRS.iter().find(|dir| relative.starts_with(dir)).map(|_| dirs.share.join(relative))
+    }
+
+    fn owns(&self, path: &Path, prefix: &str, dirs: &DestDirs) -> bool {
+        updates_caches(prefix) && !is_root() && KSERVICE_DIRS.iter().any(|dir| path.starts_with(dirs.share.join(dir)))
+    }
+
+    fn installed(&self, _paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        rebuild_sycoca()
+    }
+
+    fn removed(&self, _paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        rebuild_sycoca()
+    }
+}
+
+fn rebuild_sycoca() -> Result<()> {
+    // Outside KDE neither tool is installed and nothing reads the cache.
+    let Some(output) = ["kbuildsycoca6", "kbuildsycoca5"].iter().find_map(|tool| Command::new(tool).output().ok())
+    else {
+        return Ok(());
+    };
+    if !output.status.success() {
+        bail!("failed to rebuild KService cache: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    info!("KService cache rebuilt");
+    Ok(())
+}
+
+/// Where AppArmor reads profiles from on the target system.
+const APPARMOR_DIR: &str = "/etc/apparmor.d";
+
+/// The kernel's AppArmor interface, present when AppArmor is enabled.
+const APPARMOR_FS: &str = "/sys/kernel/security/apparmor";
+
+/// Installs AppArmor profiles (`etc/apparmor.d`) on hosts that use AppArmor,
+/// such as Debian, Ubuntu and openSUSE, and loads them; profiles shipped for
+/// reference in `usr/share/apparmor` go to the data directory unloaded. The
+/// names of loaded profiles are recorded so they can be unloaded when their
+/// files are removed.
+struct AppArmor;
+
+impl AppArmor {
+    /// Loaded profiles by the file they came from, one `path<TAB>name` line each.
+    fn record_path() -> PathBuf {
+        get_log_dir().join("apparmor-profiles")
+    }
+
+    fn records() -> Vec<(PathBuf, String)> {
+        let records = fs::read_to_string(Self::record_path()).unwrap_or_default();
+        records
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(path, name)| (PathBuf::from(path), name.to_string()))
+            .collect()
+    }
+
+    fn write_records(records: &[(PathBuf, String)]) -> Result<()> {
+        let path = Self::record_path();
+        if records.is_empty() {
+            let _ = fs::remove_file(&path);
+            return Ok(());
+        }
+        let lines: String = records.iter().map(|(file, name)| format!("{}\t{}\n", file.display(), name)).collect();
+        fs::write(&path, lines).context(format!("Failed to write {}", path.display()))
+    }
+}
+
+impl Handler for AppArmor {
+    fn name(&self) -> &str {
+        "apparmor"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        if let Ok(file) = path.strip_prefix("usr/share/apparmor") {
+            return Some(dirs.share.join("apparmor").join(file));
+        }
+        let file = path.strip_prefix(APPARMOR_DIR.trim_start_matches('/')).ok()?;
+        // Profiles are system-wide and only make sense where AppArmor is used.
+        (is_system_wide() && rooted(APPARMOR_DIR).is_dir()).then(|| rooted(APPARMOR_DIR).join(file))
+    }
+
+    fn owns(&self, path: &Path, _prefix: &str, _dirs: &DestDirs) -> bool {
+        path.starts_with(rooted(APPARMOR_DIR))
+    }
+
+    fn installed(&self, paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        // Profiles installed into another root load when that system boots.
+        if get_root() != Path::new("/") || !Path::new(APPARMOR_FS).is_dir() {
+            return Ok(());
+        }
+        // Only the profiles themselves; subdirectories hold abstractions,
+        // tunables and local additions that profiles include.
+        let profiles = paths.iter().filter(|path| path.parent() == Some(Path::new(APPARMOR_DIR)));
+        let mut records = Self::records();
+        for profile in profiles {
+            let output = Command::new("apparmor_parser").arg("-r").arg(profile).output();
+            let output = match output {
+                Ok(output) => output,
+                Err(_) => bail!("apparmor_parser is missing; the profiles load at the next boot"),
+            };
+            if !output.status.success() {
+                bail!("failed to load {}: {}", profile.display(), String::from_utf8_lossy(&output.stderr).trim());
+            }
+            let names = Command::new("apparmor_parser").arg("-N").arg(profile).output()?;
+            records.retain(|(path, _)| path != profile);
+            for name in String::from_utf8_lossy(&names.stdout).lines().filter(|name| !name.is_empty()) {
+                records.push((profile.clone(), name.to_string()));
+            }
+            info!("Loaded AppArmor profile {}", profile.display());
+        }
+        Self::write_records(&records)
+    }
+
+    fn removed(&self, paths: &[PathBuf], _prefix: &str, _dirs: &DestDirs) -> Result<()> {
+        // Subdirectories such as `local` that the package brought along, but
+        // never the profile directory itself.
+        for dir in paths.iter().filter_map(|path| path.parent()).filter(|dir| *dir != rooted(APPARMOR_DIR)) {
+            let _ = fs::remove_dir(dir);
+        }
+        let (unload, keep): (Vec<_>, Vec<_>) = Self::records().into_iter().partition(|(path, _)| paths.contains(path));
+        if unload.is_empty() {
+            return Ok(());
+        }
+        Self::write_records(&keep)?;
+        if get_root() != Path::new("/") || !Path::new(APPARMOR_FS).is_dir() {
+            return Ok(());
+        }
+        for (_, name) in unload {
+            // Removing takes the profile's name, not its file, which is gone.
+            match fs::write(Path::new(APPARMOR_FS).join(".remove"), &name) {
+                Ok(()) => info!("Unloaded AppArmor profile {}", name),
+                Err(err) => warn!("Could not unload AppArmor profile {}: {}", name, err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Installs shell completions and pixmaps into the directories this
+/// distribution uses for them, which shells and older applications read
+/// directly.
+struct DataLayout;
+
+impl Handler for DataLayout {
+    fn name(&self) -> &str {
+        "data-layout"
+    }
+
+    fn destination(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> Option<PathBuf> {
+        let (kind, file) = layout::classify(path)?;
+        Some(layout::dir(kind, &dirs.share).join(file))
+    }
+
+    fn owns(&self, path: &Path, _prefix: &str, dirs: &DestDirs) -> bool {
+        [Kind::BashCompletion, Kind::ZshCompletion, Kind::FishCompletion, Kind::Pixmap]
+            .into_iter()
+            .any(|kind| path.starts_with(layout::dir(kind, &dirs.share)))
+    }
+}
+
+/// All handlers in the order they are asked.
+pub fn handlers() -> Vec<Arc<dyn Handler>> {
+    let mut handlers = REGISTERED.read().unwrap_or_else(|err| err.into_inner()).clone();
+    for spec in &config::get().handlers {
+        match Configured::new(spec) {
+            Ok(handler) => handlers.push(Arc::new(handler)),
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+    handlers.push(Arc::new(DesktopDatabase));
+    handlers.push(Arc::new(IconCache));
+    handlers.push(Arc::new(MimeDatabase));
+    handlers.push(Arc::new(FontCache));
+    handlers.push(Arc::new(AppStream));
+    handlers.push(Arc::new(Thumbnailers));
+    handlers.push(Arc::new(ManPages));
+    handlers.push(Arc::new(Lib32));
+    handlers.push(Arc::new(KServices));
+    handlers.push(Arc::new(AppArmor));
+    handlers.push(Arc::new(DataLayout));
+    handlers
+}
+
+/// Hands each handler the files in `paths` it owns, as installed when
+/// `installed` is set and as removed otherwise. Failures are only warned
+/// about: the files are in place either way.
+pub(crate) fn notify(paths: &[PathBuf], prefix: &str, installed: bool) {
+    let dirs = DestDirs::for_prefix(prefix);
+    for handler in handlers() {
+        let owned: Vec<PathBuf> = paths.iter().filter(|path| handler.owns(path, prefix, &dirs)).cloned().collect();
+        if owned.is_empty() {
+            continue;
+        }
+        let result = if installed {
+            handler.installed(&owned, prefix, &dirs)
+        } else {
+            handler.removed(&owned, prefix, &dirs)
+        };
+        if let Err(err) = result {
+            warn!("{} handler: {:#}", handler.name(), err);
+        }
+    }
+}
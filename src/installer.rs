@@ -0,0 +1,1256 @@
+use crate::alpm;
+use crate::archive::{PkgInfo, extract_pkg_zst, fetch_package, get_package_name, parse_pkginfo, sha256_file};
+use crate::desktop;
+use crate::elf::{self, arch_runs_on_host, host_arch};
+use crate::handlers;
+use crate::error::{ConflictingFile, DesktopProblem, InstallerError, SetuidFile};
+use crate::events::{self, Event};
+use crate::journal;
+use crate::polkit;
+use crate::quarantine::{quarantine, quarantined_files};
+use crate::output::{
+    self, Action, AdoptReport, FileAction, FileKind, InstallReport, ReinstallReport, StepAction, TransactionStep,
+    UninstallReport, format_size,
+};
+use crate::paths::{
+    DestDirs, extraction_dir, get_root, get_staging_dir, get_stow_dir, get_user_home_dir, is_root, is_user_mode,
+    requires_root, rooted, staging_dir, unrooted,
+};
+use crate::plan::{
+    ICON_FORMATS, Plan, PlannedFile, install_relocated, installed_paths, plan_files, rewrite_desktop_entry,
+    rewrite_metainfo, wrapper_script,
+};
+use crate::records::{
+    get_alpm_path, get_log_path, get_options_path, get_pkginfo_path, get_prefix_db_dir, read_install_options,
+    read_installed_pkginfo, record_checksums, record_size, record_source, remove_records, source_of, write_checksums,
+};
+use crate::selinux;
+use crate::shrink::shrink;
+use crate::store;
+use crate::timings::{self, Phase};
+use crate::usrmerge;
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use log::{debug, info, log, trace, warn, Level};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tempfile::TempDir;
+
+#[derive(Args, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct InstallOptions {
+    /// Also install usr/lib and patch RUNPATHs so binaries find it outside /usr
+    #[arg(long)]
+    pub relocate: bool,
+    /// Keep binaries and libraries under <prefix>/lib/arch-installer/<pkg> and
+    /// put wrapper scripts that set library paths into <prefix>/bin
+    #[arg(long)]
+    pub wrappers: bool,
+    /// Install into <prefix>/arch-installer/<name>-<version> and only symlink
+    /// binaries, .desktop files and icons into place
+    #[arg(long, visible_alias = "link", conflicts_with = "wrappers")]
+    pub stow: bool,
+    /// Keep the package directories of --link installs in DIR instead of
+    /// <prefix>/arch-installer
+    #[arg(long, value_name = "DIR", requires = "stow", value_parser = absolute_dir)]
+    pub link_dir: Option<PathBuf>,
+    /// What to do with existing files owned by the system package manager
+    #[arg(long, value_enum, default_value = "abort")]
+    pub on_conflict: ConflictPolicy,
+    /// Also record the package in pacman's local database
+    #[arg(long)]
+    pub register_alpm: bool,
+    /// Install without asking for confirmation
+    #[arg(short, long)]
+    pub yes: bool,
+    /// Keep one copy of each file's contents in a shared store and hardlink
+    /// it into place, so identical files across packages and versions take
+    /// space once
+    #[arg(long)]
+    pub dedup: bool,
+    /// Number of files to copy in parallel (all CPUs by default)
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+    /// When reinstalling from a URL, download only a delta against the
+    /// previously installed package if the server publishes one
+    #[arg(long)]
+    pub delta: bool,
+    /// Install files that carry setuid or setgid bits, keeping the bits
+    #[arg(long)]
+    pub allow_setuid: bool,
+    /// Install executables without their executable bits until the package
+    /// is approved with `approve`
+    #[arg(long, conflicts_with = "dedup")]
+    pub quarantine: bool,
+    /// Refuse packages whose .desktop files are broken instead of warning
+    #[arg(long)]
+    pub strict_desktop: bool,
+    /// Strip debug symbols from ELF files before installing them
+    #[arg(long)]
+    pub strip: bool,
+    /// Gzip uncompressed man pages before installing them
+    #[arg(long)]
+    pub compress_man: bool,
+    /// Install packages and binaries built for another architecture, such
+    /// as when populating a sysroot with --root
+    #[arg(long)]
+    pub ignore_arch: bool,
+}
+
+/// Makes a directory given on the command line absolute, since the options
+/// are also handed to helpers running elsewhere.
+fn absolute_dir(dir: &str) -> Result<PathBuf, String> {
+    std::path::absolute(dir).map_err(|err| format!("{}: {}", dir, err))
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    #[default]
+    Abort,
+    Skip,
+    Force,
+}
+
+/// Extracts the package file at `path` to `temp_dir`, moves files of split
+/// `/usr` layouts into `usr` and applies `--strip` and `--compress-man`
+/// there, so every later step sees the files as they will be installed.
+pub(crate) fn extract_for_install(path: &str, temp_dir: &str, options: &InstallOptions) -> Result<()> {
+    extract_pkg_zst(path, temp_dir)?;
+    usrmerge::merge(Path::new(temp_dir), options)?;
+    shrink(Path::new(temp_dir), options)
+}
+
+/// Fetches, extracts and installs one package, remembering where it came from
+/// so `export` can point at it later.
+pub fn install_package(package: &str, prefix: &str, options: &InstallOptions) -> Result<InstallReport> {
+    let result = fetch_and_install(package, prefix, options);
+    let name = get_package_name(package);
+    let version = read_installed_pkginfo(&name, prefix).filter(|_| result.is_ok()).map(|pkginfo| pkginfo.version);
+    journal::transaction("install", &name, version.as_deref(), prefix, &result);
+    result
+}
+
+pub(crate) fn fetch_and_install(package: &str, prefix: &str, options: &InstallOptions) -> Result<InstallReport> {
+    check_prefix_writable(prefix)?;
+    let path = fetch_package(package)?;
+    if requires_root(prefix) && polkit::available() {
+        let extraction = extraction_dir()?;
+        let temp_dir = extraction.path().to_string_lossy().into_owned();
+        extract_for_install(&path, &temp_dir, options)?;
+        return polkit::install(&temp_dir, &path, &source_of(package), prefix, options);
+    }
+    let extraction = stage(prefix)?;
+    let temp_dir = extraction.path().to_string_lossy().into_owned();
+    let result = extract_for_install(&path, &temp_dir, options)
+        .and_then(|()| install_files(&temp_dir, prefix, &path, options, &HashSet::new()));
+    drop(extraction);
+    let _ = fs::remove_dir(get_staging_dir(prefix));
+    let report = result?;
+    record_source(package, &report.package, prefix)?;
+    Ok(report)
+}
+
+/// A directory to extract into, on the prefix's file system where possible
+/// so files can be moved into place.
+fn stage(prefix: &str) -> Result<TempDir> {
+    match staging_dir(prefix) {
+        Ok(dir) => Ok(dir),
+        Err(err) => {
+            debug!("Cannot stage in {}: {:#}", prefix, err);
+            extraction_dir()
+        }
+    }
+}
+
+/// Installed files of `package_name` whose contents match what installing
+/// the package extracted to `temp_dir` would write. Files rewritten on the
+/// way (`.desktop` files, wrappers, relocated binaries) always count as
+/// changed.
+fn unchanged_files(
+    temp_dir: &str,
+    prefix: &str,
+    package_name: &str,
+    options: &InstallOptions,
+) -> Result<HashSet<PathBuf>> {
+    if options.relocate || options.stow {
+        return Ok(HashSet::new());
+    }
+    let log = fs::read_to_string(get_log_path(package_name, prefix)).unwrap_or_default();
+    let installed: HashSet<PathBuf> = log.lines().map(rooted).collect();
+    let pkginfo = parse_pkginfo(temp_dir)?;
+    let plan = plan_files(temp_dir, prefix, package_name, &pkginfo, options)?;
+    Ok(plan
+        .files
+        .par_iter()
+        .filter(|file| matches!(file.kind, FileKind::Binary | FileKind::Icon | FileKind::Other))
+        .filter(|file| installed.contains(&file.dest) && file.dest.symlink_metadata().is_ok_and(|meta| meta.is_file()))
+        .filter(|file| matches!((sha256_file(&file.src), sha256_file(&file.dest)), (Ok(new), Ok(old)) if new == old))
+        .map(|file| file.dest.clone())
+        .collect())
+}
+
+/// Replaces the installation of `package` in `prefix` with the given package
+/// file or URL. Files whose contents did not change are left in place, with
+/// their timestamps; only the others are removed and written again.
+pub fn reinstall_package(
+    package: &str,
+    prefix: &str,
+    options: &InstallOptions,
+    confirm: bool,
+) -> Result<ReinstallReport> {
+    check_prefix_writable(prefix)?;
+    if requires_root(prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: prefix.to_string(),
+            action: "reinstall in",
+        }
+        .into());
+    }
+    let package_name = get_package_name(package);
+    if !get_log_path(&package_name, prefix).exists() {
+        return Err(InstallerError::NotInstalled {
+            package: package_name,
+            prefix: Some(prefix.to_string()),
+        }
+        .into());
+    }
+    // A package stays quarantined until it is approved.
+    let quarantined = !quarantined_files(&package_name, prefix).is_empty();
+    if quarantined && options.dedup {
+        bail!("{} is quarantined; approve it before reinstalling with --dedup", package_name);
+    }
+    let options = &InstallOptions {
+        quarantine: options.quarantine || quarantined,
+        ..options.clone()
+    };
+    if confirm && !confirm_uninstallation(&package_name, prefix)? {
+        return Err(InstallerError::Cancelled.into());
+    }
+    let path = fetch_package(package)?;
+    let extraction = stage(prefix)?;
+    let temp_dir = extraction.path().to_string_lossy().into_owned();
+    let result = extract_for_install(&path, &temp_dir, options).and_then(|()| {
+        let unchanged = unchanged_files(&temp_dir, prefix, &package_name, options)?;
+        debug!("{} files of {} are unchanged", unchanged.len(), package_name);
+        let uninstall = remove_installation(&package_name, prefix, &unchanged)?;
+        let install = install_files(&temp_dir, prefix, &path, options, &unchanged)?;
+        record_source(package, &install.package, prefix)?;
+        Ok(ReinstallReport { uninstall, install })
+    });
+    drop(extraction);
+    let _ = fs::remove_dir(get_staging_dir(prefix));
+    let version = read_installed_pkginfo(&package_name, prefix)
+        .filter(|_| result.is_ok())
+        .map(|pkginfo| pkginfo.version);
+    journal::transaction("reinstall", &package_name, version.as_deref(), prefix, &result);
+    result
+}
+
+/// Places a package already extracted to `temp_dir` and records `source` as
+/// where it came from. This is the part of an install that runs as root when
+/// polkit authorizes an unprivileged user.
+#[doc(hidden)]
+pub fn install_extracted(
+    temp_dir: &str,
+    package: &str,
+    source: &str,
+    prefix: &str,
+    options: &InstallOptions,
+) -> Result<InstallReport> {
+    let report = install_files(temp_dir, prefix, package, options, &HashSet::new())?;
+    record_source(source, &report.package, prefix)?;
+    Ok(report)
+}
+
+/// Nearest existing ancestor of `path`, which is where its file system can
+/// be queried before any directories are created.
+pub(crate) fn existing_ancestor(path: &Path) -> &Path {
+    path.ancestors().find(|dir| dir.exists()).unwrap_or(Path::new("/"))
+}
+
+/// The image-based system this is, whose `/usr` belongs to the system image.
+fn immutable_system() -> Option<&'static str> {
+    if get_root() != Path::new("/") {
+        return None;
+    }
+    let os_release = fs::read_to_string("/etc/os-release").unwrap_or_default();
+    if Path::new("/run/ostree-booted").exists() {
+        Some("an rpm-ostree system such as Fedora Silverblue")
+    } else if Path::new("/etc/NIXOS").exists() {
+        Some("NixOS")
+    } else if os_release.lines().any(|line| line == "ID=steamos") {
+        Some("SteamOS")
+    } else {
+        None
+    }
+}
+
+/// Fails with advice when `prefix` cannot take an install: its file system
+/// is mounted read-only, or it is under `/usr` on NixOS, which manages that
+/// itself and never looks there for programs.
+pub(crate) fn check_prefix_writable(prefix: &str) -> Result<()> {
+    if is_user_mode() {
+        return Ok(());
+    }
+    let dir = rooted(prefix);
+    let read_only = nix::sys::statvfs::statvfs(existing_ancestor(&dir))
+        .is_ok_and(|stat| stat.flags().contains(nix::sys::statvfs::FsFlags::ST_RDONLY));
+    let system = immutable_system();
+    let reason = match system {
+        Some(system) if read_only => format!("it is read-only on {}", system),
+        None if read_only => "it is on a read-only file system".to_string(),
+        Some("NixOS") if prefix.starts_with("/usr") => {
+            "NixOS manages /usr and does not run programs from it".to_string()
+        }
+        _ => return Ok(()),
+    };
+    Err(InstallerError::ReadOnlyPrefix {
+        prefix: prefix.to_string(),
+        reason,
+    }
+    .into())
+}
+
+/// Fails before anything is copied if a destination file system cannot hold
+/// the files planned for it.
+pub(crate) fn check_disk_space(planned: &[PlannedFile], pkginfo: &PkgInfo, moves: bool) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let mut needed: HashMap<u64, (&Path, u64)> = HashMap::new();
+    for file in planned {
+        if file.dest.symlink_metadata().is_ok() {
+            continue;
+        }
+        let dir = existing_ancestor(&file.dest);
+        let device = fs::metadata(dir).context(format!("Failed to inspect {}", dir.display()))?.dev();
+        let src = fs::metadata(&file.src).ok();
+        let size = match file.kind {
+            FileKind::Wrapper | FileKind::Symlink => 0,
+            FileKind::Binary | FileKind::Icon | FileKind::Other
+                if moves && src.as_ref().is_some_and(|meta| meta.dev() == device) =>
+            {
+                0
+            }
+            _ => src.map(|meta| meta.len()).unwrap_or(0),
+        };
+        needed.entry(device).or_insert((dir, 0)).1 += size;
+    }
+    for (dir, bytes) in needed.into_values() {
+        let stat = nix::sys::statvfs::statvfs(dir)
+            .context(format!("Failed to query free space for {}", dir.display()))?;
+        let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        debug!("{} needed, {} available on the file system of {}", bytes, available, dir.display());
+        if bytes > available {
+            return Err(InstallerError::NoSpace {
+                path: dir.display().to_string(),
+                needed: bytes,
+                available,
+                installed_size: Some(pkginfo.size).filter(|&size| size > 0),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Asks pacman, dpkg and rpm in turn which package owns `path`. On merged-usr
+/// systems dpkg may only know the `/bin/...` spelling, so that is tried too.
+pub(crate) fn native_owner(path: &Path) -> Option<String> {
+    query_native_owner(path).or_else(|| {
+        let legacy = Path::new("/").join(path.strip_prefix("/usr").ok()?);
+        let same_file = fs::canonicalize(&legacy).ok()? == fs::canonicalize(path).ok()?;
+        if same_file { query_native_owner(&legacy) } else { None }
+    })
+}
+
+pub(crate) fn query_native_owner(path: &Path) -> Option<String> {
+    let queries: [(&str, &[&str]); 3] = [
+        ("pacman", &["-Qqo"]),
+        ("dpkg", &["-S"]),
+        ("rpm", &["-qf", "--qf", "%{NAME}\n"]),
+    ];
+    for (manager, args) in queries {
+        let Ok(output) = std::process::Command::new(manager).args(args).arg(path).output() else {
+            continue;
+        };
+        if !output.status.success() {
+            continue;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let line = stdout.lines().next().unwrap_or_default();
+        let owner = match manager {
+            "dpkg" => line.rsplit_once(": ").map_or(line, |(packages, _)| packages),
+            _ => line,
+        };
+        if !owner.is_empty() {
+            return Some(format!("{} package {}", manager, owner.trim()));
+        }
+    }
+    None
+}
+
+/// Existing destination files that belong to the system package manager,
+/// mapped to a description of their owner. Only the running system's
+/// databases can be queried, so nothing is reported with `--root`.
+pub(crate) fn find_conflicts(planned: &[PlannedFile]) -> HashMap<PathBuf, String> {
+    if get_root() != Path::new("/") {
+        return HashMap::new();
+    }
+    planned
+        .iter()
+        .filter(|file| file.dest.symlink_metadata().is_ok())
+        .filter_map(|file| {
+            let owner = native_owner(&file.dest)?;
+            trace!("{} is owned by {}", file.dest.display(), owner);
+            Some((file.dest.clone(), owner))
+        })
+        .collect()
+}
+
+pub(crate) fn conflict_report(conflicts: &HashMap<PathBuf, String>) -> String {
+    let mut paths: Vec<&PathBuf> = conflicts.keys().collect();
+    paths.sort();
+    let mut text = format!("Files owned by the system package manager ({}):\n", paths.len());
+    for path in paths {
+        text.push_str(&format!("  - {} (owned by {})\n", path.display(), conflicts[path]));
+    }
+    text
+}
+
+pub(crate) fn conflict_error(conflicts: &HashMap<PathBuf, String>) -> InstallerError {
+    let mut files: Vec<ConflictingFile> = conflicts
+        .iter()
+        .map(|(path, owner)| ConflictingFile {
+            path: path.display().to_string(),
+            owner: owner.clone(),
+        })
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    InstallerError::Conflict { files }
+}
+
+/// Refuses packages built for an architecture this machine cannot run
+/// unless `--ignore-arch` was given.
+pub(crate) fn check_arch(package_name: &str, pkginfo: &PkgInfo, options: &InstallOptions) -> Result<()> {
+    if arch_runs_on_host(&pkginfo.arch) {
+        return Ok(());
+    }
+    if options.ignore_arch {
+        warn!("Installing {}, which is built for {}, on {}", package_name, pkginfo.arch, host_arch());
+        return Ok(());
+    }
+    Err(InstallerError::WrongArch {
+        package: package_name.to_string(),
+        arch: pkginfo.arch.clone(),
+        host: host_arch().to_string(),
+    }
+    .into())
+}
+
+/// Refuses to install files that would run with their owner's or group's
+/// privileges unless `--allow-setuid` was given.
+pub(crate) fn check_setuid(package_name: &str, planned: &[PlannedFile], options: &InstallOptions) -> Result<()> {
+    if options.allow_setuid {
+        return Ok(());
+    }
+    let mut files: Vec<SetuidFile> = planned
+        .iter()
+        .filter_map(|file| {
+            let meta = file.src.symlink_metadata().ok()?;
+            let mode = meta.mode() & 0o7777;
+            (meta.is_file() && mode & 0o6000 != 0).then(|| SetuidFile {
+                path: file.dest.display().to_string(),
+                mode: format!("{:04o}", mode),
+            })
+        })
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Err(InstallerError::Setuid {
+        package: package_name.to_string(),
+        files,
+    }
+    .into())
+}
+
+/// Warns about broken `.desktop` files among `planned`, or refuses them
+/// with `--strict-desktop`.
+pub(crate) fn check_desktop_entries(
+    package_name: &str,
+    planned: &[PlannedFile],
+    temp_dir: &str,
+    options: &InstallOptions,
+) -> Result<()> {
+    let installed = installed_paths(planned, temp_dir);
+    let mut problems = Vec::new();
+    for file in planned.iter().filter(|file| file.kind == FileKind::Desktop) {
+        let content = fs::read_to_string(&file.src).context(format!("Failed to read {}", file.src.display()))?;
+        let found = desktop::problems(&content, &installed).into_iter().chain(desktop::validator_errors(&file.src));
+        for problem in found {
+            warn!("{}: {}", file.dest.display(), problem);
+            problems.push(DesktopProblem {
+                path: file.dest.display().to_string(),
+                problem,
+            });
+        }
+    }
+    if problems.is_empty() || !options.strict_desktop {
+        return Ok(());
+    }
+    Err(InstallerError::InvalidDesktop {
+        package: package_name.to_string(),
+        problems,
+    }
+    .into())
+}
+
+/// Warns about programs among `planned` whose loader the target system
+/// does not have, which fail to start with a confusing "No such file or
+/// directory": glibc programs on musl systems above all.
+pub(crate) fn check_loaders(planned: &[PlannedFile], options: &InstallOptions) {
+    // Programs for other machines need other loaders anyway.
+    if options.ignore_arch {
+        return;
+    }
+    let mut missing: BTreeMap<String, usize> = BTreeMap::new();
+    for file in planned.iter().filter(|file| file.kind == FileKind::Binary) {
+        let Some(interpreter) = fs::read(&file.src).ok().and_then(|data| elf::inspect(&data)?.interpreter) else {
+            continue;
+        };
+        if !rooted(&interpreter).exists() {
+            debug!("{} needs {}", file.src.display(), interpreter);
+            *missing.entry(interpreter).or_default() += 1;
+        }
+    }
+    for (interpreter, count) in &missing {
+        warn!(
+            "{} programs need the loader {}, which this system does not have; they will not start",
+            count,
+            interpreter
+        );
+    }
+    if !missing.is_empty() && elf::host_is_musl() {
+        warn!(
+            "This system uses musl, while Arch Linux programs are built for glibc. Install gcompat \
+             (apk add gcompat, xbps-install gcompat) for simple programs, or install into a glibc system \
+             such as a distrobox container or an Arch Linux chroot with --root"
+        );
+    }
+}
+
+pub(crate) fn read_confirmation() -> Result<bool> {
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_lowercase() == "y")
+}
+
+pub(crate) fn confirm_installation(
+    package: &str,
+    pkginfo: &PkgInfo,
+    prefix: &str,
+    planned: &[PlannedFile],
+    conflicts: &HashMap<PathBuf, String>,
+    policy: ConflictPolicy,
+    unchanged: &HashSet<PathBuf>,
+) -> Result<bool> {
+    let mut text = if pkginfo.version.is_empty() {
+        format!("Package: {}\n", package)
+    } else {
+        format!("Package: {} {}\n", package, pkginfo.version)
+    };
+    text.push_str(&format!("Target prefix: {}\n", prefix));
+    if get_root() != Path::new("/") {
+        text.push_str(&format!("Target root: {}\n", get_root().display()));
+    }
+    if pkginfo.size > 0 {
+        text.push_str(&format!("Installed size: {}\n", format_size(pkginfo.size)));
+    }
+    let count = |kind: FileKind| planned.iter().filter(|file| file.kind == kind).count();
+    text.push_str(&format!(
+        "Files to install: {} binaries, {} .desktop files, {} icons",
+        count(FileKind::Binary),
+        count(FileKind::Desktop),
+        count(FileKind::Icon)
+    ));
+    if count(FileKind::Metainfo) > 0 {
+        text.push_str(&format!(", {} metainfo files", count(FileKind::Metainfo)));
+    }
+    if count(FileKind::Wrapper) > 0 {
+        text.push_str(&format!(", {} wrapper scripts", count(FileKind::Wrapper)));
+    }
+    if count(FileKind::Symlink) > 0 {
+        text.push_str(&format!(", {} symlinks", count(FileKind::Symlink)));
+    }
+    if count(FileKind::Other) > 0 {
+        text.push_str(&format!(", {} other files", count(FileKind::Other)));
+    }
+    text.push('\n');
+    if !conflicts.is_empty() {
+        text.push_str(&conflict_report(conflicts));
+        if policy == ConflictPolicy::Force {
+            text.push_str("These files will be overwritten.\n");
+        } else {
+            text.push_str("These files will be skipped.\n");
+        }
+    }
+    let existing: Vec<&PlannedFile> = planned
+        .iter()
+        .filter(|file| file.dest.exists() && !conflicts.contains_key(&file.dest) && !unchanged.contains(&file.dest))
+        .collect();
+    if !existing.is_empty() {
+        text.push_str(&format!("Existing files that will be skipped ({}):\n", existing.len()));
+        for file in existing {
+            text.push_str(&format!("  - {}\n", file.dest.display()));
+        }
+    }
+    if pkginfo.depends.is_empty() {
+        text.push_str("No required dependencies listed.\n");
+    } else {
+        text.push_str("Required dependencies:\n");
+        for dep in &pkginfo.depends {
+            text.push_str(&format!("  - {}\n", dep));
+        }
+    }
+    if pkginfo.optdepends.is_empty() {
+        text.push_str("No optional dependencies listed.\n");
+    } else {
+        text.push_str("Optional dependencies:\n");
+        for optdep in &pkginfo.optdepends {
+            text.push_str(&format!("  - {}\n", optdep));
+        }
+    }
+    text.push_str("Are you sure you want to install this package? [y/N]");
+    output::prompt(&text)?;
+    read_confirmation()
+}
+
+pub(crate) fn confirm_uninstallation(package: &str, prefix: &str) -> Result<bool> {
+    output::prompt(&format!("Are you sure you want to uninstall {} from {}? [y/N]", package, prefix))?;
+    read_confirmation()
+}
+
+pub(crate) fn clean_empty_dirs(path: &Path) -> Result<()> {
+    if path.is_dir() && path != get_root() {
+        let is_empty = fs::read_dir(path)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+        if is_empty {
+            fs::remove_dir(path)?;
+            info!("Removed empty directory: {}", path.display());
+            if let Some(parent) = path.parent() {
+                clean_empty_dirs(parent)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies a file by cloning its extents where the filesystem supports it
+/// (Btrfs, XFS), which takes no time or space. Elsewhere `fs::copy` is used,
+/// which already tries `copy_file_range` before a plain read/write loop.
+pub(crate) fn copy_file(src: &Path, dest: &Path) -> Result<()> {
+    match reflink_copy::reflink(src, dest) {
+        Ok(()) => {
+            trace!("Reflinked {} to {}", src.display(), dest.display());
+            fs::set_permissions(dest, fs::metadata(src)?.permissions())?;
+        }
+        Err(err) => {
+            trace!("Cannot reflink {}: {}", src.display(), err);
+            fs::copy(src, dest).context(format!("Failed to copy {}", src.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves a staged file into place, copying it when `dest` is on another file
+/// system, as `.desktop` files and icons under the home directory may be.
+pub(crate) fn move_file(src: &Path, dest: &Path) -> Result<()> {
+    match fs::rename(src, dest) {
+        Ok(()) => Ok(()),
+        Err(err) if err.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) => copy_file(src, dest),
+        Err(err) => Err(err).context(format!("Failed to move {} into place", dest.display())),
+    }
+}
+
+/// Everything needed to put a planned file of one package in place.
+pub(crate) struct Placement<'a> {
+    pub(crate) package_name: &'a str,
+    pub(crate) prefix: &'a str,
+    pub(crate) options: &'a InstallOptions,
+    /// Whether the package is staged on the destination file system.
+    pub(crate) moves: bool,
+    pub(crate) lib_dir: &'a Path,
+    pub(crate) desktop_dir: &'a Path,
+    pub(crate) installed_paths: &'a HashMap<PathBuf, PathBuf>,
+}
+
+impl Placement<'_> {
+    /// Writes `file` to its destination, which must not exist.
+    pub(crate) fn place(&self, file: &PlannedFile) -> Result<()> {
+        fs::create_dir_all(file.dest.parent().unwrap())?;
+        // Thumbnailers and KDE service files that handlers place run programs
+        // the same way desktop entries do.
+        let is_service_entry = file.kind == FileKind::Other
+            && file.dest.extension().is_some_and(|extension| extension == "thumbnailer" || extension == "desktop");
+        if file.kind == FileKind::Desktop || is_service_entry {
+            let content = fs::read_to_string(&file.src)
+                .context(format!("Failed to read {}", file.src.display()))?;
+            fs::write(&file.dest, rewrite_desktop_entry(&content, self.installed_paths, self.prefix != "/usr/local"))?;
+        } else if file.kind == FileKind::Metainfo {
+            let content = fs::read_to_string(&file.src)
+                .context(format!("Failed to read {}", file.src.display()))?;
+            fs::write(&file.dest, rewrite_metainfo(&content, self.installed_paths))?;
+        } else if let Some(target) = &file.target
+            && file.kind == FileKind::Wrapper
+        {
+            fs::write(&file.dest, wrapper_script(self.package_name, target, self.lib_dir, self.desktop_dir))?;
+        } else if let Some(target) = &file.target
+            && file.kind == FileKind::Symlink
+        {
+            std::os::unix::fs::symlink(unrooted(target), &file.dest)?;
+        } else if self.options.relocate && file.kind != FileKind::Icon {
+            install_relocated(file, self.lib_dir, self.installed_paths)?;
+        } else if let Some((_, mode)) = intended_mode(file).filter(|_| self.options.dedup && !self.options.quarantine) {
+            // The entry already has the final mode; the shared inode is never chmodded.
+            return store::install(&file.src, &file.dest, mode);
+        } else if self.moves {
+            move_file(&file.src, &file.dest)?;
+        } else {
+            copy_file(&file.src, &file.dest)?;
+        }
+        if file.kind == FileKind::Binary || file.kind == FileKind::Wrapper {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                // Setuid and setgid bits are only there if --allow-setuid let them through.
+                let special = fs::metadata(&file.dest)?.permissions().mode() & 0o6000;
+                fs::set_permissions(&file.dest, fs::Permissions::from_mode(0o755 | special))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a file owned by another package manager waits while
+/// `--on-conflict=force` replaces it.
+fn owned_aside(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.arch-installer-owned", name))
+}
+
+/// The mode a planned regular file gets once placed: binaries and wrappers
+/// are made executable, everything else keeps the packaged mode.
+fn intended_mode(file: &PlannedFile) -> Option<(PathBuf, u32)> {
+    if file.kind == FileKind::Symlink {
+        return None;
+    }
+    let meta = file.src.symlink_metadata().ok().filter(|meta| meta.is_file())?;
+    let mode = match file.kind {
+        FileKind::Binary | FileKind::Wrapper => 0o755 | meta.mode() & 0o6000,
+        _ => meta.mode() & 0o7777,
+    };
+    Some((file.dest.clone(), mode))
+}
+
+/// Installs the package extracted to `temp_dir`. Files in `unchanged` are
+/// already in place with the packaged contents and are left alone.
+pub(crate) fn install_files(
+    temp_dir: &str,
+    prefix: &str,
+    package: &str,
+    options: &InstallOptions,
+    unchanged: &HashSet<PathBuf>,
+) -> Result<InstallReport> {
+    check_prefix_writable(prefix)?;
+    if requires_root(prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: prefix.to_string(),
+            action: "install to",
+        }
+        .into());
+    }
+    if is_user_mode() && is_root() {
+        warn!("--user as root puts root-owned files into {}", get_user_home_dir().display());
+    }
+    let checks = Instant::now();
+    let pkginfo = parse_pkginfo(temp_dir)?;
+    let package_name = get_package_name(package);
+    let Plan {
+        files: planned,
+        skipped: mut files,
+        lib_dir,
+        desktop_dir: dest_desktop_dir,
+    } = plan_files(temp_dir, prefix, &package_name, &pkginfo, options)?;
+    let pkginfo_content = fs::read_to_string(format!("{}/.PKGINFO", temp_dir))?;
+    let alpm_entry = if options.register_alpm {
+        Some(alpm::entry_dir(get_root(), &pkginfo_content)?)
+    } else {
+        None
+    };
+    check_arch(&package_name, &pkginfo, options)?;
+    check_setuid(&package_name, &planned, options)?;
+    check_desktop_entries(&package_name, &planned, temp_dir, options)?;
+    check_loaders(&planned, options);
+    let conflicts = find_conflicts(&planned);
+    for (path, owner) in &conflicts {
+        events::emit(Event::Conflict {
+            path: path.display().to_string(),
+            owner: owner.clone(),
+        });
+    }
+    if !conflicts.is_empty() && options.on_conflict == ConflictPolicy::Abort {
+        return Err(conflict_error(&conflicts).into());
+    }
+    // Staged files that are not rewritten are moved into place, not copied.
+    let moves = Path::new(temp_dir).starts_with(get_staging_dir(prefix)) && !options.relocate && !options.dedup;
+    check_disk_space(&planned, &pkginfo, moves)?;
+    let total_bytes: u64 = planned
+        .iter()
+        .filter_map(|file| fs::metadata(&file.src).ok())
+        .map(|meta| meta.len())
+        .sum();
+    timings::record(Phase::Verify, checks.elapsed(), total_bytes);
+    if !options.yes
+        && !confirm_installation(&package_name, &pkginfo, prefix, &planned, &conflicts, options.on_conflict, unchanged)?
+    {
+        return Err(InstallerError::Cancelled.into());
+    }
+    let log_path = get_log_path(&package_name, prefix);
+    fs::create_dir_all(log_path.parent().unwrap())?;
+    let mut log_file = File::create(&log_path)
+        .context(format!("Failed to create log file {}", log_path.display()))?;
+    debug!("Recording installed files in {}", log_path.display());
+    fs::copy(format!("{}/.PKGINFO", temp_dir), get_pkginfo_path(&package_name, prefix))?;
+    debug!("Planned {} files ({} bytes) for installation", planned.len(), total_bytes);
+    fs::write(get_options_path(&package_name, prefix), serde_json::to_string(options)?)?;
+    let installed_paths = installed_paths(&planned, temp_dir);
+    let placement = Placement {
+        package_name: &package_name,
+        prefix,
+        options,
+        moves,
+        lib_dir: &lib_dir,
+        desktop_dir: &dest_desktop_dir,
+        installed_paths: &installed_paths,
+    };
+    // The log lists every planned file in plan order up front, so even an
+    // interrupted install can be uninstalled.
+    for file in &planned {
+        writeln!(log_file, "{}", unrooted(&file.dest).display())?;
+    }
+    log_file.flush()?;
+    // Read before placing, since staged files are moved away.
+    let executables: Vec<(PathBuf, u32)> = if options.quarantine {
+        planned.iter().filter_map(intended_mode).filter(|(_, mode)| mode & 0o111 != 0).collect()
+    } else {
+        Vec::new()
+    };
+    let show_files = !output::progress_enabled();
+    events::emit(Event::CopyStarted {
+        package: package_name.clone(),
+        files: planned.len(),
+        bytes: total_bytes,
+    });
+    let bar = output::files_bar(total_bytes, planned.len(), "Copying");
+    let done = AtomicUsize::new(0);
+    let place_one = |file: &PlannedFile| -> Result<FileAction> {
+        if unchanged.contains(&file.dest) {
+            debug!("{} is unchanged, keeping it", file.dest.display());
+            return Ok(FileAction::new(Action::Unchanged, file.kind, &file.dest));
+        }
+        if let Some(owner) = conflicts.get(&file.dest)
+            && options.on_conflict == ConflictPolicy::Force
+        {
+            warn!("overwriting {} owned by {}", file.dest.display(), owner);
+            // Kept aside until the install is done, so a rollback can put it back.
+            fs::rename(&file.dest, owned_aside(&file.dest))?;
+        } else if let Some(owner) = conflicts.get(&file.dest) {
+            warn!("{} is owned by {}, skipping", file.dest.display(), owner);
+            return Ok(FileAction::new(Action::Skipped, file.kind, &file.dest)
+                .with_reason(&format!("owned by {}", owner)));
+        } else if file.dest.symlink_metadata().is_ok() {
+            let noun = if file.kind == FileKind::Icon { "icon" } else { "file" };
+            warn!("{} {} already exists, skipping", noun, file.dest.display());
+            return Ok(FileAction::new(Action::Skipped, file.kind, &file.dest).with_reason("already exists"));
+        }
+        placement.place(file)?;
+        let level = if show_files { Level::Info } else { Level::Debug };
+        match file.kind {
+            FileKind::Binary => log!(level, "Installed binary: {}", file.dest.display()),
+            FileKind::Desktop => log!(level, "Installed .desktop file: {}", file.dest.display()),
+            FileKind::Icon => log!(level, "Installed icon: {}", file.dest.display()),
+            FileKind::Metainfo => log!(level, "Installed metainfo: {}", file.dest.display()),
+            FileKind::Wrapper => log!(level, "Installed wrapper: {}", file.dest.display()),
+            FileKind::Symlink => log!(level, "Linked {}", file.dest.display()),
+            FileKind::Other => log!(level, "Installed file: {}", file.dest.display()),
+        }
+        Ok(FileAction::new(Action::Installed, file.kind, &file.dest))
+    };
+    let install_one = |file: &PlannedFile| -> Result<FileAction> {
+        let size = fs::metadata(&file.src).map(|meta| meta.len()).unwrap_or(0);
+        let action = place_one(file)?;
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        output::advance_files(&bar, done, planned.len(), size);
+        events::emit(Event::File {
+            action: action.action,
+            kind: action.kind,
+            path: action.path.clone(),
+            done,
+            total: planned.len(),
+        });
+        Ok(action)
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs.unwrap_or(0))
+        .build()?;
+    let copying = Instant::now();
+    let results: Vec<Result<FileAction>> = pool.install(|| planned.par_iter().map(install_one).collect());
+    timings::record(Phase::Copy, copying.elapsed(), total_bytes);
+    if results.iter().any(Result::is_err) {
+        output::finish_bar(&bar);
+        // Take back what was placed so a failed install leaves nothing behind.
+        for (file, result) in planned.iter().zip(&results) {
+            if let Ok(action) = result
+                && action.action == Action::Installed
+                && fs::remove_file(&file.dest).is_ok()
+            {
+                let _ = clean_empty_dirs(file.dest.parent().unwrap());
+            }
+        }
+        for path in conflicts.keys() {
+            let aside = owned_aside(path);
+            if aside.symlink_metadata().is_ok() {
+                let _ = fs::rename(&aside, path);
+            }
+        }
+        let _ = fs::remove_file(&log_path);
+        let _ = fs::remove_file(get_pkginfo_path(&package_name, prefix));
+        let _ = fs::remove_file(get_options_path(&package_name, prefix));
+        let _ = fs::remove_dir(get_prefix_db_dir(prefix));
+        warn!("Rolled back the partial installation of {}", package_name);
+        return Err(results.into_iter().find_map(Result::err).unwrap());
+    }
+    files.extend(results.into_iter().map(Result::unwrap));
+    output::finish_bar(&bar);
+    let installed = files.iter().filter(|f| f.action == Action::Installed).count();
+    if !show_files {
+        info!("Installed {} of {} files", installed, planned.len());
+    }
+    let installed_files: Vec<PathBuf> = files
+        .iter()
+        .filter(|file| file.action == Action::Installed || file.action == Action::Unchanged)
+        .map(|file| PathBuf::from(&file.path))
+        .collect();
+    for path in conflicts.keys() {
+        let _ = fs::remove_file(owned_aside(path));
+    }
+    // Skipped files belong to someone else, so only what was placed or kept
+    // stays in the log for uninstall to remove.
+    let mut log_file = File::create(&log_path)
+        .context(format!("Failed to rewrite log file {}", log_path.display()))?;
+    for path in &installed_files {
+        writeln!(log_file, "{}", unrooted(path).display())?;
+    }
+    log_file.flush()?;
+    if options.quarantine {
+        let placed: HashSet<&PathBuf> = installed_files.iter().collect();
+        let executables: Vec<(PathBuf, u32)> =
+            executables.into_iter().filter(|(path, _)| placed.contains(path)).collect();
+        quarantine(&package_name, prefix, &executables)?;
+    }
+    timings::time(Phase::Verify, 0, || record_checksums(&package_name, prefix, &installed_files))?;
+    record_size(&package_name, prefix, &installed_files)?;
+    let hooks = Instant::now();
+    if let Some(entry) = &alpm_entry {
+        let paths: Vec<PathBuf> = files
+            .iter()
+            .filter(|file| file.action == Action::Installed || file.action == Action::Unchanged)
+            .map(|file| unrooted(Path::new(&file.path)))
+            .collect();
+        alpm::register(entry, get_root(), &pkginfo_content, &paths)
+            .context(format!("Failed to register {} with pacman", package_name))?;
+        fs::write(get_alpm_path(&package_name, prefix), unrooted(entry).to_string_lossy().as_bytes())?;
+        info!("Registered in the pacman database: {}", entry.display());
+    }
+    if let Err(err) = selinux::restore_contexts(&installed_files) {
+        warn!("{:#}", err);
+    }
+    handlers::notify(&installed_files, prefix, true);
+    timings::record(Phase::PostHooks, hooks.elapsed(), 0);
+    Ok(InstallReport {
+        package: package_name,
+        prefix: prefix.to_string(),
+        depends: pkginfo.depends,
+        optdepends: pkginfo.optdepends,
+        files,
+    })
+}
+
+/// The steps installing `package` into `prefix` would take, without taking
+/// them: which files are installed, overwritten or skipped, sorted by
+/// destination.
+pub fn plan_install(package: &str, prefix: &str, options: &InstallOptions) -> Result<Vec<TransactionStep>> {
+    let path = fetch_package(package)?;
+    let extraction = extraction_dir()?;
+    let temp_dir = extraction.path().to_string_lossy().into_owned();
+    extract_for_install(&path, &temp_dir, options)?;
+    let pkginfo = parse_pkginfo(&temp_dir)?;
+    let package_name = get_package_name(&path);
+    let plan = plan_files(&temp_dir, prefix, &package_name, &pkginfo, options)?;
+    check_arch(&package_name, &pkginfo, options)?;
+    check_setuid(&package_name, &plan.files, options)?;
+    check_desktop_entries(&package_name, &plan.files, &temp_dir, options)?;
+    check_loaders(&plan.files, options);
+    let conflicts = find_conflicts(&plan.files);
+    if !conflicts.is_empty() && options.on_conflict == ConflictPolicy::Abort {
+        return Err(conflict_error(&conflicts).into());
+    }
+    let in_package = |src: &Path| Path::new("/").join(src.strip_prefix(&temp_dir).unwrap_or(src)).display().to_string();
+    let mut steps: Vec<TransactionStep> = plan
+        .files
+        .iter()
+        .map(|file| {
+            let action = match conflicts.get(&file.dest) {
+                Some(_) if options.on_conflict == ConflictPolicy::Force => StepAction::Overwrite,
+                Some(_) => StepAction::Skip,
+                None if file.dest.symlink_metadata().is_ok() => StepAction::Skip,
+                None => StepAction::Install,
+            };
+            TransactionStep {
+                action,
+                package: package_name.clone(),
+                src: Some(in_package(&file.src)),
+                dest: Some(file.dest.display().to_string()),
+            }
+        })
+        .collect();
+    steps.extend(plan.skipped.iter().map(|file| TransactionStep {
+        action: StepAction::Skip,
+        package: package_name.clone(),
+        src: Some(in_package(Path::new(&file.path))),
+        dest: None,
+    }));
+    steps.sort_by(|a, b| (&a.dest, &a.src).cmp(&(&b.dest, &b.src)));
+    Ok(steps)
+}
+
+/// The files uninstalling `package` from `prefix` would remove; those
+/// already gone are skipped.
+pub fn plan_uninstall(package: &str, prefix: &str) -> Result<Vec<TransactionStep>> {
+    let package_name = get_package_name(package);
+    let log_path = get_log_path(&package_name, prefix);
+    let log = fs::read_to_string(&log_path).map_err(|_| InstallerError::NotInstalled {
+        package: package_name.clone(),
+        prefix: Some(prefix.to_string()),
+    })?;
+    let mut steps: Vec<TransactionStep> = log
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let path = rooted(line);
+            TransactionStep {
+                action: if path.symlink_metadata().is_ok() { StepAction::Remove } else { StepAction::Skip },
+                package: package_name.clone(),
+                src: None,
+                dest: Some(path.display().to_string()),
+            }
+        })
+        .collect();
+    steps.sort_by(|a, b| a.dest.cmp(&b.dest));
+    Ok(steps)
+}
+
+/// Records files already present under `prefix` as belonging to `package`
+/// without copying anything, so they can be uninstalled or reinstalled later.
+pub fn adopt_files(temp_dir: &str, prefix: &str, package: &str) -> Result<AdoptReport> {
+    if requires_root(prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: prefix.to_string(),
+            action: "adopt files in",
+        }
+        .into());
+    }
+    let package_name = get_package_name(package);
+    let log_path = get_log_path(&package_name, prefix);
+    if log_path.exists() {
+        return Err(InstallerError::AlreadyInstalled {
+            package: package_name,
+            prefix: prefix.to_string(),
+        }
+        .into());
+    }
+    let pkginfo = parse_pkginfo(temp_dir)?;
+    let plan = plan_files(temp_dir, prefix, &package_name, &pkginfo, &InstallOptions::default())?;
+    let mut files = plan.skipped;
+    let mut adopted = Vec::new();
+    let mut sums = Vec::new();
+    for file in &plan.files {
+        if !file.dest.is_file() {
+            debug!("{} is not present", file.dest.display());
+            files.push(FileAction::new(Action::Missing, file.kind, &file.dest));
+            continue;
+        }
+        let mut action = FileAction::new(Action::Adopted, file.kind, &file.dest);
+        let sum = sha256_file(&file.dest)?;
+        let same_size = fs::metadata(&file.dest)?.len() == fs::metadata(&file.src)?.len();
+        if !same_size || sha256_file(&file.src)? != sum {
+            warn!("{} differs from the packaged file", file.dest.display());
+            action = action.with_reason("differs from the packaged file");
+        } else {
+            info!("Adopted {}", file.dest.display());
+        }
+        files.push(action);
+        // Symlinks are left out of the checksums, as record_checksums does.
+        if file.dest.symlink_metadata().is_ok_and(|meta| meta.is_file()) {
+            sums.push((sum, file.dest.clone()));
+        }
+        adopted.push(unrooted(&file.dest));
+    }
+    if adopted.is_empty() {
+        anyhow::bail!("None of the files of {} were found in {}", package_name, prefix);
+    }
+    fs::create_dir_all(log_path.parent().unwrap())?;
+    let log: String = adopted.iter().map(|path| format!("{}\n", path.display())).collect();
+    fs::write(&log_path, log).context(format!("Failed to create log file {}", log_path.display()))?;
+    fs::copy(format!("{}/.PKGINFO", temp_dir), get_pkginfo_path(&package_name, prefix))?;
+    let adopted: Vec<PathBuf> = adopted.iter().map(rooted).collect();
+    write_checksums(&package_name, prefix, &sums)?;
+    record_size(&package_name, prefix, &adopted)?;
+    Ok(AdoptReport {
+        package: package_name,
+        prefix: prefix.to_string(),
+        files,
+    })
+}
+
+pub fn uninstall_files(package: &str, prefix: &str, confirm: bool) -> Result<UninstallReport> {
+    if requires_root(prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: prefix.to_string(),
+            action: "uninstall from",
+        }
+        .into());
+    }
+    let package_name = get_package_name(package);
+    if confirm && !confirm_uninstallation(&package_name, prefix)? {
+        return Err(InstallerError::Cancelled.into());
+    }
+    let version = read_installed_pkginfo(&package_name, prefix).map(|pkginfo| pkginfo.version);
+    let result = remove_installation(&package_name, prefix, &HashSet::new());
+    journal::transaction("uninstall", &package_name, version.as_deref(), prefix, &result);
+    result
+}
+
+/// Removes the files and records of an installation, except the files in
+/// `keep`.
+pub(crate) fn remove_installation(
+    package_name: &str,
+    prefix: &str,
+    keep: &HashSet<PathBuf>,
+) -> Result<UninstallReport> {
+    let log_path = get_log_path(package_name, prefix);
+    if !log_path.exists() {
+        return Err(InstallerError::NotInstalled {
+            package: package_name.to_string(),
+            prefix: Some(prefix.to_string()),
+        }
+        .into());
+    }
+    let DestDirs {
+        bin: dest_bin_dir,
+        lib: dest_lib_dir,
+        share: dest_share_dir,
+        desktop: dest_desktop_dir,
+        icon: dest_icon_dir,
+        metainfo: dest_metainfo_dir,
+    } = DestDirs::for_prefix(prefix);
+    let log_content = fs::read_to_string(&log_path)
+        .context(format!("Failed to read log file {}", log_path.display()))?;
+    let stow_root = get_stow_dir(prefix, read_install_options(package_name, prefix).link_dir.as_deref());
+    let mut stow_dirs = Vec::new();
+    let mut files: Vec<FileAction> = Vec::new();
+    let total = log_content.lines().count();
+    for line in log_content.lines() {
+        let file_path = &rooted(line);
+        if keep.contains(file_path) {
+            continue;
+        }
+        if let Ok(relative) = file_path.strip_prefix(&stow_root)
+            && let Some(first) = relative.components().next()
+        {
+            let dir = stow_root.join(first);
+            if !stow_dirs.contains(&dir) {
+                stow_dirs.push(dir);
+            }
+        }
+        let is_link = file_path.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink());
+        let kind = if is_link {
+            FileKind::Symlink
+        } else if file_path.extension().map(|e| e == "desktop").unwrap_or(false) {
+            FileKind::Desktop
+        } else if file_path.extension().and_then(|e| e.to_str()).is_some_and(|e| ICON_FORMATS.contains(&e)) {
+            FileKind::Icon
+        } else if file_path.starts_with(&dest_metainfo_dir) {
+            FileKind::Metainfo
+        } else {
+            FileKind::Other
+        };
+        if file_path.symlink_metadata().is_ok() {
+            fs::remove_file(file_path)
+                .context(format!("Failed to remove file {}", file_path.display()))?;
+            match kind {
+                FileKind::Desktop => info!("Removed .desktop file: {}", file_path.display()),
+                FileKind::Icon => info!("Removed icon: {}", file_path.display()),
+                FileKind::Metainfo => info!("Removed metainfo: {}", file_path.display()),
+                FileKind::Symlink => info!("Removed symlink: {}", file_path.display()),
+                _ => info!("Removed file: {}", file_path.display()),
+            }
+            if file_path.starts_with(&dest_lib_dir) || file_path.starts_with(&dest_share_dir) {
+                clean_empty_dirs(file_path.parent().unwrap())?;
+            }
+            files.push(FileAction::new(Action::Removed, kind, file_path));
+        } else {
+            warn!("file {} does not exist, skipping", file_path.display());
+            files.push(FileAction::new(Action::Missing, kind, file_path));
+        }
+        let file = files.last().unwrap();
+        events::emit(Event::File {
+            action: file.action,
+            kind: file.kind,
+            path: file.path.clone(),
+            done: files.len(),
+            total,
+        });
+    }
+    remove_records(package_name, prefix)?;
+    for dir in &stow_dirs {
+        if dir.exists() {
+            fs::remove_dir_all(dir).context(format!("Failed to remove {}", dir.display()))?;
+            info!("Removed package directory: {}", dir.display());
+        }
+        clean_empty_dirs(&stow_root)?;
+    }
+    clean_empty_dirs(&dest_bin_dir)?;
+    clean_empty_dirs(&dest_desktop_dir)?;
+    clean_empty_dirs(&dest_icon_dir)?;
+    let removed: Vec<PathBuf> = files
+        .iter()
+        .filter(|file| file.action == Action::Removed)
+        .map(|file| PathBuf::from(&file.path))
+        .collect();
+    handlers::notify(&removed, prefix, false);
+    Ok(UninstallReport {
+        package: package_name.to_string(),
+        prefix: prefix.to_string(),
+        files,
+    })
+}
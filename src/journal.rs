@@ -0,0 +1,74 @@
+use crate::paths::pkexec_user;
+use log::debug;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The user behind the transaction: the one who ran `sudo` or `pkexec`
+/// rather than root.
+fn invoking_user() -> String {
+    let delegated = std::env::var("SUDO_USER").ok().or_else(|| std::env::var("DOAS_USER").ok());
+    delegated.or_else(|| pkexec_user().map(|user| user.name)).unwrap_or_else(|| {
+        let uid = nix::unistd::getuid();
+        nix::unistd::User::from_uid(uid)
+            .ok()
+            .flatten()
+            .map_or_else(|| uid.to_string(), |user| user.name)
+    })
+}
+
+/// Appends a field in the journal's native format, which takes a length
+/// prefix instead of `=` when the value spans lines.
+fn push_field(datagram: &mut Vec<u8>, key: &str, value: &str) {
+    datagram.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+fn send(socket: &str, datagram: &[u8]) -> io::Result<()> {
+    UnixDatagram::unbound()?.send_to(datagram, socket)?;
+    Ok(())
+}
+
+/// Records a finished install, uninstall or repair in the system journal with
+/// one `ARCH_INSTALLER_*` field per detail, or as a plain syslog line where
+/// journald is not running. Logging never fails the transaction itself.
+pub fn transaction<T>(action: &str, package: &str, version: Option<&str>, prefix: &str, result: &anyhow::Result<T>) {
+    let user = invoking_user();
+    let version = version.unwrap_or("unknown");
+    let (outcome, priority) = match result {
+        Ok(_) => ("success".to_string(), 6),
+        Err(err) => (format!("failed: {:#}", err), 3),
+    };
+    let message = format!("{} {} {} in {} by {}: {}", action, package, version, prefix, user, outcome);
+    let sent = if Path::new(JOURNAL_SOCKET).exists() {
+        let mut datagram = Vec::new();
+        push_field(&mut datagram, "MESSAGE", &message);
+        push_field(&mut datagram, "PRIORITY", &priority.to_string());
+        push_field(&mut datagram, "SYSLOG_IDENTIFIER", "arch-installer");
+        push_field(&mut datagram, "ARCH_INSTALLER_ACTION", action);
+        push_field(&mut datagram, "ARCH_INSTALLER_PACKAGE", package);
+        push_field(&mut datagram, "ARCH_INSTALLER_VERSION", version);
+        push_field(&mut datagram, "ARCH_INSTALLER_PREFIX", prefix);
+        push_field(&mut datagram, "ARCH_INSTALLER_USER", &user);
+        push_field(&mut datagram, "ARCH_INSTALLER_RESULT", if result.is_ok() { "success" } else { "failure" });
+        send(JOURNAL_SOCKET, &datagram)
+    } else {
+        // Facility user (1) with the same severity.
+        let message = message.replace('\n', " ");
+        let line = format!("<{}>arch-installer[{}]: {}", 8 + priority, std::process::id(), message);
+        send(SYSLOG_SOCKET, line.as_bytes())
+    };
+    if let Err(err) = sent {
+        debug!("Could not log the transaction to the system log: {}", err);
+    }
+}
@@ -0,0 +1,112 @@
+//! Where files go whose directories differ between distributions: shell
+//! completions and pixmaps. Packages ship them in Arch's layout (or, when
+//! built elsewhere, in Debian's or Fedora's); the directories they are
+//! installed to are found by asking the host, with the `[layout]` table of
+//! the config file taking precedence.
+
+use crate::config;
+use crate::paths::{get_root, rooted, unrooted};
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Directories under the data directory zsh completions are put in: Arch and
+/// Fedora use `site-functions`, Debian and Ubuntu `vendor-completions`.
+const ZSH_DIRS: [&str; 2] = ["zsh/site-functions", "zsh/vendor-completions"];
+
+/// The kinds of files laid out differently across distributions.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    BashCompletion,
+    ZshCompletion,
+    FishCompletion,
+    Pixmap,
+}
+
+/// Where packages may ship files of each kind, relative to the package root.
+const SOURCES: [(&str, Kind); 7] = [
+    ("usr/share/bash-completion/completions", Kind::BashCompletion),
+    ("etc/bash_completion.d", Kind::BashCompletion),
+    ("usr/share/zsh/site-functions", Kind::ZshCompletion),
+    ("usr/share/zsh/vendor-completions", Kind::ZshCompletion),
+    ("usr/share/fish/vendor_completions.d", Kind::FishCompletion),
+    ("usr/share/fish/completions", Kind::FishCompletion),
+    ("usr/share/pixmaps", Kind::Pixmap),
+];
+
+/// Runs `program` on the host and returns its output lines, or nothing when
+/// it is missing, fails or installing into another root.
+fn ask(program: &str, args: &[&str]) -> Vec<String> {
+    if get_root() != Path::new("/") {
+        return Vec::new();
+    }
+    match Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// A `completionsdir` from pkg-config, relative to `/usr/share`.
+fn pkg_config_dir(module: &str) -> Option<PathBuf> {
+    let dir = ask("pkg-config", &["--variable=completionsdir", module]).into_iter().next()?;
+    let relative = Path::new(&dir).strip_prefix("/usr/share").ok()?.to_path_buf();
+    debug!("{} completions go to {} according to pkg-config", module, relative.display());
+    Some(relative)
+}
+
+/// The function search path of a plain zsh.
+fn fpath() -> &'static [PathBuf] {
+    static FPATH: OnceLock<Vec<PathBuf>> = OnceLock::new();
+    FPATH.get_or_init(|| ask("zsh", &["-fc", "print -rl -- $fpath"]).into_iter().map(PathBuf::from).collect())
+}
+
+/// The zsh completion directory under `share`: the one zsh searches or that
+/// exists there, or else the one zsh searches under `/usr/share`.
+fn zsh_dir(share: &Path) -> PathBuf {
+    let fpath = fpath();
+    ZSH_DIRS
+        .iter()
+        .find(|dir| fpath.contains(&unrooted(share).join(dir)) || share.join(dir).is_dir())
+        .or_else(|| ZSH_DIRS.iter().find(|dir| fpath.contains(&Path::new("/usr/share").join(dir))))
+        .map_or_else(|| PathBuf::from(ZSH_DIRS[0]), PathBuf::from)
+}
+
+/// The directory files of `kind` go to for the data directory `share`.
+pub(crate) fn dir(kind: Kind, share: &Path) -> PathBuf {
+    static BASH: OnceLock<PathBuf> = OnceLock::new();
+    static FISH: OnceLock<PathBuf> = OnceLock::new();
+    let overrides = &config::get().layout;
+    let configured = match kind {
+        Kind::BashCompletion => &overrides.bash_completions,
+        Kind::ZshCompletion => &overrides.zsh_completions,
+        Kind::FishCompletion => &overrides.fish_completions,
+        Kind::Pixmap => &overrides.pixmaps,
+    };
+    if let Some(dir) = configured {
+        // Absolute overrides are taken as they are, relative ones under `share`.
+        return if dir.is_absolute() { rooted(dir) } else { share.join(dir) };
+    }
+    let relative = match kind {
+        Kind::BashCompletion => BASH
+            .get_or_init(|| pkg_config_dir("bash-completion").unwrap_or_else(|| "bash-completion/completions".into()))
+            .clone(),
+        Kind::ZshCompletion => zsh_dir(share),
+        Kind::FishCompletion => FISH
+            .get_or_init(|| pkg_config_dir("fish").unwrap_or_else(|| "fish/vendor_completions.d".into()))
+            .clone(),
+        Kind::Pixmap => PathBuf::from("pixmaps"),
+    };
+    share.join(relative)
+}
+
+/// The kind of the packaged file at `path` and its path below the directory
+/// of that kind.
+pub(crate) fn classify(path: &Path) -> Option<(Kind, &Path)> {
+    SOURCES
+        .iter()
+        .find_map(|(source, kind)| Some((*kind, path.strip_prefix(source).ok()?)))
+        .filter(|(_, file)| !file.as_os_str().is_empty())
+}
@@ -0,0 +1,62 @@
+//! Installs Arch Linux packages (`.pkg.tar.zst`) on any distribution.
+//!
+//! The `arch-installer` binary is a thin command line frontend over this
+//! crate: [`install_package`], [`uninstall_files`] and [`list_packages`]
+//! return typed reports from [`output`] instead of printing them, so other
+//! tools can drive installations the same way.
+
+mod alpm;
+pub mod apply;
+pub mod archive;
+pub mod audit;
+pub mod build;
+pub mod bundle;
+pub mod check;
+pub mod clean;
+pub mod config;
+pub mod convert;
+pub mod create;
+pub mod delta;
+pub mod diff;
+mod desktop;
+mod elf;
+pub mod error;
+pub mod events;
+mod ffi;
+pub mod handlers;
+pub mod installer;
+pub mod journal;
+mod layout;
+pub mod link;
+pub mod manifest;
+pub mod notify;
+pub mod output;
+pub mod paths;
+mod plan;
+mod polkit;
+mod privsep;
+#[cfg(feature = "python")]
+mod python;
+pub mod quarantine;
+pub mod query;
+pub mod rebuild;
+pub mod records;
+pub mod repack;
+pub mod repair;
+pub mod repo;
+pub mod sandbox;
+pub mod sbom;
+mod selinux;
+mod shrink;
+pub mod sign;
+pub mod split;
+pub mod stats;
+pub mod store;
+pub mod timings;
+mod usrmerge;
+pub mod verify;
+
+pub use archive::PkgInfo;
+pub use installer::{ConflictPolicy, InstallOptions, install_package, uninstall_files};
+pub use query::{ListOptions, ListSort, list_packages};
+pub use records::{InstalledPackage, installed_packages};
@@ -0,0 +1,133 @@
+//! Moving the payload of `--link` installs. Everything such a package
+//! installed lives in one package directory, with symlinks pointing into it
+//! from the prefix, so the directory can be put elsewhere (another disk,
+//! say) by moving it and pointing the symlinks at the new place.
+
+use crate::error::InstallerError;
+use crate::installer::move_file;
+use crate::output::MoveReport;
+use crate::paths::{get_stow_dir, is_root, requires_root, rooted, unrooted};
+use crate::records::{
+    InstalledPackage, get_checksums_path, get_log_path, get_options_path, get_quarantine_path, read_install_options,
+};
+use anyhow::{Context, Result, bail};
+use log::{debug, info};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Moves the directory `from` to `to`, file by file when they are on
+/// different file systems.
+fn move_tree(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) => {}
+        Err(err) => return Err(err).context(format!("Failed to move {} to {}", from.display(), to.display())),
+    }
+    debug!("Copying {} to {} across file systems", from.display(), to.display());
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)?;
+        } else if entry.file_type().is_symlink() {
+            symlink(fs::read_link(entry.path())?, &dest)?;
+        } else {
+            move_file(entry.path(), &dest)?;
+        }
+    }
+    fs::remove_dir_all(from).context(format!("Failed to remove {}", from.display()))
+}
+
+/// Points the path on each line of `record`, which follows any fields
+/// before it, from below `from` to below `to`.
+fn rewrite_record(record: &Path, from: &Path, to: &Path) -> Result<()> {
+    let Ok(text) = fs::read_to_string(record) else {
+        return Ok(());
+    };
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            let start = if line.starts_with('/') {
+                0
+            } else {
+                line.find(" /").map_or(line.len(), |index| index + 1)
+            };
+            let (fields, path) = line.split_at(start);
+            match Path::new(path).strip_prefix(from) {
+                Ok(rest) => format!("{}{}\n", fields, to.join(rest).display()),
+                Err(_) => format!("{}\n", line),
+            }
+        })
+        .collect();
+    fs::write(record, lines.concat()).context(format!("Failed to update {}", record.display()))
+}
+
+/// Moves the package directory of `package`, installed with `--link`, into
+/// `dir` and points its symlinks there. `repair` and `apply` keep it in `dir`.
+pub fn move_payload(package: &InstalledPackage, dir: &Path) -> Result<MoveReport> {
+    if requires_root(&package.prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: package.prefix.clone(),
+            action: "move packages in",
+        }
+        .into());
+    }
+    let mut options = read_install_options(&package.name, &package.prefix);
+    if !options.stow {
+        bail!("{} was not installed with --link", package.name);
+    }
+    let log_path = get_log_path(&package.name, &package.prefix);
+    let log = fs::read_to_string(&log_path).context(format!("Failed to read log file {}", log_path.display()))?;
+    let stow_root = get_stow_dir(&package.prefix, options.link_dir.as_deref());
+    let Some(from) = log.lines().find_map(|line| {
+        let first = rooted(line).strip_prefix(&stow_root).ok()?.components().next()?.as_os_str().to_owned();
+        Some(stow_root.join(first))
+    }) else {
+        bail!("No package directory of {} found in {}", package.name, stow_root.display());
+    };
+    let dir = std::path::absolute(dir)?;
+    let to = rooted(&dir).join(from.file_name().context("Package directory without a name")?);
+    if to.symlink_metadata().is_ok() {
+        bail!("{} already exists", to.display());
+    }
+    fs::create_dir_all(rooted(&dir)).context(format!("Failed to create {}", dir.display()))?;
+    move_tree(&from, &to)?;
+    info!("Moved {} to {}", from.display(), to.display());
+
+    let (old, new) = (unrooted(&from), unrooted(&to));
+    let mut links = Vec::new();
+    for line in log.lines() {
+        let link = rooted(line);
+        let Ok(target) = fs::read_link(&link) else {
+            continue;
+        };
+        let Ok(rest) = target.strip_prefix(&old) else {
+            continue;
+        };
+        let target = new.join(rest);
+        fs::remove_file(&link).context(format!("Failed to replace symlink {}", link.display()))?;
+        symlink(&target, &link).context(format!("Failed to create symlink {}", link.display()))?;
+        debug!("Pointed {} at {}", link.display(), target.display());
+        links.push(link.display().to_string());
+    }
+    for record in [
+        get_log_path(&package.name, &package.prefix),
+        get_checksums_path(&package.name, &package.prefix),
+        get_quarantine_path(&package.name, &package.prefix),
+    ] {
+        rewrite_record(&record, &old, &new)?;
+    }
+    options.link_dir = Some(dir);
+    fs::write(get_options_path(&package.name, &package.prefix), serde_json::to_string(&options)?)?;
+    // The old parent goes too once it holds no other package.
+    let _ = fs::remove_dir(&stow_root);
+    Ok(MoveReport {
+        package: package.name.clone(),
+        prefix: package.prefix.clone(),
+        from: from.display().to_string(),
+        to: to.display().to_string(),
+        links,
+    })
+}
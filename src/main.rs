@@ -1,18 +1,76 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use std::fs::{self, File};
-use std::io::{self, Write};
+mod daemon;
+mod elevate;
+mod serve;
+mod tui;
+
+use anyhow::Result;
+use arch_installer::{
+    apply, audit, build, bundle, check, clean, config, convert, create, delta, diff, events, journal, link, manifest,
+    notify, quarantine, rebuild, repack, repair, repo, sandbox, sbom, sign, split, stats, store, timings, verify,
+};
+use arch_installer::archive::{
+    extract_pkg_zst, fetch_package, get_package_name, is_url, prefetch_packages, unpack_pkg_zst,
+};
+use arch_installer::error::{EXIT_CODES, InstallerError, exit_code};
+use arch_installer::installer::{
+    InstallOptions, adopt_files, install_extracted, install_package, plan_install, plan_uninstall, reinstall_package,
+    uninstall_files,
+};
+use arch_installer::output::{
+    self, Action, Change, CheckReport, ColorChoice, ConvertReport, DiffReport, ImportReport, InspectReport, ShellEnv,
+    SystemInfo, TransactionStep, format_size,
+};
+use arch_installer::paths::{
+    extraction_dir, get_config_path, is_root, migrate_legacy_state, normalize_prefix, requires_root, resolve_prefix,
+    set_helper, set_root, set_state_dir, set_user_mode,
+};
+use arch_installer::query::{
+    ListOptions, get_shell_env, get_system_info, inspect_package, list_packages, search_packages, which_command,
+};
+use arch_installer::records::{
+    find_install_prefix, installed_packages, read_installed_pkginfo, record_source, select_packages,
+};
+use arch_installer::success;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCandidates, CompletionCandidate};
+use clap_complete::env::EnvCompleter;
+use log::{debug, info, warn};
+use std::io;
 use std::path::{Path, PathBuf};
-use tar::Archive;
-use tempfile::TempDir;
-use walkdir::WalkDir;
-use zstd::stream::read::Decoder;
-use infer::Infer;
+use std::time::Instant;
 
 #[derive(Parser)]
 #[command(name = "arch-installer")]
 #[command(about = "Utility for installing and uninstalling Arch Linux packages on any distribution")]
 struct Cli {
+    /// Print machine-readable JSON instead of human-readable text
+    #[arg(long, global = true)]
+    json: bool,
+    /// When to use colors in terminal output
+    #[arg(long, global = true, value_enum, default_value = "auto")]
+    color: ColorChoice,
+    /// Increase output detail (-v for debug details, -vv for every file decision)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only print errors and final results
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Operate on a system mounted at this directory instead of /
+    #[arg(long, global = true, value_name = "DIR")]
+    root: Option<PathBuf>,
+    /// Install into ~/.local without root, keeping state apart from system installs
+    #[arg(long, global = true)]
+    user: bool,
+    /// Keep installed package records in this directory (or set
+    /// ARCH_INSTALLER_STATE_DIR)
+    #[arg(long, global = true, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Print how long each phase of the command took, with throughput
+    #[arg(long, global = true)]
+    timings: bool,
+    /// Print progress events to stderr as lines of JSON, for frontends
+    #[arg(long, global = true)]
+    json_progress: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -24,499 +82,1423 @@ enum Commands {
         package: String,
         #[arg(long, default_value = "/usr/local")]
         prefix: String,
+        /// Print what would be done, one tab-separated `action package src dest`
+        /// line per file, and exit without changing anything
+        #[arg(long)]
+        print: bool,
+        /// Install into overlays of the real directories, run the file
+        /// handlers there and show what changed, without touching anything
+        #[arg(long, conflicts_with = "print")]
+        preview_sandbox: bool,
+        /// Also install the packages next to the package file that were built
+        /// with it from the same PKGBUILD (same pkgbase and version), all or none
+        #[arg(long, conflicts_with_all = ["print", "preview_sandbox"])]
+        siblings: bool,
+        #[command(flatten)]
+        options: InstallOptions,
     },
     Uninstall {
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: String,
+        /// Prefix to remove from (default: the only prefix the package is installed in)
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Print the files that would be removed and exit without removing them
+        #[arg(long)]
+        print: bool,
+        /// Also uninstall the packages in the prefix built from the same PKGBUILD (same pkgbase)
+        #[arg(long, conflicts_with = "print")]
+        siblings: bool,
+    },
+    Reinstall {
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: String,
+        /// Prefix to remove from (default: the only prefix the package is installed in)
+        #[arg(long)]
+        prefix: Option<String>,
+        #[command(flatten)]
+        options: InstallOptions,
+    },
+    /// List installed packages; with --verbose, their sizes too
+    List {
+        #[command(flatten)]
+        options: ListOptions,
+    },
+    /// Show the OS, kernel, shell, desktop, package counts and hardware
+    Info,
+    /// Browse, search and uninstall installed packages interactively
+    Tui {
+        /// Only show packages installed into this prefix
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Find installed packages by name or description
+    Search {
+        #[arg(value_name = "QUERY")]
+        query: String,
+        /// Match the paths of installed files instead
+        #[arg(long)]
+        files: bool,
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Show which package installed a command on PATH
+    Which {
+        #[arg(value_name = "COMMAND")]
+        command: String,
+    },
+    /// Show the metadata and build information of a package file without installing it
+    Inspect {
+        /// Package file or URL
         #[arg(value_name = "PACKAGE")]
         package: String,
+    },
+    /// Compare two package files: their files, metadata and dependencies
+    Diff {
+        /// Package file or URL
+        #[arg(value_name = "OLD")]
+        old: String,
+        /// Package file or URL
+        #[arg(value_name = "NEW")]
+        new: String,
+    },
+    /// Summarize installed packages, their sizes per prefix and the cache size
+    Stats,
+    /// Print environment setup for software installed into a prefix, for eval in shell rc files
+    Shellenv {
         #[arg(long, default_value = "/usr/local")]
         prefix: String,
+        /// Syntax to print (detected from $SHELL by default)
+        #[arg(long, value_enum)]
+        shell: Option<EnvShell>,
     },
-    Reinstall {
+    /// Build an Arch package from a directory laid out as installed (usr/bin, usr/share, ...)
+    Create {
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+        #[command(flatten)]
+        metadata: Box<create::Metadata>,
+        /// Take the metadata from a TOML file with the keys of the flags instead
+        #[arg(long, value_name = "FILE", conflicts_with_all = [
+            "name", "version", "description", "arch", "url", "license", "depends", "optdepends", "provides",
+            "conflicts", "packager", "backup",
+        ])]
+        descriptor: Option<PathBuf>,
+        /// Directory to write the package to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Write a detached signature (<package>.sig) with gpg, or sq without it
+        #[arg(long)]
+        sign: bool,
+        /// Key to sign with ($GPGKEY or the default key otherwise)
+        #[arg(long, value_name = "KEY", requires = "sign")]
+        key: Option<String>,
+    },
+    /// Build a package from a PKGBUILD (simple ones: no split packages or VCS sources)
+    Build {
+        #[arg(value_name = "PKGBUILD", default_value = "PKGBUILD")]
+        pkgbuild: PathBuf,
+        /// Directory to write the package to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Build a package file again from the installed files of a package, e.g. to move it to another machine
+    Repack {
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: String,
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Directory to write the package to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Keep a directory of packages with a repository database, for pacman or `install` by name
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommand,
+    },
+    /// Turn an Arch package into a native package for another distribution
+    Convert {
+        #[command(subcommand)]
+        format: ConvertFormat,
+    },
+    /// Package an Arch package as a self-contained bundle that runs without installing
+    Bundle {
+        #[command(subcommand)]
+        format: BundleFormat,
+    },
+    /// Make a delta between two versions of a package for `install --delta`
+    Delta {
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+        /// Directory to write the delta to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Take ownership of files of a package that were copied into a prefix by hand
+    Adopt {
         #[arg(value_name = "PACKAGE")]
         package: String,
         #[arg(long, default_value = "/usr/local")]
         prefix: String,
     },
-    List,
-    Info,
+    /// Check installed files against the checksums recorded at install time
+    Verify {
+        /// Package to check (all installed packages by default)
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: Option<String>,
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Check every installed package and print nothing unless files were
+        /// changed or removed or cannot be checked, for running from cron or
+        /// a systemd timer
+        #[arg(long, conflicts_with_all = ["package", "prefix"])]
+        all: bool,
+    },
+    /// Restore missing or damaged files of a package from the package it was installed from
+    Repair {
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: String,
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Make the executables of a package installed with --quarantine executable
+    Approve {
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: String,
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Move the package directory of a package installed with --link into DIR and point its symlinks there
+    Move {
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: String,
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Free space in the cache and remove leftovers of failed runs and records of vanished installs
+    Clean {
+        /// Downloads of each package to keep in the cache (default 1)
+        #[arg(long, value_name = "N")]
+        keep: Option<usize>,
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Look up installed versions in the Arch Linux security tracker and report known vulnerabilities
+    Audit {
+        /// Package to audit (all installed packages by default)
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: Option<String>,
+        #[arg(long)]
+        prefix: Option<String>,
+        /// URL or path of the advisory database (the tracker's all.json by default)
+        #[arg(long, value_name = "SOURCE")]
+        database: Option<String>,
+    },
+    /// Check that a package file is the official build Arch's rebuilders reproduced from source
+    RebuildCheck {
+        /// Package file or URL
+        #[arg(value_name = "PACKAGE")]
+        package: String,
+        /// Base URL of the rebuilderd instance (https://reproducible.archlinux.org by default)
+        #[arg(long, value_name = "URL")]
+        rebuilderd: Option<String>,
+    },
+    /// Look for missing files, dangling symlinks, bad permissions and empty directories
+    Check {
+        /// Package to check (all installed packages by default)
+        #[arg(value_name = "PACKAGE", add = ArgValueCandidates::new(package_candidates))]
+        package: Option<String>,
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Print a JSON manifest of installed packages for import on another machine
+    Export,
+    /// Install every package listed in a manifest written by export
+    Import {
+        #[arg(value_name = "MANIFEST")]
+        manifest: PathBuf,
+        /// How many packages to download at once (default 4)
+        #[arg(long, value_name = "N")]
+        parallel_downloads: Option<usize>,
+        #[command(flatten)]
+        options: InstallOptions,
+    },
+    /// Run install, uninstall and upgrade lines from a file, or `-` for stdin, as one transaction
+    Apply {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+        /// Apply without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Serve install, uninstall and list requests from other users over a Unix socket
+    Daemon {
+        #[arg(long, default_value = daemon::DEFAULT_SOCKET)]
+        socket: PathBuf,
+        /// Members of this group may install and uninstall; anyone may list
+        #[arg(long, default_value = "wheel")]
+        group: String,
+    },
+    /// Serve an HTTP API for listing, querying, installing and uninstalling
+    /// packages, authenticated with a bearer token
+    Serve {
+        /// Address and port to listen on
+        #[arg(long, default_value = serve::DEFAULT_LISTEN)]
+        listen: String,
+        /// File holding the token clients must send (default:
+        /// $ARCH_INSTALLER_TOKEN)
+        #[arg(long)]
+        token_file: Option<PathBuf>,
+    },
+    /// Print a software bill of materials for all installed packages
+    Sbom {
+        #[arg(long, value_enum, default_value = "spdx")]
+        format: sbom::SbomFormat,
+    },
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: CompletionShell,
+    },
+    /// List the exit codes and the failures they stand for
+    ExitCodes,
+    /// Place an extracted package as root; install runs this through pkexec
+    #[command(hide = true)]
+    Place {
+        dir: String,
+        #[arg(long)]
+        package: String,
+        #[arg(long)]
+        source: String,
+        #[arg(long)]
+        prefix: String,
+        /// Install options as JSON
+        #[arg(long)]
+        options: String,
+    },
+    /// Unpack a package read from stdin; commands run as root run this as an unprivileged user
+    #[command(hide = true)]
+    Unpack {
+        dir: String,
+        /// The package file, for messages
+        #[arg(long)]
+        name: String,
+    },
 }
 
-fn extract_pkg_zst(pkg_path: &str, temp_dir: &str) -> Result<()> {
-    let file = File::open(pkg_path)
-        .context(format!("Failed to open package {}", pkg_path))?;
-    let decoder = Decoder::new(file)?;
-    let mut archive = Archive::new(decoder);
-    fs::create_dir_all(temp_dir)?;
-    archive.unpack(temp_dir)
-        .context("Error while extracting package")?;
-    println!("Extracted package {} to {}", pkg_path, temp_dir);
-    Ok(())
+#[derive(Subcommand)]
+enum BundleFormat {
+    /// Build a portable AppImage with the libraries the package needs
+    Appimage {
+        #[arg(value_name = "PACKAGE")]
+        package: String,
+        /// Directory to write the AppImage to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Build an OCI image archive with the package's files as a single layer
+    Oci {
+        #[arg(value_name = "PACKAGE")]
+        package: String,
+        /// Directory to write the image archive to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Build a systemd system extension image with the package's /usr, for systemd-sysext
+    Sysext {
+        #[arg(value_name = "PACKAGE")]
+        package: String,
+        /// Directory to write the image to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// PEM private key to sign the image's verity data with
+        #[arg(long, value_name = "PEM", requires = "certificate")]
+        key: Option<PathBuf>,
+        /// PEM certificate matching --key, which the host must trust
+        #[arg(long, value_name = "PEM", requires = "key")]
+        certificate: Option<PathBuf>,
+        /// Copy the image to /var/lib/extensions and refresh the merged extensions
+        #[arg(long)]
+        install: bool,
+    },
 }
 
-fn is_root() -> bool {
-    #[cfg(unix)]
-    {
-        nix::unistd::geteuid().is_root()
-    }
-    #[cfg(not(unix))]
-    {
-        false
-    }
+#[derive(Subcommand)]
+enum RepoCommand {
+    /// Copy package files into the repository and add them, replacing other versions
+    Add {
+        /// The database, <dir>/<repo>.db.tar.gz
+        #[arg(value_name = "DB")]
+        db: PathBuf,
+        #[arg(value_name = "PACKAGE", required = true)]
+        packages: Vec<PathBuf>,
+    },
+    /// Remove packages from the repository, with their files
+    Remove {
+        #[arg(value_name = "DB")]
+        db: PathBuf,
+        #[arg(value_name = "NAME", required = true)]
+        names: Vec<String>,
+    },
+    /// List the packages in the repository
+    List {
+        #[arg(value_name = "DB")]
+        db: PathBuf,
+    },
+    /// Serve the repository directory over HTTP, for other machines to install from
+    Serve {
+        #[arg(value_name = "DB")]
+        db: PathBuf,
+        /// Address and port to listen on
+        #[arg(long, default_value = serve::DEFAULT_REPO_LISTEN)]
+        listen: String,
+    },
 }
 
-fn get_package_name(pkg_path: &str) -> String {
-    let file_name = Path::new(pkg_path)
-        .file_name()
-        .map(|s| s.to_string_lossy().into_owned())
-        .unwrap_or("unknown".to_string());
-    file_name
-        .split('-')
-        .next()
-        .map(|s| s.to_string())
-        .unwrap_or("unknown".to_string())
+#[derive(Subcommand)]
+enum ConvertFormat {
+    /// Build a .deb for dpkg/apt
+    Deb {
+        #[arg(value_name = "PACKAGE")]
+        package: String,
+        /// Directory to write the package to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Build an .rpm for rpm/dnf/zypper
+    Rpm {
+        #[arg(value_name = "PACKAGE")]
+        package: String,
+        /// Directory to write the package to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
 }
 
-fn get_user_home_dir() -> PathBuf {
-    if let Ok(sudo_user) = std::env::var("SUDO_USER") {
-        return PathBuf::from(format!("/home/{}", sudo_user));
-    }
-    dirs::home_dir().unwrap_or_else(|| PathBuf::from("/tmp"))
+#[derive(Clone, Copy, ValueEnum)]
+enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+    Elvish,
 }
 
-fn get_log_dir() -> PathBuf {
-    get_user_home_dir().join(".local/share/arch-installer")
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EnvShell {
+    Sh,
+    Fish,
 }
 
-fn get_log_path(package: &str) -> PathBuf {
-    get_log_dir().join(format!("{}.log", package))
+fn package_candidates() -> Vec<CompletionCandidate> {
+    let mut names: Vec<String> = installed_packages()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|installed| installed.name)
+        .collect();
+    names.dedup();
+    names.into_iter().map(CompletionCandidate::new).collect()
 }
 
-fn parse_pkginfo(temp_dir: &str) -> Result<(Vec<String>, Vec<String>)> {
-    let pkginfo_path = format!("{}/.PKGINFO", temp_dir);
-    let content = fs::read_to_string(&pkginfo_path)
-        .context(format!("Failed to read .PKGINFO from {}", pkginfo_path))?;
-    let mut depends = Vec::new();
-    let mut optdepends = Vec::new();
-    for line in content.lines() {
-        if line.starts_with("depend = ") {
-            let depend = line.trim_start_matches("depend = ").trim().to_string();
-            depends.push(depend);
-        } else if line.starts_with("optdepend = ") {
-            let optdepend = line.trim_start_matches("optdepend = ").trim().to_string();
-            optdepends.push(optdepend);
-        }
-    }
-    Ok((depends, optdepends))
+/// Writes a registration script that calls back into the binary through
+/// `COMPLETE=<shell>`, so installed package names are completed live instead
+/// of being frozen into the script.
+fn print_completions(shell: CompletionShell) -> Result<()> {
+    let completer: &dyn EnvCompleter = match shell {
+        CompletionShell::Bash => &clap_complete::env::Bash,
+        CompletionShell::Zsh => &clap_complete::env::Zsh,
+        CompletionShell::Fish => &clap_complete::env::Fish,
+        CompletionShell::Elvish => &clap_complete::env::Elvish,
+    };
+    let bin = std::env::current_exe()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "arch-installer".to_string());
+    completer.write_registration("COMPLETE", "arch-installer", &bin, &bin, &mut io::stdout())?;
+    Ok(())
 }
 
-fn confirm_installation(package: &str, depends: &[String], optdepends: &[String]) -> Result<bool> {
-    println!("Package: {}", package);
-    if depends.is_empty() {
-        println!("No required dependencies listed.");
+fn print_system_info(info: &SystemInfo) {
+    println!("OS: {}", info.os);
+    println!("Kernel: {}", info.kernel);
+    println!("Shell: {}", info.shell);
+    println!("DE: {}", info.de);
+    if info.package_manager_counts.is_empty() {
+        println!("Packages: None");
     } else {
-        println!("Required dependencies:");
-        for dep in depends {
-            println!("  - {}", dep);
-        }
+        let packages: Vec<String> = info
+            .package_manager_counts
+            .iter()
+            .map(|p| match p.size {
+                Some(size) => format!("{} {} ({})", p.manager, p.count, format_size(size)),
+                None => format!("{} {}", p.manager, p.count),
+            })
+            .collect();
+        println!("Packages: {}", packages.join(", "));
     }
-    if optdepends.is_empty() {
-        println!("No optional dependencies listed.");
-    } else {
-        println!("Optional dependencies:");
-        for optdep in optdepends {
-            println!("  - {}", optdep);
+    if output::is_verbose() {
+        for package in &info.managed_packages {
+            println!("  {} {} ({})", package.name, package.version, package.prefix);
         }
     }
-    println!("Are you sure you want to install this package? [y/N]");
-    io::stdout().flush()?;
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    Ok(input.trim().to_lowercase() == "y")
-}
-
-fn confirm_uninstallation(package: &str) -> Result<bool> {
-    println!("Are you sure you want to uninstall {}? [y/N]", package);
-    io::stdout().flush()?;
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    Ok(input.trim().to_lowercase() == "y")
-}
-
-fn clean_empty_dirs(path: &Path) -> Result<()> {
-    if path.is_dir() {
-        let is_empty = fs::read_dir(path)
-            .map(|mut entries| entries.next().is_none())
-            .unwrap_or(false);
-        if is_empty {
-            fs::remove_dir(path)?;
-            println!("Removed empty directory: {}", path.display());
-            if let Some(parent) = path.parent() {
-                clean_empty_dirs(parent)?;
-            }
-        }
+    let hardware = &info.hardware;
+    let cores = if hardware.cores == 1 { "core" } else { "cores" };
+    println!("CPU: {} ({} {})", hardware.cpu, hardware.cores, cores);
+    if !hardware.gpus.is_empty() {
+        println!("GPU: {}", hardware.gpus.join(", "));
     }
-    Ok(())
+    println!("Memory: {} / {}", format_size(hardware.memory_used), format_size(hardware.memory_total));
+    println!("Uptime: {}", format_uptime(hardware.uptime));
 }
 
-fn list_packages() -> Result<()> {
-    let log_dir = get_log_dir();
-    if !log_dir.exists() {
-        println!("0");
-        return Ok(());
+/// `seconds` in days, hours and minutes, leaving out leading zero units.
+fn format_uptime(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    let unit = |count: u64, name: &str| format!("{} {}{}", count, name, if count == 1 { "" } else { "s" });
+    match (days, hours) {
+        (0, 0) => unit(minutes, "min"),
+        (0, _) => format!("{}, {}", unit(hours, "hour"), unit(minutes, "min")),
+        _ => format!("{}, {}, {}", unit(days, "day"), unit(hours, "hour"), unit(minutes, "min")),
     }
-    let count = fs::read_dir(&log_dir)?
-        .filter_map(|entry| {
-            let path = entry.ok()?.path();
-            if path.extension().map(|ext| ext == "log").unwrap_or(false) {
-                Some(())
-            } else {
-                None
-            }
-        })
-        .count();
-    println!("{}", count);
-    Ok(())
 }
 
-fn get_system_info() -> Result<()> {
-    let mut output = Vec::new();
-    if let Ok(os_release) = fs::read_to_string("/etc/os-release") {
-        let os = os_release
-            .lines()
-            .find(|line| line.starts_with("PRETTY_NAME="))
-            .map(|line| line.trim_start_matches("PRETTY_NAME=\"").trim_end_matches("\""))
-            .unwrap_or("Unknown");
-        output.push(format!("OS: {}", os));
-    } else {
-        output.push("OS: Unknown".to_string());
+fn print_inspect(report: &InspectReport) {
+    let pkginfo = &report.pkginfo;
+    println!("Name: {}", pkginfo.name);
+    println!("Version: {}", pkginfo.version);
+    if !pkginfo.pkgbase.is_empty() && pkginfo.pkgbase != pkginfo.name {
+        println!("Split from: {}", pkginfo.pkgbase);
     }
-    if let Ok(kernel) = std::process::Command::new("uname").arg("-r").output() {
-        let kernel = String::from_utf8_lossy(&kernel.stdout).trim().to_string();
-        output.push(format!("Kernel: {}", kernel));
-    } else {
-        output.push("Kernel: Unknown".to_string());
+    if !report.siblings.is_empty() {
+        println!("Installed siblings: {}", report.siblings.join(" "));
     }
-    if let Ok(shell) = std::env::var("SHELL") {
-        let shell_name = Path::new(&shell).file_name().unwrap_or_default().to_string_lossy();
-        if let Ok(version) = std::process::Command::new(&shell).arg("--version").output() {
-            let version = String::from_utf8_lossy(&version.stdout)
-                .lines()
-                .next()
-                .unwrap_or("")
-                .to_string();
-            output.push(format!("Shell: {} {}", shell_name, version));
-        } else {
-            output.push(format!("Shell: {}", shell_name));
+    println!("Architecture: {}", pkginfo.arch);
+    println!("Description: {}", pkginfo.description);
+    println!("URL: {}", pkginfo.url);
+    println!("Licenses: {}", pkginfo.licenses.join(" "));
+    println!("Depends on: {}", pkginfo.depends.join(" "));
+    println!("Installed size: {}", format_size(pkginfo.size));
+    println!("SHA-256: {}", report.sha256);
+    let Some(buildinfo) = &report.buildinfo else {
+        println!("Build info: None");
+        return;
+    };
+    println!("Packager: {}", buildinfo.packager);
+    println!("Build date: {}", sbom::timestamp(buildinfo.builddate));
+    println!("Build tool: {} {}", buildinfo.buildtool, buildinfo.buildtoolver);
+    println!("Build directory: {}", buildinfo.builddir);
+    println!("Build environment: {}", buildinfo.buildenv.join(" "));
+    println!("Build options: {}", buildinfo.options.join(" "));
+    println!("PKGBUILD SHA-256: {}", buildinfo.pkgbuild_sha256sum);
+    println!("Built with: {} packages", buildinfo.installed.len());
+    if output::is_verbose() {
+        for installed in &buildinfo.installed {
+            println!("    {}", installed);
         }
-    } else {
-        output.push("Shell: Unknown".to_string());
     }
-    let de = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_else(|_| "Unknown".to_string());
-    output.push(format!("DE: {}", de));
-    let mut packages = Vec::new();
-    let log_dir = get_log_dir();
-    let arch_installer_count = if log_dir.exists() {
-        fs::read_dir(&log_dir)?
-            .filter_map(|entry| {
-                let path = entry.ok()?.path();
-                if path.extension().map(|ext| ext == "log").unwrap_or(false) {
-                    Some(())
-                } else {
-                    None
-                }
-            })
-            .count()
-    } else {
-        0
-    };
-    if arch_installer_count > 0 {
-        packages.push(format!("arch-installer {}", arch_installer_count));
+}
+
+fn print_diff(report: &DiffReport) {
+    for field in &report.metadata {
+        let (old, new) = (field.old.as_deref().unwrap_or("(none)"), field.new.as_deref().unwrap_or("(none)"));
+        println!("{}: {} -> {}", field.key, old, new);
     }
-    if Path::new("/usr/bin/pacman").exists() {
-        if let Ok(output) = std::process::Command::new("pacman").arg("-Q").output() {
-            let count = String::from_utf8_lossy(&output.stdout).lines().count();
-            if count > 0 {
-                packages.push(format!("pacman {}", count));
-            }
-        }
+    for relation in &report.relations {
+        let added = relation.added.iter().map(|value| format!("+{}", value));
+        let removed = relation.removed.iter().map(|value| format!("-{}", value));
+        println!("{}: {}", relation.key, added.chain(removed).collect::<Vec<_>>().join(" "));
     }
-    if Path::new("/usr/bin/dpkg").exists() {
-        if let Ok(output) = std::process::Command::new("dpkg").arg("-l").output() {
-            let count = String::from_utf8_lossy(&output.stdout)
-                .lines()
-                .filter(|line| line.starts_with("ii "))
-                .count();
-            if count > 0 {
-                packages.push(format!("dpkg {}", count));
+    // Enough of a hash to tell versions of a file apart.
+    let hash = |hash: &Option<String>| hash.as_deref().map_or("-", |hash| &hash[..12]).to_string();
+    let size = |size: Option<u64>| size.map(format_size).unwrap_or_default();
+    // Directories have neither a hash nor a link target.
+    let describe = |size: Option<u64>, sha256: &Option<String>, link: &Option<String>| match (sha256, link) {
+        (_, Some(link)) => format!(" -> {}", link),
+        (Some(_), None) => format!(" ({}, sha256 {})", format_size(size.unwrap_or(0)), hash(sha256)),
+        (None, None) => "/".to_string(),
+    };
+    for file in &report.files {
+        match file.change {
+            Change::Added => println!("+ {}{}", file.path, describe(file.new_size, &file.new_sha256, &file.new_link)),
+            Change::Removed => println!("- {}{}", file.path, describe(file.old_size, &file.old_sha256, &file.old_link)),
+            Change::Modified => {
+                let mut details = vec![format!("{} -> {}", size(file.old_size), size(file.new_size))];
+                if file.old_sha256 != file.new_sha256 {
+                    details.push(format!("sha256 {} -> {}", hash(&file.old_sha256), hash(&file.new_sha256)));
+                }
+                if file.old_mode != file.new_mode {
+                    let mode = |mode: &Option<String>| mode.clone().unwrap_or_default();
+                    details.push(format!("mode {} -> {}", mode(&file.old_mode), mode(&file.new_mode)));
+                }
+                if file.old_link != file.new_link {
+                    let link = |link: &Option<String>| link.clone().unwrap_or_else(|| "(not a link)".to_string());
+                    details.push(format!("link {} -> {}", link(&file.old_link), link(&file.new_link)));
+                }
+                println!("~ {} ({})", file.path, details.join(", "));
             }
         }
     }
-    if Path::new("/usr/bin/rpm").exists() {
-        if let Ok(output) = std::process::Command::new("rpm").arg("-qa").output() {
-            let count = String::from_utf8_lossy(&output.stdout).lines().count();
-            if count > 0 {
-                packages.push(format!("rpm {}", count));
-            }
+    let count = |change: Change| report.files.iter().filter(|file| file.change == change).count();
+    let growth = if report.size_change < 0 { "-" } else { "+" };
+    println!(
+        "{} added, {} removed, {} changed files; {}{}",
+        count(Change::Added),
+        count(Change::Removed),
+        count(Change::Modified),
+        growth,
+        format_size(report.size_change.unsigned_abs())
+    );
+}
+
+/// Prepends each directory while keeping what is already set. Unset
+/// `XDG_DATA_DIRS` falls back to the spec default, and `MANPATH` keeps a
+/// trailing colon so man still searches its default paths.
+fn print_shell_env(env: &ShellEnv, shell: EnvShell) {
+    for var in &env.variables {
+        let (name, dir) = (var.name.as_str(), var.prepend.as_str());
+        match (shell, name) {
+            (EnvShell::Sh, "PATH") => println!("export PATH=\"{}:$PATH\"", dir),
+            (EnvShell::Sh, "XDG_DATA_DIRS") => println!(
+                "export XDG_DATA_DIRS=\"{}:${{XDG_DATA_DIRS:-/usr/local/share:/usr/share}}\"",
+                dir
+            ),
+            (EnvShell::Sh, "MANPATH") => println!("export MANPATH=\"{}${{MANPATH:+:$MANPATH}}:\"", dir),
+            (EnvShell::Sh, _) => println!("export {0}=\"{1}${{{0}:+:${0}}}\"", name, dir),
+            (EnvShell::Fish, "XDG_DATA_DIRS") => println!(
+                "set -q XDG_DATA_DIRS; or set -gx XDG_DATA_DIRS /usr/local/share:/usr/share; set -gx XDG_DATA_DIRS '{}':$XDG_DATA_DIRS",
+                dir
+            ),
+            (EnvShell::Fish, "MANPATH") => println!("set -gx MANPATH '{}' $MANPATH ''", dir),
+            (EnvShell::Fish, _) => println!("set -gx {} '{}' ${}", name, dir, name),
         }
     }
-    if packages.is_empty() {
-        output.push("Packages: None".to_string());
+}
+
+fn detect_env_shell() -> EnvShell {
+    let shell = std::env::var("SHELL").unwrap_or_default();
+    if shell.ends_with("/fish") || shell == "fish" {
+        EnvShell::Fish
     } else {
-        output.push(format!("Packages: {}", packages.join(", ")));
+        EnvShell::Sh
     }
-    for line in output {
-        println!("{}", line);
+}
+
+/// `--print` output: the steps as JSON, or one line each.
+fn print_steps(steps: &[TransactionStep]) -> Result<()> {
+    if output::is_json() {
+        return output::emit(&steps);
+    }
+    for step in steps {
+        println!("{}", step);
     }
     Ok(())
 }
 
-fn install_files(temp_dir: &str, prefix: &str, package: &str) -> Result<()> {
-    let needs_root = prefix.starts_with("/usr") || prefix == "/opt";
-    if needs_root && !is_root() {
-        anyhow::bail!("Please run the program with sudo or doas to install to {}", prefix);
-    }
-    let (depends, optdepends) = parse_pkginfo(temp_dir)?;
-    if !confirm_installation(&get_package_name(package), &depends, &optdepends)? {
-        anyhow::bail!("Installation cancelled by user.");
+/// What a command that installs or upgrades packages is doing, for the
+/// notification when it is done.
+fn transaction_name(command: &Commands) -> Option<String> {
+    match command {
+        Commands::Install { print: true, .. } | Commands::Install { preview_sandbox: true, .. } => None,
+        Commands::Install { package, .. } => Some(format!("Installing {}", get_package_name(package))),
+        Commands::Reinstall { package, .. } => Some(format!("Reinstalling {}", get_package_name(package))),
+        Commands::Import { manifest, .. } => Some(format!("Importing {}", manifest.display())),
+        Commands::Apply { file, .. } => Some(format!("Applying {}", file.display())),
+        _ => None,
     }
-    let src_bin_dir = format!("{}/usr/bin", temp_dir);
-    let dest_bin_dir = format!("{}/bin", prefix);
-    let src_desktop_dir = format!("{}/usr/share/applications", temp_dir);
-    let dest_desktop_dir = if prefix == "/usr/local" {
-        format!("{}/share/applications", prefix)
-    } else {
-        get_user_home_dir()
-            .join(".local/share/applications")
-            .to_string_lossy()
-            .into_owned()
-    };
-    let src_icon_dir = format!("{}/usr/share/icons", temp_dir);
-    let dest_icon_dir = if prefix == "/usr/local" {
-        format!("{}/share/icons", prefix)
-    } else {
-        get_user_home_dir()
-            .join(".local/share/icons")
-            .to_string_lossy()
-            .into_owned()
-    };
-    let package_name = get_package_name(package);
-    let log_path = get_log_path(&package_name);
-    fs::create_dir_all(log_path.parent().unwrap())?;
-    let mut log_file = File::create(&log_path)
-        .context(format!("Failed to create log file {}", log_path.display()))?;
-    let infer = Infer::new();
-    if Path::new(&src_bin_dir).exists() {
-        fs::create_dir_all(&dest_bin_dir)?;
-        for entry in WalkDir::new(&src_bin_dir).into_iter().filter_map(|e| e.ok()) {
-            let src_path = entry.path();
-            if src_path.is_file() {
-                let file_content = fs::read(src_path)?;
-                if let Some(kind) = infer.get(&file_content) {
-                    if kind.mime_type().starts_with("application/x-executable") || kind.mime_type().starts_with("application/x-sharedlib") {
-                        let relative_path = src_path.strip_prefix(&src_bin_dir)?;
-                        let dest_path = Path::new(&dest_bin_dir).join(relative_path);
-                        writeln!(log_file, "{}", dest_path.display())?;
-                        if dest_path.exists() {
-                            println!("Warning: file {} already exists, skipping", dest_path.display());
-                            continue;
-                        }
-                        fs::copy(src_path, &dest_path)?;
-                        #[cfg(unix)]
-                        {
-                            use std::os::unix::fs::PermissionsExt;
-                            fs::set_permissions(&dest_path, fs::Permissions::from_mode(0o755))?;
-                        }
-                        println!("Installed binary: {}", dest_path.display());
-                    } else {
-                        println!("Skipping non-ELF file: {}", src_path.display());
-                    }
+}
+
+fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::Install { package, prefix, print: true, options, .. } => {
+            output::hide_progress_messages();
+            print_steps(&plan_install(&package, &resolve_prefix(prefix)?, &options)?)
+        }
+        Commands::Install { package, prefix, preview_sandbox: true, options, .. } => {
+            let report = sandbox::preview_install(&package, &resolve_prefix(prefix)?, &options)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            for change in &report.changes {
+                let change_name = match change.change {
+                    Change::Added => "added",
+                    Change::Modified => "modified",
+                    Change::Removed => "removed",
+                };
+                println!("{}: {}", change_name, change.path);
+            }
+            success!("Previewed {} changes; nothing was installed", report.changes.len());
+            Ok(())
+        }
+        Commands::Install { package, prefix, siblings: true, options, .. } => {
+            if is_url(&package) {
+                anyhow::bail!("--siblings needs a package file; its siblings are looked for in the same directory");
+            }
+            let operations = split::sibling_files(&package)?
+                .into_iter()
+                .map(|package| apply::Operation::Install {
+                    package,
+                    prefix: prefix.clone(),
+                    options: options.clone(),
+                })
+                .enumerate()
+                .collect();
+            let report = apply::apply(operations, options.yes)?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else {
+                success!("Installed {} packages", report.operations.len());
+            }
+            Ok(())
+        }
+        Commands::Install { package, prefix, options, .. } => {
+            let prefix = resolve_prefix(prefix)?;
+            let report = install_package(&package, &prefix, &options)?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else {
+                success!("Installation completed!");
+            }
+            Ok(())
+        }
+        Commands::Uninstall { package, prefix, print, siblings } => {
+            let name = get_package_name(&package);
+            let prefix = find_install_prefix(&name, prefix.map(resolve_prefix).transpose()?)?;
+            if print {
+                return print_steps(&plan_uninstall(&package, &prefix)?);
+            }
+            let others = split::installed_siblings(&name, &prefix)?;
+            if siblings {
+                let operations = std::iter::once(name)
+                    .chain(others.into_iter().map(|other| other.name))
+                    .map(|package| apply::Operation::Uninstall {
+                        package,
+                        prefix: Some(prefix.clone()),
+                    })
+                    .enumerate()
+                    .collect();
+                let report = apply::apply(operations, false)?;
+                if output::is_json() {
+                    output::emit(&report)?;
                 } else {
-                    println!("Skipping non-ELF file: {}", src_path.display());
+                    success!("Uninstalled {} packages", report.operations.len());
                 }
+                return Ok(());
             }
+            if !others.is_empty() {
+                let names: Vec<&str> = others.iter().map(|other| other.name.as_str()).collect();
+                info!("Built from the same PKGBUILD and left installed: {} (see --siblings)", names.join(", "));
+            }
+            let report = uninstall_files(&package, &prefix, true)?;
+            store::prune()?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else {
+                success!("Uninstallation completed!");
+            }
+            Ok(())
         }
-    } else {
-        println!("No binaries found in /usr/bin, skipping");
-    }
-    if Path::new(&src_desktop_dir).exists() {
-        fs::create_dir_all(&dest_desktop_dir)?;
-        for entry in WalkDir::new(&src_desktop_dir)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let src_path = entry.path();
-            if src_path.is_file() && src_path.extension().map(|e| e == "desktop").unwrap_or(false) {
-                let relative_path = src_path.strip_prefix(&src_desktop_dir)?;
-                let dest_path = Path::new(&dest_desktop_dir).join(relative_path);
-                writeln!(log_file, "{}", dest_path.display())?;
-                if dest_path.exists() {
-                    println!("Warning: file {} already exists, skipping", dest_path.display());
-                    continue;
+        Commands::Reinstall { package, prefix, options } => {
+            let prefix = find_install_prefix(&get_package_name(&package), prefix.map(resolve_prefix).transpose()?)?;
+            // The delta needs the installed version's record, so fetch before uninstalling.
+            if options.delta
+                && is_url(&package)
+                && let Err(err) = delta::fetch(&package, &prefix)
+            {
+                warn!("Delta upgrade failed, downloading the whole package: {:#}", err);
+            }
+            let report = reinstall_package(&package, &prefix, &options, !options.yes)?;
+            store::prune()?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else {
+                success!("Reinstallation completed!");
+            }
+            Ok(())
+        }
+        Commands::List { mut options } => {
+            options.prefix = options.prefix.as_deref().map(normalize_prefix).map(resolve_prefix).transpose()?;
+            let list = list_packages(&options)?;
+            if output::is_json() {
+                output::emit(&list)?;
+            } else {
+                for package in &list.packages {
+                    let mut line = format!("{} ({})", package.name, package.prefix);
+                    if output::is_verbose() {
+                        line.push_str(&format!(" {}", format_size(package.size)));
+                    }
+                    if package.missing > 0 {
+                        line.push_str(&format!(
+                            " [{}: {} of {} files missing]",
+                            package.status, package.missing, package.files
+                        ));
+                    }
+                    println!("{}", line);
+                }
+                if output::is_verbose() {
+                    println!("Total size: {}", format_size(list.size));
+                }
+                if list.broken > 0 {
+                    warn!(
+                        "{} of {} installations have missing files; see `arch-installer check`, `repair` or `clean`",
+                        list.broken, list.count
+                    );
                 }
-                fs::copy(src_path, &dest_path)?;
-                println!("Installed .desktop file: {}", dest_path.display());
             }
+            Ok(())
         }
-        if prefix == "/usr/local" {
-            if let Ok(output) = std::process::Command::new("update-desktop-database")
-                .arg(&dest_desktop_dir)
-                .output()
-            {
-                if !output.status.success() {
+        Commands::Search { query, files, prefix } => {
+            let prefix = prefix.map(resolve_prefix).transpose()?;
+            let report = search_packages(&query, files, prefix.as_deref())?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            if report.results.is_empty() {
+                anyhow::bail!("No installed package matches {}", query);
+            }
+            for result in &report.results {
+                println!("{} {} ({})", result.name, result.version, result.prefix);
+                if !result.description.is_empty() {
+                    println!("    {}", result.description);
+                }
+                for path in &result.files {
+                    println!("    {}", path);
+                }
+            }
+            Ok(())
+        }
+        Commands::Which { command } => {
+            let report = which_command(&command)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            match (&report.package, &report.native_owner) {
+                (Some(package), _) => println!(
+                    "{} is from {} {} ({}), installed by arch-installer",
+                    report.path,
+                    package,
+                    report.version.as_deref().unwrap_or("?"),
+                    report.prefix.as_deref().unwrap_or_default()
+                ),
+                (None, Some(owner)) => println!("{} is not from arch-installer, it belongs to {}", report.path, owner),
+                (None, None) => println!("{} is not owned by any package", report.path),
+            }
+            Ok(())
+        }
+        Commands::Inspect { package } => {
+            let report = inspect_package(&package)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            print_inspect(&report);
+            Ok(())
+        }
+        Commands::Diff { old, new } => {
+            let report = diff::diff_packages(&old, &new)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            print_diff(&report);
+            Ok(())
+        }
+        Commands::Stats => {
+            let report = stats::collect()?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            println!("Packages: {} ({} files, {})", report.packages, report.files, format_size(report.size));
+            println!("Cache: {}", format_size(report.cache_size));
+            if !report.prefixes.is_empty() {
+                println!();
+                println!("Prefixes:");
+                for stats in &report.prefixes {
                     println!(
-                        "Warning: failed to update desktop database: {}",
-                        String::from_utf8_lossy(&output.stderr)
+                        "  {:<30} {:>5} packages {:>10}",
+                        stats.prefix,
+                        stats.packages,
+                        format_size(stats.size)
+                    );
+                }
+            }
+            if !report.largest.is_empty() {
+                println!();
+                println!("Largest packages:");
+                for package in &report.largest {
+                    println!(
+                        "  {:<30} {:>10}  {}",
+                        format!("{} {}", package.name, package.version),
+                        format_size(package.size),
+                        package.prefix
                     );
-                } else {
-                    println!("Desktop database updated");
                 }
             }
+            Ok(())
         }
-    } else {
-        println!("No .desktop files found, skipping");
-    }
-    if Path::new(&src_icon_dir).exists() {
-        fs::create_dir_all(&dest_icon_dir)?;
-        for entry in WalkDir::new(&src_icon_dir)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let src_path = entry.path();
-            if src_path.is_file() && src_path.extension().map(|e| e == "png" || e == "svg").unwrap_or(false) {
-                let file_content = fs::read(src_path)?;
-                let is_valid_icon = if let Some(kind) = infer.get(&file_content) {
-                    kind.mime_type() == "image/png" || kind.mime_type() == "image/svg+xml"
-                } else {
-                    false
-                };
-                if is_valid_icon {
-                    let relative_path = src_path.strip_prefix(&src_icon_dir)?;
-                    let dest_path = Path::new(&dest_icon_dir).join(relative_path);
-                    writeln!(log_file, "{}", dest_path.display())?;
-                    if dest_path.exists() {
-                        println!("Warning: icon {} already exists, skipping", dest_path.display());
-                        continue;
+        Commands::Tui { prefix } => tui::run(prefix.map(resolve_prefix).transpose()?.as_deref()),
+        Commands::Shellenv { prefix, shell } => {
+            let prefix = resolve_prefix(prefix)?;
+            let env = get_shell_env(&prefix);
+            if output::is_json() {
+                output::emit(&env)?;
+            } else {
+                print_shell_env(&env, shell.unwrap_or_else(detect_env_shell));
+            }
+            Ok(())
+        }
+        Commands::Create { dir, metadata, descriptor, output, sign, key } => {
+            let metadata = match descriptor {
+                Some(descriptor) => create::Metadata::read(&descriptor)?,
+                None => *metadata,
+            };
+            let mut report = create::create_package(&dir, &metadata, &output)?;
+            if sign {
+                let signature = sign::sign_file(Path::new(&report.path), key.as_deref())?;
+                report.signature = Some(signature.display().to_string());
+            }
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            match &report.signature {
+                Some(signature) => success!("Created {} and signed it as {}", report.path, signature),
+                None => success!("Created {}", report.path),
+            }
+            Ok(())
+        }
+        Commands::Build { pkgbuild, output } => {
+            let report = build::build_package(&pkgbuild, &output)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            success!("Built {}; install it with `arch-installer install {}`", report.path, report.path);
+            Ok(())
+        }
+        Commands::Repack { package, prefix, output } => {
+            let package = select_packages(Some(package), prefix)?.remove(0);
+            let report = repack::repack(&package, &output)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            success!("Created {}", report.path);
+            Ok(())
+        }
+        Commands::Repo { command } => {
+            let report = match command {
+                RepoCommand::Add { db, packages } => repo::add(&db, &packages)?,
+                RepoCommand::Remove { db, names } => repo::remove(&db, &names)?,
+                RepoCommand::List { db } => {
+                    let list = repo::list(&db)?;
+                    if output::is_json() {
+                        return output::emit(&list);
                     }
-                    fs::create_dir_all(dest_path.parent().unwrap())?;
-                    fs::copy(src_path, &dest_path)?;
-                    println!("Installed icon: {}", dest_path.display());
-                } else {
-                    println!("Skipping invalid icon: {}", src_path.display());
+                    for package in &list.packages {
+                        println!("{} {} ({}) - {}", package.name, package.version, package.arch, package.description);
+                    }
+                    return Ok(());
+                }
+                RepoCommand::Serve { db, listen } => {
+                    let repository = repo::Repository::open(&db)?;
+                    if !repository.dir().join(format!("{}.db", repository.name())).exists() {
+                        anyhow::bail!("No repository database at {}; add packages first", db.display());
+                    }
+                    let port = listen.rsplit_once(':').map_or("", |(_, port)| port);
+                    info!(
+                        "Other machines install from it with repos = [\"http://<this host>:{}/{}.db\"] in their config",
+                        port,
+                        repository.name()
+                    );
+                    return serve::run_repo(repository.dir(), &listen);
                 }
+            };
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            match (report.added.len(), report.removed.len()) {
+                (0, removed) => success!("Removed {} packages from {}", removed, report.repo),
+                (added, _) => success!("Added {} packages to {}", added, report.repo),
             }
+            Ok(())
         }
-    } else {
-        println!("No icons found in /usr/share/icons, skipping");
-    }
-    Ok(())
-}
-
-fn uninstall_files(package: &str, prefix: &str) -> Result<()> {
-    let needs_root = prefix.starts_with("/usr") || prefix == "/opt";
-    if needs_root && !is_root() {
-        anyhow::bail!("Please run the program with sudo or doas to uninstall from {}", prefix);
-    }
-    let package_name = get_package_name(package);
-    if !confirm_uninstallation(&package_name)? {
-        anyhow::bail!("Uninstallation cancelled by user.");
-    }
-    let log_path = get_log_path(&package_name);
-    if !log_path.exists() {
-        anyhow::bail!(
-            "No installation log found for package {} at {}. Run install first.",
-            package_name,
-            log_path.display()
-        );
-    }
-    let dest_bin_dir = format!("{}/bin", prefix);
-    let dest_desktop_dir = if prefix == "/usr/local" {
-        format!("{}/share/applications", prefix)
-    } else {
-        get_user_home_dir()
-            .join(".local/share/applications")
-            .to_string_lossy()
-            .into_owned()
-    };
-    let dest_icon_dir = if prefix == "/usr/local" {
-        format!("{}/share/icons", prefix)
-    } else {
-        get_user_home_dir()
-            .join(".local/share/icons")
-            .to_string_lossy()
-            .into_owned()
-    };
-    let log_content = fs::read_to_string(&log_path)
-        .context(format!("Failed to read log file {}", log_path.display()))?;
-    for line in log_content.lines() {
-        let file_path = Path::new(line);
-        if file_path.exists() {
-            fs::remove_file(file_path)
-                .context(format!("Failed to remove file {}", file_path.display()))?;
-            if file_path.extension().map(|e| e == "desktop").unwrap_or(false) {
-                println!("Removed .desktop file: {}", file_path.display());
-            } else if file_path.extension().map(|e| e == "png" || e == "svg").unwrap_or(false) {
-                println!("Removed icon: {}", file_path.display());
+        Commands::Convert { format } => {
+            let (package, out_dir, kind) = match format {
+                ConvertFormat::Deb { package, output } => (package, output, "deb"),
+                ConvertFormat::Rpm { package, output } => (package, output, "rpm"),
+            };
+            let extraction = extraction_dir()?;
+            let temp_dir = extraction.path().to_string_lossy().into_owned();
+            extract_pkg_zst(&package, &temp_dir)?;
+            let source = convert::Source::open(extraction.path())?;
+            let path = if kind == "deb" {
+                convert::to_deb(&source, &out_dir)?
+            } else {
+                convert::to_rpm(&source, &out_dir)?
+            };
+            if output::is_json() {
+                output::emit(&ConvertReport {
+                    package: get_package_name(&package),
+                    format: kind.to_string(),
+                    path: path.display().to_string(),
+                })?;
+            } else {
+                success!("Created {}", path.display());
+            }
+            Ok(())
+        }
+        Commands::Bundle { format } => {
+            let (package, out_dir, kind) = match &format {
+                BundleFormat::Appimage { package, output } => (package, output, "appimage"),
+                BundleFormat::Oci { package, output } => (package, output, "oci"),
+                BundleFormat::Sysext { package, output, .. } => (package, output, "sysext"),
+            };
+            let extraction = extraction_dir()?;
+            let temp_dir = extraction.path().to_string_lossy().into_owned();
+            extract_pkg_zst(package, &temp_dir)?;
+            let source = convert::Source::open(extraction.path())?;
+            let path = match &format {
+                BundleFormat::Appimage { .. } => bundle::to_appimage(&source, out_dir)?,
+                BundleFormat::Oci { .. } => bundle::to_oci(&source, out_dir)?,
+                BundleFormat::Sysext {
+                    key,
+                    certificate,
+                    install,
+                    ..
+                } => {
+                    let image = bundle::to_sysext(&source, out_dir, key.as_deref(), certificate.as_deref())?;
+                    if *install {
+                        bundle::install_sysext(&image)?
+                    } else {
+                        image
+                    }
+                }
+            };
+            if output::is_json() {
+                output::emit(&ConvertReport {
+                    package: get_package_name(package),
+                    format: kind.to_string(),
+                    path: path.display().to_string(),
+                })?;
             } else {
-                println!("Removed file: {}", file_path.display());
+                success!("Created {}", path.display());
             }
-        } else {
-            println!("File {} does not exist, skipping", file_path.display());
+            Ok(())
         }
-    }
-    fs::remove_file(&log_path)
-        .context(format!("Failed to remove log file {}", log_path.display()))?;
-    println!("Removed log file: {}", log_path.display());
-    clean_empty_dirs(Path::new(&dest_bin_dir))?;
-    clean_empty_dirs(Path::new(&dest_desktop_dir))?;
-    clean_empty_dirs(Path::new(&dest_icon_dir))?;
-    if prefix == "/usr/local" && Path::new(&dest_desktop_dir).exists() {
-        if let Ok(output) = std::process::Command::new("update-desktop-database")
-            .arg(&dest_desktop_dir)
-            .output()
-        {
-            if !output.status.success() {
-                println!(
-                    "Warning: failed to update desktop database: {}",
-                    String::from_utf8_lossy(&output.stderr)
+        Commands::Delta { old, new, output } => {
+            let path = delta::create(&old, &new, &output)?;
+            if output::is_json() {
+                output::emit(&ConvertReport {
+                    package: get_package_name(&new.to_string_lossy()),
+                    format: "delta".to_string(),
+                    path: path.display().to_string(),
+                })?;
+            } else {
+                success!("Created {}", path.display());
+            }
+            Ok(())
+        }
+        Commands::Daemon { socket, group } => daemon::run(&socket, &group),
+        Commands::Serve { listen, token_file } => serve::run(&listen, token_file.as_ref()),
+        Commands::Adopt { package, prefix } => {
+            let prefix = resolve_prefix(prefix)?;
+            let path = fetch_package(&package)?;
+            let extraction = extraction_dir()?;
+            let temp_dir = extraction.path().to_string_lossy().into_owned();
+            extract_pkg_zst(&path, &temp_dir)?;
+            let report = adopt_files(&temp_dir, &prefix, &path)?;
+            record_source(&package, &report.package, &prefix)?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else {
+                let adopted = report.files.iter().filter(|file| file.action == Action::Adopted).count();
+                success!("Adopted {} files of {} in {}", adopted, report.package, report.prefix);
+            }
+            Ok(())
+        }
+        Commands::Export => output::emit(&manifest::build()?),
+        Commands::Verify { package, prefix, all } => {
+            let packages = select_packages(package, prefix)?;
+            let report = verify::verify(&packages)?;
+            let failed = report.packages.iter().filter(|package| !package.is_ok()).count();
+            let total = report.packages.len();
+            if output::is_json() {
+                output::emit(&report)?;
+                if failed > 0 {
+                    return Err(InstallerError::VerifyFailed { failed, total }.into());
+                }
+                return Ok(());
+            }
+            let unchecked: Vec<&str> = report
+                .packages
+                .iter()
+                .filter(|package| !package.checksums)
+                .map(|package| package.name.as_str())
+                .collect();
+            if all && !unchecked.is_empty() {
+                // One line per run, so a timer's mail or journal is not flooded.
+                warn!(
+                    "No checksums recorded for {}, only missing files are detected; reinstall to record them",
+                    unchecked.join(", ")
                 );
+            }
+            if all && failed > 0 {
+                println!("Files changed since installation:");
+            }
+            for package in &report.packages {
+                if !package.checksums && !all {
+                    warn!(
+                        "No checksums recorded for {} ({}), only missing files are detected; reinstall it to record them",
+                        package.name, package.prefix
+                    );
+                }
+                if package.is_ok() {
+                    if !all {
+                        info!("{} ({}): {} files OK", package.name, package.prefix, package.checked);
+                    }
+                    continue;
+                }
+                println!("{} ({}):", package.name, package.prefix);
+                for path in &package.missing {
+                    println!("  missing: {}", path);
+                }
+                for path in &package.modified {
+                    println!("  modified: {}", path);
+                }
+            }
+            if failed > 0 {
+                return Err(InstallerError::VerifyFailed { failed, total }.into());
+            }
+            if !all {
+                success!("Verified {} packages", report.packages.len());
+            }
+            Ok(())
+        }
+        Commands::Approve { package, prefix } => {
+            let package = select_packages(Some(package), prefix)?.remove(0);
+            let report = quarantine::approve(&package)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            success!("Approved {} files of {}", report.files.len(), report.package);
+            Ok(())
+        }
+        Commands::Move { package, dir, prefix } => {
+            let package = select_packages(Some(package), prefix)?.remove(0);
+            let report = link::move_payload(&package, &dir)?;
+            if output::is_json() {
+                return output::emit(&report);
+            }
+            success!("Moved {} to {} and updated {} symlinks", report.package, report.to, report.links.len());
+            Ok(())
+        }
+        Commands::Repair { package, prefix } => {
+            let package = select_packages(Some(package), prefix)?.remove(0);
+            if requires_root(&package.prefix) && !is_root() {
+                return Err(InstallerError::NeedsRoot {
+                    prefix: package.prefix,
+                    action: "repair files in",
+                }
+                .into());
+            }
+            let result = repair::repair(&package);
+            // A package with nothing to repair was not changed.
+            if !result.as_ref().is_ok_and(|report| report.files.is_empty()) {
+                let version = read_installed_pkginfo(&package.name, &package.prefix).map(|pkginfo| pkginfo.version);
+                journal::transaction("repair", &package.name, version.as_deref(), &package.prefix, &result);
+            }
+            let report = result?;
+            let failed = report.files.iter().filter(|file| file.action != Action::Restored).count();
+            if output::is_json() {
+                output::emit(&report)?;
+            }
+            if failed > 0 {
+                return Err(InstallerError::RepairFailed { package: package.name, failed }.into());
+            }
+            if report.files.is_empty() {
+                success!("Nothing to repair in {} ({})", package.name, package.prefix);
             } else {
-                println!("Desktop database updated");
+                success!("Restored {} files of {}", report.files.len(), package.name);
             }
+            Ok(())
         }
-    }
-    Ok(())
-}
-
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    match cli.command {
-        Commands::Install { package, prefix } => {
-            let temp_dir = TempDir::new()?.path().to_string_lossy().into_owned();
-            extract_pkg_zst(&package, &temp_dir)?;
-            install_files(&temp_dir, &prefix, &package)?;
-            println!("Installation completed!");
+        Commands::Clean { keep, dry_run } => {
+            let keep = keep.or(config::get().cache_keep).unwrap_or(1);
+            let report = clean::clean(keep, dry_run)?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else if dry_run {
+                success!("Would reclaim {}", format_size(report.reclaimed));
+            } else {
+                success!("Reclaimed {}", format_size(report.reclaimed));
+            }
             Ok(())
         }
-        Commands::Uninstall { package, prefix } => {
-            uninstall_files(&package, &prefix)?;
-            println!("Uninstallation completed!");
+        Commands::Audit { package, prefix, database } => {
+            let packages = select_packages(package, prefix)?;
+            let report = audit::audit(&packages, database.as_deref())?;
+            let count = report.vulnerabilities.len();
+            if output::is_json() {
+                output::emit(&report)?;
+                if count > 0 {
+                    return Err(InstallerError::Vulnerable { count }.into());
+                }
+                return Ok(());
+            }
+            for vulnerability in &report.vulnerabilities {
+                let fixed = match &vulnerability.fixed {
+                    Some(version) => format!("fixed in {}", version),
+                    None => "no fix released".to_string(),
+                };
+                println!(
+                    "{} {} ({}): {} {} {}, {}",
+                    vulnerability.package,
+                    vulnerability.version,
+                    vulnerability.prefix,
+                    vulnerability.advisory,
+                    vulnerability.severity,
+                    vulnerability.kind,
+                    fixed
+                );
+                if !vulnerability.issues.is_empty() {
+                    println!("    {}", vulnerability.issues.join(" "));
+                }
+            }
+            if count > 0 {
+                return Err(InstallerError::Vulnerable { count }.into());
+            }
+            success!("No known vulnerabilities in {} packages", report.checked);
             Ok(())
         }
-        Commands::Reinstall { package, prefix } => {
-            uninstall_files(&package, &prefix)?;
-            let temp_dir = TempDir::new()?.path().to_string_lossy().into_owned();
-            extract_pkg_zst(&package, &temp_dir)?;
-            install_files(&temp_dir, &prefix, &package)?;
-            println!("Reinstallation completed!");
+        Commands::RebuildCheck { package, rebuilderd } => {
+            let report = rebuild::rebuild_check(&package, rebuilderd.as_deref())?;
+            let reason = match (report.status.as_deref(), report.matches) {
+                (None, _) => Some("is not tracked by the rebuilder".to_string()),
+                (_, Some(false)) => Some(format!(
+                    "is not the official build: its SHA-256 is {}, the official one is {}",
+                    report.sha256,
+                    report.official_sha256.as_deref().unwrap_or_default()
+                )),
+                (_, None) => Some("cannot be compared with the official build".to_string()),
+                (Some("GOOD"), Some(true)) => None,
+                (Some("BAD"), Some(true)) => {
+                    Some("is the official build, but rebuilding it from source gave different files".to_string())
+                }
+                (Some(_), Some(true)) => {
+                    Some("is the official build, but it has not been rebuilt from source yet".to_string())
+                }
+            };
+            if output::is_json() {
+                output::emit(&report)?;
+            }
+            if let Some(reason) = reason {
+                return Err(InstallerError::NotReproduced {
+                    package: report.package,
+                    version: report.version,
+                    arch: report.arch,
+                    reason,
+                }
+                .into());
+            }
+            success!(
+                "{} {} ({}) is the official build and was reproduced from source",
+                report.package,
+                report.version,
+                report.arch
+            );
+            Ok(())
+        }
+        Commands::Check { package, prefix } => {
+            let packages = select_packages(package, prefix)?;
+            let report = CheckReport {
+                packages: packages.iter().map(check::check).collect::<Result<_>>()?,
+            };
+            let failed = report.packages.iter().filter(|package| !package.is_ok()).count();
+            let total = report.packages.len();
+            if output::is_json() {
+                output::emit(&report)?;
+                if failed > 0 {
+                    return Err(InstallerError::CheckFailed { failed, total }.into());
+                }
+                return Ok(());
+            }
+            for package in &report.packages {
+                if package.is_ok() {
+                    info!("{} ({}): {} files OK", package.name, package.prefix, package.checked);
+                    continue;
+                }
+                println!("{} ({}):", package.name, package.prefix);
+                for path in &package.missing {
+                    println!("  missing: {}", path);
+                }
+                for path in &package.dangling {
+                    println!("  dangling symlink: {}", path);
+                }
+                for problem in &package.permissions {
+                    println!("  {} (mode {}): {}", problem.problem, problem.mode, problem.path);
+                }
+                for dir in &package.empty_dirs {
+                    println!("  empty directory: {}", dir);
+                }
+            }
+            if failed > 0 {
+                return Err(InstallerError::CheckFailed { failed, total }.into());
+            }
+            success!("Checked {} packages", report.packages.len());
+            Ok(())
+        }
+        Commands::Import { manifest, parallel_downloads, options } => {
+            let manifest = manifest::read(&manifest)?;
+            let installed = installed_packages()?;
+            let is_current = |entry: &manifest::ManifestEntry| {
+                installed
+                    .iter()
+                    .find(|package| package.name == entry.name && package.prefix == entry.prefix)
+                    .and_then(|package| read_installed_pkginfo(&package.name, &package.prefix))
+                    .is_some_and(|info| info.version == entry.version)
+            };
+            // Download the first usable source of every missing package at once.
+            let wanted: Vec<String> = manifest
+                .packages
+                .iter()
+                .filter(|entry| !is_current(entry))
+                .filter_map(|entry| {
+                    entry.sources().into_iter().find(|source| is_url(source) || Path::new(source).exists())
+                })
+                .collect();
+            let limit = parallel_downloads.or(config::get().parallel_downloads).unwrap_or(4);
+            prefetch_packages(&wanted, limit)?;
+            let mut report = ImportReport {
+                installed: Vec::new(),
+                skipped: Vec::new(),
+                failed: Vec::new(),
+            };
+            for entry in &manifest.packages {
+                let label = format!("{} {} ({})", entry.name, entry.version, entry.prefix);
+                if is_current(entry) {
+                    info!("{} is already installed, skipping", label);
+                    report.skipped.push(label);
+                    continue;
+                }
+                let mut result = Err(anyhow::anyhow!("no source available for {}", label));
+                for source in entry.sources() {
+                    if !is_url(&source) && !Path::new(&source).exists() {
+                        debug!("{} not found, trying the next source", source);
+                        continue;
+                    }
+                    result = install_package(&source, &entry.prefix, &options);
+                    match &result {
+                        Err(err) if is_url(&source) => warn!("{}: {:#}", source, err),
+                        _ => break,
+                    }
+                }
+                match result {
+                    Ok(install) => report.installed.push(install),
+                    Err(err) => {
+                        output::print_error(&err);
+                        report.failed.push(label);
+                    }
+                }
+            }
+            if output::is_json() {
+                output::emit(&report)?;
+            }
+            if !report.failed.is_empty() {
+                return Err(InstallerError::ImportFailed { packages: report.failed }.into());
+            }
+            success!("Imported {} packages ({} already installed)", report.installed.len(), report.skipped.len());
             Ok(())
         }
-        Commands::List => {
-            list_packages()?;
+        Commands::Apply { file, yes } => {
+            if file == Path::new("-") && !yes {
+                anyhow::bail!("Pass --yes when reading operations from stdin, which cannot answer the prompt");
+            }
+            let report = apply::apply(apply::read(&file)?, yes)?;
+            if output::is_json() {
+                output::emit(&report)?;
+            } else {
+                success!("Applied {} operations", report.operations.len());
+            }
             Ok(())
         }
+        Commands::Sbom { format } => output::emit(&sbom::generate(format)?),
+        Commands::Completions { shell } => print_completions(shell),
+        Commands::ExitCodes => {
+            if output::is_json() {
+                return output::emit(&EXIT_CODES);
+            }
+            for code in EXIT_CODES {
+                println!("{:>3}  {:<18} {}", code.code, code.kind, code.description);
+            }
+            Ok(())
+        }
+        Commands::Place { dir, package, source, prefix, options } => {
+            let options: InstallOptions = serde_json::from_str(&options)?;
+            output::emit(&install_extracted(&dir, &package, &source, &prefix, &options)?)
+        }
+        Commands::Unpack { dir, name } => unpack_pkg_zst(io::stdin().lock(), 0, &name, &dir),
         Commands::Info => {
-            get_system_info()?;
+            let info = get_system_info()?;
+            if output::is_json() {
+                output::emit(&info)?;
+            } else {
+                print_system_info(&info);
+            }
             Ok(())
         }
     }
 }
+
+fn main() {
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
+    let cli = Cli::parse();
+    output::set_json(cli.json);
+    output::set_color(cli.color);
+    output::init_logger(cli.verbose, cli.quiet);
+    if let Some(root) = cli.root {
+        if !root.is_dir() {
+            output::print_error(&anyhow::anyhow!("Root directory {} does not exist", root.display()));
+            std::process::exit(1);
+        }
+        set_root(root);
+    }
+    set_user_mode(cli.user);
+    if let Ok(exe) = std::env::current_exe() {
+        set_helper(exe);
+    }
+    if let Err(err) = config::load(&get_config_path()) {
+        output::print_error(&err);
+        std::process::exit(1);
+    }
+    let explicit_state_dir = cli.state_dir.is_some();
+    if let Some(state_dir) = cli.state_dir.or_else(config::state_dir) {
+        set_state_dir(state_dir);
+    }
+    if let Err(err) = migrate_legacy_state() {
+        warn!("could not move package records to the new state directory: {}", err);
+    }
+    if cli.timings {
+        timings::enable();
+    }
+    if cli.json_progress {
+        events::subscribe(|event| {
+            if let Ok(line) = serde_json::to_string(event) {
+                eprintln!("{}", line);
+            }
+        });
+    }
+    let transaction = transaction_name(&cli.command);
+    let start = Instant::now();
+    let result = run(cli.command);
+    timings::report();
+    if let Some(transaction) = transaction {
+        notify::transaction_finished(&transaction, start.elapsed(), &result);
+    }
+    if let Err(err) = result {
+        if output::is_json() {
+            let _ = output::emit(&output::ErrorReport::from_error(&err));
+        } else if !elevate::offer(&err, explicit_state_dir) && !elevate::offer_user(&err) {
+            output::print_error(&err);
+        }
+        std::process::exit(exit_code(&err));
+    }
+}
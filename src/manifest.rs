@@ -0,0 +1,73 @@
+use crate::records::{get_source_path, installed_packages, read_installed_pkginfo};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// A portable description of everything installed, for `export`/`import`.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub packages: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub arch: String,
+    pub prefix: String,
+    /// Package file or URL the package was installed from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Upstream project page from `.PKGINFO`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    /// The PKGBUILD the package was built from, shared by split packages.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pkgbase: String,
+}
+
+impl ManifestEntry {
+    /// Places to fetch the package from, in order: the recorded source, then
+    /// the Arch Linux Archive, which keeps every released version.
+    pub fn sources(&self) -> Vec<String> {
+        let mut sources: Vec<String> = self.source.iter().cloned().collect();
+        if let Some(first) = self.name.chars().next()
+            && !self.version.is_empty()
+            && !self.arch.is_empty()
+        {
+            sources.push(format!(
+                "https://archive.archlinux.org/packages/{}/{}/{}-{}-{}.pkg.tar.zst",
+                first, self.name, self.name, self.version, self.arch
+            ));
+        }
+        sources
+    }
+}
+
+pub fn build() -> Result<Manifest> {
+    let mut packages = Vec::new();
+    for package in installed_packages()? {
+        let info = read_installed_pkginfo(&package.name, &package.prefix).unwrap_or_default();
+        let source = fs::read_to_string(get_source_path(&package.name, &package.prefix))
+            .ok()
+            .map(|source| source.trim().to_string());
+        packages.push(ManifestEntry {
+            name: package.name,
+            version: info.version,
+            arch: info.arch,
+            prefix: package.prefix,
+            source,
+            url: info.url,
+            pkgbase: info.pkgbase,
+        });
+    }
+    Ok(Manifest { version: 1, packages })
+}
+
+pub fn read(path: &Path) -> Result<Manifest> {
+    let text = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&text).context(format!("Invalid manifest {}", path.display()))
+}
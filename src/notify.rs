@@ -0,0 +1,74 @@
+//! Desktop notifications when a long install or upgrade ends, so that it
+//! can be left running in a terminal in the background. They go through
+//! `notify-send`, or `gdbus` where libnotify is missing, to the session bus
+//! of the user who started the command, also when that was through sudo.
+
+use crate::config;
+use crate::paths::{invoking_user, is_root};
+use log::debug;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const DEFAULT_AFTER: u64 = 30;
+
+/// Whether a graphical session is there to show notifications in.
+fn in_graphical_session() -> bool {
+    ["WAYLAND_DISPLAY", "DISPLAY"].iter().any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()))
+}
+
+/// `program` set up to run as the invoking user with their session bus
+/// when running as root for someone.
+fn as_session_user(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+    if is_root()
+        && let Some(user) = invoking_user()
+    {
+        let runtime_dir = format!("/run/user/{}", user.uid);
+        command
+            .uid(user.uid.as_raw())
+            .gid(user.gid.as_raw())
+            .env("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}/bus", runtime_dir))
+            .env("XDG_RUNTIME_DIR", runtime_dir)
+            .env("HOME", user.dir);
+    }
+    command
+}
+
+fn send(summary: &str, body: &str, failed: bool) -> bool {
+    let icon = if failed { "dialog-error" } else { "system-software-install" };
+    let sent = as_session_user("notify-send")
+        .args(["--app-name=arch-installer", "--icon", icon])
+        .args(["--urgency", if failed { "critical" } else { "normal" }])
+        .args([summary, body])
+        .status();
+    if let Ok(status) = sent {
+        return status.success();
+    }
+    let sent = as_session_user("gdbus")
+        .args(["call", "--session", "--dest", "org.freedesktop.Notifications"])
+        .args(["--object-path", "/org/freedesktop/Notifications"])
+        .args(["--method", "org.freedesktop.Notifications.Notify", "arch-installer", "0", icon, summary, body])
+        .args(["[]", "{}", "-1"])
+        .status();
+    sent.is_ok_and(|status| status.success())
+}
+
+/// Tells the desktop that `transaction`, e.g. `Installing cmatrix`, ended
+/// with `result` after `elapsed`, if it took longer than the `notify_after`
+/// config key allows and notifications are not turned off.
+pub fn transaction_finished(transaction: &str, elapsed: Duration, result: &anyhow::Result<()>) {
+    let config = config::get();
+    let after = Duration::from_secs(config.notify_after.unwrap_or(DEFAULT_AFTER));
+    if config.notifications == Some(false) || elapsed < after || !in_graphical_session() {
+        return;
+    }
+    let (summary, body) = match result {
+        Ok(()) => (format!("{} finished", transaction), format!("Took {} seconds", elapsed.as_secs())),
+        Err(err) => (format!("{} failed", transaction), format!("{:#}", err)),
+    };
+    if !send(&summary, &body, result.is_err()) {
+        debug!("Could not send a desktop notification");
+    }
+}
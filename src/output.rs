@@ -0,0 +1,810 @@
+use crate::archive::{BuildInfo, PkgInfo};
+use crate::error;
+use crate::events::{self, Event};
+use anyhow::Result;
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::io::{self, IsTerminal, Write};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static COLOR: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+static ACTIVE_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+pub fn set_json(enabled: bool) {
+    JSON.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn set_color(choice: ColorChoice) {
+    COLOR.store(choice as u8, Ordering::Relaxed);
+}
+
+/// `auto` colors only when the stream is a terminal and `NO_COLOR` is unset
+/// or empty; an explicit `always`/`never` wins over both.
+fn color_enabled(stream: Stream) -> bool {
+    match COLOR.load(Ordering::Relaxed) {
+        c if c == ColorChoice::Always as u8 => true,
+        c if c == ColorChoice::Never as u8 => false,
+        _ => {
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+            let terminal = match stream {
+                Stream::Stdout => io::stdout().is_terminal(),
+                Stream::Stderr => io::stderr().is_terminal(),
+            };
+            terminal && !no_color
+        }
+    }
+}
+
+/// Wraps `text` in an ANSI SGR sequence when colors are enabled for `stream`.
+pub fn paint(text: &str, sgr: &str, stream: Stream) -> String {
+    if color_enabled(stream) {
+        format!("\x1b[{}m{}\x1b[0m", sgr, text)
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn print_error(err: &anyhow::Error) {
+    eprintln!("{} {}", paint("error:", "1;31", Stream::Stderr), err);
+    for cause in err.chain().skip(1) {
+        eprintln!("  {} {}", paint("caused by:", "31", Stream::Stderr), cause);
+    }
+}
+
+pub fn print_success(message: &str) {
+    if !is_json() {
+        println!("{} {}", paint("success:", "1;32", Stream::Stdout), message);
+    }
+}
+
+/// Prints a `success:`-tagged result line, suppressed in `--json` mode.
+#[macro_export]
+macro_rules! success {
+    ($($arg:tt)*) => {
+        $crate::output::print_success(&format!($($arg)*))
+    };
+}
+
+/// Routes `log` records to the terminal: info goes to stdout (hidden in
+/// `--json` mode), everything else to stderr with a severity tag. Records
+/// are printed around the active progress bar so they don't tear it.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let (tag, sgr) = match record.level() {
+            Level::Error => ("error:", "1;31"),
+            Level::Warn => ("warning:", "1;33"),
+            Level::Info => {
+                if !is_json() {
+                    with_bar_suspended(|| println!("{}", record.args()));
+                }
+                return;
+            }
+            Level::Debug => ("debug:", "2"),
+            Level::Trace => ("trace:", "2"),
+        };
+        with_bar_suspended(|| eprintln!("{} {}", paint(tag, sgr, Stream::Stderr), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// `--quiet` keeps only errors, the default shows progress messages and
+/// warnings, `-v` adds debug details and `-vv` traces every file decision.
+pub fn init_logger(verbose: u8, quiet: bool) {
+    let level = if quiet {
+        LevelFilter::Error
+    } else {
+        match verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// Drops progress messages unless `-v` was given, for commands whose stdout
+/// is meant for other programs.
+pub fn hide_progress_messages() {
+    if log::max_level() == LevelFilter::Info {
+        log::set_max_level(LevelFilter::Warn);
+    }
+}
+
+fn is_quiet() -> bool {
+    log::max_level() < LevelFilter::Warn
+}
+
+/// Whether `--verbose` was given, for commands that show more detail with it.
+pub fn is_verbose() -> bool {
+    log::max_level() >= LevelFilter::Debug
+}
+
+fn with_bar_suspended<F: FnOnce()>(f: F) {
+    match ACTIVE_BAR.lock().unwrap().as_ref() {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+/// Prints text meant for an interactive user. In `--json` mode it goes to
+/// stderr so stdout stays a single JSON document.
+pub fn prompt(text: &str) -> Result<()> {
+    events::emit(Event::Prompt {
+        question: text.to_string(),
+    });
+    if is_json() {
+        eprintln!("{}", text);
+        io::stderr().flush()?;
+    } else {
+        println!("{}", text);
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+/// Progress bars are only drawn for humans watching a terminal; pipes,
+/// `--quiet` and `--json` get the plain per-file lines instead, and event
+/// subscribers draw their own.
+pub fn progress_enabled() -> bool {
+    !is_json() && !is_quiet() && io::stdout().is_terminal() && !events::is_subscribed()
+}
+
+/// Clears the bar and stops routing log output around it.
+pub fn finish_bar(bar: &ProgressBar) {
+    bar.finish_and_clear();
+    ACTIVE_BAR.lock().unwrap().take();
+}
+
+fn new_bar(len: u64, template: &str, message: &str) -> ProgressBar {
+    if !progress_enabled() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(len);
+    bar.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    bar.set_message(message.to_string());
+    *ACTIVE_BAR.lock().unwrap() = Some(bar.clone());
+    bar
+}
+
+pub fn bytes_bar(len: u64, message: &str) -> ProgressBar {
+    new_bar(
+        len,
+        "{msg:12} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+        message,
+    )
+}
+
+/// A bar measured in bytes that also shows a `done/total` file count in its
+/// prefix; advance it with [`advance_files`].
+pub fn files_bar(total_bytes: u64, total_files: usize, message: &str) -> ProgressBar {
+    let bar = new_bar(
+        total_bytes,
+        "{msg:12} [{bar:30}] {prefix} files, {bytes}/{total_bytes} (ETA {eta})",
+        message,
+    );
+    bar.set_prefix(format!("0/{}", total_files));
+    bar
+}
+
+pub fn advance_files(bar: &ProgressBar, done: usize, total_files: usize, bytes: u64) {
+    bar.set_prefix(format!("{}/{}", done, total_files));
+    bar.inc(bytes);
+}
+
+pub fn emit<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct ErrorReport {
+    pub error: String,
+    pub causes: Vec<String>,
+    /// Failure class, see [`crate::error::kind`].
+    pub kind: &'static str,
+    /// The typed error's fields, e.g. the conflicting files.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ErrorReport {
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        ErrorReport {
+            error: err.to_string(),
+            causes: err.chain().skip(1).map(|c| c.to_string()).collect(),
+            kind: error::kind(err),
+            details: error::find(err)
+                .and_then(|error| serde_json::to_value(error).ok())
+                .filter(|details| !details.is_null()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Installed,
+    Skipped,
+    Removed,
+    Missing,
+    Adopted,
+    Restored,
+    /// Left in place by a reinstall because its contents did not change.
+    Unchanged,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    Binary,
+    Desktop,
+    Icon,
+    /// AppStream metainfo.
+    Metainfo,
+    Wrapper,
+    Symlink,
+    Other,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FileAction {
+    pub action: Action,
+    pub kind: FileKind,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl FileAction {
+    pub fn new(action: Action, kind: FileKind, path: &std::path::Path) -> Self {
+        FileAction {
+            action,
+            kind,
+            path: path.display().to_string(),
+            reason: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InstallReport {
+    pub package: String,
+    pub prefix: String,
+    pub depends: Vec<String>,
+    pub optdepends: Vec<String>,
+    pub files: Vec<FileAction>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StepAction {
+    Install,
+    Overwrite,
+    Skip,
+    Remove,
+}
+
+impl std::fmt::Display for StepAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            StepAction::Install => "install",
+            StepAction::Overwrite => "overwrite",
+            StepAction::Skip => "skip",
+            StepAction::Remove => "remove",
+        })
+    }
+}
+
+/// One file a transaction would touch, as `--print` shows it. `src` is the
+/// path inside the package and `dest` where it ends up; skipped package
+/// files have no `dest`, removed files no `src`.
+#[derive(Serialize)]
+pub struct TransactionStep {
+    pub action: StepAction,
+    pub package: String,
+    pub src: Option<String>,
+    pub dest: Option<String>,
+}
+
+impl std::fmt::Display for TransactionStep {
+    /// Tab-separated, with `-` for a missing path.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let src = self.src.as_deref().unwrap_or("-");
+        let dest = self.dest.as_deref().unwrap_or("-");
+        write!(f, "{}\t{}\t{}\t{}", self.action, self.package, src, dest)
+    }
+}
+
+#[derive(Serialize)]
+pub struct UninstallReport {
+    pub package: String,
+    pub prefix: String,
+    pub files: Vec<FileAction>,
+}
+
+#[derive(Serialize)]
+pub struct ReinstallReport {
+    pub uninstall: UninstallReport,
+    pub install: InstallReport,
+}
+
+#[derive(Serialize)]
+pub struct ListedPackage {
+    pub name: String,
+    pub prefix: String,
+    /// `ok`, `broken` when some recorded files are gone, or `missing` when
+    /// all of them are.
+    pub status: String,
+    pub files: usize,
+    pub missing: usize,
+    /// Bytes taken by the installed files, as recorded at install time.
+    pub size: u64,
+    /// Unix time of the installation.
+    pub installed_at: u64,
+    /// Installed packages in the same prefix that depend on this one.
+    pub required_by: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PackageList {
+    pub count: usize,
+    /// Installations with files missing, counted in `count` as well.
+    pub broken: usize,
+    pub size: u64,
+    pub packages: Vec<ListedPackage>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub name: String,
+    pub prefix: String,
+    pub version: String,
+    pub description: String,
+    /// Matching file paths, only filled in by `search --files`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct SearchReport {
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Serialize)]
+pub struct WhichReport {
+    pub command: String,
+    pub path: String,
+    /// The arch-installer package the command belongs to, if any.
+    pub package: Option<String>,
+    pub version: Option<String>,
+    pub prefix: Option<String>,
+    /// Otherwise the native package, such as `pacman package coreutils`.
+    pub native_owner: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PackageSize {
+    pub name: String,
+    pub prefix: String,
+    pub version: String,
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(Serialize)]
+pub struct PrefixStats {
+    pub prefix: String,
+    pub packages: usize,
+    pub files: usize,
+    pub size: u64,
+}
+
+/// Sizes are in bytes.
+#[derive(Serialize)]
+pub struct StatsReport {
+    pub packages: usize,
+    pub files: usize,
+    pub size: u64,
+    pub largest: Vec<PackageSize>,
+    pub prefixes: Vec<PrefixStats>,
+    pub cache_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct PackageCount {
+    pub manager: String,
+    pub count: usize,
+    /// Only known for packages installed by arch-installer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+/// An installation `info` lists under the packages arch-installer manages.
+#[derive(Serialize)]
+pub struct ManagedPackage {
+    pub name: String,
+    pub version: String,
+    pub prefix: String,
+}
+
+/// The machine `info` runs on.
+#[derive(Serialize)]
+pub struct Hardware {
+    pub cpu: String,
+    /// Logical CPUs that are online.
+    pub cores: usize,
+    /// In bytes, with used memory counting what the kernel cannot reclaim.
+    pub memory_total: u64,
+    pub memory_used: u64,
+    pub gpus: Vec<String>,
+    /// Seconds since boot.
+    pub uptime: u64,
+}
+
+/// What `info` prints. The field names are the keys of `info --json`, which
+/// inventory scripts read, so they are kept as they are; new ones are added.
+#[derive(Serialize)]
+pub struct SystemInfo {
+    pub os: String,
+    pub kernel: String,
+    pub shell: String,
+    pub de: String,
+    pub package_manager_counts: Vec<PackageCount>,
+    /// What the arch-installer count in `package_manager_counts` is made of.
+    pub managed_packages: Vec<ManagedPackage>,
+    pub hardware: Hardware,
+}
+
+#[derive(Serialize)]
+pub struct EnvVar {
+    pub name: String,
+    pub prepend: String,
+}
+
+#[derive(Serialize)]
+pub struct ShellEnv {
+    pub prefix: String,
+    pub variables: Vec<EnvVar>,
+}
+
+#[derive(Serialize)]
+pub struct ConvertReport {
+    pub package: String,
+    pub format: String,
+    pub path: String,
+}
+
+#[derive(Serialize)]
+pub struct CreateReport {
+    pub package: String,
+    pub version: String,
+    pub path: String,
+    /// The detached signature, when signed.
+    pub signature: Option<String>,
+}
+
+/// Packages added to or removed from a repository.
+#[derive(Serialize)]
+pub struct RepoReport {
+    pub repo: String,
+    /// Entries as `name-version`.
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct RepoPackage {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    pub description: String,
+    pub filename: String,
+}
+
+#[derive(Serialize)]
+pub struct RepoList {
+    pub repo: String,
+    pub packages: Vec<RepoPackage>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub installed: Vec<InstallReport>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// One operation of an `apply` batch, tagged with what was done.
+#[derive(Serialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum AppliedOperation {
+    Install(InstallReport),
+    Uninstall(UninstallReport),
+    Upgrade(ReinstallReport),
+}
+
+#[derive(Serialize)]
+pub struct ApplyReport {
+    pub operations: Vec<AppliedOperation>,
+}
+
+#[derive(Serialize)]
+pub struct CleanedItem {
+    pub kind: String,
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Serialize)]
+pub struct CleanReport {
+    pub dry_run: bool,
+    pub removed: Vec<CleanedItem>,
+    /// Bytes freed, or that would be freed in a dry run.
+    pub reclaimed: u64,
+}
+
+#[derive(Serialize)]
+pub struct RepairReport {
+    pub package: String,
+    pub prefix: String,
+    pub files: Vec<FileAction>,
+}
+
+#[derive(Serialize)]
+pub struct AdoptReport {
+    pub package: String,
+    pub prefix: String,
+    pub files: Vec<FileAction>,
+}
+
+#[derive(Serialize)]
+pub struct PackageVerification {
+    pub name: String,
+    pub prefix: String,
+    /// Whether checksums were recorded at install time; without them only
+    /// missing files can be found.
+    pub checksums: bool,
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl PackageVerification {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.modified.is_empty()
+    }
+}
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub packages: Vec<PackageVerification>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Modified,
+    Removed,
+}
+
+#[derive(Serialize)]
+pub struct PathChange {
+    pub change: Change,
+    pub path: String,
+}
+
+/// A `.PKGINFO` value that differs between two packages.
+#[derive(Serialize)]
+pub struct FieldDiff {
+    pub key: String,
+    /// Missing when the package does not set the key.
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Dependencies, provisions, conflicts and the like added or dropped.
+#[derive(Serialize)]
+pub struct RelationDiff {
+    pub key: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// A path that differs between two packages. Hashes are of regular files
+/// only, links are symlink targets.
+#[derive(Serialize)]
+pub struct FileDiff {
+    pub change: Change,
+    pub path: String,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    pub old_sha256: Option<String>,
+    pub new_sha256: Option<String>,
+    pub old_mode: Option<String>,
+    pub new_mode: Option<String>,
+    pub old_link: Option<String>,
+    pub new_link: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DiffReport {
+    pub old: String,
+    pub new: String,
+    pub metadata: Vec<FieldDiff>,
+    pub relations: Vec<RelationDiff>,
+    pub files: Vec<FileDiff>,
+    /// Bytes the new package's files take more (or less) than the old one's.
+    pub size_change: i64,
+}
+
+/// What installing a package in a sandbox did, none of which reached the
+/// real file system.
+#[derive(Serialize)]
+pub struct PreviewReport {
+    pub install: InstallReport,
+    /// Files the install and its handlers added, changed or removed.
+    pub changes: Vec<PathChange>,
+}
+
+#[derive(Serialize)]
+pub struct ApproveReport {
+    pub package: String,
+    pub prefix: String,
+    /// Files made executable again.
+    pub files: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct MoveReport {
+    pub package: String,
+    pub prefix: String,
+    /// The package directory before and after the move.
+    pub from: String,
+    pub to: String,
+    /// Symlinks pointed at the new place.
+    pub links: Vec<String>,
+}
+
+/// An advisory group that affects an installed package.
+#[derive(Serialize)]
+pub struct Vulnerability {
+    pub package: String,
+    pub prefix: String,
+    pub version: String,
+    /// The tracker's group ID, e.g. `AVG-2843`.
+    pub advisory: String,
+    pub severity: String,
+    /// What an attacker can do, e.g. `arbitrary code execution`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// CVE IDs.
+    pub issues: Vec<String>,
+    /// First version with the fix, if one has been released.
+    pub fixed: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuditReport {
+    /// Number of installed packages looked up.
+    pub checked: usize,
+    /// Most severe first.
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+/// The metadata of a package file.
+#[derive(Serialize)]
+pub struct InspectReport {
+    pub file: String,
+    pub sha256: String,
+    pub pkginfo: PkgInfo,
+    /// Missing for packages not built by makepkg.
+    pub buildinfo: Option<BuildInfo>,
+    /// Installed packages built from the same pkgbase, as `name (prefix)`.
+    pub siblings: Vec<String>,
+}
+
+/// A package file compared with the official build a rebuilder reproduced.
+#[derive(Serialize)]
+pub struct RebuildReport {
+    pub package: String,
+    pub version: String,
+    pub arch: String,
+    pub sha256: String,
+    /// The rebuilder's verdict, `GOOD`, `BAD` or `UNKWN`; missing when it
+    /// does not track this version.
+    pub status: Option<String>,
+    /// URL of the official build.
+    pub official: Option<String>,
+    pub official_sha256: Option<String>,
+    /// Whether the file is the official build; missing when that could not
+    /// be downloaded.
+    pub matches: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct PermissionProblem {
+    pub path: String,
+    /// Octal, as `chmod` takes it.
+    pub mode: String,
+    pub problem: String,
+}
+
+#[derive(Serialize)]
+pub struct PackageCheck {
+    pub name: String,
+    pub prefix: String,
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub dangling: Vec<String>,
+    pub permissions: Vec<PermissionProblem>,
+    pub empty_dirs: Vec<String>,
+}
+
+impl PackageCheck {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.dangling.is_empty() && self.permissions.is_empty() && self.empty_dirs.is_empty()
+    }
+}
+
+#[derive(Serialize)]
+pub struct CheckReport {
+    pub packages: Vec<PackageCheck>,
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
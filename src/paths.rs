@@ -0,0 +1,418 @@
+use crate::records::{get_log_path, get_pkginfo_path};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use tempfile::TempDir;
+
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        nix::unistd::geteuid().is_root()
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Installs into the system mounted at `root` instead of `/`; only the first
+/// call has an effect.
+pub fn set_root(root: PathBuf) {
+    let _ = ROOT.set(root);
+}
+
+static HELPER: OnceLock<PathBuf> = OnceLock::new();
+
+/// The `arch-installer` binary that steps needing other privileges run in;
+/// the command line tool sets itself. Library users without one get the
+/// "run with sudo" error instead of a polkit prompt.
+pub fn set_helper(path: PathBuf) {
+    let _ = HELPER.set(path);
+}
+
+pub(crate) fn get_helper() -> Option<&'static Path> {
+    HELPER.get().map(PathBuf::as_path)
+}
+
+/// The directory the target system is mounted at, `/` unless `--root` was given.
+pub(crate) fn get_root() -> &'static Path {
+    ROOT.get().map(PathBuf::as_path).unwrap_or(Path::new("/"))
+}
+
+/// Maps an absolute path on the target system to where it is reachable from
+/// the running system.
+pub fn rooted(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    get_root().join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Inverse of [`rooted`]: the path as seen from inside the target system,
+/// which is what manifests record.
+pub(crate) fn unrooted(path: &Path) -> PathBuf {
+    match path.strip_prefix(get_root()) {
+        Ok(relative) => Path::new("/").join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+static USER_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Switches to per-user installs into `~/.local`, as `--user` does.
+pub fn set_user_mode(user: bool) {
+    USER_MODE.store(user, Ordering::Relaxed);
+}
+
+pub(crate) fn is_user_mode() -> bool {
+    USER_MODE.load(Ordering::Relaxed)
+}
+
+/// In `--user` mode every command works on `~/.local`, so any other
+/// `--prefix` is rejected. A prefix resolved already comes back unchanged.
+pub fn resolve_prefix(prefix: String) -> Result<String> {
+    if !is_user_mode() {
+        return Ok(prefix);
+    }
+    let local = get_user_home_dir().join(".local").display().to_string();
+    if prefix != "/usr/local" && prefix != local {
+        anyhow::bail!("--prefix cannot be combined with --user");
+    }
+    Ok(local)
+}
+
+/// `prefix` without trailing `/` and `.` components, the way installs
+/// record it.
+pub fn normalize_prefix(prefix: &str) -> String {
+    Path::new(prefix).components().collect::<PathBuf>().display().to_string()
+}
+
+pub fn requires_root(prefix: &str) -> bool {
+    !is_user_mode() && (prefix.starts_with("/usr") || prefix == "/opt")
+}
+
+/// Whether caches in the prefix's data dir, such as the desktop database and
+/// icon theme caches, are kept up to date.
+pub(crate) fn updates_caches(prefix: &str) -> bool {
+    prefix == "/usr/local" || is_user_mode()
+}
+
+/// Where each file category lands for a given prefix, already mapped into
+/// the target root.
+pub struct DestDirs {
+    pub bin: PathBuf,
+    pub lib: PathBuf,
+    /// Data directory: `<prefix>/share`, or `~/.local/share` for prefixes
+    /// desktop environments do not look in.
+    pub share: PathBuf,
+    pub desktop: PathBuf,
+    pub icon: PathBuf,
+    /// AppStream metainfo, which software centers list applications from.
+    pub metainfo: PathBuf,
+}
+
+impl DestDirs {
+    pub fn for_prefix(prefix: &str) -> Self {
+        let share = if is_user_mode() {
+            get_data_home()
+        } else if prefix == "/usr/local" {
+            PathBuf::from(prefix).join("share")
+        } else {
+            get_user_home_dir().join(".local/share")
+        };
+        DestDirs {
+            bin: rooted(format!("{}/bin", prefix)),
+            lib: rooted(format!("{}/lib", prefix)),
+            share: rooted(&share),
+            desktop: rooted(share.join("applications")),
+            icon: rooted(share.join("icons")),
+            metainfo: rooted(share.join("metainfo")),
+        }
+    }
+}
+
+/// Parent of the per-package directories used by `--link` installs: `dir`
+/// when given by `--link-dir`, otherwise `<prefix>/arch-installer`.
+pub(crate) fn get_stow_dir(prefix: &str, dir: Option<&Path>) -> PathBuf {
+    dir.map_or_else(|| rooted(prefix).join("arch-installer"), rooted)
+}
+
+/// Home of the invoking user: for the user behind `sudo`, `doas` or
+/// `pkexec`, the one the passwd database has for their uid, which may be
+/// anywhere, not only under `/home`.
+pub(crate) fn get_user_home_dir() -> PathBuf {
+    static HOME: OnceLock<PathBuf> = OnceLock::new();
+    HOME.get_or_init(|| {
+        if let Some(user) = invoking_user() {
+            return user.dir;
+        }
+        let delegated = std::env::var("SUDO_UID").ok().or_else(|| std::env::var("DOAS_USER").ok());
+        if let Some(user) = delegated.filter(|user| user != "0") {
+            if let Some(home) = getent_home(&user) {
+                return home;
+            }
+            let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
+            warn!("Could not find the home directory of user {}, using {}", user, home.display());
+            return home;
+        }
+        dirs::home_dir().unwrap_or_else(|| PathBuf::from("/tmp"))
+    })
+    .clone()
+}
+
+/// The home directory `getent passwd` reports for `user` (a name or uid).
+/// It asks every NSS source, such as LDAP, which a static build of this
+/// program cannot do itself.
+fn getent_home(user: &str) -> Option<PathBuf> {
+    let output = std::process::Command::new("getent").args(["passwd", user]).output().ok()?;
+    let line = String::from_utf8_lossy(&output.stdout).lines().next()?.to_string();
+    let home = line.split(':').nth(5).filter(|home| home.starts_with('/'))?;
+    debug!("Home of {} from getent: {}", user, home);
+    Some(PathBuf::from(home))
+}
+
+/// The first `name` among the directories of `PATH`.
+pub fn find_executable(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// The user who authorized a `pkexec` run, which sets `PKEXEC_UID`.
+pub(crate) fn pkexec_user() -> Option<nix::unistd::User> {
+    let uid = std::env::var("PKEXEC_UID").ok()?.parse().ok()?;
+    nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)).ok().flatten()
+}
+
+/// The user who ran sudo, doas or pkexec to get root, if any.
+pub(crate) fn invoking_user() -> Option<nix::unistd::User> {
+    let sudo_user = || {
+        let uid = std::env::var("SUDO_UID").ok()?.parse().ok()?;
+        nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)).ok().flatten()
+    };
+    let doas_user = || nix::unistd::User::from_name(&std::env::var("DOAS_USER").ok()?).ok().flatten();
+    sudo_user().or_else(doas_user).or_else(pkexec_user).filter(|user| !user.uid.is_root())
+}
+
+/// An XDG base directory from `var`, falling back to `fallback` under the
+/// home directory when unset or not absolute, as the spec requires.
+pub(crate) fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match std::env::var_os(var).map(PathBuf::from) {
+        Some(path) if path.is_absolute() => path,
+        _ => get_user_home_dir().join(fallback),
+    }
+}
+
+pub(crate) fn get_data_home() -> PathBuf {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+pub(crate) fn get_state_home() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// System-wide installs by root are shared by every user, so they get
+/// system locations instead of the invoking user's XDG directories.
+pub(crate) fn is_system_wide() -> bool {
+    is_root() && !is_user_mode()
+}
+
+pub fn get_config_path() -> PathBuf {
+    if is_system_wide() {
+        PathBuf::from("/etc/arch-installer/config.toml")
+    } else {
+        xdg_dir("XDG_CONFIG_HOME", ".config").join("arch-installer/config.toml")
+    }
+}
+
+/// Scratch space for package extraction.
+pub(crate) fn get_cache_dir() -> PathBuf {
+    if is_system_wide() {
+        PathBuf::from("/var/cache/arch-installer")
+    } else {
+        xdg_dir("XDG_CACHE_HOME", ".cache").join("arch-installer")
+    }
+}
+
+/// Set from `--state-dir` or the config file; used as is, not under `--root`.
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_state_dir(state_dir: PathBuf) {
+    let _ = STATE_DIR.set(state_dir);
+}
+
+/// The state directory set with [`set_state_dir`], if any.
+pub(crate) fn state_dir_override() -> Option<&'static Path> {
+    STATE_DIR.get().map(PathBuf::as_path)
+}
+
+pub(crate) fn default_state_dir() -> PathBuf {
+    if is_user_mode() {
+        rooted(get_state_home().join("arch-installer"))
+    } else if is_root() {
+        rooted("/var/lib/arch-installer")
+    } else {
+        rooted(get_data_home().join("arch-installer"))
+    }
+}
+
+pub fn get_log_dir() -> PathBuf {
+    STATE_DIR.get().cloned().unwrap_or_else(default_state_dir)
+}
+
+/// Moves package records from `~/.local/share/arch-installer`, where every
+/// install used to be tracked, into the default state directory, then sorts
+/// records that predate per-prefix tracking into their prefix directories.
+pub fn migrate_legacy_state() -> Result<()> {
+    let legacy = rooted(get_user_home_dir().join(".local/share/arch-installer"));
+    let state_dir = default_state_dir();
+    if STATE_DIR.get().is_some() || is_user_mode() || legacy == state_dir || !legacy.is_dir() {
+        return split_flat_records();
+    }
+    let mut moved = 0;
+    for entry in fs::read_dir(&legacy)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_record = path.extension().is_some_and(|ext| ext == "log" || ext == "PKGINFO");
+        let dest = state_dir.join(entry.file_name());
+        if !is_record || dest.exists() {
+            continue;
+        }
+        fs::create_dir_all(&state_dir)?;
+        fs::copy(&path, &dest)?;
+        fs::remove_file(&path)?;
+        moved += 1;
+    }
+    if moved > 0 {
+        info!("Moved {} package records from {} to {}", moved, legacy.display(), state_dir.display());
+        let _ = fs::remove_dir(&legacy);
+    }
+    split_flat_records()
+}
+
+/// Flat `<name>.log` records don't say which prefix they belong to; it is
+/// recovered from the first installed binary's path, defaulting to `/usr/local`.
+pub(crate) fn split_flat_records() -> Result<()> {
+    let log_dir = get_log_dir();
+    if !log_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(&log_dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "log") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let content = fs::read_to_string(&path)?;
+        let prefix = content
+            .lines()
+            .find_map(|line| line.split_once("/bin/").map(|(prefix, _)| prefix.to_string()))
+            .filter(|prefix| !prefix.is_empty())
+            .unwrap_or_else(|| "/usr/local".to_string());
+        let dest = get_log_path(&name, &prefix);
+        if dest.exists() {
+            continue;
+        }
+        fs::create_dir_all(dest.parent().unwrap())?;
+        fs::rename(&path, &dest)?;
+        let pkginfo = log_dir.join(format!("{}.PKGINFO", name));
+        if pkginfo.exists() {
+            fs::rename(&pkginfo, get_pkginfo_path(&name, &prefix))?;
+        }
+        debug!("Recorded {} as installed in {}", name, prefix);
+    }
+    Ok(())
+}
+
+/// Where packages are extracted when installing into `prefix`. Being on the
+/// destination file system lets files be moved into place instead of copied.
+pub(crate) fn get_staging_dir(prefix: &str) -> PathBuf {
+    rooted(prefix).join(".arch-installer-staging")
+}
+
+/// A temporary extraction directory in the staging directory of `prefix`,
+/// removed on drop.
+pub(crate) fn staging_dir(prefix: &str) -> Result<TempDir> {
+    let dir = get_staging_dir(prefix);
+    fs::create_dir_all(&dir)?;
+    Ok(tempfile::Builder::new().prefix("tx-").tempdir_in(&dir)?)
+}
+
+pub fn extraction_dir() -> Result<TempDir> {
+    let cache_dir = get_cache_dir();
+    fs::create_dir_all(&cache_dir)
+        .context(format!("Failed to create cache directory {}", cache_dir.display()))?;
+    Ok(TempDir::new_in(&cache_dir)?)
+}
+
+/// Names a prefix's record directory the way `systemd-escape --path` would,
+/// e.g. `/usr/local` becomes `usr-local` and `/opt/my-app` `opt-my\x2dapp`.
+pub(crate) fn escape_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".to_string();
+    }
+    let mut escaped = String::new();
+    for (index, byte) in trimmed.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if index == 0 => escaped.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' => escaped.push(b as char),
+            b => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn unescape_prefix(key: &str) -> String {
+    if key == "-" {
+        return "/".to_string();
+    }
+    let mut bytes = vec![b'/'];
+    let mut rest = key.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\'
+            && tail.first() == Some(&b'x')
+            && let Some(code) = tail.get(1..3)
+            && let Ok(value) = u8::from_str_radix(&String::from_utf8_lossy(code), 16)
+        {
+            bytes.push(value);
+            rest = &tail[3..];
+            continue;
+        }
+        bytes.push(if byte == b'-' { b'/' } else { byte });
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_prefix, resolve_prefix, set_user_mode};
+
+    /// Uninstalling with `--siblings` resolves the prefix once for the
+    /// lookup and again in the batch.
+    #[test]
+    fn user_prefixes_resolve_to_themselves() {
+        set_user_mode(true);
+        let local = resolve_prefix("/usr/local".to_string()).ok();
+        let again = local.clone().map(resolve_prefix);
+        let other = resolve_prefix("/opt/tool".to_string());
+        set_user_mode(false);
+        let local = local.unwrap();
+        assert!(local.ends_with("/.local"), "{}", local);
+        assert_eq!(again.unwrap().unwrap(), local);
+        assert!(other.is_err());
+        assert_eq!(resolve_prefix("/opt/tool".to_string()).unwrap(), "/opt/tool");
+    }
+
+    #[test]
+    fn prefixes_lose_trailing_slashes_and_dots() {
+        assert_eq!(normalize_prefix("/usr/local/"), "/usr/local");
+        assert_eq!(normalize_prefix("/opt/x/."), "/opt/x");
+        assert_eq!(normalize_prefix("/opt/./x//"), "/opt/x");
+        assert_eq!(normalize_prefix("/"), "/");
+    }
+}
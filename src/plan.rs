@@ -0,0 +1,536 @@
+use crate::archive::PkgInfo;
+use crate::elf;
+use crate::handlers::handlers;
+use crate::installer::{InstallOptions, copy_file};
+use crate::output::{Action, FileAction, FileKind};
+use crate::paths::{DestDirs, get_stow_dir, rooted, unrooted};
+use anyhow::{Result, bail};
+use infer::Infer;
+use log::{debug, info, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub(crate) struct PlannedFile {
+    pub(crate) src: PathBuf,
+    pub(crate) dest: PathBuf,
+    pub(crate) kind: FileKind,
+    /// What a wrapper or symlink points at.
+    pub(crate) target: Option<PathBuf>,
+}
+
+/// Points `Exec`, `TryExec` and `Icon` at the locations the package's files
+/// were actually installed to. `installed` maps in-package paths such as
+/// `/usr/bin/foo` to their in-target destinations; bare program names are
+/// only made absolute when `absolutize` is set, i.e. when the bin directory
+/// is unlikely to be on the launcher's `PATH`.
+pub(crate) fn rewrite_desktop_entry(content: &str, installed: &HashMap<PathBuf, PathBuf>, absolutize: bool) -> String {
+    let lookup = |program: &str| -> Option<String> {
+        let source = if program.starts_with('/') {
+            PathBuf::from(program)
+        } else if absolutize && !program.is_empty() && !program.contains('/') {
+            Path::new("/usr/bin").join(program)
+        } else {
+            return None;
+        };
+        installed.get(&source).map(|dest| dest.display().to_string())
+    };
+    let mut rewritten = String::with_capacity(content.len());
+    for line in content.lines() {
+        let new_line = match line.split_once('=') {
+            Some((key, value)) if matches!(key.trim(), "Exec" | "TryExec") => {
+                let value = value.trim_start();
+                let (program, rest, quoted) = match value.strip_prefix('"') {
+                    Some(stripped) => match stripped.find('"') {
+                        Some(end) => (&stripped[..end], &stripped[end + 1..], true),
+                        None => (value, "", false),
+                    },
+                    None => match value.find(' ') {
+                        Some(end) => (&value[..end], &value[end..], false),
+                        None => (value, "", false),
+                    },
+                };
+                lookup(program).map(|dest| {
+                    trace!("rewriting {} {} -> {}", key.trim(), program, dest);
+                    if quoted {
+                        format!("{}=\"{}\"{}", key, dest, rest)
+                    } else {
+                        format!("{}={}{}", key, dest, rest)
+                    }
+                })
+            }
+            Some((key, value)) if key.trim() == "Icon" && value.trim().starts_with('/') => {
+                lookup(value.trim()).map(|dest| {
+                    trace!("rewriting Icon {} -> {}", value.trim(), dest);
+                    format!("{}={}", key, dest)
+                })
+            }
+            _ => None,
+        };
+        rewritten.push_str(new_line.as_deref().unwrap_or(line));
+        rewritten.push('\n');
+    }
+    if !content.ends_with('\n') {
+        rewritten.pop();
+    }
+    rewritten
+}
+
+/// Points absolute paths in AppStream metainfo, such as those of
+/// `<icon type="local">`, at the locations the package's files were installed
+/// to. Component and desktop IDs stay as they are: installed `.desktop` files
+/// keep their names.
+pub(crate) fn rewrite_metainfo(content: &str, installed: &HashMap<PathBuf, PathBuf>) -> String {
+    let mut rewritten = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find(">/") {
+        let (before, after) = rest.split_at(start + 1);
+        rewritten.push_str(before);
+        let (path, tail) = after.split_at(after.find('<').unwrap_or(after.len()));
+        match installed.get(Path::new(path)) {
+            Some(dest) => {
+                trace!("rewriting {} -> {}", path, dest.display());
+                rewritten.push_str(&dest.display().to_string());
+            }
+            None => rewritten.push_str(path),
+        }
+        rest = tail;
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// `$ORIGIN`-relative search path from `dir` to `lib_dir`, both on the same prefix.
+pub(crate) fn origin_runpath(dir: &Path, lib_dir: &Path) -> String {
+    let prefix = lib_dir.parent().unwrap_or(lib_dir);
+    let depth = dir.strip_prefix(prefix).map(|rel| rel.components().count()).unwrap_or(0);
+    let mut runpath = String::from("$ORIGIN");
+    match dir.strip_prefix(lib_dir) {
+        Ok(rel) => (0..rel.components().count()).for_each(|_| runpath.push_str("/..")),
+        Err(_) => {
+            (0..depth).for_each(|_| runpath.push_str("/.."));
+            runpath.push_str("/lib");
+        }
+    }
+    runpath
+}
+
+/// Copies an ELF file with its RUNPATH pointed at the prefix's lib directory
+/// and its interpreter at a bundled loader, if the package ships one. Other
+/// files, and ELF files that can't be patched, are copied unchanged.
+pub(crate) fn install_relocated(
+    file: &PlannedFile,
+    lib_dir: &Path,
+    installed: &HashMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    let data = fs::read(&file.src)?;
+    if !elf::is_elf(&data) {
+        copy_file(&file.src, &file.dest)?;
+        return Ok(());
+    }
+    let runpath = origin_runpath(file.dest.parent().unwrap(), lib_dir);
+    let map_interpreter = |interp: &str| {
+        let name = Path::new(interp).file_name()?;
+        installed
+            .get(Path::new(interp))
+            .or_else(|| installed.get(&Path::new("/usr/lib").join(name)))
+            .map(|dest| dest.display().to_string())
+    };
+    match elf::relocate(&data, &runpath, map_interpreter) {
+        Ok(Some(patched)) => {
+            debug!("Set RUNPATH of {} to {}", file.dest.display(), runpath);
+            fs::write(&file.dest, patched)?;
+        }
+        Ok(None) => {
+            fs::write(&file.dest, data)?;
+        }
+        Err(err) => {
+            warn!("could not relocate {}: {}", file.src.display(), err);
+            fs::write(&file.dest, data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Launcher placed in `<prefix>/bin` for `--wrappers` installs; it points
+/// the loader and XDG lookups at the package's private directories.
+pub(crate) fn wrapper_script(package: &str, target: &Path, lib_dir: &Path, desktop_dir: &Path) -> String {
+    let share = unrooted(desktop_dir.parent().unwrap());
+    format!(
+        "#!/bin/sh\n\
+         # Generated by arch-installer for {}\n\
+         export LD_LIBRARY_PATH=\"{}${{LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}}\"\n\
+         export XDG_DATA_DIRS=\"{}:${{XDG_DATA_DIRS:-/usr/local/share:/usr/share}}\"\n\
+         exec \"{}\" \"$@\"\n",
+        package,
+        unrooted(lib_dir).display(),
+        share.display(),
+        unrooted(target).display()
+    )
+}
+
+/// Icon file extensions icon themes use.
+pub(crate) const ICON_FORMATS: [&str; 4] = ["png", "svg", "svgz", "xpm"];
+
+/// Whether `content` is an image in the format its `extension` says. SVG is
+/// text, so it is recognized by its root element rather than a signature.
+fn is_valid_icon(extension: &str, content: &[u8], infer: &Infer) -> bool {
+    let head = String::from_utf8_lossy(&content[..content.len().min(4096)]);
+    match extension {
+        "png" => infer.get(content).is_some_and(|kind| kind.mime_type() == "image/png"),
+        "svg" => head.contains("<svg"),
+        "svgz" => infer.get(content).is_some_and(|kind| kind.mime_type() == "application/gzip"),
+        "xpm" => head.starts_with("/* XPM */"),
+        _ => false,
+    }
+}
+
+/// Where the kernel loads modules and firmware from, which only the system's
+/// own `/usr/lib` can hold: no prefix is searched.
+const KERNEL_DIRS: [&str; 2] = ["usr/lib/modules", "usr/lib/firmware"];
+
+const KERNEL_REASON: &str = "the kernel only loads modules and firmware from the system's /usr/lib";
+
+/// Where each file of an extracted package goes, plus the files that were
+/// rejected while looking.
+pub(crate) struct Plan {
+    pub(crate) files: Vec<PlannedFile>,
+    pub(crate) skipped: Vec<FileAction>,
+    pub(crate) lib_dir: PathBuf,
+    pub(crate) desktop_dir: PathBuf,
+}
+
+/// Why the program at `path` cannot run on this machine, if it cannot.
+/// With `--ignore-arch` programs for other machines are taken as they are.
+fn skip_reason(path: &Path, options: &InstallOptions) -> Result<Option<String>> {
+    let file_content = fs::read(path)?;
+    Ok(match elf::inspect(&file_content) {
+        Some(info) if !info.runs_on_host() && !options.ignore_arch => Some(format!(
+            "built for {}, not {}",
+            elf::machine_name(info.machine),
+            std::env::consts::ARCH
+        )),
+        Some(info) => {
+            trace!("{}: {}", path.display(), info);
+            None
+        }
+        None if file_content.starts_with(b"#!") => {
+            let shebang = file_content.split(|&byte| byte == b'\n').next().unwrap_or_default();
+            trace!("{}: script for {}", path.display(), String::from_utf8_lossy(&shebang[2..]).trim());
+            None
+        }
+        None => Some("not an ELF binary or script".to_string()),
+    })
+}
+
+pub(crate) fn plan_files(
+    temp_dir: &str,
+    prefix: &str,
+    package_name: &str,
+    pkginfo: &PkgInfo,
+    options: &InstallOptions,
+) -> Result<Plan> {
+    let src_bin_dir = format!("{}/usr/bin", temp_dir);
+    let src_desktop_dir = format!("{}/usr/share/applications", temp_dir);
+    let src_icon_dir = format!("{}/usr/share/icons", temp_dir);
+    let src_lib_dir = format!("{}/usr/lib", temp_dir);
+    let DestDirs {
+        bin: dest_bin_dir,
+        lib: dest_lib_dir,
+        desktop: dest_desktop_dir,
+        icon: dest_icon_dir,
+        metainfo: dest_metainfo_dir,
+        ..
+    } = DestDirs::for_prefix(prefix);
+    let stow_dir = get_stow_dir(prefix, options.link_dir.as_deref()).join(if pkginfo.version.is_empty() {
+        package_name.to_string()
+    } else {
+        format!("{}-{}", package_name, pkginfo.version)
+    });
+    let (bin_dir, lib_dir, desktop_dir, icon_dir) = if options.stow {
+        (
+            stow_dir.join("bin"),
+            stow_dir.join("lib"),
+            stow_dir.join("share/applications"),
+            stow_dir.join("share/icons"),
+        )
+    } else if options.wrappers {
+        let package_dir = dest_lib_dir.join("arch-installer").join(package_name);
+        (
+            package_dir.join("bin"),
+            package_dir.join("lib"),
+            dest_desktop_dir.clone(),
+            dest_icon_dir.clone(),
+        )
+    } else {
+        (
+            dest_bin_dir.clone(),
+            dest_lib_dir.clone(),
+            dest_desktop_dir.clone(),
+            dest_icon_dir.clone(),
+        )
+    };
+    let metainfo_dir = if options.stow {
+        stow_dir.join("share/metainfo")
+    } else {
+        dest_metainfo_dir.clone()
+    };
+    let mut files = Vec::new();
+    let mut planned = Vec::new();
+    let infer = Infer::new();
+    if Path::new(&src_bin_dir).exists() {
+        for entry in WalkDir::new(&src_bin_dir).into_iter().filter_map(|e| e.ok()) {
+            let src_path = entry.path();
+            if src_path.is_file() {
+                if let Some(reason) = skip_reason(src_path, options)? {
+                    warn!("skipping {}: {}", src_path.display(), reason);
+                    files.push(
+                        FileAction::new(Action::Skipped, FileKind::Binary, src_path).with_reason(&reason),
+                    );
+                } else {
+                    let relative_path = src_path.strip_prefix(&src_bin_dir)?;
+                    let dest_path = bin_dir.join(relative_path);
+                    trace!("binary {} -> {}", src_path.display(), dest_path.display());
+                    planned.push(PlannedFile {
+                        src: src_path.to_path_buf(),
+                        dest: dest_path.clone(),
+                        kind: FileKind::Binary,
+                        target: None,
+                    });
+                    if options.wrappers {
+                        let wrapper_path = dest_bin_dir.join(relative_path);
+                        trace!("wrapper {} -> {}", wrapper_path.display(), dest_path.display());
+                        planned.push(PlannedFile {
+                            src: src_path.to_path_buf(),
+                            dest: wrapper_path,
+                            kind: FileKind::Wrapper,
+                            target: Some(dest_path),
+                        });
+                    }
+                }
+            }
+        }
+    } else {
+        info!("No binaries found in /usr/bin, skipping");
+    }
+    // Only left by `usrmerge::merge` on hosts that keep `sbin` apart, and
+    // without --link and --wrappers.
+    let src_sbin_dir = Path::new(temp_dir).join("usr/sbin");
+    for entry in WalkDir::new(&src_sbin_dir).into_iter().filter_map(|e| e.ok()) {
+        let src_path = entry.path();
+        if !src_path.is_file() {
+            continue;
+        }
+        if let Some(reason) = skip_reason(src_path, options)? {
+            warn!("skipping {}: {}", src_path.display(), reason);
+            files.push(FileAction::new(Action::Skipped, FileKind::Binary, src_path).with_reason(&reason));
+            continue;
+        }
+        let dest_path = rooted(prefix).join("sbin").join(src_path.strip_prefix(&src_sbin_dir)?);
+        trace!("binary {} -> {}", src_path.display(), dest_path.display());
+        planned.push(PlannedFile {
+            src: src_path.to_path_buf(),
+            dest: dest_path,
+            kind: FileKind::Binary,
+            target: None,
+        });
+    }
+    let in_kernel_dir = |path: &Path| KERNEL_DIRS.iter().any(|dir| path.starts_with(Path::new(temp_dir).join(dir)));
+    let mut kernel_files = 0;
+    for dir in KERNEL_DIRS {
+        for entry in WalkDir::new(Path::new(temp_dir).join(dir)).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_dir() {
+                files.push(FileAction::new(Action::Skipped, FileKind::Other, entry.path()).with_reason(KERNEL_REASON));
+                kernel_files += 1;
+            }
+        }
+    }
+    if kernel_files > 0 {
+        warn!("Skipping {} kernel module and firmware files: {}", kernel_files, KERNEL_REASON);
+    }
+    if (options.relocate || options.wrappers) && Path::new(&src_lib_dir).exists() {
+        let entries = WalkDir::new(&src_lib_dir).into_iter().filter_entry(|entry| !in_kernel_dir(entry.path()));
+        for entry in entries.filter_map(|e| e.ok()) {
+            let src_path = entry.path();
+            if src_path.is_file() {
+                let dest_path = lib_dir.join(src_path.strip_prefix(&src_lib_dir)?);
+                trace!("library file {} -> {}", src_path.display(), dest_path.display());
+                planned.push(PlannedFile {
+                    src: src_path.to_path_buf(),
+                    dest: dest_path,
+                    kind: FileKind::Other,
+                    target: None,
+                });
+            }
+        }
+    }
+    if Path::new(&src_desktop_dir).exists() {
+        for entry in WalkDir::new(&src_desktop_dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let src_path = entry.path();
+            if src_path.is_file() && src_path.extension().map(|e| e == "desktop").unwrap_or(false) {
+                let relative_path = src_path.strip_prefix(&src_desktop_dir)?;
+                let dest_path = desktop_dir.join(relative_path);
+                trace!(".desktop file {} -> {}", src_path.display(), dest_path.display());
+                planned.push(PlannedFile {
+                    src: src_path.to_path_buf(),
+                    dest: dest_path,
+                    kind: FileKind::Desktop,
+                    target: None,
+                });
+            } else if src_path.is_file() {
+                trace!("ignoring non-.desktop file {}", src_path.display());
+            }
+        }
+    } else {
+        info!("No .desktop files found, skipping");
+    }
+    if Path::new(&src_icon_dir).exists() {
+        // Themes keep their `<theme>/<size>/<context>/` layout, scalable and
+        // symbolic icons included, so lookups by size and context work.
+        for entry in WalkDir::new(&src_icon_dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            let src_path = entry.path();
+            let relative_path = src_path.strip_prefix(&src_icon_dir)?;
+            if !src_path.is_file() {
+                continue;
+            }
+            if entry.depth() == 2 && entry.file_name() == "index.theme" {
+                let dest_path = icon_dir.join(relative_path);
+                trace!("icon theme index {} -> {}", src_path.display(), dest_path.display());
+                planned.push(PlannedFile {
+                    src: src_path.to_path_buf(),
+                    dest: dest_path,
+                    kind: FileKind::Other,
+                    target: None,
+                });
+                continue;
+            }
+            let Some(extension) = src_path.extension().and_then(|e| e.to_str()).filter(|e| ICON_FORMATS.contains(e))
+            else {
+                // Including `icon-theme.cache`, which is rebuilt after install.
+                continue;
+            };
+            let file_content = fs::read(src_path)?;
+            if is_valid_icon(extension, &file_content, &infer) {
+                let dest_path = icon_dir.join(relative_path);
+                trace!("icon {} -> {}", src_path.display(), dest_path.display());
+                planned.push(PlannedFile {
+                    src: src_path.to_path_buf(),
+                    dest: dest_path,
+                    kind: FileKind::Icon,
+                    target: None,
+                });
+            } else {
+                warn!("skipping invalid icon: {}", src_path.display());
+                files.push(
+                    FileAction::new(Action::Skipped, FileKind::Icon, src_path)
+                        .with_reason(&format!("not a valid {} image", extension.to_uppercase())),
+                );
+            }
+        }
+    } else {
+        info!("No icons found in /usr/share/icons, skipping");
+    }
+    // `usr/share/appdata` is where metainfo used to go.
+    for src_metainfo_dir in ["usr/share/metainfo", "usr/share/appdata"] {
+        let src_metainfo_dir = Path::new(temp_dir).join(src_metainfo_dir);
+        if !src_metainfo_dir.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(&src_metainfo_dir).max_depth(1).into_iter().filter_map(|e| e.ok()) {
+            let src_path = entry.path();
+            if src_path.is_file() && src_path.extension().is_some_and(|e| e == "xml") {
+                let dest_path = metainfo_dir.join(entry.file_name());
+                trace!("metainfo {} -> {}", src_path.display(), dest_path.display());
+                planned.push(PlannedFile {
+                    src: src_path.to_path_buf(),
+                    dest: dest_path,
+                    kind: FileKind::Metainfo,
+                    target: None,
+                });
+            }
+        }
+    }
+    // What the steps above did not take is offered to the file-class handlers.
+    let handlers = handlers();
+    let dirs = DestDirs::for_prefix(prefix);
+    let taken: HashSet<PathBuf> = planned
+        .iter()
+        .map(|file| file.src.clone())
+        .chain(files.iter().map(|file| PathBuf::from(&file.path)))
+        .collect();
+    // `.PKGINFO`, `.MTREE` and the like are not part of the payload.
+    let is_metadata = |e: &walkdir::DirEntry| e.depth() == 1 && e.file_name().to_string_lossy().starts_with('.');
+    for entry in WalkDir::new(temp_dir).min_depth(1).into_iter().filter_entry(|e| !is_metadata(e)) {
+        let entry = entry?;
+        if !entry.file_type().is_file() || taken.contains(entry.path()) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(temp_dir)?;
+        if let Some((handler, dest)) =
+            handlers.iter().find_map(|handler| Some((handler, handler.destination(relative, prefix, &dirs)?)))
+        {
+            trace!("{} file {} -> {}", handler.name(), entry.path().display(), dest.display());
+            planned.push(PlannedFile {
+                src: entry.path().to_path_buf(),
+                dest,
+                kind: FileKind::Other,
+                target: None,
+            });
+        }
+    }
+    if options.stow {
+        let links: Vec<PlannedFile> = planned
+            .iter()
+            .filter_map(|file| {
+                let (real_dir, link_dir) = match file.kind {
+                    FileKind::Binary => (&bin_dir, &dest_bin_dir),
+                    FileKind::Desktop => (&desktop_dir, &dest_desktop_dir),
+                    FileKind::Icon => (&icon_dir, &dest_icon_dir),
+                    FileKind::Metainfo => (&metainfo_dir, &dest_metainfo_dir),
+                    _ => return None,
+                };
+                let dest = link_dir.join(file.dest.strip_prefix(real_dir).ok()?);
+                trace!("symlink {} -> {}", dest.display(), file.dest.display());
+                Some(PlannedFile {
+                    src: file.src.clone(),
+                    dest,
+                    kind: FileKind::Symlink,
+                    target: Some(file.dest.clone()),
+                })
+            })
+            .collect();
+        planned.extend(links);
+    }
+    if planned.is_empty() && kernel_files > 0 {
+        bail!(
+            "{} only holds kernel modules or firmware, which cannot be installed into a prefix: {}. \
+             Install it with the system's package manager, or build its modules for the running kernel with DKMS",
+            package_name,
+            KERNEL_REASON
+        );
+    }
+    Ok(Plan {
+        files: planned,
+        skipped: files,
+        lib_dir,
+        desktop_dir: dest_desktop_dir,
+    })
+}
+
+/// Maps in-package paths such as `/usr/bin/foo` to where `planned` puts them.
+pub(crate) fn installed_paths(planned: &[PlannedFile], temp_dir: &str) -> HashMap<PathBuf, PathBuf> {
+    planned
+        .iter()
+        .filter_map(|file| {
+            let relative = file.src.strip_prefix(temp_dir).ok()?;
+            Some((Path::new("/").join(relative), unrooted(&file.dest)))
+        })
+        .collect()
+}
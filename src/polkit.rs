@@ -0,0 +1,73 @@
+//! Authorization through polkit for unprivileged users installing into a
+//! system prefix. The package is fetched and extracted as the user; only
+//! placing its files runs as root, in a `place` child started by `pkexec`,
+//! which shows the desktop's authentication dialog (or asks on the terminal).
+
+use crate::config;
+use crate::error::{InstallerError, from_child_report};
+use crate::installer::InstallOptions;
+use crate::output::InstallReport;
+use crate::paths::{find_executable, get_helper, get_root, is_root, state_dir_override};
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// `pkexec`'s exit status when the user dismissed the dialog or is not
+/// allowed; 127 when no authentication agent could ask.
+const NOT_AUTHORIZED: i32 = 126;
+const NO_AGENT: i32 = 127;
+
+/// Whether an unprivileged user can ask for authorization instead of being
+/// told to rerun with sudo: `pkexec` and the helper binary are there and
+/// `polkit = false` is not set in the config file.
+pub(crate) fn available() -> bool {
+    !is_root()
+        && get_helper().is_some()
+        && config::get().polkit != Some(false)
+        && find_executable("pkexec").is_some()
+}
+
+/// Places the package extracted in `temp_dir` into `prefix` as root. `source`
+/// is recorded as where it came from.
+pub(crate) fn install(
+    temp_dir: &str,
+    package: &str,
+    source: &str,
+    prefix: &str,
+    options: &InstallOptions,
+) -> Result<InstallReport> {
+    let helper = get_helper().context("No helper binary to run as root")?;
+    let mut command = Command::new("pkexec");
+    command.arg(helper).arg("--json");
+    if get_root() != Path::new("/") {
+        command.arg("--root").arg(get_root());
+    }
+    if let Some(state_dir) = state_dir_override() {
+        command.arg("--state-dir").arg(state_dir);
+    }
+    command
+        .arg("place")
+        .arg(temp_dir)
+        .args(["--package", package, "--source", source, "--prefix", prefix])
+        .arg("--options")
+        .arg(serde_json::to_string(options)?);
+    debug!("Asking polkit to place {} into {}", package, prefix);
+    // The child prompts on stderr and reads the answer from our stdin.
+    let output = command
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run pkexec")?;
+    match output.status.code() {
+        Some(0) => serde_json::from_slice(&output.stdout).context("Unreadable report from the privileged installer"),
+        Some(NOT_AUTHORIZED | NO_AGENT) => {
+            let needs_root = InstallerError::NeedsRoot {
+                prefix: prefix.to_string(),
+                action: "install to",
+            };
+            Err(anyhow::Error::from(needs_root).context("Authorization through polkit was not granted"))
+        }
+        _ => Err(from_child_report(&output.stdout, "The privileged installer failed")),
+    }
+}
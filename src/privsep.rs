@@ -0,0 +1,131 @@
+//! Privilege separation for commands run as root. Decompressing and
+//! unpacking a package, the part that parses untrusted data, happens in a
+//! child running as the user behind sudo, doas or pkexec (or `nobody`),
+//! which reads the archive on stdin and writes into a directory lent to it.
+//! Root takes the directory back and checks it before reading anything, so
+//! only placing the files runs privileged.
+
+use crate::archive::unpack_pkg_zst;
+use crate::config;
+use crate::error::{InstallerError, from_child_report};
+use crate::paths::{get_helper, invoking_user, is_root};
+use crate::timings::{self, Phase};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use nix::unistd::{User, setgid, setgroups, setuid};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::unix::fs::{MetadataExt, PermissionsExt, lchown};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use walkdir::WalkDir;
+
+/// Whether packages are unpacked in an unprivileged child: when running as
+/// root with the helper binary known, unless `privilege_separation = false`
+/// is set in the config file.
+pub(crate) fn is_enabled() -> bool {
+    is_root() && get_helper().is_some() && config::get().privilege_separation != Some(false)
+}
+
+/// Who unpacks: the user who ran sudo, doas or pkexec, otherwise `nobody`.
+fn unprivileged_user() -> Option<User> {
+    invoking_user().or_else(|| User::from_name("nobody").ok().flatten()).filter(|user| !user.uid.is_root())
+}
+
+/// Whether `user` may search the directory or run the file at `path`,
+/// going by owner and primary group only.
+fn can_execute(path: &Path, user: &User) -> bool {
+    fs::metadata(path).is_ok_and(|meta| {
+        let mode = meta.mode();
+        if meta.uid() == user.uid.as_raw() {
+            mode & 0o100 != 0
+        } else if meta.gid() == user.gid.as_raw() {
+            mode & 0o010 != 0
+        } else {
+            mode & 0o001 != 0
+        }
+    })
+}
+
+/// Whether `user` can get to `path` through its parents.
+fn reachable(path: &Path, user: &User) -> bool {
+    path.ancestors().skip(1).all(|parent| can_execute(parent, user))
+}
+
+/// Gives the unpacked tree back to root, from the top down so the child's
+/// user can no longer rearrange it, and rejects what a package never holds:
+/// device files, FIFOs and sockets, and hard links to files outside it.
+fn take_back(pkg_path: &str, temp_dir: &str) -> Result<()> {
+    let bad_archive = |reason: String| InstallerError::BadArchive {
+        package: pkg_path.to_string(),
+        reason,
+    };
+    // Hard-linked inodes, with their link count and the links seen here.
+    let mut links: HashMap<(u64, u64), (u64, u64)> = HashMap::new();
+    for entry in WalkDir::new(temp_dir) {
+        let entry = entry?;
+        let path = entry.path();
+        let meta = path.symlink_metadata()?;
+        lchown(path, Some(0), Some(0)).context(format!("Failed to take back {}", path.display()))?;
+        let file_type = meta.file_type();
+        // Changing the owner clears setuid and setgid bits; installing decides about them.
+        if file_type.is_file() && meta.mode() & 0o6000 != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+        }
+        if !file_type.is_dir() && !file_type.is_file() && !file_type.is_symlink() {
+            return Err(bad_archive(format!("it contains the special file {}", path.display())).into());
+        }
+        if file_type.is_file() && meta.nlink() > 1 {
+            links.entry((meta.dev(), meta.ino())).or_insert((meta.nlink(), 0)).1 += 1;
+        }
+    }
+    if links.values().any(|(nlink, seen)| seen < nlink) {
+        return Err(bad_archive("it has hard links to files outside the package".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Unpacks `package` into `temp_dir` as an unprivileged user, or in this
+/// process when there is none or it could not reach the directory or run
+/// the helper.
+pub(crate) fn unpack(package: File, pkg_path: &str, temp_dir: &str) -> Result<()> {
+    let size = package.metadata()?.len();
+    fs::create_dir_all(temp_dir)?;
+    let (Some(helper), Some(user)) = (get_helper(), unprivileged_user()) else {
+        return unpack_pkg_zst(package, size, pkg_path, temp_dir);
+    };
+    if !reachable(Path::new(temp_dir), &user) || !reachable(helper, &user) || !can_execute(helper, &user) {
+        debug!("{} cannot reach {} or run {}, unpacking as root", user.name, temp_dir, helper.display());
+        return unpack_pkg_zst(package, size, pkg_path, temp_dir);
+    }
+    let start = Instant::now();
+    lchown(temp_dir, Some(user.uid.as_raw()), Some(user.gid.as_raw()))?;
+    let (uid, gid) = (user.uid, user.gid);
+    let mut command = Command::new(helper);
+    command
+        .env_clear()
+        .env("PATH", "/usr/bin:/bin")
+        .args(["--json", "unpack", temp_dir, "--name", pkg_path])
+        .stdin(Stdio::from(package))
+        .stderr(Stdio::inherit());
+    // SAFETY: only system calls run between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            setgroups(&[])?;
+            setgid(gid)?;
+            setuid(uid)?;
+            Ok(())
+        });
+    }
+    debug!("Unpacking {} as {}", pkg_path, user.name);
+    let output = command.output().context("Failed to run the unprivileged unpacker")?;
+    if !output.status.success() {
+        return Err(from_child_report(&output.stdout, "Unpacking the package failed"));
+    }
+    take_back(pkg_path, temp_dir)?;
+    timings::record(Phase::Extract, start.elapsed(), size);
+    info!("Extracted package {} to {} as {}", pkg_path, temp_dir, user.name);
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! The `arch_installer` Python module. Results are the same reports
+//! `--json` prints, as dicts and lists; failures raise `InstallerError`.
+
+use crate::archive::{fetch_package, get_package_name, read_pkginfo};
+use crate::config;
+use crate::installer::{InstallOptions, install_package, uninstall_files};
+use crate::output::{self, ErrorReport};
+use crate::paths::{get_config_path, migrate_legacy_state, resolve_prefix, set_root, set_state_dir, set_user_mode};
+use crate::query::{ListOptions, list_packages, search_packages, which_command};
+use crate::records::find_install_prefix;
+use anyhow::Context;
+use log::warn;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Once;
+
+create_exception!(
+    arch_installer,
+    InstallerError,
+    PyException,
+    "An arch-installer operation failed. `causes` lists the errors that led to it, `kind` names the failure class and \
+     `details` holds its fields as a dict, or None."
+);
+
+static INIT: Once = Once::new();
+
+/// Loads the config file on the first call, as the command line tool does
+/// on start, so that `configure` can still override it before.
+fn init() {
+    INIT.call_once(|| {
+        if let Err(err) = config::load(&get_config_path()) {
+            warn!("{:#}", err);
+        }
+        if let Some(state_dir) = config::state_dir() {
+            set_state_dir(state_dir);
+        }
+        if let Err(err) = migrate_legacy_state() {
+            warn!("could not move package records to the new state directory: {}", err);
+        }
+    });
+}
+
+fn to_error(py: Python<'_>, err: anyhow::Error) -> PyErr {
+    let error = InstallerError::new_err(err.to_string());
+    let report = ErrorReport::from_error(&err);
+    let details = match &report.details {
+        Some(details) => py.import("json").and_then(|json| json.call_method1("loads", (details.to_string(),))),
+        None => Ok(py.None().into_bound(py)),
+    };
+    let set = details.and_then(|details| {
+        let value = error.value(py);
+        value.setattr("causes", report.causes)?;
+        value.setattr("kind", report.kind)?;
+        value.setattr("details", details)
+    });
+    if let Err(err) = set {
+        return err;
+    }
+    error
+}
+
+/// Runs `f` without holding the GIL and converts its report to Python
+/// objects by way of JSON.
+fn call<T: Serialize + Send>(py: Python<'_>, f: impl FnOnce() -> anyhow::Result<T> + Send) -> PyResult<Py<PyAny>> {
+    init();
+    let json = py
+        .detach(|| f().and_then(|report| Ok(serde_json::to_string(&report)?)))
+        .map_err(|err| to_error(py, err))?;
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+/// Sets what `--root`, `--user` and `--state-dir` set. Call it before
+/// anything else; each setting takes effect once per process.
+#[pyfunction]
+#[pyo3(signature = (root=None, user=false, state_dir=None))]
+fn configure(root: Option<PathBuf>, user: bool, state_dir: Option<PathBuf>) {
+    if let Some(root) = root {
+        set_root(root);
+    }
+    set_user_mode(user);
+    if let Some(state_dir) = state_dir {
+        set_state_dir(state_dir);
+    }
+}
+
+/// Installs a package file or URL. Keyword arguments are the install
+/// options by their long names, e.g. `relocate=True`.
+#[pyfunction]
+#[pyo3(signature = (package, prefix="/usr/local", **options))]
+fn install(py: Python<'_>, package: &str, prefix: &str, options: Option<&Bound<'_, PyDict>>) -> PyResult<Py<PyAny>> {
+    let options = match options {
+        Some(options) => py.import("json")?.call_method1("dumps", (options,))?.extract::<String>()?,
+        None => "{}".to_string(),
+    };
+    call(py, || {
+        let mut options: InstallOptions = serde_json::from_str(&options).context("Invalid install options")?;
+        options.yes = true;
+        install_package(package, &resolve_prefix(prefix.to_string())?, &options)
+    })
+}
+
+/// Uninstalls a package from `prefix`, or from the only prefix it is in.
+#[pyfunction]
+#[pyo3(signature = (package, prefix=None))]
+fn uninstall(py: Python<'_>, package: &str, prefix: Option<String>) -> PyResult<Py<PyAny>> {
+    call(py, || {
+        let prefix = find_install_prefix(&get_package_name(package), prefix.map(resolve_prefix).transpose()?)?;
+        uninstall_files(package, &prefix, false)
+    })
+}
+
+/// Installed packages, only those in `prefix` if given.
+#[pyfunction(name = "list")]
+#[pyo3(signature = (prefix=None))]
+fn list_installed(py: Python<'_>, prefix: Option<String>) -> PyResult<Py<PyAny>> {
+    call(py, || {
+        list_packages(&ListOptions {
+            prefix: prefix.map(resolve_prefix).transpose()?,
+            ..Default::default()
+        })
+    })
+}
+
+/// Installed packages whose name or description contains `query`, or with
+/// `files=True` those with a matching file path.
+#[pyfunction]
+#[pyo3(signature = (query, files=false, prefix=None))]
+fn search(py: Python<'_>, query: &str, files: bool, prefix: Option<String>) -> PyResult<Py<PyAny>> {
+    call(py, || search_packages(query, files, prefix.map(resolve_prefix).transpose()?.as_deref()))
+}
+
+/// The package providing a command on `PATH`.
+#[pyfunction]
+fn which(py: Python<'_>, command: &str) -> PyResult<Py<PyAny>> {
+    call(py, || which_command(command))
+}
+
+/// Metadata of a package file or URL, without installing it.
+#[pyfunction]
+fn inspect(py: Python<'_>, package: &str) -> PyResult<Py<PyAny>> {
+    call(py, || read_pkginfo(&fetch_package(package)?))
+}
+
+#[pymodule]
+fn arch_installer(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    // Progress bars and `success:` lines are for the command line only.
+    output::set_json(true);
+    m.add("InstallerError", m.py().get_type::<InstallerError>())?;
+    m.add_function(wrap_pyfunction!(configure, m)?)?;
+    m.add_function(wrap_pyfunction!(install, m)?)?;
+    m.add_function(wrap_pyfunction!(uninstall, m)?)?;
+    m.add_function(wrap_pyfunction!(list_installed, m)?)?;
+    m.add_function(wrap_pyfunction!(search, m)?)?;
+    m.add_function(wrap_pyfunction!(which, m)?)?;
+    m.add_function(wrap_pyfunction!(inspect, m)?)?;
+    Ok(())
+}
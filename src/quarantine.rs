@@ -0,0 +1,84 @@
+//! Installs from untrusted sources with `--quarantine`: every file that would
+//! be executable is installed without its executable bits, and the mode it
+//! should have is recorded until `approve` gives it back.
+
+use crate::error::InstallerError;
+use crate::output::ApproveReport;
+use crate::paths::{is_root, requires_root, rooted, unrooted};
+use crate::records::{InstalledPackage, get_options_path, get_quarantine_path, read_install_options};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// The quarantined files of an installation with the modes to restore; empty
+/// when nothing is quarantined.
+pub(crate) fn quarantined_files(package: &str, prefix: &str) -> Vec<(PathBuf, u32)> {
+    let record = fs::read_to_string(get_quarantine_path(package, prefix)).unwrap_or_default();
+    record
+        .lines()
+        .filter_map(|line| {
+            let (mode, path) = line.split_once(' ')?;
+            Some((rooted(path), u32::from_str_radix(mode, 8).ok()?))
+        })
+        .collect()
+}
+
+/// Takes the executable bits off `files`, given with the modes they should
+/// have, and records them for `approve`.
+pub(crate) fn quarantine(package: &str, prefix: &str, files: &[(PathBuf, u32)]) -> Result<()> {
+    let mut record = String::new();
+    for (path, mode) in files {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & !0o111))
+            .context(format!("Failed to quarantine {}", path.display()))?;
+        record.push_str(&format!("{:o} {}\n", mode, unrooted(path).display()));
+    }
+    fs::write(get_quarantine_path(package, prefix), record)?;
+    if !files.is_empty() {
+        info!(
+            "Installed {} executables of {} without their executable bits; run `arch-installer approve {}` when ready",
+            files.len(),
+            package,
+            package
+        );
+    }
+    Ok(())
+}
+
+/// Gives the quarantined files of `package` their modes back and installs it
+/// normally from then on, when it is reinstalled or repaired.
+pub fn approve(package: &InstalledPackage) -> Result<ApproveReport> {
+    if requires_root(&package.prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: package.prefix.clone(),
+            action: "approve files in",
+        }
+        .into());
+    }
+    let mut report = ApproveReport {
+        package: package.name.clone(),
+        prefix: package.prefix.clone(),
+        files: Vec::new(),
+    };
+    for (path, mode) in quarantined_files(&package.name, &package.prefix) {
+        if !path.symlink_metadata().is_ok_and(|meta| meta.is_file()) {
+            warn!("{} is gone, skipping", path.display());
+            continue;
+        }
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+            .context(format!("Failed to restore the mode of {}", path.display()))?;
+        info!("Approved {}", path.display());
+        report.files.push(path.display().to_string());
+    }
+    let _ = fs::remove_file(get_quarantine_path(&package.name, &package.prefix));
+    let mut options = read_install_options(&package.name, &package.prefix);
+    if options.quarantine {
+        options.quarantine = false;
+        let options_path = get_options_path(&package.name, &package.prefix);
+        if options_path.exists() {
+            fs::write(&options_path, serde_json::to_string(&options)?)?;
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,526 @@
+use crate::archive::{fetch_package, read_buildinfo, read_pkginfo, sha256_file};
+use crate::installer::native_owner;
+use crate::output::{
+    EnvVar, Hardware, InspectReport, ListedPackage, ManagedPackage, PackageCount, PackageList, SearchReport,
+    SearchResult, ShellEnv, SystemInfo, WhichReport,
+};
+use crate::paths::{DestDirs, get_user_home_dir, normalize_prefix, rooted, unrooted};
+use crate::records::{InstalledPackage, get_log_path, installed_packages, installed_size, read_installed_pkginfo};
+use crate::split::installed_with_base;
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(Args, Default)]
+pub struct ListOptions {
+    /// Only list packages whose name matches this glob, e.g. 'lib*'
+    #[arg(value_name = "PATTERN")]
+    pub pattern: Option<String>,
+    /// Only list packages installed into this prefix
+    #[arg(long)]
+    pub prefix: Option<String>,
+    #[arg(long, value_enum, default_value = "name")]
+    pub sort: ListSort,
+    /// Only list packages no other installed package depends on
+    #[arg(long, conflicts_with = "deps")]
+    pub explicit: bool,
+    /// Only list packages another installed package depends on
+    #[arg(long)]
+    pub deps: bool,
+    /// Measure the installed files again instead of using the sizes
+    /// recorded at install time
+    #[arg(long)]
+    pub refresh_sizes: bool,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum ListSort {
+    #[default]
+    Name,
+    /// Largest first
+    Size,
+    /// Most recently installed first
+    Date,
+}
+
+/// Installed packages in each prefix that other packages there depend on,
+/// with the names of those packages.
+pub(crate) fn required_by(installed: &[InstalledPackage]) -> HashMap<(String, String), Vec<String>> {
+    let mut required = HashMap::new();
+    for package in installed {
+        let Some(pkginfo) = read_installed_pkginfo(&package.name, &package.prefix) else {
+            continue;
+        };
+        for dep in &pkginfo.depends {
+            let name = dep.split(['<', '>', '=']).next().unwrap_or(dep);
+            required
+                .entry((package.prefix.clone(), name.to_string()))
+                .or_insert_with(Vec::new)
+                .push(package.name.clone());
+        }
+    }
+    required
+}
+
+/// Lists installations, checking only that each recorded file still exists
+/// so that installs whose files were deleted by hand are flagged.
+pub fn list_packages(options: &ListOptions) -> Result<PackageList> {
+    let pattern = options
+        .pattern
+        .as_deref()
+        .map(glob::Pattern::new)
+        .transpose()
+        .context("Invalid name pattern")?;
+    let prefix = options.prefix.as_deref().map(normalize_prefix);
+    let installed = installed_packages()?;
+    let mut required = required_by(&installed);
+    let mut packages = Vec::new();
+    for installed in installed {
+        if prefix.as_ref().is_some_and(|prefix| &installed.prefix != prefix)
+            || pattern.as_ref().is_some_and(|pattern| !pattern.matches(&installed.name))
+        {
+            continue;
+        }
+        let required_by = required
+            .remove(&(installed.prefix.clone(), installed.name.clone()))
+            .unwrap_or_default();
+        if (options.explicit && !required_by.is_empty()) || (options.deps && required_by.is_empty()) {
+            continue;
+        }
+        let log_path = get_log_path(&installed.name, &installed.prefix);
+        let installed_at = fs::metadata(&log_path)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        let log = fs::read_to_string(&log_path)?;
+        let files: Vec<&str> = log.lines().filter(|line| !line.is_empty()).collect();
+        let missing = files.iter().filter(|path| rooted(path).symlink_metadata().is_err()).count();
+        let status = if missing == 0 {
+            "ok"
+        } else if missing == files.len() {
+            "missing"
+        } else {
+            "broken"
+        };
+        packages.push(ListedPackage {
+            size: installed_size(&installed.name, &installed.prefix, options.refresh_sizes)?,
+            installed_at,
+            required_by,
+            name: installed.name,
+            prefix: installed.prefix,
+            status: status.to_string(),
+            files: files.len(),
+            missing,
+        });
+    }
+    match options.sort {
+        ListSort::Name => {}
+        ListSort::Size => packages.sort_by_key(|package| Reverse(package.size)),
+        ListSort::Date => packages.sort_by_key(|package| Reverse(package.installed_at)),
+    }
+    Ok(PackageList {
+        count: packages.len(),
+        size: packages.iter().map(|package| package.size).sum(),
+        broken: packages.iter().filter(|package| package.missing > 0).count(),
+        packages,
+    })
+}
+
+/// Installed packages whose name or description contains `query`, ignoring
+/// case, or with `files`, those with a matching file path.
+pub fn search_packages(query: &str, files: bool, prefix: Option<&str>) -> Result<SearchReport> {
+    let query = query.to_lowercase();
+    let mut results = Vec::new();
+    for installed in installed_packages()? {
+        if prefix.is_some_and(|prefix| installed.prefix != prefix) {
+            continue;
+        }
+        let pkginfo = read_installed_pkginfo(&installed.name, &installed.prefix).unwrap_or_default();
+        let matching_files: Vec<String> = if files {
+            fs::read_to_string(get_log_path(&installed.name, &installed.prefix))?
+                .lines()
+                .filter(|path| path.to_lowercase().contains(&query))
+                .map(str::to_string)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let matches = if files {
+            !matching_files.is_empty()
+        } else {
+            installed.name.to_lowercase().contains(&query) || pkginfo.description.to_lowercase().contains(&query)
+        };
+        if matches {
+            results.push(SearchResult {
+                name: installed.name,
+                prefix: installed.prefix,
+                version: pkginfo.version,
+                description: pkginfo.description,
+                files: matching_files,
+            });
+        }
+    }
+    Ok(SearchReport { results })
+}
+
+/// First executable named `command` on `PATH`, or `command` itself when it
+/// is a path.
+pub(crate) fn find_on_path(command: &str) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let is_executable = |path: &Path| {
+        fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if command.contains('/') {
+        let path = std::path::absolute(command).ok()?;
+        return is_executable(&path).then_some(path);
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(command))
+        .find(|path| is_executable(path))
+}
+
+/// Which installed package, or else which native package, `command` comes
+/// from. The command and the file it links to are both looked up, so
+/// `--stow` links and `--wrappers` targets are found too.
+pub fn which_command(command: &str) -> Result<WhichReport> {
+    let Some(path) = find_on_path(command) else {
+        anyhow::bail!("{} was not found on PATH", command);
+    };
+    let mut candidates = vec![unrooted(&path)];
+    if let Ok(target) = fs::canonicalize(&path)
+        && target != path
+    {
+        candidates.push(unrooted(&target));
+    }
+    let mut report = WhichReport {
+        command: command.to_string(),
+        path: path.display().to_string(),
+        package: None,
+        version: None,
+        prefix: None,
+        native_owner: None,
+    };
+    for installed in installed_packages()? {
+        let log = fs::read_to_string(get_log_path(&installed.name, &installed.prefix))?;
+        if log.lines().any(|line| candidates.iter().any(|candidate| candidate == Path::new(line))) {
+            report.version = read_installed_pkginfo(&installed.name, &installed.prefix).map(|pkginfo| pkginfo.version);
+            report.package = Some(installed.name);
+            report.prefix = Some(installed.prefix);
+            return Ok(report);
+        }
+    }
+    report.native_owner = native_owner(&path);
+    Ok(report)
+}
+
+/// Reads the metadata of `package`, a file or URL, without installing it.
+pub fn inspect_package(package: &str) -> Result<InspectReport> {
+    let path = fetch_package(package)?;
+    let pkginfo = read_pkginfo(&path)?;
+    let siblings = if pkginfo.pkgbase.is_empty() {
+        Vec::new()
+    } else {
+        installed_with_base(&pkginfo.pkgbase)?
+            .into_iter()
+            .filter(|installed| installed.name != pkginfo.name)
+            .map(|installed| format!("{} ({})", installed.name, installed.prefix))
+            .collect()
+    };
+    Ok(InspectReport {
+        sha256: sha256_file(Path::new(&path))?,
+        pkginfo,
+        siblings,
+        buildinfo: read_buildinfo(&path)?,
+        file: path,
+    })
+}
+
+/// The CPU model and the number of online logical CPUs, from `/proc/cpuinfo`.
+fn cpu() -> (String, usize) {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    let fields: Vec<(&str, &str)> = cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect();
+    let value = |key: &str| fields.iter().find(|(name, _)| *name == key).map(|(_, value)| *value);
+    // ARM kernels leave out the model name; some name the board instead.
+    let model = value("model name").or_else(|| value("Hardware")).or_else(|| value("Model")).unwrap_or("Unknown");
+    let cores = match fields.iter().filter(|(name, _)| *name == "processor").count() {
+        0 => std::thread::available_parallelism().map_or(1, usize::from),
+        count => count,
+    };
+    (model.split_whitespace().collect::<Vec<_>>().join(" "), cores)
+}
+
+/// Total and used memory in bytes, from `/proc/meminfo`.
+fn memory() -> (u64, u64) {
+    let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+    let kib = |key: &str| {
+        let line = meminfo.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix(':'));
+        line.and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok()).unwrap_or(0) * 1024
+    };
+    let total = kib("MemTotal");
+    (total, total.saturating_sub(kib("MemAvailable")))
+}
+
+/// Display controllers as `lspci` names them, or without it by vendor and
+/// driver from sysfs.
+fn gpus() -> Vec<String> {
+    if let Ok(output) = std::process::Command::new("lspci").arg("-mm").output()
+        && output.status.success()
+    {
+        // 00:02.0 "VGA compatible controller" "Intel Corporation" "UHD Graphics 620" -r07 ...
+        return String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split('"').collect();
+                let class = fields.get(1)?;
+                if !["VGA", "3D", "Display"].iter().any(|kind| class.starts_with(kind)) {
+                    return None;
+                }
+                Some(format!("{} {}", fields.get(3)?, fields.get(5)?))
+            })
+            .collect();
+    }
+    let Ok(cards) = fs::read_dir("/sys/class/drm") else {
+        return Vec::new();
+    };
+    let mut gpus: Vec<String> = cards
+        .filter_map(|entry| entry.ok())
+        // Connectors such as `card0-HDMI-A-1` are listed next to the cards.
+        .filter(|entry| entry.file_name().to_str().is_some_and(|name| name.starts_with("card") && !name.contains('-')))
+        .filter_map(|card| {
+            let device = card.path().join("device");
+            let driver = fs::read_link(device.join("driver")).ok()?.file_name()?.to_string_lossy().into_owned();
+            let vendor = match fs::read_to_string(device.join("vendor")).unwrap_or_default().trim() {
+                "0x8086" => "Intel",
+                "0x10de" => "NVIDIA",
+                "0x1002" => "AMD",
+                "0x1af4" => "Virtio",
+                "0x15ad" => "VMware",
+                "0x1234" => "QEMU",
+                // SoC GPUs on ARM boards are platform devices without one.
+                _ => return Some(driver),
+            };
+            Some(format!("{} ({})", vendor, driver))
+        })
+        .collect();
+    gpus.sort();
+    gpus
+}
+
+fn uptime() -> u64 {
+    let uptime = fs::read_to_string("/proc/uptime").unwrap_or_default();
+    uptime.split_whitespace().next().and_then(|seconds| seconds.parse::<f64>().ok()).unwrap_or(0.0) as u64
+}
+
+fn hardware() -> Hardware {
+    let (cpu, cores) = cpu();
+    let (memory_total, memory_used) = memory();
+    Hardware {
+        cpu,
+        cores,
+        memory_total,
+        memory_used,
+        gpus: gpus(),
+        uptime: uptime(),
+    }
+}
+
+/// Lines `program` prints, or 0 when it is not installed or fails.
+fn output_lines(program: &str, args: &[&str]) -> usize {
+    match std::process::Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).lines().count(),
+        _ => 0,
+    }
+}
+
+/// Subdirectories of `dir`, or 0 when it does not exist.
+fn subdir_count(dir: &Path) -> usize {
+    fs::read_dir(dir).map_or(0, |entries| entries.filter_map(|e| e.ok()).filter(|entry| entry.path().is_dir()).count())
+}
+
+/// Alpine's database has a `P:` line with the name of each package.
+fn apk_count() -> usize {
+    let installed = fs::read_to_string("/lib/apk/db/installed").unwrap_or_default();
+    installed.lines().filter(|line| line.starts_with("P:")).count()
+}
+
+fn xbps_count() -> usize {
+    output_lines("xbps-query", &["-l"])
+}
+
+/// Gentoo keeps a `<category>/<package>-<version>` directory per package.
+fn portage_count() -> usize {
+    let Ok(categories) = fs::read_dir("/var/db/pkg") else {
+        return 0;
+    };
+    categories.filter_map(|e| e.ok()).map(|category| subdir_count(&category.path())).sum()
+}
+
+/// Store paths the system profile and the user's profile need, as neofetch
+/// and fastfetch count them.
+fn nix_count() -> usize {
+    let profiles = [PathBuf::from("/run/current-system/sw"), get_user_home_dir().join(".nix-profile")];
+    profiles
+        .iter()
+        .filter(|profile| profile.exists())
+        .map(|profile| output_lines("nix-store", &["-q", "--requisites", &profile.to_string_lossy()]))
+        .sum()
+}
+
+/// Applications installed system-wide and per user; runtimes are left out.
+fn flatpak_count() -> usize {
+    let user = subdir_count(&get_user_home_dir().join(".local/share/flatpak/app"));
+    subdir_count(Path::new("/var/lib/flatpak/app")) + user
+}
+
+/// Each snap is mounted under `/snap/<name>`, next to `/snap/bin`.
+fn snap_count() -> usize {
+    subdir_count(Path::new("/snap")).saturating_sub(usize::from(Path::new("/snap/bin").is_dir()))
+}
+
+/// AppImages in the directories they are usually kept in, since nothing
+/// records them.
+fn appimage_count() -> usize {
+    let home = get_user_home_dir();
+    let dirs = [home.join("Applications"), home.join("AppImages"), home.join(".local/bin"), PathBuf::from("/opt")];
+    dirs.iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|extension| extension.eq_ignore_ascii_case("appimage")))
+        .count()
+}
+
+pub fn get_system_info() -> Result<SystemInfo> {
+    let os = if let Ok(os_release) = fs::read_to_string("/etc/os-release") {
+        os_release
+            .lines()
+            .find(|line| line.starts_with("PRETTY_NAME="))
+            .map(|line| line.trim_start_matches("PRETTY_NAME=\"").trim_end_matches("\""))
+            .unwrap_or("Unknown")
+            .to_string()
+    } else {
+        "Unknown".to_string()
+    };
+    let kernel = if let Ok(kernel) = std::process::Command::new("uname").arg("-r").output() {
+        String::from_utf8_lossy(&kernel.stdout).trim().to_string()
+    } else {
+        "Unknown".to_string()
+    };
+    let shell = if let Ok(shell) = std::env::var("SHELL") {
+        let shell_name = Path::new(&shell).file_name().unwrap_or_default().to_string_lossy();
+        if let Ok(version) = std::process::Command::new(&shell).arg("--version").output() {
+            let version = String::from_utf8_lossy(&version.stdout)
+                .lines()
+                .next()
+                .unwrap_or("")
+                .to_string();
+            format!("{} {}", shell_name, version)
+        } else {
+            shell_name.into_owned()
+        }
+    } else {
+        "Unknown".to_string()
+    };
+    let de = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_else(|_| "Unknown".to_string());
+    let mut package_manager_counts = Vec::new();
+    let installed = installed_packages()?;
+    if !installed.is_empty() {
+        let mut size = 0;
+        for package in &installed {
+            size += installed_size(&package.name, &package.prefix, false)?;
+        }
+        package_manager_counts.push(PackageCount {
+            manager: "arch-installer".to_string(),
+            count: installed.len(),
+            size: Some(size),
+        });
+    }
+    let managed_packages = installed
+        .into_iter()
+        .map(|package| ManagedPackage {
+            version: read_installed_pkginfo(&package.name, &package.prefix)
+                .map(|pkginfo| pkginfo.version)
+                .unwrap_or_default(),
+            name: package.name,
+            prefix: package.prefix,
+        })
+        .collect();
+    if Path::new("/usr/bin/pacman").exists()
+        && let Ok(output) = std::process::Command::new("pacman").arg("-Q").output()
+    {
+        let count = String::from_utf8_lossy(&output.stdout).lines().count();
+        if count > 0 {
+            package_manager_counts.push(PackageCount { manager: "pacman".to_string(), count, size: None });
+        }
+    }
+    if Path::new("/usr/bin/dpkg").exists()
+        && let Ok(output) = std::process::Command::new("dpkg").arg("-l").output()
+    {
+        let count = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.starts_with("ii "))
+            .count();
+        if count > 0 {
+            package_manager_counts.push(PackageCount { manager: "dpkg".to_string(), count, size: None });
+        }
+    }
+    if Path::new("/usr/bin/rpm").exists()
+        && let Ok(output) = std::process::Command::new("rpm").arg("-qa").output()
+    {
+        let count = String::from_utf8_lossy(&output.stdout).lines().count();
+        if count > 0 {
+            package_manager_counts.push(PackageCount { manager: "rpm".to_string(), count, size: None });
+        }
+    }
+    let others = [
+        ("apk", apk_count()),
+        ("xbps", xbps_count()),
+        ("portage", portage_count()),
+        ("nix", nix_count()),
+        ("flatpak", flatpak_count()),
+        ("snap", snap_count()),
+        ("appimage", appimage_count()),
+    ];
+    for (manager, count) in others.into_iter().filter(|(_, count)| *count > 0) {
+        package_manager_counts.push(PackageCount { manager: manager.to_string(), count, size: None });
+    }
+    Ok(SystemInfo {
+        os,
+        kernel,
+        shell,
+        de,
+        package_manager_counts,
+        managed_packages,
+        hardware: hardware(),
+    })
+}
+
+pub fn get_shell_env(prefix: &str) -> ShellEnv {
+    let dirs = DestDirs::for_prefix(prefix);
+    let share = unrooted(&dirs.share);
+    let variables = [
+        ("PATH", unrooted(&dirs.bin)),
+        ("LD_LIBRARY_PATH", unrooted(&dirs.lib)),
+        ("XDG_DATA_DIRS", share.clone()),
+        ("MANPATH", share.join("man")),
+    ];
+    ShellEnv {
+        prefix: prefix.to_string(),
+        variables: variables
+            .into_iter()
+            .map(|(name, dir)| EnvVar {
+                name: name.to_string(),
+                prepend: dir.display().to_string(),
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,81 @@
+//! Reproducibility checks against Arch Linux's rebuilders. A rebuilderd
+//! instance (`https://reproducible.archlinux.org` by default) builds every
+//! official package again from source and records whether the result is
+//! bit for bit the same as the published one. A package file that hashes
+//! the same as that published build is then known to match its source.
+
+use crate::archive::{download, fetch_package, read_pkginfo, sha256_file};
+use crate::config;
+use crate::error::InstallerError;
+use crate::output::{self, RebuildReport};
+use anyhow::Result;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::path::Path;
+
+const REBUILDERD_URL: &str = "https://reproducible.archlinux.org";
+
+/// One package release as rebuilderd lists it.
+#[derive(Deserialize)]
+struct Release {
+    name: String,
+    version: String,
+    /// `GOOD` when reproduced, `BAD` when the rebuild differed, `UNKWN`
+    /// while it has not been rebuilt.
+    status: String,
+    architecture: String,
+    /// The published package the rebuild was compared with.
+    artifact_url: Option<String>,
+}
+
+fn releases(base: &str, name: &str) -> Result<Vec<Release>> {
+    let url = format!("{}/api/v0/pkgs/list?name={}", base.trim_end_matches('/'), name);
+    debug!("Looking up {} at {}", name, url);
+    let failed = |reason: String| InstallerError::Download {
+        url: url.clone(),
+        reason,
+    };
+    let response = ureq::get(&url).call().map_err(|err| failed(err.to_string()))?;
+    let text = response.into_body().read_to_string().map_err(|err| failed(err.to_string()))?;
+    Ok(serde_json::from_str(&text).map_err(|err| failed(format!("unexpected response: {}", err)))?)
+}
+
+/// Looks `package`, a file or URL, up on the rebuilder at `rebuilderd` (the
+/// `rebuilderd` config key or Arch's by default) and compares its SHA-256
+/// with the official build the rebuilder checked.
+pub fn rebuild_check(package: &str, rebuilderd: Option<&str>) -> Result<RebuildReport> {
+    let base = rebuilderd.or(config::get().rebuilderd.as_deref()).unwrap_or(REBUILDERD_URL);
+    let path = fetch_package(package)?;
+    let pkginfo = read_pkginfo(&path)?;
+    let mut report = RebuildReport {
+        package: pkginfo.name.clone(),
+        version: pkginfo.version.clone(),
+        arch: pkginfo.arch.clone(),
+        sha256: sha256_file(Path::new(&path))?,
+        status: None,
+        official: None,
+        official_sha256: None,
+        matches: None,
+    };
+    let releases = releases(base, &pkginfo.name)?;
+    let Some(release) = releases.into_iter().find(|release| {
+        release.name == pkginfo.name && release.version == pkginfo.version && release.architecture == pkginfo.arch
+    }) else {
+        return Ok(report);
+    };
+    report.status = Some(release.status);
+    if let Some(url) = release.artifact_url {
+        let bar = output::bytes_bar(0, "Downloading");
+        match download(&url, &bar) {
+            Ok(official) => {
+                let official_sha256 = sha256_file(&official)?;
+                report.matches = Some(official_sha256 == report.sha256);
+                report.official_sha256 = Some(official_sha256);
+            }
+            Err(err) => warn!("Cannot download the official build: {:#}", err),
+        }
+        output::finish_bar(&bar);
+        report.official = Some(url);
+    }
+    Ok(report)
+}
@@ -0,0 +1,251 @@
+use crate::archive::{PkgInfo, get_package_name, is_url, parse_pkginfo_content, sha256_file};
+use crate::error::InstallerError;
+use crate::installer::InstallOptions;
+use crate::paths::{escape_prefix, get_log_dir, resolve_prefix, rooted, unescape_prefix, unrooted};
+use anyhow::{Context, Result};
+use log::info;
+use rayon::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+
+/// How `package` is recorded as a source: URLs as they are, files by their
+/// absolute path.
+pub(crate) fn source_of(package: &str) -> String {
+    if is_url(package) {
+        package.to_string()
+    } else {
+        fs::canonicalize(package).map_or(package.to_string(), |path| path.to_string_lossy().into_owned())
+    }
+}
+
+pub fn record_source(package: &str, name: &str, prefix: &str) -> Result<()> {
+    fs::write(get_source_path(name, prefix), source_of(package))?;
+    Ok(())
+}
+
+/// Package records for one prefix live in their own directory, so the same
+/// package can be installed into several prefixes side by side.
+pub(crate) fn get_prefix_db_dir(prefix: &str) -> PathBuf {
+    get_log_dir().join(escape_prefix(prefix))
+}
+
+pub fn get_log_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.log", package))
+}
+
+pub(crate) fn get_pkginfo_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.PKGINFO", package))
+}
+
+/// Holds the package file or URL an installation came from.
+pub(crate) fn get_source_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.source", package))
+}
+
+/// The install options a package was installed with, as JSON, so it can be
+/// repaired the same way.
+pub(crate) fn get_options_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.options", package))
+}
+
+/// Holds the pacman database entry created by `--register-alpm`.
+pub(crate) fn get_alpm_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.alpm", package))
+}
+
+/// SHA-256 of every installed regular file, in `sha256sum` format.
+pub(crate) fn get_checksums_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.sha256", package))
+}
+
+/// Files installed without their executable bits by `--quarantine`, one
+/// `<octal mode> <path>` line each, until `approve` restores the modes.
+pub(crate) fn get_quarantine_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.quarantine", package))
+}
+
+/// Holds the on-disk size of the installed files, in bytes.
+pub(crate) fn get_size_path(package: &str, prefix: &str) -> PathBuf {
+    get_prefix_db_dir(prefix).join(format!("{}.size", package))
+}
+
+/// Sums the sizes of the regular files among `paths`, which may be gone, and
+/// records the total for `list` and `info`.
+pub(crate) fn record_size(package: &str, prefix: &str, paths: &[PathBuf]) -> Result<u64> {
+    let size: u64 = paths
+        .iter()
+        .filter_map(|path| path.symlink_metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum();
+    fs::write(get_size_path(package, prefix), size.to_string())?;
+    Ok(size)
+}
+
+/// The recorded size of an installation, measured from its log when it was
+/// installed before sizes were recorded or when `refresh` is set.
+pub(crate) fn installed_size(package: &str, prefix: &str, refresh: bool) -> Result<u64> {
+    if !refresh
+        && let Some(size) = fs::read_to_string(get_size_path(package, prefix))
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+    {
+        return Ok(size);
+    }
+    let log = fs::read_to_string(get_log_path(package, prefix))?;
+    let paths: Vec<PathBuf> = log.lines().filter(|line| !line.is_empty()).map(rooted).collect();
+    if refresh {
+        return record_size(package, prefix, &paths).context(format!("Failed to record the size of {}", package));
+    }
+    Ok(paths
+        .iter()
+        .filter_map(|path| path.symlink_metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum())
+}
+
+/// Hashes the installed files at `paths` for `verify`; symlinks are left out.
+pub(crate) fn record_checksums(package: &str, prefix: &str, paths: &[PathBuf]) -> Result<()> {
+    let sums: Vec<(String, PathBuf)> = paths
+        .par_iter()
+        .filter(|path| path.symlink_metadata().is_ok_and(|meta| meta.is_file()))
+        .map(|path| Ok((sha256_file(path)?, path.clone())))
+        .collect::<Result<_>>()?;
+    write_checksums(package, prefix, &sums)
+}
+
+/// Records SHA-256 sums already taken of installed files, with their paths.
+pub(crate) fn write_checksums(package: &str, prefix: &str, sums: &[(String, PathBuf)]) -> Result<()> {
+    let lines: String = sums.iter().map(|(sum, path)| format!("{}  {}\n", sum, unrooted(path).display())).collect();
+    fs::write(get_checksums_path(package, prefix), lines)?;
+    Ok(())
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub prefix: String,
+}
+
+/// The one installation of `package` when given, otherwise every installation
+/// in `prefix`, or everywhere.
+pub fn select_packages(package: Option<String>, prefix: Option<String>) -> Result<Vec<InstalledPackage>> {
+    let prefix = prefix.map(resolve_prefix).transpose()?;
+    match package {
+        Some(package) => {
+            let name = get_package_name(&package);
+            let prefix = find_install_prefix(&name, prefix)?;
+            if !get_log_path(&name, &prefix).exists() {
+                return Err(InstallerError::NotInstalled {
+                    package: name,
+                    prefix: Some(prefix),
+                }
+                .into());
+            }
+            Ok(vec![InstalledPackage { name, prefix }])
+        }
+        None => Ok(installed_packages()?
+            .into_iter()
+            .filter(|package| prefix.as_ref().is_none_or(|prefix| &package.prefix == prefix))
+            .collect()),
+    }
+}
+
+/// Every recorded installation, sorted by name and then prefix.
+pub fn installed_packages() -> Result<Vec<InstalledPackage>> {
+    let log_dir = get_log_dir();
+    if !log_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut packages = Vec::new();
+    for dir in fs::read_dir(&log_dir)?.filter_map(|e| e.ok()) {
+        if !dir.path().is_dir() {
+            continue;
+        }
+        let prefix = unescape_prefix(&dir.file_name().to_string_lossy());
+        for entry in fs::read_dir(dir.path())?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().map(|ext| ext == "log").unwrap_or(false) {
+                packages.push(InstalledPackage {
+                    name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+                    prefix: prefix.clone(),
+                });
+            }
+        }
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+/// The prefix to uninstall `package` from: the given one, or the only prefix
+/// it is installed in.
+pub fn find_install_prefix(package: &str, prefix: Option<String>) -> Result<String> {
+    if let Some(prefix) = prefix {
+        return Ok(prefix);
+    }
+    let prefixes: Vec<String> = installed_packages()?
+        .into_iter()
+        .filter(|installed| installed.name == package)
+        .map(|installed| installed.prefix)
+        .collect();
+    match prefixes.as_slice() {
+        [] => Err(InstallerError::NotInstalled {
+            package: package.to_string(),
+            prefix: None,
+        }
+        .into()),
+        [prefix] => Ok(prefix.clone()),
+        _ => Err(InstallerError::AmbiguousPrefix {
+            package: package.to_string(),
+            prefixes,
+        }
+        .into()),
+    }
+}
+
+/// Metadata of an installed package, kept as a copy of its `.PKGINFO` next
+/// to the installation log.
+pub fn read_installed_pkginfo(package: &str, prefix: &str) -> Option<PkgInfo> {
+    let content = fs::read_to_string(get_pkginfo_path(package, prefix)).ok()?;
+    Some(parse_pkginfo_content(&content))
+}
+
+/// The options `package` was installed with, or the defaults when they were
+/// not recorded.
+pub(crate) fn read_install_options(package: &str, prefix: &str) -> InstallOptions {
+    fs::read_to_string(get_options_path(package, prefix))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Deletes everything recorded about an installation of `package`, and its
+/// pacman database entry, but none of its files.
+pub(crate) fn remove_records(package: &str, prefix: &str) -> Result<()> {
+    let log_path = get_log_path(package, prefix);
+    fs::remove_file(&log_path)
+        .context(format!("Failed to remove log file {}", log_path.display()))?;
+    info!("Removed log file: {}", log_path.display());
+    let pkginfo_path = get_pkginfo_path(package, prefix);
+    if pkginfo_path.exists() {
+        fs::remove_file(&pkginfo_path)
+            .context(format!("Failed to remove {}", pkginfo_path.display()))?;
+    }
+    let _ = fs::remove_file(get_source_path(package, prefix));
+    let _ = fs::remove_file(get_checksums_path(package, prefix));
+    let _ = fs::remove_file(get_size_path(package, prefix));
+    let _ = fs::remove_file(get_options_path(package, prefix));
+    let _ = fs::remove_file(get_quarantine_path(package, prefix));
+    let alpm_path = get_alpm_path(package, prefix);
+    if alpm_path.exists() {
+        let entry = rooted(fs::read_to_string(&alpm_path)?.trim());
+        if entry.exists() {
+            fs::remove_dir_all(&entry).context(format!("Failed to remove {}", entry.display()))?;
+            info!("Removed pacman database entry: {}", entry.display());
+        }
+        fs::remove_file(&alpm_path)?;
+    }
+    let _ = fs::remove_dir(get_prefix_db_dir(prefix));
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Building a package file again from an installation, for when the file
+//! it was installed from is gone. Installed files are mapped back to their
+//! places in the package by the layout the install options gave them, and
+//! `.desktop` files and metainfo get their original paths back.
+
+use crate::archive::{parse_pkginfo_content, pkginfo_values};
+use crate::create::{build_date, payload, tree_size, write_package};
+use crate::output::CreateReport;
+use crate::paths::{DestDirs, get_stow_dir, rooted, unrooted};
+use crate::plan::{rewrite_desktop_entry, rewrite_metainfo};
+use crate::quarantine::quarantined_files;
+use crate::records::{InstalledPackage, get_log_path, get_pkginfo_path, read_install_options};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+
+/// Where the installed file at `path` was in the package, relative to the
+/// package root, given the installed directories and what they hold.
+fn package_path(path: &Path, roots: &[(PathBuf, &str)]) -> Option<PathBuf> {
+    roots.iter().find_map(|(dir, source)| Some(Path::new(source).join(path.strip_prefix(dir).ok()?)))
+}
+
+/// Writes a package file of `package` into `out_dir` from its installed files
+/// and recorded `.PKGINFO`.
+pub fn repack(package: &InstalledPackage, out_dir: &Path) -> Result<CreateReport> {
+    let (name, prefix) = (&package.name, &package.prefix);
+    let pkginfo = fs::read_to_string(get_pkginfo_path(name, prefix))
+        .context(format!("No .PKGINFO recorded for {}; reinstall it to be able to repack it", name))?;
+    let info = parse_pkginfo_content(&pkginfo);
+    let options = read_install_options(name, prefix);
+    let log_path = get_log_path(name, prefix);
+    let log = fs::read_to_string(&log_path).context(format!("Failed to read log file {}", log_path.display()))?;
+    if options.relocate {
+        warn!("Binaries of {} keep the RUNPATH --relocate gave them", name);
+    }
+
+    let dirs = DestDirs::for_prefix(prefix);
+    let mut roots = Vec::new();
+    let stow_dir = options.stow.then(|| {
+        get_stow_dir(prefix, options.link_dir.as_deref()).join(if info.version.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}-{}", name, info.version)
+        })
+    });
+    if let Some(stow_dir) = &stow_dir {
+        roots.push((stow_dir.join("bin"), "usr/bin"));
+        roots.push((stow_dir.join("lib"), "usr/lib"));
+        roots.push((stow_dir.join("share"), "usr/share"));
+    }
+    if options.wrappers {
+        let package_dir = dirs.lib.join("arch-installer").join(name);
+        roots.push((package_dir.join("bin"), "usr/bin"));
+        roots.push((package_dir.join("lib"), "usr/lib"));
+    } else {
+        roots.push((dirs.bin.clone(), "usr/bin"));
+    }
+    roots.push((rooted(prefix).join("sbin"), "usr/sbin"));
+    roots.push((dirs.lib.clone(), "usr/lib"));
+    roots.push((dirs.share.clone(), "usr/share"));
+
+    // Installed paths mapped back to in-package ones such as `/usr/bin/foo`,
+    // which `.desktop` files and metainfo pointed at before installing.
+    let mut original = HashMap::new();
+    let mut files = Vec::new();
+    for line in log.lines() {
+        let path = rooted(line);
+        let Ok(meta) = path.symlink_metadata() else {
+            warn!("{} is missing, leaving it out", path.display());
+            continue;
+        };
+        if options.wrappers && path.starts_with(&dirs.bin) {
+            // A generated launcher for the binary of the same name.
+            original.insert(unrooted(&path), Path::new("/usr/bin").join(path.file_name().unwrap_or_default()));
+            continue;
+        }
+        let link_target = fs::read_link(&path).ok().filter(|_| meta.file_type().is_symlink()).map(rooted);
+        if let (Some(stow_dir), Some(target)) = (&stow_dir, &link_target)
+            && target.starts_with(stow_dir)
+        {
+            // A --link symlink to a file of the package.
+            if let Some(relative) = package_path(target, &roots) {
+                original.insert(unrooted(&path), Path::new("/").join(relative));
+            }
+        } else if let Some(relative) = package_path(&path, &roots) {
+            original.insert(unrooted(&path), Path::new("/").join(&relative));
+            files.push((path, relative));
+        } else {
+            warn!("{} has no place in the package, leaving it out", path.display());
+        }
+    }
+
+    let modes: HashMap<PathBuf, u32> = quarantined_files(name, prefix).into_iter().collect();
+    let tree = tempfile::tempdir()?;
+    for (path, relative) in &files {
+        let dest = tree.path().join(relative);
+        fs::create_dir_all(dest.parent().unwrap())?;
+        let meta = path.symlink_metadata()?;
+        let extension = relative.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+        let is_metainfo = ["usr/share/metainfo", "usr/share/appdata"].iter().any(|dir| relative.starts_with(dir));
+        if meta.file_type().is_symlink() {
+            symlink(fs::read_link(path)?, &dest)?;
+            continue;
+        } else if matches!(extension, "desktop" | "thumbnailer") || is_metainfo {
+            let content = fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+            let content = if is_metainfo {
+                rewrite_metainfo(&content, &original)
+            } else {
+                rewrite_desktop_entry(&content, &original, false)
+            };
+            fs::write(&dest, content)?;
+            fs::set_permissions(&dest, meta.permissions())?;
+        } else {
+            fs::copy(path, &dest).context(format!("Failed to copy {}", path.display()))?;
+        }
+        if let Some(mode) = modes.get(path) {
+            fs::set_permissions(&dest, fs::Permissions::from_mode(*mode))?;
+        }
+        debug!("{} -> {}", path.display(), relative.display());
+    }
+
+    let paths = payload(tree.path())?;
+    let size = tree_size(tree.path(), &paths);
+    let pkginfo: String = pkginfo
+        .lines()
+        .map(|line| if line.starts_with("size = ") { format!("size = {}\n", size) } else { format!("{}\n", line) })
+        .collect();
+    let builddate = match pkginfo_values(&pkginfo, "builddate").first().and_then(|date| date.parse().ok()) {
+        Some(date) => date,
+        None => build_date()?,
+    };
+    let arch = if info.arch.is_empty() { "any" } else { &info.arch };
+    let out = out_dir.join(format!("{}-{}-{}.pkg.tar.zst", name, info.version, arch));
+    write_package(tree.path(), &paths, &pkginfo, builddate, &out)?;
+    Ok(CreateReport {
+        package: name.clone(),
+        version: info.version,
+        path: out.display().to_string(),
+        signature: None,
+    })
+}
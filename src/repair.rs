@@ -0,0 +1,116 @@
+use crate::archive::{download_path, fetch_package, is_url, parse_pkginfo, sha256_file};
+use crate::check;
+use crate::installer::{Placement, extract_for_install};
+use crate::output::{Action, FileAction, FileKind, RepairReport};
+use crate::paths::{extraction_dir, rooted, unrooted};
+use crate::plan::{installed_paths, plan_files};
+use crate::records::{InstalledPackage, get_checksums_path, get_source_path, read_install_options};
+use crate::selinux;
+use crate::verify;
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+/// The package file `package` was installed from, downloading it again if it
+/// came from a URL and is no longer cached.
+pub(crate) fn source_package(package: &InstalledPackage) -> Result<String> {
+    let source = fs::read_to_string(get_source_path(&package.name, &package.prefix))
+        .context(format!("No record of where {} was installed from", package.name))?;
+    if is_url(&source) {
+        let cached = download_path(&source);
+        if cached.is_file() {
+            return Ok(cached.to_string_lossy().into_owned());
+        }
+        return fetch_package(&source);
+    }
+    if !fs::metadata(&source).is_ok_and(|meta| meta.is_file()) {
+        bail!("{} was installed from {}, which no longer exists", package.name, source);
+    }
+    Ok(source)
+}
+
+/// Files `verify` or `check` would complain about, as recorded in the log.
+fn damaged_files(package: &InstalledPackage) -> Result<BTreeSet<String>> {
+    let verification = verify::verify(std::slice::from_ref(package))?;
+    let checked = check::check(package)?;
+    let mut damaged = BTreeSet::new();
+    for found in verification.packages {
+        damaged.extend(found.missing);
+        damaged.extend(found.modified);
+    }
+    damaged.extend(checked.dangling);
+    damaged.extend(checked.permissions.into_iter().map(|problem| problem.path));
+    Ok(damaged)
+}
+
+/// Puts back the damaged files of `package` from the package it was
+/// installed from, laid out with the options it was installed with. Files
+/// whose restored contents differ from the recorded checksums are reported,
+/// since the package file itself may have changed.
+pub fn repair(package: &InstalledPackage) -> Result<RepairReport> {
+    let mut report = RepairReport {
+        package: package.name.clone(),
+        prefix: package.prefix.clone(),
+        files: Vec::new(),
+    };
+    let damaged = damaged_files(package)?;
+    if damaged.is_empty() {
+        return Ok(report);
+    }
+    debug!("{} damaged files in {}", damaged.len(), package.name);
+    let source = source_package(package)?;
+    let options = read_install_options(&package.name, &package.prefix);
+    let checksums: HashMap<String, String> = fs::read_to_string(get_checksums_path(&package.name, &package.prefix))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, path)| (path.to_string(), hash.to_string()))
+        .collect();
+    let extraction = extraction_dir()?;
+    let temp_dir = extraction.path().to_string_lossy().into_owned();
+    extract_for_install(&source, &temp_dir, &options)?;
+    let pkginfo = parse_pkginfo(&temp_dir)?;
+    let plan = plan_files(&temp_dir, &package.prefix, &package.name, &pkginfo, &options)?;
+    let installed_paths = installed_paths(&plan.files, &temp_dir);
+    let placement = Placement {
+        package_name: &package.name,
+        prefix: &package.prefix,
+        options: &options,
+        moves: false,
+        lib_dir: &plan.lib_dir,
+        desktop_dir: &plan.desktop_dir,
+        installed_paths: &installed_paths,
+    };
+    let mut remaining = damaged;
+    for file in &plan.files {
+        let recorded = unrooted(&file.dest).display().to_string();
+        if !remaining.remove(&recorded) {
+            continue;
+        }
+        if file.dest.symlink_metadata().is_ok() {
+            fs::remove_file(&file.dest).context(format!("Failed to remove {}", file.dest.display()))?;
+        }
+        placement.place(file)?;
+        let mut action = FileAction::new(Action::Restored, file.kind, &file.dest);
+        if let Some(expected) = checksums.get(&recorded)
+            && &sha256_file(&file.dest)? != expected
+        {
+            warn!("{} was restored but differs from the installed version", file.dest.display());
+            action = action.with_reason("differs from the installed version");
+        } else {
+            info!("Restored {}", file.dest.display());
+        }
+        report.files.push(action);
+    }
+    let restored: Vec<PathBuf> = report.files.iter().map(|file| PathBuf::from(&file.path)).collect();
+    if let Err(err) = selinux::restore_contexts(&restored) {
+        warn!("{:#}", err);
+    }
+    for path in remaining {
+        warn!("{} is not in {}, cannot restore it", path, source);
+        report.files.push(FileAction::new(Action::Skipped, FileKind::Other, &rooted(&path)).with_reason("not in the package"));
+    }
+    Ok(report)
+}
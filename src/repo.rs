@@ -0,0 +1,409 @@
+//! Local package repositories in the layout `repo-add` keeps: package files
+//! next to `<name>.db.tar.gz`, a gzipped tar with a `<pkgname>-<pkgver>/desc`
+//! entry per package, and `<name>.files.tar.gz`, which adds the file list of
+//! each, with `<name>.db` and `<name>.files` symlinked to them. pacman can
+//! use such a directory as a `file://` or HTTP repository, and `install`
+//! looks bare package names up in the repositories listed under `repos` in
+//! the config.
+
+use crate::archive::{download, is_url, pkginfo_values, read_metadata, sha256_file};
+use crate::config;
+use crate::convert::gzip;
+use crate::elf::{arch_runs_on_host, host_arch};
+use crate::error::InstallerError;
+use crate::output::{self, RepoList, RepoPackage, RepoReport};
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use flate2::read::GzDecoder;
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use tar::{Archive, Builder, EntryType, Header};
+use zstd::stream::read::Decoder;
+
+/// `desc` sections in the order `repo-add` writes them, with the `.PKGINFO`
+/// key each one is filled from; the others are worked out from the file.
+const DESC_FIELDS: [(&str, &str); 23] = [
+    ("FILENAME", ""),
+    ("NAME", "pkgname"),
+    ("BASE", "pkgbase"),
+    ("VERSION", "pkgver"),
+    ("DESC", "pkgdesc"),
+    ("GROUPS", "group"),
+    ("CSIZE", ""),
+    ("ISIZE", "size"),
+    ("MD5SUM", ""),
+    ("SHA256SUM", ""),
+    ("PGPSIG", ""),
+    ("URL", "url"),
+    ("LICENSE", "license"),
+    ("ARCH", "arch"),
+    ("BUILDDATE", "builddate"),
+    ("PACKAGER", "packager"),
+    ("REPLACES", "replaces"),
+    ("CONFLICTS", "conflict"),
+    ("PROVIDES", "provides"),
+    ("DEPENDS", "depend"),
+    ("OPTDEPENDS", "optdepend"),
+    ("MAKEDEPENDS", "makedepend"),
+    ("CHECKDEPENDS", "checkdepend"),
+];
+
+/// A package's entry in the databases.
+struct Entry {
+    desc: String,
+    /// The `files` file, only kept in `<name>.files.tar.gz`.
+    files: Option<String>,
+}
+
+/// The value of the `%SECTION%` of `desc`, one line per value.
+fn section<'a>(desc: &'a str, name: &str) -> Vec<&'a str> {
+    let header = format!("%{}%", name);
+    desc.lines().skip_while(|line| *line != header).skip(1).take_while(|line| !line.is_empty()).collect()
+}
+
+fn first<'a>(desc: &'a str, name: &str) -> &'a str {
+    section(desc, name).first().copied().unwrap_or_default()
+}
+
+/// A repository database, by the directory it is in and its name.
+pub struct Repository {
+    dir: PathBuf,
+    name: String,
+}
+
+impl Repository {
+    /// The repository whose database is at `path`, `<dir>/<name>.db.tar.gz`
+    /// as given to `repo-add`, or its `<name>.db` link.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file_name = path.file_name().context("Repository database without a file name")?.to_string_lossy();
+        let Some(name) = file_name.strip_suffix(".db.tar.gz").or_else(|| file_name.strip_suffix(".db")) else {
+            bail!("{} is not a repository database; name it <repo>.db.tar.gz", path.display());
+        };
+        let dir = std::path::absolute(path)?.parent().context("Repository database without a directory")?.to_path_buf();
+        Ok(Self {
+            dir,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn database(&self, kind: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.tar.gz", self.name, kind))
+    }
+
+    /// The entries of the repository by directory name, with file lists when
+    /// the files database is there; empty for a new repository.
+    fn read(&self) -> Result<BTreeMap<String, Entry>> {
+        let files_db = self.database("files");
+        let path = if files_db.exists() { files_db } else { self.database("db") };
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        read_entries(&fs::read(&path).context(format!("Failed to read {}", path.display()))?)
+    }
+
+    /// Writes both databases and their links from `entries`.
+    fn write(&self, entries: &BTreeMap<String, Entry>) -> Result<()> {
+        for kind in ["db", "files"] {
+            let mut tar = Builder::new(Vec::new());
+            for (dir, entry) in entries {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                tar.append_data(&mut header, format!("{}/", dir), std::io::empty())?;
+                let mut add = |name: &str, content: &str| -> Result<()> {
+                    let mut header = Header::new_gnu();
+                    header.set_mode(0o644);
+                    header.set_size(content.len() as u64);
+                    tar.append_data(&mut header, format!("{}/{}", dir, name), content.as_bytes())?;
+                    Ok(())
+                };
+                add("desc", &entry.desc)?;
+                if kind == "files"
+                    && let Some(files) = &entry.files
+                {
+                    add("files", files)?;
+                }
+            }
+            let path = self.database(kind);
+            // Written aside and renamed over, so pacman never reads half a database.
+            let partial = path.with_extension("gz.part");
+            fs::write(&partial, gzip(&tar.into_inner()?)?).context(format!("Failed to write {}", path.display()))?;
+            fs::rename(&partial, &path)?;
+            let link = self.dir.join(format!("{}.{}", self.name, kind));
+            if link.symlink_metadata().is_ok() {
+                fs::remove_file(&link)?;
+            }
+            symlink(path.file_name().unwrap(), &link).context(format!("Failed to create {}", link.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the entries of a gzipped database.
+fn read_entries(data: &[u8]) -> Result<BTreeMap<String, Entry>> {
+    let mut entries: BTreeMap<String, Entry> = BTreeMap::new();
+    let mut archive = Archive::new(GzDecoder::new(data));
+    for file in archive.entries()? {
+        let mut file = file?;
+        let path = file.path()?.into_owned();
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let entry = entries.entry(dir.to_string_lossy().into_owned()).or_insert(Entry {
+            desc: String::new(),
+            files: None,
+        });
+        match name.to_str() {
+            Some("desc") => entry.desc = content,
+            Some("files") => entry.files = Some(content),
+            _ => {}
+        }
+    }
+    entries.retain(|_, entry| !entry.desc.is_empty());
+    Ok(entries)
+}
+
+/// The `desc` and `files` entries of the package file at `path`.
+fn package_entry(path: &Path) -> Result<(String, Entry)> {
+    let display = path.display().to_string();
+    let Some(pkginfo) = read_metadata(&display, ".PKGINFO")? else {
+        bail!("{} has no .PKGINFO", display);
+    };
+    let name = pkginfo_values(&pkginfo, "pkgname").first().copied().unwrap_or_default().to_string();
+    let version = pkginfo_values(&pkginfo, "pkgver").first().copied().unwrap_or_default().to_string();
+    if name.is_empty() || version.is_empty() {
+        bail!("{} lacks pkgname or pkgver in .PKGINFO", display);
+    }
+    let data = fs::read(path).context(format!("Failed to read {}", display))?;
+    let signature = fs::read(format!("{}.sig", display)).ok();
+    let mut desc = String::new();
+    for (section, key) in DESC_FIELDS {
+        let values = match section {
+            "FILENAME" => vec![path.file_name().unwrap().to_string_lossy().into_owned()],
+            "CSIZE" => vec![data.len().to_string()],
+            "MD5SUM" => vec![format!("{:x}", Md5::digest(&data))],
+            "SHA256SUM" => vec![sha256_file(path)?],
+            "PGPSIG" => signature.iter().map(|sig| base64::engine::general_purpose::STANDARD.encode(sig)).collect(),
+            _ => pkginfo_values(&pkginfo, key).into_iter().map(str::to_string).collect(),
+        };
+        if !values.is_empty() {
+            desc.push_str(&format!("%{}%\n{}\n\n", section, values.join("\n")));
+        }
+    }
+
+    let mut files = String::from("%FILES%\n");
+    let mut archive = Archive::new(Decoder::new(File::open(path)?)?);
+    for file in archive.entries()? {
+        let file = file?;
+        let listed = file.path()?.to_string_lossy().trim_start_matches("./").to_string();
+        if listed.is_empty() || listed.starts_with('.') {
+            continue;
+        }
+        files.push_str(&listed);
+        if file.header().entry_type().is_dir() && !listed.ends_with('/') {
+            files.push('/');
+        }
+        files.push('\n');
+    }
+    Ok((format!("{}-{}", name, version), Entry { desc, files: Some(files) }))
+}
+
+/// The repository entry of `name` among `entries`, by directory.
+fn find_entry(entries: &BTreeMap<String, Entry>, name: &str) -> Option<String> {
+    entries.iter().find(|(_, entry)| first(&entry.desc, "NAME") == name).map(|(dir, _)| dir.clone())
+}
+
+/// Removes the package file of `entry`, and its signature.
+fn remove_package_file(repo: &Repository, entry: &Entry) {
+    let file = first(&entry.desc, "FILENAME");
+    if file.is_empty() {
+        return;
+    }
+    for path in [repo.dir.join(file), repo.dir.join(format!("{}.sig", file))] {
+        if path.exists()
+            && let Err(err) = fs::remove_file(&path)
+        {
+            warn!("Failed to remove {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Adds `packages` to the repository with the database at `db`, copying them
+/// next to it, and replaces any other version of each.
+pub fn add(db: &Path, packages: &[PathBuf]) -> Result<RepoReport> {
+    let repo = Repository::open(db)?;
+    fs::create_dir_all(&repo.dir).context(format!("Failed to create {}", repo.dir.display()))?;
+    let mut entries = repo.read()?;
+    let mut added = Vec::new();
+    for package in packages {
+        let file_name = package.file_name().context(format!("{} is not a file", package.display()))?;
+        let dest = repo.dir.join(file_name);
+        let source = fs::canonicalize(package).context(format!("Cannot find {}", package.display()))?;
+        if source != fs::canonicalize(&dest).unwrap_or_default() {
+            fs::copy(package, &dest).context(format!("Failed to copy {}", package.display()))?;
+            let signature = PathBuf::from(format!("{}.sig", package.display()));
+            if signature.exists() {
+                fs::copy(&signature, format!("{}.sig", dest.display()))?;
+            }
+        }
+        let (dir, entry) = package_entry(&dest)?;
+        let name = first(&entry.desc, "NAME").to_string();
+        if let Some(old) = find_entry(&entries, &name)
+            && let Some(old_entry) = entries.remove(&old)
+        {
+            info!("Replacing {} with {}", old, dir);
+            if first(&old_entry.desc, "FILENAME") != first(&entry.desc, "FILENAME") {
+                remove_package_file(&repo, &old_entry);
+            }
+        }
+        debug!("Adding {} to {}", dir, repo.name);
+        entries.insert(dir.clone(), entry);
+        added.push(dir);
+    }
+    repo.write(&entries)?;
+    Ok(RepoReport {
+        repo: repo.name,
+        added,
+        removed: Vec::new(),
+    })
+}
+
+/// Removes the packages called `names` from the repository with the database
+/// at `db`, with their package files.
+pub fn remove(db: &Path, names: &[String]) -> Result<RepoReport> {
+    let repo = Repository::open(db)?;
+    let mut entries = repo.read()?;
+    let mut removed = Vec::new();
+    for name in names {
+        let Some(entry) = find_entry(&entries, name).and_then(|dir| entries.remove_entry(&dir)) else {
+            bail!("{} is not in repository {}", name, repo.name);
+        };
+        remove_package_file(&repo, &entry.1);
+        removed.push(entry.0);
+    }
+    repo.write(&entries)?;
+    Ok(RepoReport {
+        repo: repo.name,
+        added: Vec::new(),
+        removed,
+    })
+}
+
+/// The packages in the repository with the database at `db`.
+pub fn list(db: &Path) -> Result<RepoList> {
+    let repo = Repository::open(db)?;
+    if !repo.database("db").exists() {
+        bail!("No repository database at {}", repo.database("db").display());
+    }
+    let packages = repo
+        .read()?
+        .values()
+        .map(|entry| RepoPackage {
+            name: first(&entry.desc, "NAME").to_string(),
+            version: first(&entry.desc, "VERSION").to_string(),
+            arch: first(&entry.desc, "ARCH").to_string(),
+            description: first(&entry.desc, "DESC").to_string(),
+            filename: first(&entry.desc, "FILENAME").to_string(),
+        })
+        .collect();
+    Ok(RepoList {
+        repo: repo.name,
+        packages,
+    })
+}
+
+/// A package found in a repository, with what its database says of the file.
+pub struct Found {
+    /// The package file or URL.
+    pub location: String,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl Found {
+    /// Fails with `bad_archive` unless the file at `path` is the one the
+    /// database describes, since nothing else protects it on the way.
+    pub fn verify(&self, path: &Path) -> Result<()> {
+        let mismatch = |reason: String| InstallerError::BadArchive {
+            package: self.location.clone(),
+            reason,
+        };
+        let size = fs::metadata(path).context(format!("Failed to read {}", path.display()))?.len();
+        if let Some(expected) = self.size
+            && size != expected
+        {
+            return Err(mismatch(format!("it is {} bytes, the repository database says {}", size, expected)).into());
+        }
+        if let Some(expected) = &self.sha256 {
+            let actual = sha256_file(path)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                let reason = format!("its SHA-256 is {}, the repository database says {}", actual, expected);
+                return Err(mismatch(reason).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The package file or URL of `name` in the first repository listed in the
+/// config that has it for this machine, or else in the first that has it at
+/// all, which installing then refuses unless `--ignore-arch` is given.
+/// `$arch` in repository URLs stands for this machine's architecture, as in
+/// pacman mirror lists, so one config serves Arch Linux and Arch Linux ARM.
+pub fn find(name: &str) -> Option<Found> {
+    let mut foreign = None;
+    for db in &config::get().repos {
+        let db = &db.replace("$arch", host_arch());
+        let path = if is_url(db) {
+            let bar = output::bytes_bar(0, "Downloading");
+            let path = download(db, &bar);
+            output::finish_bar(&bar);
+            path
+        } else {
+            Ok(PathBuf::from(db))
+        };
+        let entries = path.and_then(|path| read_entries(&fs::read(&path)?));
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Skipping repository {}: {:#}", db, err);
+                continue;
+            }
+        };
+        let Some(entry) = find_entry(&entries, name).and_then(|dir| entries.get(&dir)) else {
+            continue;
+        };
+        let file = first(&entry.desc, "FILENAME");
+        let base = db.rsplit_once('/').map_or(".", |(base, _)| base);
+        let found = Found {
+            location: format!("{}/{}", base, file),
+            sha256: Some(first(&entry.desc, "SHA256SUM").to_string()).filter(|sum| !sum.is_empty()),
+            size: first(&entry.desc, "CSIZE").parse().ok(),
+        };
+        let arch = first(&entry.desc, "ARCH");
+        if !arch_runs_on_host(arch) {
+            debug!("{} in {} is built for {}, looking further", name, db, arch);
+            foreign.get_or_insert(found);
+            continue;
+        }
+        debug!("Found {} in {}", name, db);
+        return Some(found);
+    }
+    foreign
+}
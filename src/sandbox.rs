@@ -0,0 +1,248 @@
+//! Trial installs. The package is placed, and its file handlers run, by a
+//! child in its own mount namespace (and user namespace, when not root)
+//! where every directory the install writes to is covered by an overlay.
+//! Writes land in the overlays' upper layers, which are read back as the
+//! list of changes once the child is gone; the real file system is never
+//! touched.
+
+use crate::archive::{extract_pkg_zst, fetch_package, sha256_file};
+use crate::error::{InstallerError, from_child_report};
+use crate::installer::InstallOptions;
+use crate::output::{Change, InstallReport, PathChange, PreviewReport};
+use crate::paths::{
+    DestDirs, extraction_dir, get_cache_dir, get_helper, get_log_dir, get_root, is_root, requires_root, rooted,
+    state_dir_override,
+};
+use crate::records::source_of;
+use anyhow::{Context, Result, bail};
+use log::debug;
+use nix::fcntl::{OFlag, open};
+use nix::mount::{MsFlags, mount};
+use nix::sched::{CloneFlags, unshare};
+use nix::sys::stat::Mode;
+use nix::unistd::{Gid, Uid, close, write};
+use std::ffi::CString;
+use std::fs;
+use std::os::fd::BorrowedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// A directory covered by an overlay, and where its upper layer is.
+struct Layer {
+    target: PathBuf,
+    upper: PathBuf,
+    work: PathBuf,
+}
+
+/// The existing directories to cover so that everything installing into
+/// `prefix` writes is caught: the nearest existing ancestor of each place
+/// written to, leaving out those inside another one.
+fn targets(prefix: &str, options: &InstallOptions) -> Vec<PathBuf> {
+    let dirs = DestDirs::for_prefix(prefix);
+    let mut wanted = vec![rooted(prefix), dirs.bin, dirs.lib, dirs.share, get_log_dir()];
+    if options.dedup {
+        wanted.push(get_cache_dir());
+    }
+    if options.register_alpm {
+        wanted.push(get_root().join("var/lib/pacman/local"));
+    }
+    let mut targets: Vec<PathBuf> = wanted
+        .into_iter()
+        .filter_map(|dir| dir.ancestors().find(|dir| dir.is_dir()).map(Path::to_path_buf))
+        .collect();
+    targets.sort();
+    targets.dedup();
+    let covered = targets.clone();
+    targets.retain(|dir| !covered.iter().any(|other| other != dir && dir.starts_with(other)));
+    targets
+}
+
+/// A scratch directory for the upper layers outside every target, since an
+/// overlay cannot keep its upper layer inside its lower one.
+fn scratch_dir(targets: &[PathBuf]) -> Result<TempDir> {
+    let candidates = [std::env::temp_dir(), PathBuf::from("/dev/shm"), get_cache_dir()];
+    candidates
+        .iter()
+        .filter(|base| !targets.iter().any(|target| base.starts_with(target)))
+        .find_map(|base| tempfile::Builder::new().prefix("arch-installer-sandbox-").tempdir_in(base).ok())
+        .context("No writable directory for the sandbox outside the directories it covers")
+}
+
+fn c_string(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Writes `text` to a file in `/proc/self`, without allocating, for use
+/// between fork and exec.
+pub(crate) fn write_proc(path: &std::ffi::CStr, text: &[u8]) -> nix::Result<()> {
+    let fd = open(path, OFlag::O_WRONLY, Mode::empty())?;
+    // SAFETY: `fd` was just opened and is closed below.
+    let result = write(unsafe { BorrowedFd::borrow_raw(fd) }, text);
+    close(fd)?;
+    result.map(drop)
+}
+
+/// Runs the `place` helper for the package extracted to `temp_dir` with
+/// `layers` mounted over their targets.
+fn place(
+    layers: &[Layer],
+    temp_dir: &str,
+    package: &str,
+    source: &str,
+    prefix: &str,
+    options: &InstallOptions,
+) -> Result<InstallReport> {
+    let helper = get_helper().context("No helper binary to run in the sandbox")?;
+    let mut mounts = Vec::new();
+    for layer in layers {
+        let dirs = [&layer.target, &layer.upper, &layer.work];
+        if dirs.iter().any(|dir| dir.to_string_lossy().contains([',', ':', '\\'])) {
+            bail!("Cannot cover {} with an overlay: a path has a comma, colon or backslash", layer.target.display());
+        }
+        let mut data = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            layer.target.display(),
+            layer.upper.display(),
+            layer.work.display()
+        );
+        if !is_root() {
+            // Overlay metadata in trusted.* xattrs needs root in the initial namespace.
+            data.push_str(",userxattr");
+        }
+        mounts.push((c_string(&layer.target)?, CString::new(data)?));
+    }
+    let (uid, gid) = (Uid::current(), Gid::current());
+    let uid_map = format!("{} {} 1", uid, uid).into_bytes();
+    let gid_map = format!("{} {} 1", gid, gid).into_bytes();
+    let root = is_root();
+
+    let mut command = Command::new(helper);
+    command.arg("--json");
+    if get_root() != Path::new("/") {
+        command.arg("--root").arg(get_root());
+    }
+    if let Some(state_dir) = state_dir_override() {
+        command.arg("--state-dir").arg(state_dir);
+    }
+    command
+        .arg("place")
+        .arg(temp_dir)
+        .args(["--package", package, "--source", source, "--prefix", prefix])
+        .arg("--options")
+        .arg(serde_json::to_string(&InstallOptions { yes: true, ..options.clone() })?)
+        .stderr(Stdio::inherit());
+    // SAFETY: only system calls run between fork and exec; every string was
+    // built beforehand.
+    unsafe {
+        command.pre_exec(move || {
+            if root {
+                unshare(CloneFlags::CLONE_NEWNS)?;
+            } else {
+                // Keeps our own IDs, so the helper sees the same user and paths.
+                unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+                write_proc(c"/proc/self/setgroups", b"deny")?;
+                write_proc(c"/proc/self/uid_map", &uid_map)?;
+                write_proc(c"/proc/self/gid_map", &gid_map)?;
+            }
+            // Keep the overlays from propagating back to the real mount namespace.
+            let _ = mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>);
+            for (target, data) in &mounts {
+                mount(Some(c"overlay"), target.as_c_str(), Some(c"overlay"), MsFlags::empty(), Some(data.as_c_str()))?;
+            }
+            Ok(())
+        });
+    }
+    debug!("Placing {} into {} in a sandbox", package, prefix);
+    let output = command.output().context("Failed to start the sandbox")?;
+    if !output.status.success() {
+        return Err(from_child_report(&output.stdout, "Installing in the sandbox failed"));
+    }
+    serde_json::from_slice(&output.stdout).context("Unreadable report from the sandbox")
+}
+
+/// What the upper layer of `layer` holds compared with the real directory:
+/// new files, files whose contents or permissions changed, and whiteouts for
+/// removed ones. Directories are only reported when removed.
+fn changes_in(layer: &Layer) -> Result<Vec<PathChange>> {
+    let mut changes = Vec::new();
+    for entry in WalkDir::new(&layer.upper).min_depth(1) {
+        let entry = entry?;
+        let path = layer.target.join(entry.path().strip_prefix(&layer.upper)?);
+        let meta = entry.path().symlink_metadata()?;
+        let real = path.symlink_metadata().ok();
+        let change = if meta.file_type().is_char_device() && meta.rdev() == 0 {
+            Change::Removed
+        } else if meta.is_dir() {
+            continue;
+        } else if let Some(real) = real {
+            let same = real.file_type() == meta.file_type()
+                && real.mode() == meta.mode()
+                && if meta.is_file() {
+                    sha256_file(entry.path())? == sha256_file(&path)?
+                } else {
+                    fs::read_link(entry.path())? == fs::read_link(&path)?
+                };
+            if same {
+                continue;
+            }
+            Change::Modified
+        } else {
+            Change::Added
+        };
+        changes.push(PathChange {
+            change,
+            path: path.display().to_string(),
+        });
+    }
+    Ok(changes)
+}
+
+/// Installs `package` into overlays of the directories it would write to and
+/// reports what the install would change, leaving the real file system as
+/// it was. Downloads still go to the cache.
+pub fn preview_install(package: &str, prefix: &str, options: &InstallOptions) -> Result<PreviewReport> {
+    if requires_root(prefix) && !is_root() {
+        return Err(InstallerError::NeedsRoot {
+            prefix: prefix.to_string(),
+            action: "preview an install to",
+        }
+        .into());
+    }
+    let path = fetch_package(package)?;
+    let extraction = extraction_dir()?;
+    let temp_dir = extraction.path().to_string_lossy().into_owned();
+    extract_pkg_zst(&path, &temp_dir)?;
+    let targets = targets(prefix, options);
+    let scratch = scratch_dir(&targets)?;
+    let mut layers = Vec::new();
+    for (index, target) in targets.into_iter().enumerate() {
+        let layer = Layer {
+            target,
+            upper: scratch.path().join(format!("{}/upper", index)),
+            work: scratch.path().join(format!("{}/work", index)),
+        };
+        fs::create_dir_all(&layer.upper)?;
+        fs::create_dir_all(&layer.work)?;
+        debug!("Covering {} with an overlay", layer.target.display());
+        layers.push(layer);
+    }
+    let result = place(&layers, &temp_dir, &path, &source_of(package), prefix, options).and_then(|install| {
+        let mut changes = Vec::new();
+        for layer in &layers {
+            changes.extend(changes_in(layer)?);
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(PreviewReport { install, changes })
+    });
+    // The kernel leaves a mode 000 directory in each work dir, which would
+    // keep the scratch directory from being removed.
+    for layer in &layers {
+        let _ = fs::set_permissions(layer.work.join("work"), fs::Permissions::from_mode(0o700));
+    }
+    result
+}
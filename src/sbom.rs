@@ -0,0 +1,298 @@
+use crate::archive::PkgInfo;
+use crate::paths::rooted;
+use crate::records::{InstalledPackage, get_log_path, installed_packages, read_installed_pkginfo};
+use anyhow::Result;
+use clap::ValueEnum;
+use log::warn;
+use serde_json::{Value, json};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SbomFormat {
+    Spdx,
+    Cyclonedx,
+}
+
+struct HashedFile {
+    path: String,
+    sha1: String,
+    sha256: String,
+}
+
+struct Entry {
+    package: InstalledPackage,
+    info: PkgInfo,
+    files: Vec<HashedFile>,
+}
+
+impl Entry {
+    fn version(&self) -> &str {
+        if self.info.version.is_empty() { "unknown" } else { &self.info.version }
+    }
+
+    fn purl(&self) -> String {
+        let mut purl = format!("pkg:alpm/arch/{}@{}", self.package.name, self.version());
+        if !self.info.arch.is_empty() {
+            purl.push_str(&format!("?arch={}", self.info.arch));
+        }
+        purl
+    }
+
+    /// Names of the package's dependencies without version constraints.
+    fn dependency_names(&self) -> impl Iterator<Item = &str> {
+        self.info
+            .depends
+            .iter()
+            .map(|dep| dep.split(['<', '>', '=']).next().unwrap_or(dep))
+    }
+}
+
+fn load_entries() -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for package in installed_packages()? {
+        let log = fs::read_to_string(get_log_path(&package.name, &package.prefix)).unwrap_or_default();
+        let mut files = Vec::new();
+        for line in log.lines() {
+            let path = rooted(line);
+            if path.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink()) {
+                continue;
+            }
+            match fs::read(&path) {
+                Ok(data) => files.push(HashedFile {
+                    path: line.to_string(),
+                    sha1: format!("{:x}", Sha1::digest(&data)),
+                    sha256: format!("{:x}", Sha256::digest(&data)),
+                }),
+                Err(err) => warn!("cannot hash {}: {}", path.display(), err),
+            }
+        }
+        entries.push(Entry {
+            info: read_installed_pkginfo(&package.name, &package.prefix).unwrap_or_default(),
+            package,
+            files,
+        });
+    }
+    Ok(entries)
+}
+
+/// Seconds since the epoch as an ISO 8601 UTC timestamp.
+pub fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil-from-days conversion for the proleptic Gregorian calendar.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// A random-looking but content-derived UUID, so documents for different
+/// machines and times get different identifiers.
+fn document_uuid(seed: &str) -> String {
+    let hash = Sha256::digest(seed.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// SPDX identifiers allow letters, digits, `.` and `-` only.
+fn spdx_id(kind: &str, value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '-' })
+        .collect();
+    format!("SPDXRef-{}-{}", kind, value)
+}
+
+/// Arch licenses are mostly SPDX identifiers already; anything else, like
+/// `custom`, cannot be asserted.
+fn spdx_license(licenses: &[String]) -> String {
+    let valid = !licenses.is_empty()
+        && licenses.iter().all(|license| {
+            !license.starts_with("custom")
+                && license.chars().all(|c| c.is_ascii_alphanumeric() || ".-+".contains(c))
+        });
+    if valid { licenses.join(" AND ") } else { "NOASSERTION".to_string() }
+}
+
+fn spdx(entries: &[Entry], created: &str, uuid: &str) -> Value {
+    let mut packages = Vec::new();
+    let mut files = Vec::new();
+    let mut relationships = Vec::new();
+    for entry in entries {
+        let id = spdx_id("Package", &format!("{}-{}", entry.package.name, entry.package.prefix));
+        let mut sha1s: Vec<&str> = entry.files.iter().map(|file| file.sha1.as_str()).collect();
+        sha1s.sort();
+        let verification = format!("{:x}", Sha1::digest(sha1s.concat().as_bytes()));
+        let mut package = json!({
+            "SPDXID": id,
+            "name": entry.package.name,
+            "versionInfo": entry.version(),
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": true,
+            "packageVerificationCode": { "packageVerificationCodeValue": verification },
+            "licenseConcluded": "NOASSERTION",
+            "licenseDeclared": spdx_license(&entry.info.licenses),
+            "copyrightText": "NOASSERTION",
+            "comment": format!("Installed into {}", entry.package.prefix),
+            "externalRefs": [{
+                "referenceCategory": "PACKAGE-MANAGER",
+                "referenceType": "purl",
+                "referenceLocator": entry.purl(),
+            }],
+        });
+        if !entry.info.description.is_empty() {
+            package["summary"] = json!(entry.info.description);
+        }
+        if !entry.info.url.is_empty() {
+            package["homepage"] = json!(entry.info.url);
+        }
+        packages.push(package);
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": id,
+        }));
+        for file in &entry.files {
+            let file_id = spdx_id("File", &format!("{}-{}", entry.package.name, files.len() + 1));
+            files.push(json!({
+                "SPDXID": file_id,
+                "fileName": format!(".{}", file.path),
+                "checksums": [
+                    { "algorithm": "SHA1", "checksumValue": file.sha1 },
+                    { "algorithm": "SHA256", "checksumValue": file.sha256 },
+                ],
+                "licenseConcluded": "NOASSERTION",
+                "copyrightText": "NOASSERTION",
+            }));
+            relationships.push(json!({
+                "spdxElementId": id,
+                "relationshipType": "CONTAINS",
+                "relatedSpdxElement": file_id,
+            }));
+        }
+        for dep in entry.dependency_names() {
+            for other in entries.iter().filter(|other| other.package.name == dep) {
+                relationships.push(json!({
+                    "spdxElementId": id,
+                    "relationshipType": "DEPENDS_ON",
+                    "relatedSpdxElement": spdx_id("Package", &format!("{}-{}", other.package.name, other.package.prefix)),
+                }));
+            }
+        }
+    }
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": "arch-installer installed packages",
+        "documentNamespace": format!("https://spdx.org/spdxdocs/arch-installer-{}", uuid),
+        "creationInfo": {
+            "created": created,
+            "creators": [format!("Tool: arch-installer-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "files": files,
+        "relationships": relationships,
+    })
+}
+
+fn cyclonedx(entries: &[Entry], created: &str, uuid: &str) -> Value {
+    let bom_ref = |entry: &Entry| format!("{}#{}", entry.purl(), entry.package.prefix);
+    let mut components = Vec::new();
+    let mut dependencies = Vec::new();
+    for entry in entries {
+        let files: Vec<Value> = entry
+            .files
+            .iter()
+            .map(|file| {
+                json!({
+                    "type": "file",
+                    "name": file.path,
+                    "hashes": [
+                        { "alg": "SHA-1", "content": file.sha1 },
+                        { "alg": "SHA-256", "content": file.sha256 },
+                    ],
+                })
+            })
+            .collect();
+        let mut component = json!({
+            "type": "application",
+            "bom-ref": bom_ref(entry),
+            "name": entry.package.name,
+            "version": entry.version(),
+            "purl": entry.purl(),
+            "properties": [{ "name": "arch-installer:prefix", "value": entry.package.prefix }],
+            "components": files,
+        });
+        if !entry.info.description.is_empty() {
+            component["description"] = json!(entry.info.description);
+        }
+        let license = spdx_license(&entry.info.licenses);
+        if license != "NOASSERTION" {
+            component["licenses"] = json!([{ "expression": license }]);
+        }
+        if !entry.info.url.is_empty() {
+            component["externalReferences"] = json!([{ "type": "website", "url": entry.info.url }]);
+        }
+        components.push(component);
+        let depends_on: Vec<String> = entry
+            .dependency_names()
+            .flat_map(|dep| entries.iter().filter(move |other| other.package.name == dep))
+            .map(bom_ref)
+            .collect();
+        dependencies.push(json!({ "ref": bom_ref(entry), "dependsOn": depends_on }));
+    }
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid),
+        "version": 1,
+        "metadata": {
+            "timestamp": created,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": "arch-installer",
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+        },
+        "components": components,
+        "dependencies": dependencies,
+    })
+}
+
+/// Builds a bill of materials for every installed package, hashing the files
+/// recorded in their installation logs.
+pub fn generate(format: SbomFormat) -> Result<Value> {
+    let entries = load_entries()?;
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let host = fs::read_to_string("/etc/hostname").unwrap_or_default();
+    let uuid = document_uuid(&format!("{}{}{}", host.trim(), secs, std::process::id()));
+    let created = timestamp(secs);
+    Ok(match format {
+        SbomFormat::Spdx => spdx(&entries, &created, &uuid),
+        SbomFormat::Cyclonedx => cyclonedx(&entries, &created, &uuid),
+    })
+}
@@ -0,0 +1,45 @@
+//! Labelling installed files on SELinux systems such as Fedora and RHEL.
+//! Copied files get the context of the directory they were extracted in,
+//! which the policy may block from running or being read; `restorecon` sets
+//! the one the policy defines for their installed paths.
+
+use crate::paths::get_root;
+use anyhow::{Context, Result, bail};
+use log::{debug, warn};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Whether SELinux is enabled, which it is when its file system is mounted.
+fn enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+/// Sets the default SELinux contexts on `paths`, when SELinux is enabled.
+/// Files installed into another root are left to that system's policy,
+/// which labels them on its next relabel.
+pub(crate) fn restore_contexts(paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() || !enabled() || get_root() != Path::new("/") {
+        return Ok(());
+    }
+    let restorecon = Command::new("restorecon").args(["-f", "-"]).stdin(Stdio::piped()).stderr(Stdio::piped()).spawn();
+    let mut child = match restorecon {
+        Ok(child) => child,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            warn!("SELinux is enabled but restorecon is missing; installed files keep their copied contexts");
+            return Ok(());
+        }
+        Err(err) => return Err(err).context("Failed to run restorecon"),
+    };
+    let mut stdin = child.stdin.take().unwrap();
+    for path in paths {
+        writeln!(stdin, "{}", path.display())?;
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("restorecon failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    debug!("Restored the SELinux contexts of {} files", paths.len());
+    Ok(())
+}
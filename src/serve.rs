@@ -0,0 +1,430 @@
+use anyhow::{Context, Result, anyhow, bail};
+use arch_installer::archive::{get_package_name, is_url};
+use arch_installer::error::{InstallerError, kind};
+use arch_installer::installer::{InstallOptions, install_package, uninstall_files};
+use arch_installer::output::ErrorReport;
+use arch_installer::query::{ListOptions, list_packages, search_packages, which_command};
+use arch_installer::records::{find_install_prefix, get_log_path, installed_packages, read_installed_pkginfo};
+use arch_installer::timings;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server, StatusCode};
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:7780";
+
+/// Where `repo serve` listens: every interface, for other machines.
+pub const DEFAULT_REPO_LISTEN: &str = "0.0.0.0:7781";
+
+/// Requests handled at once; changes are serialized by `CHANGES` anyway.
+const WORKERS: usize = 4;
+
+/// Held while a request changes installed packages.
+static CHANGES: Mutex<()> = Mutex::new(());
+
+/// Body of `POST /packages`.
+#[derive(Deserialize)]
+struct InstallRequest {
+    package: String,
+    #[serde(default = "default_prefix")]
+    prefix: String,
+    #[serde(flatten)]
+    options: InstallOptions,
+}
+
+fn default_prefix() -> String {
+    "/usr/local".to_string()
+}
+
+#[derive(Serialize)]
+struct Installation {
+    name: String,
+    prefix: String,
+    info: Option<arch_installer::PkgInfo>,
+}
+
+/// A failed request: the status to answer with and why.
+struct Failure {
+    status: u16,
+    error: anyhow::Error,
+}
+
+impl Failure {
+    fn new(status: u16, error: anyhow::Error) -> Self {
+        Failure { status, error }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(error: anyhow::Error) -> Self {
+        let status = match kind(&error) {
+            "not_installed" => 404,
+            "conflict" | "already_installed" | "setuid" | "invalid_desktop" => 409,
+            "bad_archive" | "ambiguous_prefix" => 400,
+            "needs_root" => 403,
+            "download" => 502,
+            "no_space" => 507,
+            _ => 500,
+        };
+        Failure::new(status, error)
+    }
+}
+
+/// Compares without returning early, so the time taken does not reveal how
+/// much of the token was right.
+fn same_token(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn is_authorized(request: &Request, token: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .is_some_and(|given| same_token(given.trim().as_bytes(), token.as_bytes()))
+}
+
+fn query_params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (form_decode(key), form_decode(value))
+        })
+        .collect()
+}
+
+/// Decodes a query string value, where `+` stands for a space.
+fn form_decode(text: &str) -> String {
+    percent_decode(&text.replace('+', " "))
+}
+
+/// Decodes `%XX` escapes only: a `+` in a path is a `+`, as in `libc++`.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reply<T: Serialize>(value: T) -> Result<Value, Failure> {
+    Ok(serde_json::to_value(value).map_err(anyhow::Error::from)?)
+}
+
+fn installations(name: &str) -> Result<Vec<Installation>, Failure> {
+    let found: Vec<Installation> = installed_packages()?
+        .into_iter()
+        .filter(|installed| installed.name == name)
+        .map(|installed| Installation {
+            info: read_installed_pkginfo(&installed.name, &installed.prefix),
+            name: installed.name,
+            prefix: installed.prefix,
+        })
+        .collect();
+    if found.is_empty() {
+        return Err(anyhow::Error::from(InstallerError::NotInstalled {
+            package: name.to_string(),
+            prefix: None,
+        })
+        .into());
+    }
+    Ok(found)
+}
+
+fn files(name: &str, prefix: Option<String>) -> Result<Value, Failure> {
+    let prefix = find_install_prefix(name, prefix)?;
+    let log = fs::read_to_string(get_log_path(name, &prefix))
+        .map_err(|err| Failure::new(404, anyhow!("{} is not installed in {}: {}", name, prefix, err)))?;
+    let files: Vec<&str> = log.lines().filter(|line| !line.is_empty()).collect();
+    Ok(json!({ "name": name, "prefix": prefix, "files": files }))
+}
+
+fn install(request: &mut Request) -> Result<Value, Failure> {
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body).context("Failed to read the request body")?;
+    let InstallRequest { package, prefix, mut options } =
+        serde_json::from_str(&body).map_err(|err| Failure::new(400, anyhow!("Malformed request: {}", err)))?;
+    // The server's working directory means nothing to the client.
+    if !is_url(&package) && !Path::new(&package).is_absolute() {
+        return Err(Failure::new(400, anyhow!("Package paths must be absolute: {}", package)));
+    }
+    info!("Installing {} into {} on behalf of {}", package, prefix, remote(request));
+    options.yes = true;
+    let _guard = CHANGES.lock().unwrap_or_else(|err| err.into_inner());
+    let result = install_package(&package, &prefix, &options);
+    timings::report();
+    reply(result?)
+}
+
+fn uninstall(request: &Request, name: &str, prefix: Option<String>) -> Result<Value, Failure> {
+    let _guard = CHANGES.lock().unwrap_or_else(|err| err.into_inner());
+    let prefix = find_install_prefix(&get_package_name(name), prefix)?;
+    info!("Uninstalling {} from {} on behalf of {}", name, prefix, remote(request));
+    reply(uninstall_files(name, &prefix, false)?)
+}
+
+fn remote(request: &Request) -> String {
+    request.remote_addr().map_or_else(|| "unknown".to_string(), |addr| addr.to_string())
+}
+
+/// The decoded segments of an API path, checked after decoding so that a
+/// package name cannot smuggle in a path.
+fn path_segments(path: &str) -> Result<Vec<String>, Failure> {
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    if let Some(bad) = segments.iter().find(|s| s.is_empty() || s.contains('/') || s.starts_with('.')) {
+        return Err(Failure::new(400, anyhow!("Invalid name in the path: {:?}", bad)));
+    }
+    Ok(segments)
+}
+
+fn route(request: &mut Request) -> Result<Value, Failure> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let mut params = query_params(query);
+    let segments = path_segments(path)?;
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    match (request.method(), segments.as_slice()) {
+        (Method::Get, ["packages"]) => reply(list_packages(&ListOptions {
+            prefix: params.remove("prefix"),
+            pattern: params.remove("name"),
+            ..Default::default()
+        })?),
+        (Method::Post, ["packages"]) => install(request),
+        (Method::Get, ["packages", name]) => reply(installations(name)?),
+        (Method::Delete, ["packages", name]) => uninstall(request, name, params.remove("prefix")),
+        (Method::Get, ["packages", name, "files"]) => files(name, params.remove("prefix")),
+        (Method::Get, ["search"]) => {
+            let query = params.remove("q").ok_or_else(|| Failure::new(400, anyhow!("Missing the q parameter")))?;
+            let files = params.get("files").is_some_and(|value| value == "1" || value == "true");
+            reply(search_packages(&query, files, params.get("prefix").map(String::as_str))?)
+        }
+        (Method::Get, ["which", command]) => reply(which_command(command)?),
+        (_, ["packages"] | ["packages", _] | ["packages", _, "files"] | ["search"] | ["which", _]) => {
+            Err(Failure::new(405, anyhow!("Method not allowed")))
+        }
+        _ => Err(Failure::new(404, anyhow!("No such endpoint: {}", path))),
+    }
+}
+
+fn respond(mut request: Request, token: &str) {
+    let (status, body) = if !is_authorized(&request, token) {
+        (401, json!(ErrorReport::from_error(&anyhow!("Missing or wrong bearer token"))))
+    } else {
+        match route(&mut request) {
+            Ok(value) => (200, value),
+            Err(failure) => {
+                warn!("{} {} failed: {:#}", request.method(), request.url(), failure.error);
+                (failure.status, json!(ErrorReport::from_error(&failure.error)))
+            }
+        }
+    };
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    if let Err(err) = request.respond(response) {
+        warn!("Failed to send a response: {}", err);
+    }
+}
+
+/// Reads the API token from `token_file`, or from `ARCH_INSTALLER_TOKEN`.
+fn load_token(token_file: Option<&PathBuf>) -> Result<String> {
+    let token = match token_file {
+        Some(path) => fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?,
+        None => std::env::var("ARCH_INSTALLER_TOKEN").unwrap_or_default(),
+    };
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        bail!("An API token is required: pass --token-file or set ARCH_INSTALLER_TOKEN");
+    }
+    Ok(token)
+}
+
+/// Handles requests on `listen` with `handle` in a few threads, until killed.
+fn serve_with(listen: &str, handle: impl Fn(Request) + Send + Sync + 'static) -> Result<()> {
+    let server = Arc::new(Server::http(listen).map_err(|err| anyhow!("Failed to listen on {}: {}", listen, err))?);
+    let handle = Arc::new(handle);
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let handle = Arc::clone(&handle);
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Serves the HTTP API on `listen` until killed.
+pub fn run(listen: &str, token_file: Option<&PathBuf>) -> Result<()> {
+    let token = load_token(token_file)?;
+    info!("Serving the HTTP API on http://{}", listen);
+    serve_with(listen, move |request| respond(request, &token))
+}
+
+/// The first and last byte of a `Range` header's single range within `size`
+/// bytes, or `None` when it cannot be satisfied. Several ranges are not
+/// supported and get the whole file, as HTTP allows.
+fn byte_range(value: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let Some(spec) = value.trim().strip_prefix("bytes=").filter(|spec| !spec.contains(',')) else {
+        return Some(None);
+    };
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size.checked_sub(1)?),
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size.checked_sub(1)?)),
+    };
+    (start <= end).then_some(Some((start, end)))
+}
+
+/// The file name a request for `url` asks the repository for.
+fn repo_file_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    percent_decode(path.trim_start_matches('/'))
+}
+
+/// The response to a request for a file of the repository in `dir`.
+fn repo_response(request: &Request, dir: &Path) -> Result<ResponseBox, u16> {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return Err(405);
+    }
+    let name = repo_file_name(request.url());
+    if name.is_empty() {
+        // A listing, for people poking at the mirror with a browser.
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .map_err(|_| 500u16)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with('.') && !name.ends_with(".part"))
+            .collect();
+        names.sort();
+        return Ok(Response::from_string(names.join("\n") + "\n").boxed());
+    }
+    if name.contains('/') || name.starts_with('.') {
+        return Err(404);
+    }
+    let mut file = File::open(dir.join(&name)).map_err(|_| 404u16)?;
+    let meta = file.metadata().map_err(|_| 500u16)?;
+    if !meta.is_file() {
+        return Err(404);
+    }
+    let size = meta.len();
+    let range = request.headers().iter().find(|header| header.field.equiv("Range"));
+    let (status, start, length) = match range.map(|header| byte_range(header.value.as_str(), size)) {
+        Some(Some(Some((start, end)))) => (206, start, end - start + 1),
+        Some(None) => {
+            let header = Header::from_bytes("Content-Range", format!("bytes */{}", size)).unwrap();
+            return Ok(Response::empty(416).with_header(header).boxed());
+        }
+        _ => (200, 0, size),
+    };
+    file.seek(SeekFrom::Start(start)).map_err(|_| 500u16)?;
+    let mut headers = vec![
+        Header::from_bytes("Accept-Ranges", "bytes").unwrap(),
+        Header::from_bytes("Content-Type", "application/octet-stream").unwrap(),
+    ];
+    if status == 206 {
+        let value = format!("bytes {}-{}/{}", start, start + length - 1, size);
+        headers.push(Header::from_bytes("Content-Range", value).unwrap());
+    }
+    let body: Box<dyn Read + Send> = Box::new(file.take(length));
+    Ok(Response::new(StatusCode(status), headers, body, Some(length as usize), None).boxed())
+}
+
+/// Serves the files of the repository in `dir` on `listen` until killed.
+pub fn run_repo(dir: &Path, listen: &str) -> Result<()> {
+    let dir = dir.to_path_buf();
+    info!("Serving {} on http://{}", dir.display(), listen);
+    serve_with(listen, move |request| {
+        let response = repo_response(&request, &dir).unwrap_or_else(|status| Response::empty(status).boxed());
+        debug!("{} {} {}: {}", remote(&request), request.method(), request.url(), response.status_code().0);
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to send a response: {}", err);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{byte_range, path_segments, repo_file_name};
+
+    #[test]
+    fn suffix_ranges_count_from_the_end() {
+        assert_eq!(byte_range("bytes=-100", 1000), Some(Some((900, 999))));
+        assert_eq!(byte_range("bytes=-5000", 1000), Some(Some((0, 999))));
+    }
+
+    #[test]
+    fn open_ranges_run_to_the_end() {
+        assert_eq!(byte_range("bytes=500-", 1000), Some(Some((500, 999))));
+        assert_eq!(byte_range("bytes=0-499", 1000), Some(Some((0, 499))));
+        assert_eq!(byte_range("bytes=900-5000", 1000), Some(Some((900, 999))));
+    }
+
+    #[test]
+    fn ranges_past_the_end_cannot_be_satisfied() {
+        assert_eq!(byte_range("bytes=1000-", 1000), None);
+        assert_eq!(byte_range("bytes=2000-3000", 1000), None);
+        assert_eq!(byte_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn several_ranges_get_the_whole_file() {
+        assert_eq!(byte_range("bytes=0-1,5-6", 1000), Some(None));
+    }
+
+    #[test]
+    fn garbage_is_refused_or_ignored() {
+        assert_eq!(byte_range("bytes=abc", 1000), None);
+        assert_eq!(byte_range("bytes=a-b", 1000), None);
+        assert_eq!(byte_range("bytes=500-100", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), Some(None));
+    }
+
+    #[test]
+    fn repo_file_names_keep_plus_signs() {
+        assert_eq!(repo_file_name("/libc++-17.0.6-1-x86_64.pkg.tar.zst"), "libc++-17.0.6-1-x86_64.pkg.tar.zst");
+        assert_eq!(repo_file_name("/memtest86%2B-6.20-1-any.pkg.tar.zst?x=1"), "memtest86+-6.20-1-any.pkg.tar.zst");
+        assert_eq!(repo_file_name("/gtk%20doc.db"), "gtk doc.db");
+    }
+
+    #[test]
+    fn path_segments_keep_plus_signs() {
+        assert_eq!(path_segments("/packages/libstdc++").ok(), Some(vec!["packages".into(), "libstdc++".into()]));
+        assert_eq!(path_segments("/which/g%2B%2B").ok(), Some(vec!["which".into(), "g++".into()]));
+    }
+
+    #[test]
+    fn path_segments_cannot_traverse() {
+        let paths =
+            ["/packages/..", "/packages/../files", "/packages/..%2F..%2Fvar%2Flog%2Fx/files", "/packages/a%2Fb"];
+        for path in paths {
+            assert_eq!(path_segments(path).err().map(|failure| failure.status), Some(400), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Making an extracted package smaller before it is installed: stripping
+//! debug symbols from ELF files (`--strip`) and gzipping man pages
+//! (`--compress-man`). Both rewrite the extracted tree, so the install log
+//! and checksums record the files as they were installed.
+
+use crate::convert::gzip;
+use crate::elf::is_elf_file;
+use crate::installer::InstallOptions;
+use crate::output::format_size;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use walkdir::WalkDir;
+
+/// Extensions of man pages that are already compressed.
+const COMPRESSED: [&str; 6] = ["gz", "bz2", "xz", "zst", "lzma", "Z"];
+
+/// Applies `--strip` and `--compress-man` to the package extracted to `tree`.
+pub(crate) fn shrink(tree: &Path, options: &InstallOptions) -> Result<()> {
+    let saved = AtomicU64::new(0);
+    if options.strip {
+        strip_debug(tree, &saved)?;
+    }
+    if options.compress_man {
+        compress_man_pages(tree, &saved)?;
+    }
+    if options.strip || options.compress_man {
+        info!("Saved {}", format_size(saved.load(Ordering::Relaxed)));
+    }
+    Ok(())
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::symlink_metadata(path).map(|meta| meta.len()).unwrap_or(0)
+}
+
+/// Strips debug symbols from the ELF files in `tree` with `strip --strip-debug`.
+fn strip_debug(tree: &Path, saved: &AtomicU64) -> Result<()> {
+    if let Err(err) = Command::new("strip").arg("--version").output() {
+        if err.kind() == ErrorKind::NotFound {
+            warn!("strip is not installed (it comes with binutils), installing binaries as they are");
+            return Ok(());
+        }
+        return Err(err.into());
+    }
+    let elves: Vec<PathBuf> = WalkDir::new(tree)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_file() && is_elf_file(entry.path()))
+        .map(|entry| entry.into_path())
+        .collect();
+    let stripped: usize = elves
+        .par_iter()
+        .map(|path| {
+            let before = file_size(path);
+            let output = Command::new("strip").arg("--strip-debug").arg(path).output()?;
+            if !output.status.success() {
+                // Firmware and other foreign objects may not be strippable;
+                // they are installed as they are.
+                debug!("Not stripping {}: {}", path.display(), String::from_utf8_lossy(&output.stderr).trim());
+                return Ok(0);
+            }
+            saved.fetch_add(before.saturating_sub(file_size(path)), Ordering::Relaxed);
+            Ok(1)
+        })
+        .sum::<Result<usize>>()?;
+    info!("Stripped debug symbols from {} files", stripped);
+    Ok(())
+}
+
+/// Gzips the man pages in `tree` and points symlinks to them at the
+/// compressed names.
+fn compress_man_pages(tree: &Path, saved: &AtomicU64) -> Result<()> {
+    let man_dir = tree.join("usr/share/man");
+    if !man_dir.is_dir() {
+        return Ok(());
+    }
+    let is_compressed = |path: &Path| {
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        extension.is_some_and(|extension| COMPRESSED.contains(&extension))
+    };
+    let entries: Vec<_> = WalkDir::new(&man_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| !entry.file_type().is_dir() && !is_compressed(entry.path()))
+        .collect();
+    let mut compressed = 0;
+    for entry in entries.iter().filter(|entry| entry.file_type().is_file()) {
+        let path = entry.path();
+        let content = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        let dest = gz_name(path);
+        fs::write(&dest, gzip(&content)?).context(format!("Failed to write {}", dest.display()))?;
+        fs::set_permissions(&dest, entry.metadata()?.permissions())?;
+        fs::remove_file(path)?;
+        saved.fetch_add((content.len() as u64).saturating_sub(file_size(&dest)), Ordering::Relaxed);
+        compressed += 1;
+    }
+    for entry in entries.iter().filter(|entry| entry.file_type().is_symlink()) {
+        let path = entry.path();
+        let target = fs::read_link(path)?;
+        let resolved = match target.strip_prefix("/") {
+            Ok(relative) => tree.join(relative),
+            Err(_) => path.parent().unwrap_or(tree).join(&target),
+        };
+        // Every uncompressed name under the man directory gains `.gz`, links
+        // included, so links within it keep working.
+        if !resolved.starts_with(&man_dir) {
+            continue;
+        }
+        debug!("Pointing {} at the compressed {}", path.display(), target.display());
+        fs::remove_file(path)?;
+        symlink(gz_name(&target), gz_name(path))?;
+    }
+    info!("Compressed {} man pages", compressed);
+    Ok(())
+}
+
+fn gz_name(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
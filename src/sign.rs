@@ -0,0 +1,44 @@
+//! Detached OpenPGP signatures for package files, in the binary `.sig` form
+//! pacman and `repo-add` look for next to a package. Signing is left to
+//! GnuPG, or to Sequoia's `sq` where GnuPG is not installed, so secret keys
+//! stay in the user's keyring or agent.
+
+use anyhow::{Context, Result, bail};
+use log::debug;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Signs the file at `path` with `key`, or the `GPGKEY` environment variable
+/// as in `makepkg`, or else the default key, and returns where the
+/// signature was written: `path` with `.sig` appended.
+pub fn sign_file(path: &Path, key: Option<&str>) -> Result<PathBuf> {
+    let signature = PathBuf::from(format!("{}.sig", path.display()));
+    let key = key.map(str::to_string).or_else(|| std::env::var("GPGKEY").ok().filter(|key| !key.is_empty()));
+    let mut gpg = Command::new("gpg");
+    gpg.args(["--yes", "--detach-sign", "--use-agent", "--no-armor"]);
+    if let Some(key) = &key {
+        gpg.arg("--local-user").arg(key);
+    }
+    debug!("Signing {} with gpg", path.display());
+    let output = match gpg.arg("--output").arg(&signature).arg(path).output() {
+        Ok(output) => output,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let Some(key) = &key else {
+                bail!("gpg is not installed, and signing with sq needs a key; pass --key or set GPGKEY");
+            };
+            debug!("Signing {} with sq", path.display());
+            Command::new("sq")
+                .args(["sign", "--binary", "--signer", key, "--signature-file"])
+                .arg(&signature)
+                .arg(path)
+                .output()
+                .context("Neither gpg nor sq is installed to sign with")?
+        }
+        Err(err) => return Err(err).context("Failed to run gpg"),
+    };
+    if !output.status.success() {
+        bail!("Signing {} failed: {}", path.display(), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(signature)
+}
@@ -0,0 +1,62 @@
+//! Split packages: the packages one PKGBUILD builds, such as `python` and
+//! `python-tests`, which share the `pkgbase` of their `.PKGINFO` and are
+//! usually meant to be installed together.
+
+use crate::archive::read_pkginfo;
+use crate::records::{InstalledPackage, installed_packages, read_installed_pkginfo};
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs;
+use std::path::Path;
+
+/// The package files in the directory of `package` built from the same
+/// PKGBUILD run: same `pkgbase` and version. `package` comes first.
+pub fn sibling_files(package: &str) -> Result<Vec<String>> {
+    let info = read_pkginfo(package)?;
+    let mut files = vec![package.to_string()];
+    if info.pkgbase.is_empty() {
+        return Ok(files);
+    }
+    let dir = Path::new(package).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .context(format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().contains(".pkg.tar")))
+        .filter(|path| !path.to_string_lossy().ends_with(".sig"))
+        .collect();
+    entries.sort();
+    for path in entries {
+        let path = path.display().to_string();
+        if fs::canonicalize(&path).ok() == fs::canonicalize(package).ok() {
+            continue;
+        }
+        match read_pkginfo(&path) {
+            Ok(other) if other.pkgbase == info.pkgbase && other.version == info.version => files.push(path),
+            Ok(_) => {}
+            Err(err) => debug!("Skipping {}: {:#}", path, err),
+        }
+    }
+    Ok(files)
+}
+
+/// Installed packages built from `pkgbase`, in any prefix.
+pub fn installed_with_base(pkgbase: &str) -> Result<Vec<InstalledPackage>> {
+    Ok(installed_packages()?
+        .into_iter()
+        .filter(|package| {
+            read_installed_pkginfo(&package.name, &package.prefix).is_some_and(|info| info.pkgbase == pkgbase)
+        })
+        .collect())
+}
+
+/// The other packages installed into `prefix` with the same `pkgbase` as
+/// `name`.
+pub fn installed_siblings(name: &str, prefix: &str) -> Result<Vec<InstalledPackage>> {
+    match read_installed_pkginfo(name, prefix).map(|info| info.pkgbase) {
+        Some(pkgbase) if !pkgbase.is_empty() => Ok(installed_with_base(&pkgbase)?
+            .into_iter()
+            .filter(|package| package.prefix == prefix && package.name != name)
+            .collect()),
+        _ => Ok(Vec::new()),
+    }
+}
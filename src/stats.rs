@@ -0,0 +1,74 @@
+use crate::output::{PackageSize, PrefixStats, StatsReport};
+use crate::paths::{get_cache_dir, rooted};
+use crate::records::{get_log_path, installed_packages, read_installed_pkginfo};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// How many of the largest packages the report lists.
+const LARGEST: usize = 10;
+
+/// Sum of the sizes of the regular files recorded for a package that still
+/// exist; symlinks and missing files count as nothing.
+fn installed_size(name: &str, prefix: &str) -> Result<(usize, u64)> {
+    let log = fs::read_to_string(get_log_path(name, prefix))?;
+    let mut files = 0;
+    let mut size = 0;
+    for path in log.lines().filter(|line| !line.is_empty()) {
+        files += 1;
+        if let Ok(meta) = rooted(path).symlink_metadata()
+            && meta.is_file()
+        {
+            size += meta.len();
+        }
+    }
+    Ok((files, size))
+}
+
+pub fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+pub fn collect() -> Result<StatsReport> {
+    let mut packages = Vec::new();
+    let mut prefixes: BTreeMap<String, PrefixStats> = BTreeMap::new();
+    for installed in installed_packages()? {
+        let (files, size) = installed_size(&installed.name, &installed.prefix)?;
+        let stats = prefixes.entry(installed.prefix.clone()).or_insert_with(|| PrefixStats {
+            prefix: installed.prefix.clone(),
+            packages: 0,
+            files: 0,
+            size: 0,
+        });
+        stats.packages += 1;
+        stats.files += files;
+        stats.size += size;
+        packages.push(PackageSize {
+            version: read_installed_pkginfo(&installed.name, &installed.prefix)
+                .map(|pkginfo| pkginfo.version)
+                .unwrap_or_default(),
+            name: installed.name,
+            prefix: installed.prefix,
+            files,
+            size,
+        });
+    }
+    let prefixes: Vec<PrefixStats> = prefixes.into_values().collect();
+    packages.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    Ok(StatsReport {
+        packages: packages.len(),
+        files: prefixes.iter().map(|stats| stats.files).sum(),
+        size: prefixes.iter().map(|stats| stats.size).sum(),
+        largest: packages.into_iter().take(LARGEST).collect(),
+        prefixes,
+        cache_size: dir_size(&get_cache_dir()),
+    })
+}
@@ -0,0 +1,72 @@
+use crate::archive::sha256_file;
+use crate::installer::copy_file;
+use crate::output::format_size;
+use crate::paths::get_cache_dir;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use nix::errno::Errno;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Content-addressed copies of installed files, named by SHA-256 and mode,
+/// since hardlinks share the mode too. Installed files are hardlinks to
+/// these, so identical files across packages and versions take space once. Removing the store never breaks installed
+/// files; it only loses the sharing.
+fn store_dir() -> PathBuf {
+    get_cache_dir().join("store")
+}
+
+/// Places `src` at `dest` with `mode` as a hardlink to its store entry,
+/// adding the entry first if needed. Falls back to a copy when the store is
+/// on another file system than `dest`.
+pub fn install(src: &Path, dest: &Path, mode: u32) -> Result<()> {
+    let hash = sha256_file(src)?;
+    let entry = store_dir().join(&hash[..2]).join(format!("{}-{:o}", &hash[2..], mode));
+    if entry.exists() {
+        debug!("{} is already in the store", dest.display());
+    } else {
+        let dir = entry.parent().unwrap();
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+        // Parallel installs may add the same entry; the rename makes that safe.
+        let temp = tempfile::Builder::new().prefix(".").tempfile_in(dir)?.into_temp_path();
+        fs::remove_file(&temp)?;
+        copy_file(src, &temp)?;
+        fs::set_permissions(&temp, fs::Permissions::from_mode(mode))?;
+        temp.persist(&entry)?;
+    }
+    match fs::hard_link(&entry, dest) {
+        Ok(()) => Ok(()),
+        Err(err) if err.raw_os_error() == Some(Errno::EXDEV as i32) => {
+            debug!("{} is on another file system than the store, copying", dest.display());
+            copy_file(src, dest)?;
+            Ok(fs::set_permissions(dest, fs::Permissions::from_mode(mode))?)
+        }
+        Err(err) => Err(err).context(format!("Failed to link {}", dest.display())),
+    }
+}
+
+/// Deletes store entries no installed file links to any more.
+pub fn prune() -> Result<()> {
+    let dir = store_dir();
+    if !dir.exists() {
+        return Ok(());
+    }
+    let mut removed = 0;
+    let mut freed = 0;
+    for entry in WalkDir::new(&dir).min_depth(2).into_iter().filter_map(|e| e.ok()) {
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_file() && meta.nlink() == 1 {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+            freed += meta.len();
+        }
+    }
+    if removed > 0 {
+        info!("Removed {} unused files ({}) from the store", removed, format_size(freed));
+    }
+    Ok(())
+}
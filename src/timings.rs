@@ -0,0 +1,118 @@
+use crate::output::format_size;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Time and bytes spent in each phase since the last report.
+static PHASES: Mutex<Vec<(Phase, Duration, u64)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Download,
+    Decompress,
+    Extract,
+    Verify,
+    Copy,
+    PostHooks,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [
+        Phase::Download,
+        Phase::Decompress,
+        Phase::Extract,
+        Phase::Verify,
+        Phase::Copy,
+        Phase::PostHooks,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Download => "download",
+            Phase::Decompress => "decompress",
+            Phase::Extract => "extract",
+            Phase::Verify => "verify",
+            Phase::Copy => "copy",
+            Phase::PostHooks => "post-hooks",
+        }
+    }
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Adds `elapsed` and `bytes` to `phase`. Repeated phases, such as the
+/// downloads of an import, add up.
+pub fn record(phase: Phase, elapsed: Duration, bytes: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut phases = PHASES.lock().unwrap();
+    match phases.iter_mut().find(|(recorded, _, _)| *recorded == phase) {
+        Some(entry) => {
+            entry.1 += elapsed;
+            entry.2 += bytes;
+        }
+        None => phases.push((phase, elapsed, bytes)),
+    }
+}
+
+/// Runs `f` and records its duration under `phase`.
+pub fn time<T>(phase: Phase, bytes: u64, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    record(phase, start.elapsed(), bytes);
+    result
+}
+
+/// Prints the phases recorded since the last report to stderr, so it never
+/// mixes with `--json` output, and starts over.
+pub fn report() {
+    let phases = std::mem::take(&mut *PHASES.lock().unwrap());
+    if phases.is_empty() {
+        return;
+    }
+    eprintln!("Timings:");
+    let mut total = Duration::ZERO;
+    for phase in Phase::ALL {
+        let Some(&(_, elapsed, bytes)) = phases.iter().find(|(recorded, _, _)| *recorded == phase) else {
+            continue;
+        };
+        total += elapsed;
+        let throughput = if bytes > 0 && !elapsed.is_zero() {
+            format!("{}/s", format_size((bytes as f64 / elapsed.as_secs_f64()) as u64))
+        } else {
+            "-".to_string()
+        };
+        let size = if bytes > 0 { format_size(bytes) } else { "-".to_string() };
+        eprintln!("  {:<11} {:>9.3}s {:>11} {:>13}", phase.name(), elapsed.as_secs_f64(), size, throughput);
+    }
+    eprintln!("  {:<11} {:>9.3}s", "total", total.as_secs_f64());
+}
+
+/// Counts the time spent inside `read` of the wrapped reader and the bytes it
+/// returned, which separates decompression from writing the extracted files.
+pub struct TimedReader<R> {
+    inner: R,
+    pub elapsed: Duration,
+    pub bytes: u64,
+}
+
+impl<R> TimedReader<R> {
+    pub fn new(inner: R) -> Self {
+        TimedReader { inner, elapsed: Duration::ZERO, bytes: 0 }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let read = self.inner.read(buf)?;
+        self.elapsed += start.elapsed();
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
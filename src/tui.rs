@@ -0,0 +1,383 @@
+use anyhow::Result;
+use arch_installer::archive::PkgInfo;
+use arch_installer::installer::uninstall_files;
+use arch_installer::output::{self, format_size};
+use arch_installer::paths::rooted;
+use arch_installer::records::{InstalledPackage, get_log_path, installed_packages, read_installed_pkginfo};
+use ratatui::DefaultTerminal;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::{Line, Text};
+use ratatui::widgets::{Block, Borders, Clear, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::Frame;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+struct PackageEntry {
+    package: InstalledPackage,
+    info: Option<PkgInfo>,
+    files: Vec<PathBuf>,
+    size: u64,
+}
+
+impl PackageEntry {
+    fn load(package: InstalledPackage) -> Self {
+        let files: Vec<PathBuf> = fs::read_to_string(get_log_path(&package.name, &package.prefix))
+            .unwrap_or_default()
+            .lines()
+            .map(rooted)
+            .collect();
+        let size = files
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum();
+        PackageEntry {
+            info: read_installed_pkginfo(&package.name, &package.prefix),
+            package,
+            files,
+            size,
+        }
+    }
+
+    fn version(&self) -> &str {
+        self.info
+            .as_ref()
+            .map(|info| info.version.as_str())
+            .filter(|version| !version.is_empty())
+            .unwrap_or("unknown")
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        let filter = filter.to_lowercase();
+        self.package.name.to_lowercase().contains(&filter)
+            || self
+                .info
+                .as_ref()
+                .is_some_and(|info| info.description.to_lowercase().contains(&filter))
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Mode {
+    Browse,
+    Search,
+    Details,
+    ConfirmUninstall,
+}
+
+enum Exit {
+    Quit,
+    Uninstall(Vec<InstalledPackage>),
+}
+
+struct App {
+    entries: Vec<PackageEntry>,
+    prefix: Option<String>,
+    filter: String,
+    selected: BTreeSet<InstalledPackage>,
+    table: TableState,
+    mode: Mode,
+    details_scroll: u16,
+    status: String,
+}
+
+impl App {
+    fn new(prefix: Option<&str>) -> Result<Self> {
+        let mut app = App {
+            entries: Vec::new(),
+            prefix: prefix.map(str::to_string),
+            filter: String::new(),
+            selected: BTreeSet::new(),
+            table: TableState::default(),
+            mode: Mode::Browse,
+            details_scroll: 0,
+            status: String::new(),
+        };
+        app.reload()?;
+        Ok(app)
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        self.entries = installed_packages()?
+            .into_iter()
+            .filter(|package| self.prefix.as_ref().is_none_or(|prefix| &package.prefix == prefix))
+            .map(PackageEntry::load)
+            .collect();
+        self.selected
+            .retain(|package| self.entries.iter().any(|entry| &entry.package == package));
+        self.clamp_cursor();
+        Ok(())
+    }
+
+    fn visible(&self) -> Vec<&PackageEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.matches(&self.filter))
+            .collect()
+    }
+
+    fn current(&self) -> Option<&PackageEntry> {
+        self.table
+            .selected()
+            .and_then(|index| self.visible().get(index).copied())
+    }
+
+    fn clamp_cursor(&mut self) {
+        let len = self.visible().len();
+        if len == 0 {
+            self.table.select(None);
+        } else {
+            let index = self.table.selected().unwrap_or(0).min(len - 1);
+            self.table.select(Some(index));
+        }
+    }
+
+    fn move_cursor(&mut self, delta: isize) {
+        let len = self.visible().len();
+        if len == 0 {
+            return;
+        }
+        let index = self.table.selected().unwrap_or(0) as isize + delta;
+        self.table.select(Some(index.clamp(0, len as isize - 1) as usize));
+    }
+
+    fn toggle_selected(&mut self) {
+        if let Some(package) = self.current().map(|entry| entry.package.clone())
+            && !self.selected.remove(&package)
+        {
+            self.selected.insert(package);
+        }
+    }
+
+    /// Packages an uninstall would act on: the marked ones, or the one under
+    /// the cursor when nothing is marked.
+    fn uninstall_targets(&self) -> Vec<InstalledPackage> {
+        if self.selected.is_empty() {
+            self.current().map(|entry| vec![entry.package.clone()]).unwrap_or_default()
+        } else {
+            self.selected.iter().cloned().collect()
+        }
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<Exit> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match self.mode {
+                Mode::Browse => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(Exit::Quit),
+                    KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+                    KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+                    KeyCode::PageDown => self.move_cursor(10),
+                    KeyCode::PageUp => self.move_cursor(-10),
+                    KeyCode::Char(' ') => {
+                        self.toggle_selected();
+                        self.move_cursor(1);
+                    }
+                    KeyCode::Char('/') => self.mode = Mode::Search,
+                    KeyCode::Enter if self.current().is_some() => {
+                        self.details_scroll = 0;
+                        self.mode = Mode::Details;
+                    }
+                    KeyCode::Char('u') | KeyCode::Delete => {
+                        if self.uninstall_targets().is_empty() {
+                            self.status = "Nothing to uninstall".to_string();
+                        } else {
+                            self.mode = Mode::ConfirmUninstall;
+                        }
+                    }
+                    _ => {}
+                },
+                Mode::Search => match key.code {
+                    KeyCode::Enter => self.mode = Mode::Browse,
+                    KeyCode::Esc => {
+                        self.filter.clear();
+                        self.mode = Mode::Browse;
+                        self.clamp_cursor();
+                    }
+                    KeyCode::Backspace => {
+                        self.filter.pop();
+                        self.clamp_cursor();
+                    }
+                    KeyCode::Char(c) => {
+                        self.filter.push(c);
+                        self.table.select(Some(0));
+                        self.clamp_cursor();
+                    }
+                    _ => {}
+                },
+                Mode::Details => match key.code {
+                    KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => self.mode = Mode::Browse,
+                    KeyCode::Down | KeyCode::Char('j') => {
+                        self.details_scroll = self.details_scroll.saturating_add(1)
+                    }
+                    KeyCode::Up | KeyCode::Char('k') => {
+                        self.details_scroll = self.details_scroll.saturating_sub(1)
+                    }
+                    _ => {}
+                },
+                Mode::ConfirmUninstall => match key.code {
+                    KeyCode::Char('y') | KeyCode::Char('Y') => {
+                        self.mode = Mode::Browse;
+                        return Ok(Exit::Uninstall(self.uninstall_targets()));
+                    }
+                    _ => self.mode = Mode::Browse,
+                },
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [search_area, body_area, status_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let search_style = if self.mode == Mode::Search {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        let search = Paragraph::new(self.filter.as_str())
+            .style(search_style)
+            .block(Block::default().borders(Borders::ALL).title(" Search (/) "));
+        frame.render_widget(search, search_area);
+
+        let visible = self.visible();
+        let rows: Vec<Row> = visible
+            .iter()
+            .map(|entry| {
+                let mark = if self.selected.contains(&entry.package) { "[x]" } else { "[ ]" };
+                Row::new(vec![
+                    mark.to_string(),
+                    entry.package.name.clone(),
+                    entry.version().to_string(),
+                    entry.package.prefix.clone(),
+                    entry.files.len().to_string(),
+                    format_size(entry.size),
+                ])
+            })
+            .collect();
+        let title = format!(" Installed packages ({}/{}) ", visible.len(), self.entries.len());
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(3),
+                Constraint::Fill(2),
+                Constraint::Fill(1),
+                Constraint::Fill(2),
+                Constraint::Length(6),
+                Constraint::Length(10),
+            ],
+        )
+        .header(Row::new(vec!["", "Name", "Version", "Prefix", "Files", "Size"]).bold())
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, body_area, &mut self.table);
+
+        let help = if self.status.is_empty() {
+            "↑/↓ move  space select  enter details  / search  u uninstall  q quit".to_string()
+        } else {
+            self.status.clone()
+        };
+        frame.render_widget(Paragraph::new(help).dim(), status_area);
+
+        match self.mode {
+            Mode::Details => self.draw_details(frame, body_area),
+            Mode::ConfirmUninstall => self.draw_confirm(frame),
+            _ => {}
+        }
+    }
+
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        let Some(entry) = self.current() else {
+            return;
+        };
+        let mut lines = vec![
+            Line::from(format!("Name:        {}", entry.package.name)),
+            Line::from(format!("Version:     {}", entry.version())),
+            Line::from(format!("Prefix:      {}", entry.package.prefix)),
+        ];
+        if let Some(info) = &entry.info {
+            lines.push(Line::from(format!("Description: {}", info.description)));
+            lines.push(Line::from(format!("URL:         {}", info.url)));
+            lines.push(Line::from(format!("Arch:        {}", info.arch)));
+            lines.push(Line::from(format!("Depends:     {}", info.depends.join(", "))));
+        }
+        lines.push(Line::from(format!("Size:        {}", format_size(entry.size))));
+        lines.push(Line::from(""));
+        lines.push(Line::from(format!("Files ({}):", entry.files.len())).bold());
+        for file in &entry.files {
+            let line = Line::from(format!("  {}", file.display()));
+            lines.push(if file.exists() { line } else { line.red() });
+        }
+        let details = Paragraph::new(Text::from(lines))
+            .wrap(Wrap { trim: false })
+            .scroll((self.details_scroll, 0))
+            .block(Block::default().borders(Borders::ALL).title(" Details (esc to close) "));
+        frame.render_widget(Clear, area);
+        frame.render_widget(details, area);
+    }
+
+    fn draw_confirm(&self, frame: &mut Frame) {
+        let targets = self.uninstall_targets();
+        let names: Vec<String> = targets
+            .iter()
+            .map(|package| format!("{} ({})", package.name, package.prefix))
+            .collect();
+        let text = format!("Uninstall {}? [y/N]", names.join(", "));
+        let [area] = Layout::horizontal([Constraint::Percentage(60)])
+            .flex(Flex::Center)
+            .areas(frame.area());
+        let [area] = Layout::vertical([Constraint::Length(5)])
+            .flex(Flex::Center)
+            .areas(area);
+        let popup = Paragraph::new(text)
+            .wrap(Wrap { trim: true })
+            .block(Block::default().borders(Borders::ALL).title(" Confirm "));
+        frame.render_widget(Clear, area);
+        frame.render_widget(popup, area);
+    }
+}
+
+/// Runs the package browser. Uninstalls happen outside the alternate screen
+/// so their regular output stays readable; the browser resumes afterwards.
+pub fn run(prefix: Option<&str>) -> Result<()> {
+    let mut app = App::new(prefix)?;
+    loop {
+        let mut terminal = ratatui::init();
+        let exit = app.event_loop(&mut terminal);
+        ratatui::restore();
+        match exit? {
+            Exit::Quit => return Ok(()),
+            Exit::Uninstall(packages) => {
+                let mut failed = 0;
+                for package in &packages {
+                    if let Err(err) = uninstall_files(&package.name, &package.prefix, false) {
+                        output::print_error(&err);
+                        failed += 1;
+                    }
+                }
+                app.status = format!(
+                    "Uninstalled {} of {} package(s)",
+                    packages.len() - failed,
+                    packages.len()
+                );
+                output::prompt("Press Enter to return to the package list")?;
+                io::stdin().read_line(&mut String::new())?;
+                app.reload()?;
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Packages built for distributions without a merged `/usr` ship files in
+//! `bin`, `sbin` and `lib` at the top of the package, and many ship programs
+//! in `usr/sbin`. The installer only looks in `usr/bin` and `usr/lib`, so
+//! such files are moved there first, unless the host keeps `sbin` apart and
+//! the layout allows giving them a `sbin` directory of the prefix too.
+
+use crate::installer::InstallOptions;
+use crate::paths::rooted;
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Directories of the package moved into others, which are under `usr`.
+const MERGED: [(&str, &str); 7] = [
+    ("bin", "usr/bin"),
+    ("sbin", "usr/sbin"),
+    ("lib", "usr/lib"),
+    ("lib64", "usr/lib"),
+    ("lib32", "usr/lib32"),
+    ("usr/lib64", "usr/lib"),
+    ("usr/sbin", "usr/bin"),
+];
+
+/// Whether `dir` of the target system is a symlink to `target`, relative or
+/// absolute, as the merged directories are.
+fn links_to(dir: &str, target: &str) -> bool {
+    fs::read_link(rooted(dir)).is_ok_and(|link| {
+        let link = link.to_string_lossy();
+        link.trim_start_matches('/') == target || link == Path::new(target).file_name().unwrap().to_string_lossy()
+    })
+}
+
+/// Whether the host keeps programs of `/usr/sbin` apart from `/usr/bin`, as
+/// Debian does; Arch and newer Fedora link one to the other.
+fn separate_sbin() -> bool {
+    rooted("/usr/sbin").is_dir() && !links_to("/usr/sbin", "usr/bin")
+}
+
+/// Moves the files in the package extracted to `tree` from the directories
+/// of split layouts into `usr/bin` and `usr/lib`. `usr/sbin` only stays when
+/// the host has a separate `sbin` and the files are placed directly.
+pub(crate) fn merge(tree: &Path, options: &InstallOptions) -> Result<()> {
+    let keep_sbin = separate_sbin() && !options.stow && !options.wrappers;
+    for (from, to) in MERGED {
+        let from_dir = tree.join(from);
+        // A link to the merged directory, as `lib64 -> usr/lib` in Arch's
+        // glibc, has nothing of its own to move.
+        if (from == "usr/sbin" && keep_sbin) || !from_dir.symlink_metadata().is_ok_and(|meta| meta.is_dir()) {
+            continue;
+        }
+        debug!("Moving {} into {}", from, to);
+        for entry in WalkDir::new(&from_dir).min_depth(1).contents_first(true) {
+            let entry = entry?;
+            let dest = tree.join(to).join(entry.path().strip_prefix(&from_dir)?);
+            if entry.file_type().is_dir() {
+                let _ = fs::remove_dir(entry.path());
+                continue;
+            }
+            // Split packages often link the old place to the new one or the
+            // other way around; the real file wins.
+            if dest.symlink_metadata().is_ok_and(|meta| !meta.file_type().is_symlink()) {
+                debug!("{} is also in {}, keeping that", entry.path().display(), to);
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::rename(entry.path(), &dest).context(format!("Failed to move {}", entry.path().display()))?;
+        }
+        fs::remove_dir(&from_dir).context(format!("Failed to remove {}", from_dir.display()))?;
+    }
+    Ok(())
+}
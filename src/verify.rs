@@ -0,0 +1,91 @@
+use crate::archive::sha256_file;
+use crate::output::{self, PackageVerification, VerifyReport};
+use crate::paths::rooted;
+use crate::records::{InstalledPackage, get_checksums_path, get_log_path};
+use anyhow::Result;
+use log::trace;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+enum Outcome {
+    Ok,
+    Missing,
+    Modified,
+}
+
+/// Checks that every file recorded for `packages` still exists and, where a
+/// checksum was recorded, still has the same contents. Files of all packages
+/// are hashed together on every core.
+pub fn verify(packages: &[InstalledPackage]) -> Result<VerifyReport> {
+    let mut report = VerifyReport { packages: Vec::new() };
+    let mut work = Vec::new();
+    for (index, package) in packages.iter().enumerate() {
+        let log = fs::read_to_string(get_log_path(&package.name, &package.prefix))?;
+        let checksums = fs::read_to_string(get_checksums_path(&package.name, &package.prefix)).ok();
+        let expected: HashMap<String, String> = checksums
+            .iter()
+            .flat_map(|text| text.lines())
+            .filter_map(|line| line.split_once("  "))
+            .map(|(hash, path)| (path.to_string(), hash.to_string()))
+            .collect();
+        for line in log.lines() {
+            work.push((index, line.to_string(), expected.get(line).cloned()));
+        }
+        report.packages.push(PackageVerification {
+            name: package.name.clone(),
+            prefix: package.prefix.clone(),
+            checksums: checksums.is_some(),
+            checked: 0,
+            missing: Vec::new(),
+            modified: Vec::new(),
+        });
+    }
+
+    let total_bytes = work
+        .iter()
+        .filter(|(_, _, hash)| hash.is_some())
+        .filter_map(|(_, path, _)| fs::metadata(rooted(path)).ok())
+        .map(|meta| meta.len())
+        .sum();
+    let bar = output::files_bar(total_bytes, work.len(), "Verifying");
+    let done = AtomicUsize::new(0);
+    let outcomes: Vec<Outcome> = work
+        .par_iter()
+        .map(|(_, path, expected)| {
+            let path = rooted(path);
+            let size = fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+            let outcome = if path.symlink_metadata().is_err() {
+                Outcome::Missing
+            } else {
+                match expected {
+                    Some(hash) => match sha256_file(&path) {
+                        Ok(actual) if actual == *hash => Outcome::Ok,
+                        Ok(actual) => {
+                            trace!("{}: expected {}, found {}", path.display(), hash, actual);
+                            Outcome::Modified
+                        }
+                        // Unreadable contents cannot be vouched for.
+                        Err(_) => Outcome::Modified,
+                    },
+                    None => Outcome::Ok,
+                }
+            };
+            output::advance_files(&bar, done.fetch_add(1, Ordering::Relaxed) + 1, work.len(), size);
+            outcome
+        })
+        .collect();
+    output::finish_bar(&bar);
+
+    for ((index, path, _), outcome) in work.into_iter().zip(outcomes) {
+        let package = &mut report.packages[index];
+        package.checked += 1;
+        match outcome {
+            Outcome::Ok => {}
+            Outcome::Missing => package.missing.push(path),
+            Outcome::Modified => package.modified.push(path),
+        }
+    }
+    Ok(report)
+}